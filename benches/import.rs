@@ -0,0 +1,90 @@
+//! Import throughput: a layer with lots of small files (the common case for OS images), imported
+//! with oci::import_layer() and, for comparison, by storing each file with ensure_object().
+//!
+//! Set CFS_TEST_TMPDIR to a directory on a filesystem with fs-verity support to benchmark a
+//! regular repository.  Otherwise an insecure repository in a temporary directory is used.
+
+use std::{io::Read, path::PathBuf};
+
+use anyhow::Result;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+
+use composefs::{
+    fsverity::Sha256HashValue,
+    oci,
+    repository::{Repository, RepositoryOptions},
+};
+
+const FILES: usize = 10000;
+const FILE_SIZE: usize = 4096;
+
+/// A tar file containing FILES distinct files of FILE_SIZE bytes each
+fn fixture() -> Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(vec![]);
+    for i in 0..FILES {
+        let mut content = vec![0u8; FILE_SIZE];
+        content[..8].copy_from_slice(&(i as u64).to_le_bytes());
+        let mut header = tar::Header::new_ustar();
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(FILE_SIZE as u64);
+        builder.append_data(&mut header, format!("file{i}"), content.as_slice())?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+fn new_repo() -> Result<(TempDir, Repository<Sha256HashValue>)> {
+    let mut options = RepositoryOptions::default();
+    let tmpdir = match std::env::var_os("CFS_TEST_TMPDIR") {
+        Some(path) => TempDir::with_prefix_in("composefs-bench-", PathBuf::from(path))?,
+        None => {
+            options.insecure = true;
+            TempDir::with_prefix("composefs-bench-")?
+        }
+    };
+    let repo = Repository::init(tmpdir.path().join("repo"), &options)?;
+    Ok((tmpdir, repo))
+}
+
+fn import(c: &mut Criterion) {
+    let layer = fixture().unwrap();
+    let layer_sha256: Sha256HashValue = Sha256::digest(&layer).into();
+
+    let mut group = c.benchmark_group("import");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(layer.len() as u64));
+
+    group.bench_function("import_layer", |b| {
+        b.iter_batched(
+            || new_repo().unwrap(),
+            |(tmpdir, repo)| {
+                oci::import_layer(&repo, &layer_sha256, None, &mut layer.as_slice()).unwrap();
+                (tmpdir, repo) // don't time the cleanup
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("ensure_object", |b| {
+        b.iter_batched(
+            || new_repo().unwrap(),
+            |(tmpdir, repo)| {
+                let mut archive = tar::Archive::new(layer.as_slice());
+                for entry in archive.entries().unwrap() {
+                    let mut data = vec![];
+                    entry.unwrap().read_to_end(&mut data).unwrap();
+                    repo.ensure_object(&data).unwrap();
+                }
+                (tmpdir, repo)
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, import);
+criterion_main!(benches);
@@ -1,9 +1,24 @@
-use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 
-use composefs::{oci, repository::Repository, util::parse_sha256};
+use composefs::{
+    fsverity::{
+        digest::FsVerityHasher, signature, FsVerityHashValue, FsVerityParams, Sha256HashValue,
+        Sha512HashValue,
+    },
+    mount::VerityMode,
+    oci,
+    repository::{LockWait, OpenOptions, Repository, RepositoryMetadata, RepositoryOptions},
+    util::parse_sha256,
+};
 
 /// cfsctl
 #[derive(Debug, Parser)]
@@ -15,6 +30,10 @@ pub struct App {
     user: bool,
     #[clap(long, group = "repopath")]
     system: bool,
+    /// give up if the repository is locked for longer than this many seconds (by default, wait
+    /// for as long as it takes; 0 means don't wait at all)
+    #[clap(long)]
+    lock_timeout: Option<f64>,
 
     #[clap(subcommand)]
     cmd: Command,
@@ -35,9 +54,20 @@ enum OciCommand {
     CreateDumpfile {
         layers: Vec<String>,
     },
+    /// Pulls a container image into the repository
     Pull {
+        /// the image to pull, like `docker://quay.io/fedora/fedora:latest`
         image: String,
+        /// a ref to point at the manifest of the pulled image (or, for a multi-platform image, at
+        /// its index)
         name: Option<String>,
+        /// for multi-platform images, the platform to pull (like `linux/arm64` or
+        /// `linux/arm64/v8`) instead of the host platform
+        #[clap(long, conflicts_with = "all_platforms")]
+        platform: Option<oci::Platform>,
+        /// for multi-platform images, pull every platform
+        #[clap(long)]
+        all_platforms: bool,
     },
     CreateImage {
         config: String,
@@ -60,21 +90,112 @@ enum OciCommand {
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum RefCommand {
+    /// Lists refs, optionally only those under the given directory (like `system/rootfs`)
+    Ls { prefix: Option<String> },
+    /// Prints the digest of the image or stream that a ref points to
+    Show { name: String },
+    /// Points a ref at an image or stream, atomically replacing it if it already exists
+    Set {
+        name: String,
+        /// the fs-verity digest of the image (or, with --streams, the sha256 of the stream)
+        digest: String,
+        /// only replace the ref if it currently points at this digest
+        #[clap(long)]
+        expect: Option<String>,
+        /// put the ref in the namespace of the current user (`system` for root, otherwise the uid)
+        #[clap(long)]
+        namespaced: bool,
+    },
+    /// Removes a ref
+    Rm { name: String },
+    /// Renames a ref
+    Mv { old: String, new: String },
+    /// Creates the ref namespace for a user, allowing them to manage refs under `refs/<uid>/`
+    AddNamespace { uid: u32 },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
 #[derive(Debug, Subcommand)]
 enum Command {
-    /// Take a transaction lock on the repository.
-    /// This prevents garbage collection from occurring.
-    Transaction,
+    /// Creates a new repository
+    Init {
+        /// don't use fs-verity, for filesystems which don't support it (contents aren't verified
+        /// when mounting!)
+        #[clap(long)]
+        insecure: bool,
+        /// the fs-verity hash algorithm used to name objects
+        #[clap(long, value_enum, default_value = "sha256")]
+        algorithm: HashAlgorithm,
+        /// the fs-verity block size (a power of two, at most the page size)
+        #[clap(long, default_value_t = 4096)]
+        block_size: usize,
+        /// a salt for the fs-verity digests, in hex
+        #[clap(long)]
+        salt: Option<String>,
+        /// another repository to share objects with (read-only), may be given more than once
+        #[clap(long = "alternate")]
+        alternates: Vec<PathBuf>,
+    },
+    /// Prints the metadata of the repository
+    Info,
+    /// Runs a command while holding the repository open, which keeps garbage collection from
+    /// removing anything written to the repository until the command exits.  The command opens
+    /// the repository itself, so what it writes isn't recorded in the journal of our transaction:
+    /// it's protected by the (shared) repository lock that we hold, which garbage collection
+    /// waits for.  For the same reason, the command can't be `cfsctl gc`.
+    Transaction {
+        /// the command to run, and its arguments
+        #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
     /// Reconstitutes a split stream and writes it to stdout
     Cat {
         /// the name of the stream to cat, either a sha256 digest or prefixed with 'ref/'
         name: String,
     },
     /// Perform garbage collection
-    GC,
+    GC {
+        /// only print what would be removed
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Check the integrity of the repository
+    Fsck,
     /// Imports a composefs image (unsafe!)
     ImportImage {
         reference: String,
+        /// an fs-verity builtin signature for the image, in DER form (see sign-image)
+        #[clap(long)]
+        signature: Option<PathBuf>,
+    },
+    /// Creates an fs-verity builtin signature for an image, for use with import-image --signature
+    SignImage {
+        /// the composefs image to sign
+        image: PathBuf,
+        /// the PEM-encoded private key to sign with
+        #[clap(long)]
+        key: PathBuf,
+        /// the PEM-encoded certificate for the key
+        #[clap(long)]
+        cert: PathBuf,
+        /// where to write the signature (default: the image path with `.sig` appended)
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Commands for managing image refs (or stream refs, with --streams)
+    Ref {
+        /// operate on the refs of streams instead of images
+        #[clap(long)]
+        streams: bool,
+        #[clap(subcommand)]
+        cmd: RefCommand,
     },
     /// Commands for dealing with OCI layers
     Oci {
@@ -83,48 +204,231 @@ enum Command {
     },
     /// Mounts a composefs, possibly enforcing fsverity of the image
     Mount {
-        /// the name of the image to mount, either a sha256 digest or prefixed with 'ref/'
+        /// the name of the image to mount, either its fs-verity digest or a ref (prefixed with
+        /// 'refs/')
         name: String,
         /// the mountpoint
         mountpoint: String,
     },
     CreateImage {
         path: PathBuf,
+        /// read the image contents from a dumpfile at `path` instead of a directory
+        #[clap(long)]
+        from_dumpfile: bool,
     },
     CreateDumpfile {
         path: PathBuf,
     },
 }
 
+/// Set in the environment of the command run by `cfsctl transaction`
+const TRANSACTION_ENV: &str = "CFSCTL_TRANSACTION";
+
+fn warn_if_unverified(verity: VerityMode) {
+    if verity != VerityMode::Require {
+        eprintln!(
+            "WARNING: mounted with verity={}: file contents are NOT verified",
+            verity.as_str()
+        );
+    }
+}
+
+/// Creates a builtin signature for a file with the given fs-verity digest with openssl, using the
+/// PEM-encoded private key and certificate at `key` and `cert`.  The signature is returned in DER
+/// form, as the kernel wants it.
+fn sign_digest<H: FsVerityHashValue>(digest: &H, key: &Path, cert: &Path) -> Result<Vec<u8>> {
+    let mut openssl = std::process::Command::new("openssl")
+        .args(["smime", "-sign", "-binary", "-noattr", "-nocerts"])
+        .args(["-outform", "DER", "-md", H::ID])
+        .arg("-inkey")
+        .arg(key)
+        .arg("-signer")
+        .arg(cert)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Unable to run openssl")?;
+
+    let mut stdin = openssl.stdin.take().unwrap();
+    stdin.write_all(&signature::formatted_digest(digest))?;
+    drop(stdin);
+
+    let output = openssl.wait_with_output()?;
+    if !output.status.success() {
+        bail!("openssl smime -sign failed ({})", output.status);
+    }
+    Ok(output.stdout)
+}
+
+fn ref_command<ObjectID: FsVerityHashValue, D: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    category: &str,
+    cmd: RefCommand,
+) -> Result<()> {
+    match cmd {
+        RefCommand::Ls { prefix } => {
+            for (name, id) in repo.list_refs::<D>(category, prefix.as_deref().unwrap_or(""))? {
+                println!("{} refs/{name}", hex::encode(id));
+            }
+        }
+        RefCommand::Show { ref name } => {
+            println!("{}", hex::encode(repo.show_ref::<D>(category, name)?));
+        }
+        RefCommand::Set {
+            ref name,
+            ref digest,
+            ref expect,
+            namespaced,
+        } => {
+            let name = if namespaced {
+                Repository::<ObjectID>::namespaced_ref_name(name)
+            } else {
+                name.clone()
+            };
+            let expect = expect.as_ref().map(D::from_hex).transpose()?;
+            repo.update_ref(category, &name, &D::from_hex(digest)?, expect.as_ref())?;
+        }
+        RefCommand::Rm { ref name } => {
+            repo.remove_ref(category, name)?;
+        }
+        RefCommand::Mv { ref old, ref new } => {
+            repo.rename_ref(category, old, new)?;
+        }
+        RefCommand::AddNamespace { uid } => {
+            repo.create_ref_namespace(uid)?;
+        }
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let args = App::parse();
 
-    let repo = (if let Some(path) = args.repo {
-        Repository::open_path(path)
+    let path = if let Some(path) = args.repo {
+        path
     } else if args.system {
-        Repository::open_system()
+        Repository::<Sha256HashValue>::system_path()
     } else if args.user {
-        Repository::open_user()
+        Repository::<Sha256HashValue>::user_path()?
     } else if rustix::process::getuid().is_root() {
-        Repository::open_system()
+        Repository::<Sha256HashValue>::system_path()
     } else {
-        Repository::open_user()
-    })?;
+        Repository::<Sha256HashValue>::user_path()?
+    };
+
+    if let Command::Init {
+        insecure,
+        algorithm,
+        block_size,
+        ref salt,
+        ref alternates,
+    } = args.cmd
+    {
+        let salt = salt.as_deref().map(hex::decode).transpose()?;
+        let mut options = RepositoryOptions::default();
+        options.insecure = insecure;
+        options.fsverity = FsVerityParams::new(block_size, salt.as_deref().unwrap_or_default())?;
+        options.alternates = alternates.clone();
+        match algorithm {
+            HashAlgorithm::Sha256 => {
+                Repository::<Sha256HashValue>::init(path, &options)?;
+            }
+            HashAlgorithm::Sha512 => {
+                Repository::<Sha512HashValue>::init(path, &options)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut options = OpenOptions::default();
+    options.lock_wait = match args.lock_timeout {
+        None => LockWait::Block,
+        Some(secs) if secs <= 0.0 => LockWait::NonBlocking,
+        Some(secs) => LockWait::Timeout(Duration::try_from_secs_f64(secs)?),
+    };
 
-    match args.cmd {
-        Command::Transaction => {
-            // just wait for ^C
-            loop {
-                std::thread::park();
+    // The repository metadata says which hash algorithm it uses (repositories without metadata
+    // are always SHA-256)
+    match RepositoryMetadata::read(&path)? {
+        Some(metadata) if metadata.algorithm == Sha512HashValue::ID => run(
+            Repository::<Sha512HashValue>::open_path_with_options(path, &options)?,
+            args.cmd,
+        ),
+        _ => run(
+            Repository::<Sha256HashValue>::open_path_with_options(path, &options)?,
+            args.cmd,
+        ),
+    }
+}
+
+fn run<ObjectID: FsVerityHashValue>(repo: Repository<ObjectID>, cmd: Command) -> Result<()> {
+    match cmd {
+        Command::Init { .. } => unreachable!(),
+        Command::Info => {
+            match repo.metadata() {
+                Some(metadata) => print!("{metadata}"),
+                None => println!("(repository has no metadata)"),
+            }
+            for alternate in repo.alternates() {
+                println!("alternate = {}", alternate.display());
+            }
+        }
+        Command::Transaction { command } => {
+            let transaction = repo.begin_transaction()?;
+            let status = std::process::Command::new(&command[0])
+                .args(&command[1..])
+                .env(TRANSACTION_ENV, "1")
+                .status()
+                .with_context(|| format!("Unable to run {:?}", command[0]))?;
+            transaction.commit()?;
+            if !status.success() {
+                std::process::exit(status.code().unwrap_or(1));
             }
         }
         Command::Cat { name } => {
             repo.merge_splitstream(&name, None, &mut std::io::stdout())?;
         }
-        Command::ImportImage { reference } => {
-            let image_id = repo.import_image(&reference, &mut std::io::stdin())?;
+        Command::ImportImage {
+            reference,
+            signature,
+        } => {
+            let image_id = match signature {
+                Some(path) => repo.import_image_signed(
+                    &reference,
+                    &mut std::io::stdin(),
+                    &std::fs::read(path)?,
+                )?,
+                None => repo.import_image(&reference, &mut std::io::stdin())?,
+            };
             println!("{}", hex::encode(image_id));
         }
+        Command::SignImage {
+            image,
+            key,
+            cert,
+            output,
+        } => {
+            // The digest depends on the repository's fs-verity parameters
+            let digest = FsVerityHasher::<ObjectID>::hash_reader_with_params(
+                &mut File::open(&image)?,
+                repo.fsverity_params(),
+            )?;
+            let signature = sign_digest(&digest, &key, &cert)?;
+            let output = output.unwrap_or_else(|| {
+                let mut path = image.into_os_string();
+                path.push(".sig");
+                path.into()
+            });
+            std::fs::write(&output, signature)?;
+            println!("{}", hex::encode(digest));
+        }
+        Command::Ref { streams, cmd } => {
+            if streams {
+                ref_command::<ObjectID, Sha256HashValue>(&repo, "streams", cmd)?;
+            } else {
+                ref_command::<ObjectID, ObjectID>(&repo, "images", cmd)?;
+            }
+        }
         Command::Oci { cmd: oci_cmd } => match oci_cmd {
             OciCommand::ImportLayer { name, sha256 } => {
                 let object_id = oci::import_layer(
@@ -145,17 +449,30 @@ fn main() -> Result<()> {
                 let image_id = oci::image::create_image(&repo, &config, name.as_deref(), None)?;
                 println!("{}", hex::encode(image_id));
             }
-            OciCommand::Pull { ref image, name } => {
+            OciCommand::Pull {
+                ref image,
+                name,
+                platform,
+                all_platforms,
+            } => {
+                let mut options = oci::PullOptions::default();
+                options.platform = platform;
+                options.all_platforms = all_platforms;
                 let runtime = tokio::runtime::Builder::new_current_thread()
                     .enable_all()
                     .build()
                     .expect("Failed to build tokio runtime");
                 // And invoke the async_main
-                runtime.block_on(async move { oci::pull(&repo, image, name.as_deref()).await })?;
+                runtime.block_on(async move {
+                    oci::pull_with_options(&repo, image, name.as_deref(), &options).await
+                })?;
             }
             OciCommand::Seal { verity, ref name } => {
-                let (sha256, verity) =
-                    oci::seal(&repo, name, verity.map(parse_sha256).transpose()?.as_ref())?;
+                let (sha256, verity) = oci::seal(
+                    &repo,
+                    name,
+                    verity.map(ObjectID::from_hex).transpose()?.as_ref(),
+                )?;
                 println!("sha256 {}", hex::encode(sha256));
                 println!("verity {}", hex::encode(verity));
             }
@@ -163,7 +480,7 @@ fn main() -> Result<()> {
                 ref name,
                 ref mountpoint,
             } => {
-                oci::mount(&repo, name, mountpoint, None)?;
+                warn_if_unverified(oci::mount(&repo, name, mountpoint, None)?);
             }
             OciCommand::MetaLayer { ref name } => {
                 oci::meta_layer(&repo, name, None)?;
@@ -173,19 +490,120 @@ fn main() -> Result<()> {
                 oci::prepare_boot(&repo, name, None, &output)?;
             }
         },
-        Command::CreateImage { ref path } => {
-            let image_id = composefs::fs::create_image(path, Some(&repo))?;
+        Command::CreateImage {
+            ref path,
+            from_dumpfile,
+        } => {
+            let image_id = if from_dumpfile {
+                composefs::fs::create_image_from_dumpfile(path, Some(&repo))?
+            } else {
+                composefs::fs::create_image(path, Some(&repo))?
+            };
             println!("{}", hex::encode(image_id));
         }
         Command::CreateDumpfile { ref path } => {
-            composefs::fs::create_dumpfile(path)?;
+            composefs::fs::create_dumpfile::<ObjectID>(path)?;
         }
         Command::Mount { name, mountpoint } => {
-            repo.mount(&name, &mountpoint)?;
+            warn_if_unverified(repo.mount(&name, &mountpoint)?);
         }
-        Command::GC => {
-            repo.gc()?;
+        Command::Fsck => {
+            let report = repo.fsck()?;
+            for problem in &report.problems {
+                println!("{problem}");
+            }
+            if !report.problems.is_empty() {
+                bail!("{} problems found", report.problems.len());
+            }
+        }
+        Command::GC { dry_run } => {
+            if std::env::var_os(TRANSACTION_ENV).is_some() {
+                // the transaction holds a shared lock on the repository until we exit, and we'd
+                // wait forever for it to go away
+                bail!("Cannot run garbage collection inside of cfsctl transaction");
+            }
+            let report = repo.gc(dry_run)?;
+            for path in &report.dangling {
+                eprintln!("warning: {path} is referred to but missing");
+            }
+            for path in &report.invalid {
+                eprintln!("warning: {path} is not a valid ref, ignoring it");
+            }
+            if dry_run {
+                for path in &report.removed {
+                    println!("would remove {path}");
+                }
+            }
+            println!(
+                "{} {} unreferenced objects ({} bytes)",
+                if dry_run { "Would remove" } else { "Removed" },
+                report.objects_removed,
+                report.bytes_freed
+            );
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use composefs::fsverity::{digest::FsVerityHasher, Sha256HashValue, Sha512HashValue};
+
+    use super::*;
+
+    /// Checks `signature` against `message` with openssl
+    fn verify(dir: &Path, signature: &[u8], message: &[u8], cert: &Path) -> Result<bool> {
+        let content = dir.join("message");
+        let sig = dir.join("message.sig");
+        std::fs::write(&content, message)?;
+        std::fs::write(&sig, signature)?;
+        let status = std::process::Command::new("openssl")
+            .args(["smime", "-verify", "-binary", "-inform", "DER", "-noverify"])
+            .arg("-in")
+            .arg(&sig)
+            .arg("-content")
+            .arg(&content)
+            .arg("-certfile")
+            .arg(cert)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        Ok(status.success())
+    }
+
+    #[test]
+    fn test_sign_digest() -> Result<()> {
+        // a throwaway key and certificate
+        let tmp = tempfile::tempdir()?;
+        let key = tmp.path().join("key.pem");
+        let cert = tmp.path().join("cert.pem");
+        let status = std::process::Command::new("openssl")
+            .args([
+                "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1",
+            ])
+            .args(["-subj", "/CN=composefs test"])
+            .arg("-keyout")
+            .arg(&key)
+            .arg("-out")
+            .arg(&cert)
+            .stderr(Stdio::null())
+            .status()?;
+        assert!(status.success());
+
+        let digest: Sha256HashValue = FsVerityHasher::hash(b"hello world");
+        let signature = sign_digest(&digest, &key, &cert)?;
+        let message = signature::formatted_digest(&digest);
+        assert!(verify(tmp.path(), &signature, &message, &cert)?);
+        // it's only valid for that digest
+        let other: Sha256HashValue = FsVerityHasher::hash(b"goodbye world");
+        let message = signature::formatted_digest(&other);
+        assert!(!verify(tmp.path(), &signature, &message, &cert)?);
+
+        let digest: Sha512HashValue = FsVerityHasher::hash(b"hello world");
+        let signature = sign_digest(&digest, &key, &cert)?;
+        let message = signature::formatted_digest(&digest);
+        assert!(verify(tmp.path(), &signature, &message, &cert)?);
+
+        Ok(())
+    }
+}
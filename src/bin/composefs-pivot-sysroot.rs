@@ -2,39 +2,56 @@ use std::path::Path;
 
 use anyhow::{bail, Result};
 
-use composefs::{fsverity::Sha256HashValue, repository::Repository};
+use composefs::{
+    fsverity::{FsVerityHashValue, Sha256HashValue, Sha512HashValue},
+    mount::VerityMode,
+    repository::{Repository, RepositoryMetadata},
+};
 
-fn parse_composefs_cmdline(cmdline: &[u8]) -> Result<Sha256HashValue> {
+fn parse_composefs_cmdline<ObjectID: FsVerityHashValue>(cmdline: &[u8]) -> Result<ObjectID> {
     // TODO?: officially we need to understand quoting with double-quotes...
     for part in cmdline.split(|c| c.is_ascii_whitespace()) {
         if let Some(digest) = part.strip_prefix(b"composefs=") {
-            let mut value = [0; 32];
-            hex::decode_to_slice(digest, &mut value)?;
-            return Ok(value);
+            return Ok(ObjectID::from_hex(digest)?);
         }
     }
     bail!("Unable to find composefs= cmdline parameter");
 }
 
+fn pivot_sysroot<ObjectID: FsVerityHashValue>(cmdline: &[u8]) -> Result<()> {
+    let repo = Repository::<ObjectID>::open_system()?;
+    let image = parse_composefs_cmdline::<ObjectID>(cmdline)?;
+    let verity = repo.pivot_sysroot(&hex::encode(image), Path::new("/sysroot"))?;
+    if verity != VerityMode::Require {
+        eprintln!(
+            "WARNING: mounted /sysroot with verity={}: file contents are NOT verified",
+            verity.as_str()
+        );
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
-    let repo = Repository::open_system()?;
     let cmdline = std::fs::read("/proc/cmdline")?;
-    let image = parse_composefs_cmdline(&cmdline)?;
-    repo.pivot_sysroot(&hex::encode(image), Path::new("/sysroot"))?;
-
-    Ok(())
+    let path = Repository::<Sha256HashValue>::system_path();
+    match RepositoryMetadata::read(&path)? {
+        Some(metadata) if metadata.algorithm == Sha512HashValue::ID => {
+            pivot_sysroot::<Sha512HashValue>(&cmdline)
+        }
+        _ => pivot_sysroot::<Sha256HashValue>(&cmdline),
+    }
 }
 
 #[test]
 fn test_parse() {
     let failing = ["", "foo", "composefs", "composefs=foo"];
     for case in failing {
-        assert!(parse_composefs_cmdline(case.as_bytes()).is_err());
+        assert!(parse_composefs_cmdline::<Sha256HashValue>(case.as_bytes()).is_err());
     }
     let digest = "8b7df143d91c716ecfa5fc1730022f6b421b05cedee8fd52b1fc65a96030ad52";
     let digest_bytes = hex::decode(digest).unwrap();
     assert_eq!(
-        parse_composefs_cmdline(format!("composefs={digest}").as_bytes())
+        parse_composefs_cmdline::<Sha256HashValue>(format!("composefs={digest}").as_bytes())
             .unwrap()
             .as_slice(),
         &digest_bytes
@@ -8,11 +8,11 @@ use std::{
     rc::Rc,
 };
 
-use anyhow::Result;
 use rustix::fs::FileType;
 
 use crate::{
-    fsverity::Sha256HashValue,
+    error::Result,
+    fsverity::FsVerityHashValue,
     image::{DirEnt, Directory, FileSystem, Inode, Leaf, LeafContent, Stat},
 };
 
@@ -49,7 +49,7 @@ fn write_entry(
     rdev: u64,
     payload: impl AsRef<OsStr>,
     content: &[u8],
-    digest: Option<&Sha256HashValue>,
+    digest: Option<&[u8]>,
 ) -> fmt::Result {
     let mode = stat.st_mode | ifmt.as_raw_mode();
     let uid = stat.st_uid;
@@ -101,11 +101,11 @@ pub fn write_directory(
     )
 }
 
-pub fn write_leaf(
+pub fn write_leaf<ObjectID: FsVerityHashValue>(
     writer: &mut impl fmt::Write,
     path: &Path,
     stat: &Stat,
-    content: &LeafContent,
+    content: &LeafContent<ObjectID>,
     nlink: usize,
 ) -> fmt::Result {
     match content {
@@ -129,9 +129,9 @@ pub fn write_leaf(
             *size,
             nlink,
             0,
-            format!("{:02x}/{}", id[0], hex::encode(&id[1..])),
+            id.to_object_pathname(),
             &[],
-            Some(id),
+            Some(id.as_ref()),
         ),
         LeafContent::BlockDevice(rdev) => write_entry(
             writer,
@@ -204,8 +204,8 @@ pub fn write_hardlink(writer: &mut impl fmt::Write, path: &Path, target: &OsStr)
     Ok(())
 }
 
-struct DumpfileWriter<'a, W: Write> {
-    hardlinks: HashMap<*const Leaf, OsString>,
+struct DumpfileWriter<'a, W: Write, ObjectID: FsVerityHashValue> {
+    hardlinks: HashMap<*const Leaf<ObjectID>, OsString>,
     writer: &'a mut W,
 }
 
@@ -215,7 +215,7 @@ fn writeln_fmt(writer: &mut impl Write, f: impl Fn(&mut String) -> fmt::Result)
     Ok(writeln!(writer, "{}", tmp)?)
 }
 
-impl<'a, W: Write> DumpfileWriter<'a, W> {
+impl<'a, W: Write, ObjectID: FsVerityHashValue> DumpfileWriter<'a, W, ObjectID> {
     fn new(writer: &'a mut W) -> Self {
         Self {
             hardlinks: HashMap::new(),
@@ -223,7 +223,7 @@ impl<'a, W: Write> DumpfileWriter<'a, W> {
         }
     }
 
-    fn write_dir(&mut self, path: &mut PathBuf, dir: &Directory) -> Result<()> {
+    fn write_dir(&mut self, path: &mut PathBuf, dir: &Directory<ObjectID>) -> Result<()> {
         // nlink is 2 + number of subdirectories
         // this is also true for the root dir since '..' is another self-ref
         let nlink = dir.entries.iter().fold(2, |count, ent| {
@@ -256,7 +256,7 @@ impl<'a, W: Write> DumpfileWriter<'a, W> {
         Ok(())
     }
 
-    fn write_leaf(&mut self, path: &Path, leaf: &Rc<Leaf>) -> Result<()> {
+    fn write_leaf(&mut self, path: &Path, leaf: &Rc<Leaf<ObjectID>>) -> Result<()> {
         let nlink = Rc::strong_count(leaf);
 
         if nlink > 1 {
@@ -276,7 +276,10 @@ impl<'a, W: Write> DumpfileWriter<'a, W> {
     }
 }
 
-pub fn write_dumpfile<W: Write>(writer: &mut W, fs: &FileSystem) -> Result<()> {
+pub fn write_dumpfile<W: Write, ObjectID: FsVerityHashValue>(
+    writer: &mut W,
+    fs: &FileSystem<ObjectID>,
+) -> Result<()> {
     // default pipe capacity on Linux is 16 pages (65536 bytes), but
     // sometimes the BufWriter will write more than its capacity...
     let mut buffer = BufWriter::with_capacity(32768, writer);
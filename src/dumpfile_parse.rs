@@ -4,22 +4,29 @@
 //! serializion of the metadata file.  This module supports parsing
 //! and generating dump file entries.
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fmt::Display;
 use std::fmt::Write as WriteFmt;
 use std::fs::File;
-use std::io::BufRead;
 use std::io::Write;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::str::FromStr;
 
+use anyhow::anyhow;
 use anyhow::Context;
-use anyhow::{anyhow, Result};
 use rustix::fs::FileType;
 
+use crate::{
+    erofs::{
+        format::FILE_TYPE_CHRDEV,
+        reader::{DirEntry, Image},
+    },
+    error::{bail, Result},
+};
+
 /// https://github.com/torvalds/linux/blob/47ac09b91befbb6a235ab620c32af719f8208399/include/uapi/linux/limits.h#L13
 const PATH_MAX: u32 = 4096;
 /// Maximum size accepted for inline content.
@@ -131,14 +138,14 @@ pub enum Item<'p> {
 
 /// Unescape a byte array according to the composefs dump file escaping format,
 /// limiting the maximum possible size.
-fn unescape_limited(s: &str, max: usize) -> Result<Cow<[u8]>> {
+fn unescape_limited(s: &str, max: usize) -> Result<Cow<'_, [u8]>> {
     // If there are no escapes, just return the input unchanged. However,
     // it must also be ASCII to maintain a 1-1 correspondence between byte
     // and character.
     if !s.contains('\\') && s.is_ascii() {
         let len = s.len();
         if len > max {
-            anyhow::bail!("Input {len} exceeded maximum length {max}");
+            bail!("Input {len} exceeded maximum length {max}");
         }
         return Ok(Cow::Borrowed(s.as_bytes()));
     }
@@ -146,7 +153,7 @@ fn unescape_limited(s: &str, max: usize) -> Result<Cow<[u8]>> {
     let mut r = Vec::new();
     while let Some(c) = it.next() {
         if r.len() == max {
-            anyhow::bail!("Input exceeded maximum length {max}");
+            bail!("Input exceeded maximum length {max}");
         }
         if c != '\\' {
             write!(r, "{c}").unwrap();
@@ -171,7 +178,7 @@ fn unescape_limited(s: &str, max: usize) -> Result<Cow<[u8]>> {
 
                 u8::from_str_radix(&s, 16).with_context(|| anyhow!("Invalid hex escape {s}"))?
             }
-            o => anyhow::bail!("Invalid escape {o}"),
+            o => bail!("Invalid escape {o}"),
         };
         r.push(c);
     }
@@ -179,16 +186,16 @@ fn unescape_limited(s: &str, max: usize) -> Result<Cow<[u8]>> {
 }
 
 /// Unescape a byte array according to the composefs dump file escaping format.
-fn unescape(s: &str) -> Result<Cow<[u8]>> {
-    return unescape_limited(s, usize::MAX);
+fn unescape(s: &str) -> Result<Cow<'_, [u8]>> {
+    unescape_limited(s, usize::MAX)
 }
 
 /// Unescape a string into a Rust `OsStr` which is really just an alias for a byte array,
 /// but we also impose a constraint that it can not have an embedded NUL byte.
-fn unescape_to_osstr(s: &str) -> Result<Cow<OsStr>> {
+fn unescape_to_osstr(s: &str) -> Result<Cow<'_, OsStr>> {
     let v = unescape(s)?;
     if v.contains(&0u8) {
-        anyhow::bail!("Invalid embedded NUL");
+        bail!("Invalid embedded NUL");
     }
     let r = match v {
         Cow::Borrowed(v) => Cow::Borrowed(OsStr::from_bytes(v)),
@@ -201,14 +208,14 @@ fn unescape_to_osstr(s: &str) -> Result<Cow<OsStr>> {
 /// with a few constraints:
 /// - Cannot contain an embedded NUL
 /// - Cannot be empty, or longer than PATH_MAX
-fn unescape_to_path(s: &str) -> Result<Cow<Path>> {
+fn unescape_to_path(s: &str) -> Result<Cow<'_, Path>> {
     let v = unescape_to_osstr(s).and_then(|v| {
         if v.is_empty() {
-            anyhow::bail!("Invalid empty path");
+            bail!("Invalid empty path");
         }
         let l = v.len();
         if l > PATH_MAX as usize {
-            anyhow::bail!("Path is too long: {l} bytes");
+            bail!("Path is too long: {l} bytes");
         }
         Ok(v)
     })?;
@@ -224,15 +231,15 @@ fn unescape_to_path(s: &str) -> Result<Cow<Path>> {
 /// which in particular removes `.` and extra `//`.
 ///
 /// We also deny uplinks `..` and empty paths.
-fn unescape_to_path_canonical(s: &str) -> Result<Cow<Path>> {
+fn unescape_to_path_canonical(s: &str) -> Result<Cow<'_, Path>> {
     let p = unescape_to_path(s)?;
     let mut components = p.components();
     let mut r = std::path::PathBuf::new();
     let Some(first) = components.next() else {
-        anyhow::bail!("Invalid empty path");
+        bail!("Invalid empty path");
     };
     if first != std::path::Component::RootDir {
-        anyhow::bail!("Invalid non-absolute path");
+        bail!("Invalid non-absolute path");
     }
     r.push(first);
     for component in components {
@@ -242,10 +249,10 @@ fn unescape_to_path_canonical(s: &str) -> Result<Cow<Path>> {
             std::path::Component::Prefix(_)
             | std::path::Component::RootDir
             | std::path::Component::CurDir => {
-                anyhow::bail!("Internal error in unescape_to_path_canonical");
+                bail!("Internal error in unescape_to_path_canonical");
             }
             std::path::Component::ParentDir => {
-                anyhow::bail!("Invalid \"..\" in path");
+                bail!("Invalid \"..\" in path");
             }
             std::path::Component::Normal(_) => {
                 r.push(component);
@@ -307,7 +314,7 @@ fn optional_str(s: &str) -> Option<&str> {
 }
 
 impl FromStr for Mtime {
-    type Err = anyhow::Error;
+    type Err = crate::Error;
 
     fn from_str(s: &str) -> Result<Self> {
         let (sec, nsec) = s
@@ -328,7 +335,7 @@ impl<'k> Xattr<'k> {
         let key = unescape_to_osstr(key)?;
         let keylen = key.as_bytes().len();
         if keylen > XATTR_NAME_MAX {
-            anyhow::bail!(
+            bail!(
                 "xattr name too long; max={} found={}",
                 XATTR_NAME_MAX,
                 keylen
@@ -337,7 +344,7 @@ impl<'k> Xattr<'k> {
         let value = unescape(value)?;
         let valuelen = value.len();
         if valuelen > XATTR_SIZE_MAX {
-            anyhow::bail!(
+            bail!(
                 "xattr value too long; max={} found={}",
                 XATTR_SIZE_MAX,
                 keylen
@@ -350,17 +357,17 @@ impl<'k> Xattr<'k> {
 impl<'p> Entry<'p> {
     fn check_nonregfile(content: Option<&str>, fsverity_digest: Option<&str>) -> Result<()> {
         if content.is_some() {
-            anyhow::bail!("entry cannot have content");
+            bail!("entry cannot have content");
         }
         if fsverity_digest.is_some() {
-            anyhow::bail!("entry cannot have fsverity digest");
+            bail!("entry cannot have fsverity digest");
         }
         Ok(())
     }
 
     fn check_rdev(rdev: u64) -> Result<()> {
         if rdev != 0 {
-            anyhow::bail!("entry cannot have device (rdev) {rdev}");
+            bail!("entry cannot have device (rdev) {rdev}");
         }
         Ok(())
     }
@@ -377,10 +384,17 @@ impl<'p> Entry<'p> {
         } else {
             (false, u32::from_str_radix(modeval, 8)?)
         };
-        let nlink = u32::from_str(next("nlink")?)?;
-        let uid = u32::from_str(next("uid")?)?;
-        let gid = u32::from_str(next("gid")?)?;
-        let rdev = u64::from_str(next("rdev")?)?;
+        // The metadata fields of hardlinks are ignored, and may be written as `-`
+        let mut numeric = |name: &str| -> Result<u64> {
+            match next(name)? {
+                "-" if is_hardlink => Ok(0),
+                s => Ok(u64::from_str(s)?),
+            }
+        };
+        let nlink = u32::try_from(numeric("nlink")?)?;
+        let uid = u32::try_from(numeric("uid")?)?;
+        let gid = u32::try_from(numeric("gid")?)?;
+        let rdev = numeric("rdev")?;
         let mtime = Mtime::from_str(next("mtime")?)?;
         let payload = optional_str(next("payload")?);
         let content = optional_str(next("content")?);
@@ -391,17 +405,17 @@ impl<'p> Entry<'p> {
                 // Limit the total length of keys.
                 let total_namelen = total_namelen.saturating_add(xattr.key.len());
                 if total_namelen > XATTR_LIST_MAX {
-                    anyhow::bail!("Too many xattrs");
+                    bail!("Too many xattrs");
                 }
                 acc.push(xattr);
-                Ok((acc, total_namelen))
+                Ok::<_, crate::Error>((acc, total_namelen))
             })?
             .0;
 
         let ty = FileType::from_raw_mode(mode);
         let item = if is_hardlink {
             if ty == FileType::Directory {
-                anyhow::bail!("Invalid hardlinked directory");
+                bail!("Invalid hardlinked directory");
             }
             let target =
                 unescape_to_path_canonical(payload.ok_or_else(|| anyhow!("Missing payload"))?)?;
@@ -424,7 +438,7 @@ impl<'p> Entry<'p> {
                         let content = content.unwrap_or_default();
                         let content = unescape_limited(content, MAX_INLINE_CONTENT.into())?;
                         if fsverity_digest.is_some() {
-                            anyhow::bail!("Inline file cannot have fsverity digest");
+                            bail!("Inline file cannot have fsverity digest");
                         }
                         Item::RegularInline { nlink, content }
                     }
@@ -440,7 +454,7 @@ impl<'p> Entry<'p> {
                         unescape_to_path(payload.ok_or_else(|| anyhow!("Missing payload"))?)?;
                     let targetlen = target.as_os_str().as_bytes().len();
                     if targetlen > PATH_MAX as usize {
-                        anyhow::bail!("Target length too large {}", targetlen);
+                        bail!("Target length too large {}", targetlen);
                     }
                     Item::Symlink { nlink, target }
                 }
@@ -461,10 +475,10 @@ impl<'p> Entry<'p> {
                     Item::Directory { size, nlink }
                 }
                 FileType::Socket => {
-                    anyhow::bail!("sockets are not supported");
+                    bail!("sockets are not supported");
                 }
                 FileType::Unknown => {
-                    anyhow::bail!("Unhandled file type from raw mode: {mode}")
+                    bail!("Unhandled file type from raw mode: {mode}")
                 }
             }
         };
@@ -539,6 +553,11 @@ impl Display for Mtime {
 impl<'p> Display for Entry<'p> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         escape(f, self.path.as_os_str().as_bytes(), EscapeMode::Standard)?;
+        if let Item::Hardlink { target } = &self.item {
+            write!(f, " 0 @{:o} - - - - 0.0 ", self.mode)?;
+            escape(f, target.as_os_str().as_bytes(), EscapeMode::Standard)?;
+            return write!(f, " - -");
+        }
         write!(
             f,
             " {} {:o} {} {} {} {} {} ",
@@ -589,51 +608,180 @@ pub struct DumpConfig<'a> {
     pub filters: Option<&'a [&'a str]>,
 }
 
-/// Parse the provided composefs into dumpfile entries.
-pub fn dump<F>(input: File, config: DumpConfig, mut handler: F) -> Result<()>
+fn bytes_to_path(bytes: Cow<'_, [u8]>) -> Cow<'_, Path> {
+    match bytes {
+        Cow::Borrowed(bytes) => Cow::Borrowed(Path::new(OsStr::from_bytes(bytes))),
+        Cow::Owned(bytes) => Cow::Owned(PathBuf::from(OsString::from_vec(bytes))),
+    }
+}
+
+/// Walks an erofs image and converts its inodes into dumpfile entries, the way that
+/// `composefs-info dump` does.
+struct ImageDumper<'a, F> {
+    image: &'a Image,
+    filters: Option<&'a [&'a str]>,
+    hardlinks: HashMap<u64, PathBuf>,
+    directories: HashSet<u64>,
+    handler: F,
+}
+
+impl<F> ImageDumper<'_, F>
 where
-    F: FnMut(Entry<'_>) -> Result<()> + Send,
+    F: FnMut(Entry<'_>) -> Result<()>,
 {
-    let mut proc = Command::new("composefs-info");
-    proc.arg("dump");
-    if let Some(filter) = config.filters {
-        proc.args(filter.iter().flat_map(|f| ["--filter", f]));
-    }
-    proc.args(["/dev/stdin"])
-        .stdin(std::process::Stdio::from(input))
-        .stderr(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped());
-    let mut proc = proc.spawn().context("Spawning composefs-info")?;
-
-    // SAFETY: we set up these streams
-    let child_stdout = proc.stdout.take().unwrap();
-    let child_stderr = proc.stderr.take().unwrap();
-
-    std::thread::scope(|s| {
-        let stderr_copier = s.spawn(move || {
-            let mut child_stderr = std::io::BufReader::new(child_stderr);
-            let mut buf = Vec::new();
-            std::io::copy(&mut child_stderr, &mut buf)?;
-            anyhow::Ok(buf)
-        });
+    /// The 256 whiteouts which the writer adds to the root directory to hide the object
+    /// subdirectories aren't part of the filesystem
+    fn is_object_dir_whiteout(&self, entry: &DirEntry) -> Result<bool> {
+        Ok(entry.file_type == FILE_TYPE_CHRDEV
+            && entry.name.len() == 2
+            && entry
+                .name
+                .iter()
+                .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(c))
+            && self.image.inode(entry.nid)?.rdev() == 0)
+    }
+
+    fn dump_inode(&mut self, path: &mut PathBuf, nid: u64) -> Result<()> {
+        let inode = self.image.inode(nid)?;
+        let ty = inode.file_type();
 
-        let child_stdout = std::io::BufReader::new(child_stdout);
-        for line in child_stdout.lines() {
-            let line = line.context("Reading dump stdout")?;
-            let entry = Entry::parse(&line)?.filter_special();
-            handler(entry)?;
+        if ty != FileType::Directory && inode.nlink > 1 {
+            if let Some(target) = self.hardlinks.get(&nid) {
+                return (self.handler)(Entry {
+                    path: Cow::Borrowed(path),
+                    uid: 0,
+                    gid: 0,
+                    mode: FileType::Symlink.as_raw_mode(),
+                    mtime: Mtime { sec: 0, nsec: 0 },
+                    item: Item::Hardlink {
+                        target: Cow::Borrowed(target),
+                    },
+                    xattrs: vec![],
+                });
+            }
+            self.hardlinks.insert(nid, path.clone());
         }
 
-        let r = proc.wait()?;
-        let stderr = stderr_copier.join().unwrap()?;
-        if !r.success() {
-            let stderr = String::from_utf8_lossy(&stderr);
-            let stderr = stderr.trim();
-            anyhow::bail!("composefs-info dump failed: {r}: {stderr}")
+        // Whiteouts are stored as regular files with an escaped overlayfs whiteout xattr
+        let whiteout = ty == FileType::RegularFile
+            && inode.xattr(b"trusted.overlay.overlay.whiteout").is_some();
+
+        let xattrs = inode
+            .visible_xattrs()
+            .filter(|(name, _)| {
+                !whiteout
+                    || !matches!(
+                        &name[..],
+                        b"trusted.overlay.whiteout" | b"user.overlay.whiteout"
+                    )
+            })
+            .map(|(name, value)| Xattr {
+                key: match name {
+                    Cow::Borrowed(name) => Cow::Borrowed(OsStr::from_bytes(name)),
+                    Cow::Owned(name) => Cow::Owned(OsString::from_vec(name)),
+                },
+                value: Cow::Borrowed(value),
+            })
+            .collect();
+
+        let mut mode = inode.mode;
+        let nlink = inode.nlink;
+        let item = match ty {
+            FileType::Directory => Item::Directory {
+                size: inode.size,
+                nlink,
+            },
+            FileType::RegularFile if whiteout => {
+                mode = FileType::CharacterDevice.as_raw_mode() | (mode & 0o7777);
+                Item::Device { nlink, rdev: 0 }
+            }
+            FileType::RegularFile => match inode.xattr(b"trusted.overlay.redirect") {
+                Some(redirect) => Item::Regular {
+                    size: inode.size,
+                    nlink,
+                    path: Cow::Borrowed(Path::new(OsStr::from_bytes(
+                        redirect.strip_prefix(b"/").unwrap_or(redirect),
+                    ))),
+                    fsverity_digest: inode.metacopy_digest().map(hex::encode),
+                },
+                None if inode.is_chunked() => {
+                    bail!("External file {path:?} has no overlay redirect")
+                }
+                None => Item::RegularInline {
+                    nlink,
+                    content: inode.data()?,
+                },
+            },
+            FileType::Symlink => Item::Symlink {
+                nlink,
+                target: bytes_to_path(inode.data()?),
+            },
+            FileType::Fifo => Item::Fifo { nlink },
+            FileType::CharacterDevice | FileType::BlockDevice => Item::Device {
+                nlink,
+                rdev: inode.rdev() as u64,
+            },
+            FileType::Socket => bail!("sockets are not supported"),
+            FileType::Unknown => bail!("Unhandled file type from raw mode: {mode}"),
+        };
+
+        (self.handler)(
+            Entry {
+                path: Cow::Borrowed(path),
+                uid: inode.uid,
+                gid: inode.gid,
+                mode,
+                mtime: Mtime {
+                    sec: inode.mtime,
+                    nsec: inode.mtime_nsec as u64,
+                },
+                item,
+                xattrs,
+            }
+            .filter_special(),
+        )?;
+
+        if ty == FileType::Directory {
+            if !self.directories.insert(nid) {
+                bail!("Directory loop at {path:?}");
+            }
+            let is_root = nid == self.image.root_nid();
+            for entry in inode.entries()? {
+                if is_root {
+                    let name = std::str::from_utf8(entry.name).ok();
+                    if let Some(filters) = self.filters {
+                        if !name.is_some_and(|name| filters.contains(&name)) {
+                            continue;
+                        }
+                    }
+                    if self.is_object_dir_whiteout(&entry)? {
+                        continue;
+                    }
+                }
+                path.push(OsStr::from_bytes(entry.name));
+                self.dump_inode(path, entry.nid)?;
+                path.pop();
+            }
         }
 
         Ok(())
-    })
+    }
+}
+
+/// Parse the provided composefs into dumpfile entries.
+pub fn dump<F>(input: File, config: DumpConfig, handler: F) -> Result<()>
+where
+    F: FnMut(Entry<'_>) -> Result<()> + Send,
+{
+    let image = Image::read(input)?;
+    let mut dumper = ImageDumper {
+        image: &image,
+        filters: config.filters,
+        hardlinks: HashMap::new(),
+        directories: HashSet::new(),
+        handler,
+    };
+    dumper.dump_inode(&mut PathBuf::from("/"), image.root_nid())
 }
 
 #[cfg(test)]
@@ -641,7 +789,7 @@ mod tests {
     use std::{
         fs::File,
         io::{BufWriter, Seek},
-        process::Stdio,
+        process::{Command, Stdio},
     };
 
     use super::*;
@@ -653,7 +801,7 @@ mod tests {
     fn mkcomposefs(dumpfile: &str, out: &mut File) -> Result<()> {
         let mut tf = tempfile::tempfile().map(BufWriter::new)?;
         tf.write_all(dumpfile.as_bytes())?;
-        let mut tf = tf.into_inner()?;
+        let mut tf = tf.into_inner().map_err(|err| err.into_error())?;
         tf.seek(std::io::SeekFrom::Start(0))?;
         let mut mkcomposefs = Command::new("mkcomposefs")
             .args(["--from-file", "-", "-"])
@@ -664,7 +812,7 @@ mod tests {
 
         let st = mkcomposefs.wait()?;
         if !st.success() {
-            anyhow::bail!("mkcomposefs failed: {st}");
+            bail!("mkcomposefs failed: {st}");
         };
 
         Ok(())
@@ -889,8 +1037,9 @@ mod tests {
         mkcomposefs(SPECIAL_DUMP, &mut tmpf).unwrap();
         let mut entries = String::new();
         tmpf.seek(std::io::SeekFrom::Start(0))?;
-        let mut filter = DumpConfig::default();
-        filter.filters = Some(&["blockdev", "inline"]);
+        let filter = DumpConfig {
+            filters: Some(&["blockdev", "inline"]),
+        };
         dump(tmpf, filter, |e| {
             writeln!(entries, "{e}")?;
             Ok(())
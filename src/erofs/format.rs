@@ -0,0 +1,468 @@
+//! On-disk structures of the erofs images written by composefs.
+//!
+//! These mirror the definitions in linux/fs/erofs/erofs_fs.h and the composefs header from
+//! libcomposefs/lcfs-erofs.h.  All values are stored little-endian.
+
+/// The erofs block size (bits)
+pub const BLOCK_BITS: u8 = 12;
+/// The erofs block size (bytes)
+pub const BLOCK_SIZE: usize = 1 << BLOCK_BITS;
+
+pub const COMPOSEFS_MAGIC: u32 = 0xd078629a;
+pub const COMPOSEFS_VERSION: u32 = 1;
+pub const COMPOSEFS_FORMAT_VERSION: u32 = 1;
+pub const COMPOSEFS_FLAGS_HAS_ACL: u32 = 1 << 0;
+
+pub const EROFS_MAGIC: u32 = 0xe0f5e1e2;
+/// The superblock lives at this fixed offset in the image
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+
+pub const FEATURE_COMPAT_MTIME: u32 = 0x2;
+pub const FEATURE_COMPAT_XATTR_FILTER: u32 = 0x4;
+pub const FEATURE_INCOMPAT_CHUNKED_FILE: u32 = 0x4;
+
+/// Inodes are addressed by "nid": their offset from the start of the metadata area in units of
+/// this many bytes.
+pub const INODE_SLOT_BITS: u8 = 5;
+pub const INODE_SLOT_SIZE: usize = 1 << INODE_SLOT_BITS;
+
+pub const INODE_LAYOUT_COMPACT: u16 = 0;
+pub const INODE_LAYOUT_EXTENDED: u16 = 1;
+
+pub const INODE_DATALAYOUT_FLAT_PLAIN: u16 = 0;
+pub const INODE_DATALAYOUT_FLAT_INLINE: u16 = 2;
+pub const INODE_DATALAYOUT_CHUNK_BASED: u16 = 4;
+
+/// Chunk index value meaning "this chunk has no data on this device"
+pub const NULL_ADDR: u32 = 0xffffffff;
+
+pub const FILE_TYPE_REG_FILE: u8 = 1;
+pub const FILE_TYPE_DIR: u8 = 2;
+pub const FILE_TYPE_CHRDEV: u8 = 3;
+pub const FILE_TYPE_BLKDEV: u8 = 4;
+pub const FILE_TYPE_FIFO: u8 = 5;
+pub const FILE_TYPE_SOCK: u8 = 6;
+pub const FILE_TYPE_SYMLINK: u8 = 7;
+
+/// The well-known xattr name prefixes, by index.  Index 0 means "no prefix".
+pub const XATTR_PREFIXES: [&[u8]; 7] = [
+    b"",
+    b"user.",
+    b"system.posix_acl_access",
+    b"system.posix_acl_default",
+    b"trusted.",
+    b"lustre.",
+    b"security.",
+];
+
+pub const XATTR_FILTER_SEED: u32 = 0x25bbe08f;
+pub const XATTR_FILTER_BITS: u32 = 32;
+
+fn get_u16(input: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(input[offset..offset + 2].try_into().unwrap())
+}
+
+fn get_u32(input: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(input[offset..offset + 4].try_into().unwrap())
+}
+
+fn get_u64(input: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(input[offset..offset + 8].try_into().unwrap())
+}
+
+fn put_u16(output: &mut Vec<u8>, value: u16) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(output: &mut Vec<u8>, value: u32) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(output: &mut Vec<u8>, value: u64) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+/// The composefs header, found at the very start of the image (before the superblock)
+#[derive(Debug, Default)]
+pub struct ComposefsHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub composefs_version: u32,
+}
+
+impl ComposefsHeader {
+    pub const SIZE: usize = 32;
+
+    /// Parses the header from the start of `input`, which must be at least `SIZE` bytes
+    pub fn read(input: &[u8]) -> Self {
+        ComposefsHeader {
+            magic: get_u32(input, 0),
+            version: get_u32(input, 4),
+            flags: get_u32(input, 8),
+            composefs_version: get_u32(input, 12),
+        }
+    }
+
+    pub fn write(&self, output: &mut Vec<u8>) {
+        put_u32(output, self.magic);
+        put_u32(output, self.version);
+        put_u32(output, self.flags);
+        put_u32(output, self.composefs_version);
+        output.extend_from_slice(&[0; 16]);
+    }
+}
+
+/// The erofs superblock
+#[derive(Debug, Default)]
+pub struct Superblock {
+    pub magic: u32,
+    pub checksum: u32,
+    pub feature_compat: u32,
+    pub blkszbits: u8,
+    pub extslots: u8,
+    pub root_nid: u16,
+    pub inos: u64,
+    pub build_time: u64,
+    pub build_time_nsec: u32,
+    pub blocks: u32,
+    pub meta_blkaddr: u32,
+    pub xattr_blkaddr: u32,
+    pub uuid: [u8; 16],
+    pub volume_name: [u8; 16],
+    pub feature_incompat: u32,
+    pub available_compr_algs: u16,
+    pub extra_devices: u16,
+    pub devt_slotoff: u16,
+    pub dirblkbits: u8,
+    pub xattr_prefix_count: u8,
+    pub xattr_prefix_start: u32,
+    pub packed_nid: u64,
+    pub xattr_filter_reserved: u8,
+}
+
+impl Superblock {
+    pub const SIZE: usize = 128;
+
+    /// Parses the superblock from the start of `input`, which must be at least `SIZE` bytes
+    pub fn read(input: &[u8]) -> Self {
+        Superblock {
+            magic: get_u32(input, 0),
+            checksum: get_u32(input, 4),
+            feature_compat: get_u32(input, 8),
+            blkszbits: input[12],
+            extslots: input[13],
+            root_nid: get_u16(input, 14),
+            inos: get_u64(input, 16),
+            build_time: get_u64(input, 24),
+            build_time_nsec: get_u32(input, 32),
+            blocks: get_u32(input, 36),
+            meta_blkaddr: get_u32(input, 40),
+            xattr_blkaddr: get_u32(input, 44),
+            uuid: input[48..64].try_into().unwrap(),
+            volume_name: input[64..80].try_into().unwrap(),
+            feature_incompat: get_u32(input, 80),
+            available_compr_algs: get_u16(input, 84),
+            extra_devices: get_u16(input, 86),
+            devt_slotoff: get_u16(input, 88),
+            dirblkbits: input[90],
+            xattr_prefix_count: input[91],
+            xattr_prefix_start: get_u32(input, 92),
+            packed_nid: get_u64(input, 96),
+            xattr_filter_reserved: input[104],
+        }
+    }
+
+    pub fn write(&self, output: &mut Vec<u8>) {
+        put_u32(output, self.magic);
+        put_u32(output, self.checksum);
+        put_u32(output, self.feature_compat);
+        output.push(self.blkszbits);
+        output.push(self.extslots);
+        put_u16(output, self.root_nid);
+        put_u64(output, self.inos);
+        put_u64(output, self.build_time);
+        put_u32(output, self.build_time_nsec);
+        put_u32(output, self.blocks);
+        put_u32(output, self.meta_blkaddr);
+        put_u32(output, self.xattr_blkaddr);
+        output.extend_from_slice(&self.uuid);
+        output.extend_from_slice(&self.volume_name);
+        put_u32(output, self.feature_incompat);
+        put_u16(output, self.available_compr_algs);
+        put_u16(output, self.extra_devices);
+        put_u16(output, self.devt_slotoff);
+        output.push(self.dirblkbits);
+        output.push(self.xattr_prefix_count);
+        put_u32(output, self.xattr_prefix_start);
+        put_u64(output, self.packed_nid);
+        output.push(self.xattr_filter_reserved);
+        output.extend_from_slice(&[0; 23]);
+    }
+}
+
+/// Combines the inode layout and data layout into the `i_format` field
+pub fn inode_format(layout: u16, datalayout: u16) -> u16 {
+    layout | (datalayout << 1)
+}
+
+/// The 32-byte inode format, used when everything fits and the mtime equals the build time
+#[derive(Debug, Default)]
+pub struct CompactInodeHeader {
+    pub format: u16,
+    pub xattr_icount: u16,
+    pub mode: u16,
+    pub nlink: u16,
+    pub size: u32,
+    pub u: u32,
+    pub ino: u32,
+    pub uid: u16,
+    pub gid: u16,
+}
+
+impl CompactInodeHeader {
+    pub const SIZE: usize = 32;
+
+    /// Parses the inode from the start of `input`, which must be at least `SIZE` bytes
+    pub fn read(input: &[u8]) -> Self {
+        CompactInodeHeader {
+            format: get_u16(input, 0),
+            xattr_icount: get_u16(input, 2),
+            mode: get_u16(input, 4),
+            nlink: get_u16(input, 6),
+            size: get_u32(input, 8),
+            u: get_u32(input, 16),
+            ino: get_u32(input, 20),
+            uid: get_u16(input, 24),
+            gid: get_u16(input, 26),
+        }
+    }
+
+    pub fn write(&self, output: &mut Vec<u8>) {
+        put_u16(output, self.format);
+        put_u16(output, self.xattr_icount);
+        put_u16(output, self.mode);
+        put_u16(output, self.nlink);
+        put_u32(output, self.size);
+        put_u32(output, 0);
+        put_u32(output, self.u);
+        put_u32(output, self.ino);
+        put_u16(output, self.uid);
+        put_u16(output, self.gid);
+        put_u32(output, 0);
+    }
+}
+
+/// The 64-byte inode format
+#[derive(Debug, Default)]
+pub struct ExtendedInodeHeader {
+    pub format: u16,
+    pub xattr_icount: u16,
+    pub mode: u16,
+    pub size: u64,
+    pub u: u32,
+    pub ino: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+    pub nlink: u32,
+}
+
+impl ExtendedInodeHeader {
+    pub const SIZE: usize = 64;
+
+    /// Parses the inode from the start of `input`, which must be at least `SIZE` bytes
+    pub fn read(input: &[u8]) -> Self {
+        ExtendedInodeHeader {
+            format: get_u16(input, 0),
+            xattr_icount: get_u16(input, 2),
+            mode: get_u16(input, 4),
+            size: get_u64(input, 8),
+            u: get_u32(input, 16),
+            ino: get_u32(input, 20),
+            uid: get_u32(input, 24),
+            gid: get_u32(input, 28),
+            mtime: get_u64(input, 32),
+            mtime_nsec: get_u32(input, 40),
+            nlink: get_u32(input, 44),
+        }
+    }
+
+    pub fn write(&self, output: &mut Vec<u8>) {
+        put_u16(output, self.format);
+        put_u16(output, self.xattr_icount);
+        put_u16(output, self.mode);
+        put_u16(output, 0);
+        put_u64(output, self.size);
+        put_u32(output, self.u);
+        put_u32(output, self.ino);
+        put_u32(output, self.uid);
+        put_u32(output, self.gid);
+        put_u64(output, self.mtime);
+        put_u32(output, self.mtime_nsec);
+        put_u32(output, self.nlink);
+        output.extend_from_slice(&[0; 16]);
+    }
+}
+
+/// Precedes the xattrs stored in an inode: the shared xattr ids follow immediately after
+#[derive(Debug, Default)]
+pub struct InodeXAttrHeader {
+    pub name_filter: u32,
+    pub shared_count: u8,
+}
+
+impl InodeXAttrHeader {
+    pub const SIZE: usize = 12;
+
+    /// Parses the header from the start of `input`, which must be at least `SIZE` bytes
+    pub fn read(input: &[u8]) -> Self {
+        InodeXAttrHeader {
+            name_filter: get_u32(input, 0),
+            shared_count: input[4],
+        }
+    }
+
+    pub fn write(&self, output: &mut Vec<u8>) {
+        put_u32(output, self.name_filter);
+        output.push(self.shared_count);
+        output.extend_from_slice(&[0; 7]);
+    }
+}
+
+/// Precedes the name suffix and value of each xattr entry
+#[derive(Debug, Default)]
+pub struct XAttrHeader {
+    pub name_len: u8,
+    pub name_index: u8,
+    pub value_size: u16,
+}
+
+impl XAttrHeader {
+    pub const SIZE: usize = 4;
+
+    /// Parses the header from the start of `input`, which must be at least `SIZE` bytes
+    pub fn read(input: &[u8]) -> Self {
+        XAttrHeader {
+            name_len: input[0],
+            name_index: input[1],
+            value_size: get_u16(input, 2),
+        }
+    }
+
+    pub fn write(&self, output: &mut Vec<u8>) {
+        output.push(self.name_len);
+        output.push(self.name_index);
+        put_u16(output, self.value_size);
+    }
+}
+
+/// A directory entry.  The names are stored after all the entries in the same block.
+#[derive(Debug, Default)]
+pub struct DirectoryEntryHeader {
+    pub inode_offset: u64,
+    pub name_offset: u16,
+    pub file_type: u8,
+}
+
+impl DirectoryEntryHeader {
+    pub const SIZE: usize = 12;
+
+    /// Parses the entry from the start of `input`, which must be at least `SIZE` bytes
+    pub fn read(input: &[u8]) -> Self {
+        DirectoryEntryHeader {
+            inode_offset: get_u64(input, 0),
+            name_offset: get_u16(input, 8),
+            file_type: input[10],
+        }
+    }
+
+    pub fn write(&self, output: &mut Vec<u8>) {
+        put_u64(output, self.inode_offset);
+        put_u16(output, self.name_offset);
+        output.push(self.file_type);
+        output.push(0);
+    }
+}
+
+/// The xxh32 hash function, as used for the xattr name bloom filter
+pub fn xxh32(input: &[u8], seed: u32) -> u32 {
+    const PRIME1: u32 = 0x9e3779b1;
+    const PRIME2: u32 = 0x85ebca77;
+    const PRIME3: u32 = 0xc2b2ae3d;
+    const PRIME4: u32 = 0x27d4eb2f;
+    const PRIME5: u32 = 0x165667b1;
+
+    fn round(acc: u32, lane: &[u8]) -> u32 {
+        let lane = u32::from_le_bytes(lane.try_into().unwrap());
+        acc.wrapping_add(lane.wrapping_mul(PRIME2))
+            .rotate_left(13)
+            .wrapping_mul(PRIME1)
+    }
+
+    let mut rest = input;
+    let mut hash = if input.len() >= 16 {
+        let mut v = [
+            seed.wrapping_add(PRIME1).wrapping_add(PRIME2),
+            seed.wrapping_add(PRIME2),
+            seed,
+            seed.wrapping_sub(PRIME1),
+        ];
+        while rest.len() >= 16 {
+            for (i, acc) in v.iter_mut().enumerate() {
+                *acc = round(*acc, &rest[i * 4..i * 4 + 4]);
+            }
+            rest = &rest[16..];
+        }
+        v[0].rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(PRIME5)
+    };
+
+    hash = hash.wrapping_add(input.len() as u32);
+
+    while rest.len() >= 4 {
+        let lane = u32::from_le_bytes(rest[..4].try_into().unwrap());
+        hash = hash
+            .wrapping_add(lane.wrapping_mul(PRIME3))
+            .rotate_left(17)
+            .wrapping_mul(PRIME4);
+        rest = &rest[4..];
+    }
+
+    for byte in rest {
+        hash = hash
+            .wrapping_add((*byte as u32).wrapping_mul(PRIME5))
+            .rotate_left(11)
+            .wrapping_mul(PRIME1);
+    }
+
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(PRIME2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(PRIME3);
+    hash ^= hash >> 16;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xxh32() {
+        // reference values from the xxHash test suite
+        assert_eq!(xxh32(b"", 0), 0x02cc5d05);
+        assert_eq!(xxh32(b"", 0x9e3779b1), 0x36b78ae7);
+        assert_eq!(xxh32(b"a", 0), 0x550d7456);
+        assert_eq!(xxh32(b"abc", 0), 0x32d153ff);
+        assert_eq!(
+            xxh32(b"Nobody inspects the spammish repetition", 0),
+            0xe2293b2f
+        );
+    }
+}
@@ -0,0 +1,452 @@
+//! Native reader for composefs erofs images.
+//!
+//! This is the counterpart of [`crate::erofs::writer`]: it parses the images produced by
+//! `mkfs_erofs()` or `mkcomposefs` so that we can list the objects referenced by an image or dump
+//! its contents without needing `composefs-info`.  Only the subset of erofs that composefs uses is
+//! supported: uncompressed flat and chunk-based inodes, with the standard xattr name prefixes.
+
+use std::{borrow::Cow, collections::HashSet, io::Read};
+
+use anyhow::Context;
+use rustix::fs::FileType;
+
+use crate::{erofs::format::*, fsverity::FsVerityHashValue};
+
+use crate::error::{bail, ensure, Result};
+
+const OVERLAY_PREFIX: &[u8] = b"trusted.overlay.";
+const OVERLAY_ESCAPED_PREFIX: &[u8] = b"trusted.overlay.overlay.";
+
+/// A composefs erofs image, held in memory
+#[derive(Debug)]
+pub struct Image {
+    data: Vec<u8>,
+    superblock: Superblock,
+}
+
+/// An extended attribute, with its full (unabbreviated) name as stored in the image
+#[derive(Debug)]
+pub struct XAttr<'img> {
+    pub name: Vec<u8>,
+    pub value: &'img [u8],
+}
+
+/// A directory entry, other than '.' and '..'
+#[derive(Debug)]
+pub struct DirEntry<'img> {
+    pub name: &'img [u8],
+    pub nid: u64,
+    pub file_type: u8,
+}
+
+/// An inode, with the fields of the compact and extended formats merged
+#[derive(Debug)]
+pub struct Inode<'img> {
+    pub nid: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+    pub xattrs: Vec<XAttr<'img>>,
+    datalayout: u16,
+    u: u32,
+    /// The bytes following the inode header and xattrs, up to the end of the image
+    tail: &'img [u8],
+    image: &'img Image,
+}
+
+impl Image {
+    /// Reads the entire image from `input`
+    pub fn read(mut input: impl Read) -> Result<Self> {
+        let mut data = vec![];
+        input
+            .read_to_end(&mut data)
+            .context("Reading erofs image")?;
+        Image::new(data)
+    }
+
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        ensure!(
+            data.len() >= SUPERBLOCK_OFFSET + Superblock::SIZE,
+            "erofs image is too small"
+        );
+        let superblock = Superblock::read(&data[SUPERBLOCK_OFFSET..]);
+        ensure!(
+            superblock.magic == EROFS_MAGIC,
+            "Bad erofs superblock magic"
+        );
+        ensure!(
+            superblock.blkszbits == BLOCK_BITS,
+            "Unsupported erofs block size 2^{}",
+            superblock.blkszbits
+        );
+        Ok(Image { data, superblock })
+    }
+
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
+    pub fn root_nid(&self) -> u64 {
+        self.superblock.root_nid as u64
+    }
+
+    fn slice(&self, offset: usize, len: usize) -> Result<&[u8]> {
+        Ok(offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(offset..end))
+            .with_context(|| format!("erofs image truncated reading {len} bytes at {offset}"))?)
+    }
+
+    fn block_offset(blkaddr: u32) -> usize {
+        blkaddr as usize * BLOCK_SIZE
+    }
+
+    /// Reads the xattr entry at `offset`, returning it along with its padded size
+    fn xattr(&self, offset: usize) -> Result<(XAttr<'_>, usize)> {
+        let header = XAttrHeader::read(self.slice(offset, XAttrHeader::SIZE)?);
+        let Some(prefix) = XATTR_PREFIXES.get(header.name_index as usize) else {
+            bail!("Unsupported xattr name index {}", header.name_index);
+        };
+        let name_len = header.name_len as usize;
+        let value_size = header.value_size as usize;
+        let suffix = self.slice(offset + XAttrHeader::SIZE, name_len)?;
+        let value = self.slice(offset + XAttrHeader::SIZE + name_len, value_size)?;
+        let size = (XAttrHeader::SIZE + name_len + value_size).next_multiple_of(4);
+        let name = [*prefix, suffix].concat();
+        Ok((XAttr { name, value }, size))
+    }
+
+    pub fn inode(&self, nid: u64) -> Result<Inode<'_>> {
+        let offset = usize::try_from(nid)
+            .ok()
+            .and_then(|nid| nid.checked_mul(INODE_SLOT_SIZE))
+            .and_then(|off| off.checked_add(Self::block_offset(self.superblock.meta_blkaddr)))
+            .with_context(|| format!("Invalid nid {nid}"))?;
+
+        let format = u16::from_le_bytes(self.slice(offset, 2)?.try_into().unwrap());
+        let datalayout = (format >> 1) & 0x7;
+        let mut inode = match format & 1 {
+            INODE_LAYOUT_COMPACT => {
+                let header =
+                    CompactInodeHeader::read(self.slice(offset, CompactInodeHeader::SIZE)?);
+                Inode {
+                    nid,
+                    mode: header.mode as u32,
+                    nlink: header.nlink as u32,
+                    uid: header.uid as u32,
+                    gid: header.gid as u32,
+                    size: header.size as u64,
+                    mtime: self.superblock.build_time,
+                    mtime_nsec: self.superblock.build_time_nsec,
+                    xattrs: vec![],
+                    datalayout,
+                    u: header.u,
+                    tail: &[],
+                    image: self,
+                }
+            }
+            _ => {
+                let header =
+                    ExtendedInodeHeader::read(self.slice(offset, ExtendedInodeHeader::SIZE)?);
+                Inode {
+                    nid,
+                    mode: header.mode as u32,
+                    nlink: header.nlink,
+                    uid: header.uid,
+                    gid: header.gid,
+                    size: header.size,
+                    mtime: header.mtime,
+                    mtime_nsec: header.mtime_nsec,
+                    xattrs: vec![],
+                    datalayout,
+                    u: header.u,
+                    tail: &[],
+                    image: self,
+                }
+            }
+        };
+
+        let xattr_start = offset
+            + match format & 1 {
+                INODE_LAYOUT_COMPACT => CompactInodeHeader::SIZE,
+                _ => ExtendedInodeHeader::SIZE,
+            };
+        let xattr_icount = u16::from_le_bytes(self.slice(offset + 2, 2)?.try_into().unwrap());
+        let xattr_end = match xattr_icount {
+            0 => xattr_start,
+            n => xattr_start + InodeXAttrHeader::SIZE + 4 * (n as usize - 1),
+        };
+
+        if xattr_icount > 0 {
+            let header = InodeXAttrHeader::read(self.slice(xattr_start, InodeXAttrHeader::SIZE)?);
+            let mut pos = xattr_start + InodeXAttrHeader::SIZE;
+            let mut shared = vec![];
+            for _ in 0..header.shared_count {
+                let id = u32::from_le_bytes(self.slice(pos, 4)?.try_into().unwrap());
+                shared.push(id);
+                pos += 4;
+            }
+            // Inline xattrs first, then the shared ones: the same order as composefs-info
+            while pos < xattr_end {
+                let (xattr, size) = self.xattr(pos)?;
+                inode.xattrs.push(xattr);
+                pos += size;
+            }
+            let shared_base = Self::block_offset(self.superblock.xattr_blkaddr);
+            for id in shared {
+                let (xattr, _) = self.xattr(shared_base + 4 * id as usize)?;
+                inode.xattrs.push(xattr);
+            }
+        }
+
+        inode.tail = self
+            .data
+            .get(xattr_end..)
+            .with_context(|| format!("erofs image truncated reading inode {nid}"))?;
+        Ok(inode)
+    }
+}
+
+impl<'img> Inode<'img> {
+    pub fn file_type(&self) -> FileType {
+        FileType::from_raw_mode(self.mode)
+    }
+
+    /// The device number of a block or character device
+    pub fn rdev(&self) -> u32 {
+        self.u
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.datalayout == INODE_DATALAYOUT_CHUNK_BASED
+    }
+
+    pub fn xattr(&self, name: &[u8]) -> Option<&'img [u8]> {
+        self.xattrs
+            .iter()
+            .find(|xattr| xattr.name == name)
+            .map(|xattr| xattr.value)
+    }
+
+    /// The xattrs as they'd appear on the mounted filesystem: the overlayfs xattrs which are
+    /// interpreted by the kernel are hidden, and the escaped ones are unescaped.
+    pub fn visible_xattrs(&self) -> impl Iterator<Item = (Cow<'_, [u8]>, &'img [u8])> {
+        self.xattrs.iter().filter_map(|xattr| {
+            let name = &xattr.name[..];
+            if let Some(rest) = name.strip_prefix(OVERLAY_ESCAPED_PREFIX) {
+                Some((Cow::Owned([OVERLAY_PREFIX, rest].concat()), xattr.value))
+            } else if name.starts_with(OVERLAY_PREFIX) {
+                None
+            } else {
+                Some((Cow::Borrowed(name), xattr.value))
+            }
+        })
+    }
+
+    /// The ID of the repository object holding the content of this file, if any, taken from its
+    /// overlayfs redirect xattr
+    pub fn object_id<ObjectID: FsVerityHashValue>(&self) -> Result<Option<ObjectID>> {
+        let Some(redirect) = self.xattr(b"trusted.overlay.redirect") else {
+            return Ok(None);
+        };
+        // "/xx/yyyyyyyy..."
+        let Some(pathname) = redirect.strip_prefix(b"/") else {
+            bail!(
+                "Invalid overlay redirect {:?} in inode {}",
+                String::from_utf8_lossy(redirect),
+                self.nid
+            );
+        };
+        Ok(ObjectID::from_object_pathname(pathname)
+            .map(Some)
+            .with_context(|| format!("Invalid overlay redirect in inode {}", self.nid))?)
+    }
+
+    /// The fs-verity digest of the external file content, taken from its overlayfs metacopy xattr
+    pub fn metacopy_digest(&self) -> Option<&'img [u8]> {
+        // struct ovl_metacopy: version, length, flags, digest algorithm, digest
+        let value = self.xattr(b"trusted.overlay.metacopy")?;
+        value.get(4..value.get(1).copied()? as usize)
+    }
+
+    /// Returns the data stored in full blocks, and the tail which is stored inline
+    fn data_parts(&self) -> Result<(&'img [u8], &'img [u8])> {
+        let size = usize::try_from(self.size)?;
+        let (n_blocks, inline) = match self.datalayout {
+            INODE_DATALAYOUT_FLAT_PLAIN => (size.div_ceil(BLOCK_SIZE), 0),
+            INODE_DATALAYOUT_FLAT_INLINE => {
+                let n_blocks = size.div_ceil(BLOCK_SIZE).saturating_sub(1);
+                (n_blocks, size - n_blocks * BLOCK_SIZE)
+            }
+            other => bail!("Unsupported data layout {other} in inode {}", self.nid),
+        };
+
+        let blocks = match n_blocks {
+            0 => &[][..],
+            _ => self
+                .image
+                .slice(Image::block_offset(self.u), size - inline)?,
+        };
+        let tail = self
+            .tail
+            .get(..inline)
+            .with_context(|| format!("erofs image truncated reading inode {}", self.nid))?;
+        Ok((blocks, tail))
+    }
+
+    /// The content of an inline file, the target of a symlink, or the raw directory data
+    pub fn data(&self) -> Result<Cow<'img, [u8]>> {
+        match self.data_parts()? {
+            ([], tail) => Ok(Cow::Borrowed(tail)),
+            (blocks, []) => Ok(Cow::Borrowed(blocks)),
+            (blocks, tail) => Ok(Cow::Owned([blocks, tail].concat())),
+        }
+    }
+
+    /// The directory entries, excluding '.' and '..'
+    pub fn entries(&self) -> Result<Vec<DirEntry<'img>>> {
+        ensure!(
+            self.file_type() == FileType::Directory,
+            "Inode {} is not a directory",
+            self.nid
+        );
+        let (blocks, tail) = self.data_parts()?;
+
+        let mut entries = vec![];
+        for block in blocks.chunks(BLOCK_SIZE).chain([tail]) {
+            if block.is_empty() {
+                continue;
+            }
+            let first = DirectoryEntryHeader::read(
+                block
+                    .get(..DirectoryEntryHeader::SIZE)
+                    .context("Truncated directory block")?,
+            );
+            let count = first.name_offset as usize / DirectoryEntryHeader::SIZE;
+            ensure!(count > 0, "Empty directory block in inode {}", self.nid);
+
+            let headers: Vec<DirectoryEntryHeader> = (0..count)
+                .map(|i| {
+                    let start = i * DirectoryEntryHeader::SIZE;
+                    block
+                        .get(start..start + DirectoryEntryHeader::SIZE)
+                        .map(DirectoryEntryHeader::read)
+                        .context("Truncated directory block")
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            for (i, header) in headers.iter().enumerate() {
+                let start = header.name_offset as usize;
+                let name = match headers.get(i + 1) {
+                    Some(next) => block.get(start..next.name_offset as usize),
+                    // The last name runs to the end of the block, possibly with padding
+                    None => block.get(start..).map(|name| {
+                        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+                        &name[..len]
+                    }),
+                }
+                .context("Invalid directory entry name offset")?;
+
+                if name != b"." && name != b".." {
+                    entries.push(DirEntry {
+                        name,
+                        nid: header.inode_offset,
+                        file_type: header.file_type,
+                    });
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Returns the set of repository objects referred to by the image
+pub fn collect_objects<ObjectID: FsVerityHashValue>(image: &Image) -> Result<HashSet<ObjectID>> {
+    let mut objects = HashSet::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![image.root_nid()];
+
+    while let Some(nid) = pending.pop() {
+        if !visited.insert(nid) {
+            continue;
+        }
+        for entry in image.inode(nid)?.entries()? {
+            if entry.file_type == FILE_TYPE_DIR {
+                pending.push(entry.nid);
+            } else if entry.file_type == FILE_TYPE_REG_FILE {
+                if let Some(id) = image.inode(entry.nid)?.object_id()? {
+                    objects.insert(id);
+                }
+            }
+        }
+    }
+
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeMap, ffi::OsStr, path::Path};
+
+    use super::*;
+    use crate::{
+        erofs::writer::mkfs_erofs,
+        fsverity::Sha256HashValue,
+        image::{FileSystem, Leaf, LeafContent, Stat},
+    };
+
+    fn leaf(content: LeafContent<Sha256HashValue>) -> Leaf<Sha256HashValue> {
+        Leaf {
+            stat: Stat {
+                st_mode: 0o644,
+                st_uid: 0,
+                st_gid: 0,
+                st_mtim_sec: 0,
+                xattrs: RefCell::new(BTreeMap::new()),
+            },
+            content,
+        }
+    }
+
+    #[test]
+    fn test_collect_objects() -> Result<()> {
+        let mut fs = FileSystem::new();
+        fs.insert(
+            Path::new("/a"),
+            leaf(LeafContent::ExternalFile([1; 32], 100)),
+        )?;
+        fs.insert(
+            Path::new("/b"),
+            leaf(LeafContent::ExternalFile([2; 32], 100)),
+        )?;
+        fs.hardlink(Path::new("/c"), OsStr::new("/b"))?;
+        fs.insert(
+            Path::new("/inline"),
+            leaf(LeafContent::InlineFile(b"hello".to_vec())),
+        )?;
+
+        let image = Image::new(mkfs_erofs(&fs)?)?;
+        assert_eq!(
+            collect_objects::<Sha256HashValue>(&image)?,
+            HashSet::from([[1; 32], [2; 32]])
+        );
+
+        let root = image.inode(image.root_nid())?;
+        let names: Vec<&[u8]> = root.entries()?.iter().map(|ent| ent.name).collect();
+        assert_eq!(names[..5], [b"00", b"01", b"02", b"03", b"04"]);
+        assert!(names.contains(&&b"inline"[..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_image() {
+        assert!(Image::new(vec![]).is_err());
+        assert!(Image::new(vec![0; BLOCK_SIZE]).is_err());
+    }
+}
@@ -0,0 +1,1154 @@
+//! Native writer for composefs erofs images.
+//!
+//! This follows the conventions of `mkcomposefs`: xattrs which are used by more than one inode
+//! are shared, and regular files stored in the repository are represented as sparse chunk-based
+//! files pointing at their object via overlayfs metacopy/redirect xattrs.  The inodes are numbered
+//! and laid out breadth-first, in the same order as `mkcomposefs` (see `InodeCollector`).
+//!
+//! The output is meant to be byte-for-byte identical to what `mkcomposefs` produces from the
+//! dumpfile of the filesystem.  `test_golden_images` checks that against images made by
+//! `mkcomposefs` from the dumpfiles in `src/tests/assets`; until it passes,
+//! [`crate::image::mkcomposefs`] keeps running `mkcomposefs`.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, VecDeque},
+    os::unix::ffi::OsStrExt,
+    rc::Rc,
+};
+
+use crate::{
+    erofs::format::*,
+    error::{ensure, Result},
+    fsverity::FsVerityHashValue,
+    image::{DirEnt, Directory, FileSystem, Inode as ImageInode, Leaf, LeafContent, Stat},
+};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFIFO: u32 = 0o010000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+
+const OVERLAY_PREFIX: &[u8] = b"trusted.overlay.";
+const OVERLAY_ESCAPED_PREFIX: &[u8] = b"trusted.overlay.overlay.";
+
+fn round_up(n: usize, to: usize) -> usize {
+    n.div_ceil(to) * to
+}
+
+fn pad_to(output: &mut Vec<u8>, alignment: usize) {
+    output.resize(round_up(output.len(), alignment), 0);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct XAttr {
+    prefix: u8,
+    suffix: Box<[u8]>,
+    value: Box<[u8]>,
+}
+
+impl XAttr {
+    fn new(name: &[u8], value: &[u8]) -> Result<Self> {
+        // Prefer the longest matching prefix.  The empty prefix (index 0) always matches.
+        let (prefix, suffix) = XATTR_PREFIXES
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, prefix)| Some((idx as u8, name.strip_prefix(*prefix)?)))
+            .min_by_key(|(_, suffix)| suffix.len())
+            .unwrap();
+
+        // The header only has room for a u8 name length and a u16 value size
+        ensure!(
+            suffix.len() <= u8::MAX as usize,
+            "xattr name {:?} is too long",
+            String::from_utf8_lossy(name)
+        );
+        ensure!(
+            value.len() <= u16::MAX as usize,
+            "value of xattr {:?} is too long ({} bytes)",
+            String::from_utf8_lossy(name),
+            value.len()
+        );
+
+        Ok(XAttr {
+            prefix,
+            suffix: Box::from(suffix),
+            value: Box::from(value),
+        })
+    }
+
+    fn size(&self) -> usize {
+        round_up(XAttrHeader::SIZE + self.suffix.len() + self.value.len(), 4)
+    }
+
+    fn filter_bit(&self) -> u32 {
+        let seed = XATTR_FILTER_SEED.wrapping_add(self.prefix as u32);
+        1 << (xxh32(&self.suffix, seed) % XATTR_FILTER_BITS)
+    }
+
+    fn is_acl(&self) -> bool {
+        self.prefix == 2 || self.prefix == 3
+    }
+
+    fn write(&self, output: &mut Vec<u8>) {
+        // The sizes were checked in XAttr::new()
+        XAttrHeader {
+            name_len: self.suffix.len() as u8,
+            name_index: self.prefix,
+            value_size: self.value.len() as u16,
+        }
+        .write(output);
+        output.extend_from_slice(&self.suffix);
+        output.extend_from_slice(&self.value);
+        pad_to(output, 4);
+    }
+}
+
+#[derive(Debug)]
+struct DirEntry<'a> {
+    name: Cow<'a, [u8]>,
+    inode: usize,
+    file_type: u8,
+}
+
+#[derive(Debug)]
+enum Content<'a> {
+    /// Directory entries, sorted by name, including '.' and '..'
+    Directory(Vec<DirEntry<'a>>),
+    /// Regular file content or symlink target
+    Data(&'a [u8]),
+    /// A file whose content lives in the repository
+    External(u64),
+    /// Block or character device
+    Device(u32),
+    /// Fifos, sockets and empty files
+    Empty,
+}
+
+#[derive(Debug)]
+struct Inode<'a> {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    nlink: u32,
+    content: Content<'a>,
+    xattrs: Vec<XAttr>,
+    shared: Vec<u32>,
+}
+
+impl Inode<'_> {
+    fn size(&self) -> u64 {
+        match &self.content {
+            Content::Directory(entries) => dir_size(entries) as u64,
+            Content::Data(data) => data.len() as u64,
+            Content::External(size) => *size,
+            Content::Device(..) | Content::Empty => 0,
+        }
+    }
+
+    fn file_type(&self) -> u8 {
+        match self.mode & S_IFMT {
+            S_IFREG => FILE_TYPE_REG_FILE,
+            S_IFDIR => FILE_TYPE_DIR,
+            S_IFCHR => FILE_TYPE_CHRDEV,
+            S_IFBLK => FILE_TYPE_BLKDEV,
+            S_IFIFO => FILE_TYPE_FIFO,
+            S_IFSOCK => FILE_TYPE_SOCK,
+            S_IFLNK => FILE_TYPE_SYMLINK,
+            _ => unreachable!(),
+        }
+    }
+
+    fn xattr_size(&self) -> usize {
+        if self.xattrs.is_empty() && self.shared.is_empty() {
+            0
+        } else {
+            InodeXAttrHeader::SIZE
+                + 4 * self.shared.len()
+                + self.xattrs.iter().map(XAttr::size).sum::<usize>()
+        }
+    }
+}
+
+/// Splits the (sorted) directory entries into blocks.  Each block holds the entry headers
+/// followed by the names, and no entry may cross a block boundary.
+fn dir_blocks<'a, 'b>(entries: &'b [DirEntry<'a>]) -> Vec<&'b [DirEntry<'a>]> {
+    let mut blocks = vec![];
+    let mut start = 0;
+    let mut size = 0;
+
+    for (idx, entry) in entries.iter().enumerate() {
+        let entry_size = DirectoryEntryHeader::SIZE + entry.name.len();
+        if size + entry_size > BLOCK_SIZE {
+            blocks.push(&entries[start..idx]);
+            start = idx;
+            size = 0;
+        }
+        size += entry_size;
+    }
+    blocks.push(&entries[start..]);
+
+    blocks
+}
+
+fn dir_size(entries: &[DirEntry]) -> usize {
+    let blocks = dir_blocks(entries);
+    let last = blocks.last().unwrap();
+    (blocks.len() - 1) * BLOCK_SIZE
+        + last
+            .iter()
+            .map(|entry| DirectoryEntryHeader::SIZE + entry.name.len())
+            .sum::<usize>()
+}
+
+/// Numbers the inodes the way `mkcomposefs` does (`lcfs_compute_tree()` in libcomposefs):
+/// breadth-first, starting from the root, with the entries of each directory in name order.  A
+/// file with several links is numbered where its first link in the dumpfile (a depth-first walk)
+/// is reached.
+struct InodeCollector<'a, ObjectID: FsVerityHashValue> {
+    inodes: Vec<Inode<'a>>,
+    /// The first link to each leaf with more than one, in dumpfile order
+    first_links: HashMap<*const Leaf<ObjectID>, *const DirEnt<ObjectID>>,
+    /// The inodes of the leaves with more than one link which have been numbered so far
+    hardlinks: HashMap<*const Leaf<ObjectID>, usize>,
+    /// Directories which still need their entries collected, with their inode and their parent's
+    queue: VecDeque<(&'a Directory<ObjectID>, usize, usize)>,
+    /// Entries of the directory inode (by name) which are links to a leaf that hasn't been
+    /// numbered yet
+    unresolved: Vec<(usize, &'a [u8], *const Leaf<ObjectID>)>,
+}
+
+impl<'a, ObjectID: FsVerityHashValue> InodeCollector<'a, ObjectID> {
+    fn push(&mut self, inode: Inode<'a>) -> usize {
+        self.inodes.push(inode);
+        self.inodes.len() - 1
+    }
+
+    fn xattrs(stat: &Stat) -> Result<Vec<XAttr>> {
+        stat.xattrs
+            .borrow()
+            .iter()
+            .map(|(name, value)| {
+                let name = name.as_bytes();
+                // Escape any overlayfs xattrs so that they're visible in the mounted image
+                if let Some(rest) = name.strip_prefix(OVERLAY_PREFIX) {
+                    XAttr::new(&[OVERLAY_ESCAPED_PREFIX, rest].concat(), value)
+                } else {
+                    XAttr::new(name, value)
+                }
+            })
+            .collect()
+    }
+
+    fn new_inode(stat: &Stat, ifmt: u32, nlink: usize, content: Content<'a>) -> Result<Inode<'a>> {
+        Ok(Inode {
+            mode: ifmt | (stat.st_mode & !S_IFMT),
+            uid: stat.st_uid,
+            gid: stat.st_gid,
+            mtime: stat.st_mtim_sec as u64,
+            nlink: nlink as u32,
+            content,
+            xattrs: Self::xattrs(stat)?,
+            shared: vec![],
+        })
+    }
+
+    /// Records the first link to each leaf with more than one, walking `dir` depth-first
+    fn find_first_links(&mut self, dir: &'a Directory<ObjectID>) {
+        for ent in &dir.entries {
+            match &ent.inode {
+                ImageInode::Directory(subdir) => self.find_first_links(subdir),
+                ImageInode::Leaf(leaf) if Rc::strong_count(leaf) > 1 => {
+                    self.first_links.entry(Rc::as_ptr(leaf)).or_insert(ent);
+                }
+                ImageInode::Leaf(..) => {}
+            }
+        }
+    }
+
+    /// Returns the inode index of the leaf that `ent` links to, unless that's another link to a
+    /// leaf whose first link comes later in the dumpfile.
+    fn collect_leaf(
+        &mut self,
+        ent: &'a DirEnt<ObjectID>,
+        leaf: &'a Rc<Leaf<ObjectID>>,
+    ) -> Result<Option<usize>> {
+        let nlink = Rc::strong_count(leaf);
+        if nlink > 1 {
+            if let Some(idx) = self.hardlinks.get(&Rc::as_ptr(leaf)) {
+                return Ok(Some(*idx));
+            }
+            if self.first_links.get(&Rc::as_ptr(leaf)) != Some(&(ent as *const _)) {
+                return Ok(None);
+            }
+        }
+
+        let stat = &leaf.stat;
+        let mut inode = match &leaf.content {
+            LeafContent::InlineFile(data) if data.is_empty() => {
+                Self::new_inode(stat, S_IFREG, nlink, Content::Empty)?
+            }
+            LeafContent::InlineFile(data) => {
+                Self::new_inode(stat, S_IFREG, nlink, Content::Data(data))?
+            }
+            LeafContent::ExternalFile(id, size) => {
+                let mut inode = Self::new_inode(stat, S_IFREG, nlink, Content::External(*size))?;
+                inode.xattrs.push(metacopy_xattr(id)?);
+                inode.xattrs.push(redirect_xattr(id)?);
+                inode
+            }
+            LeafContent::BlockDevice(rdev) => {
+                Self::new_inode(stat, S_IFBLK, nlink, Content::Device(*rdev as u32))?
+            }
+            LeafContent::CharacterDevice(0) => {
+                // A whiteout.  If we stored it as-is then overlayfs would hide the file when
+                // mounting the image, so convert it to an xattr whiteout instead.
+                let mut inode = Self::new_inode(stat, S_IFREG, nlink, Content::Empty)?;
+                inode
+                    .xattrs
+                    .push(XAttr::new(b"trusted.overlay.overlay.whiteout", b"")?);
+                inode
+                    .xattrs
+                    .push(XAttr::new(b"user.overlay.whiteout", b"")?);
+                inode
+            }
+            LeafContent::CharacterDevice(rdev) => {
+                Self::new_inode(stat, S_IFCHR, nlink, Content::Device(*rdev as u32))?
+            }
+            LeafContent::Fifo => Self::new_inode(stat, S_IFIFO, nlink, Content::Empty)?,
+            LeafContent::Socket => Self::new_inode(stat, S_IFSOCK, nlink, Content::Empty)?,
+            LeafContent::Symlink(target) => {
+                Self::new_inode(stat, S_IFLNK, nlink, Content::Data(target.as_bytes()))?
+            }
+        };
+        inode.xattrs.sort();
+
+        let idx = self.push(inode);
+        if nlink > 1 {
+            self.hardlinks.insert(Rc::as_ptr(leaf), idx);
+        }
+        Ok(Some(idx))
+    }
+
+    fn dir_inode(dir: &Directory<ObjectID>) -> Result<Inode<'a>> {
+        // nlink is 2 + number of subdirectories, as in the dumpfile
+        let nlink = 2 + dir
+            .entries
+            .iter()
+            .filter(|ent| matches!(ent.inode, ImageInode::Directory(..)))
+            .count();
+        Self::new_inode(&dir.stat, S_IFDIR, nlink, Content::Directory(vec![]))
+    }
+
+    /// Collects the inodes of the filesystem with the given root directory
+    fn collect(&mut self, root: &'a Directory<ObjectID>) -> Result<()> {
+        self.find_first_links(root);
+
+        let me = self.push(Self::dir_inode(root)?);
+        self.queue.push_back((root, me, me));
+        while let Some((dir, me, parent)) = self.queue.pop_front() {
+            self.collect_dir(dir, me, parent)?;
+        }
+
+        // Now that every leaf has been numbered, fill in the links to the ones that weren't yet
+        for (dir, name, leaf) in std::mem::take(&mut self.unresolved) {
+            let inode = self.hardlinks[&leaf];
+            let file_type = self.inodes[inode].file_type();
+            let Content::Directory(entries) = &mut self.inodes[dir].content else {
+                unreachable!();
+            };
+            let idx = entries
+                .binary_search_by(|entry| entry.name.as_ref().cmp(name))
+                .unwrap();
+            entries[idx].inode = inode;
+            entries[idx].file_type = file_type;
+        }
+
+        Ok(())
+    }
+
+    /// Fills in the entries of the directory inode `me`, adding inodes for the entries (after
+    /// everything that's been collected already) and queueing up the subdirectories
+    fn collect_dir(
+        &mut self,
+        dir: &'a Directory<ObjectID>,
+        me: usize,
+        parent: usize,
+    ) -> Result<()> {
+        let mut entries = vec![
+            DirEntry {
+                name: Cow::Borrowed(b"."),
+                inode: me,
+                file_type: FILE_TYPE_DIR,
+            },
+            DirEntry {
+                name: Cow::Borrowed(b".."),
+                inode: parent,
+                file_type: FILE_TYPE_DIR,
+            },
+        ];
+
+        // The root directory gets whiteouts for the 256 object subdirectories so that they
+        // don't show through if the repository is used as a regular lower layer.
+        let whiteouts = if me == 0 {
+            (0..=255u8)
+                .map(|i| format!("{i:02x}"))
+                .filter(|name| dir.find_entry(name.as_ref()).is_err())
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let mut children: Vec<(&[u8], Option<&'a DirEnt<ObjectID>>)> = dir
+            .entries
+            .iter()
+            .map(|ent| (ent.name.as_bytes(), Some(ent)))
+            .chain(whiteouts.iter().map(|name| (name.as_bytes(), None)))
+            .collect();
+        children.sort_by_key(|(name, _)| *name);
+
+        let mut has_whiteouts = false;
+        for (name, ent) in children {
+            let (inode, name) = match ent {
+                Some(DirEnt {
+                    name,
+                    inode: ImageInode::Directory(subdir),
+                }) => {
+                    let idx = self.push(Self::dir_inode(subdir)?);
+                    self.queue.push_back((subdir, idx, me));
+                    (idx, Cow::Borrowed(name.as_bytes()))
+                }
+                Some(
+                    ent @ DirEnt {
+                        name,
+                        inode: ImageInode::Leaf(leaf),
+                    },
+                ) => {
+                    has_whiteouts |= matches!(leaf.content, LeafContent::CharacterDevice(0));
+                    let name = name.as_bytes();
+                    match self.collect_leaf(ent, leaf)? {
+                        Some(idx) => (idx, Cow::Borrowed(name)),
+                        None => {
+                            // The file type is filled in along with the inode (in collect())
+                            self.unresolved.push((me, name, Rc::as_ptr(leaf)));
+                            entries.push(DirEntry {
+                                name: Cow::Borrowed(name),
+                                inode: usize::MAX,
+                                file_type: 0,
+                            });
+                            continue;
+                        }
+                    }
+                }
+                None => {
+                    let stat = &dir.stat;
+                    let idx = self.push(Inode {
+                        mode: S_IFCHR | 0o644,
+                        uid: stat.st_uid,
+                        gid: stat.st_gid,
+                        mtime: stat.st_mtim_sec as u64,
+                        nlink: 1,
+                        content: Content::Device(0),
+                        xattrs: vec![],
+                        shared: vec![],
+                    });
+                    (idx, Cow::Owned(name.to_vec()))
+                }
+            };
+            let file_type = self.inodes[inode].file_type();
+            entries.push(DirEntry {
+                name,
+                inode,
+                file_type,
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let inode = &mut self.inodes[me];
+        if has_whiteouts {
+            // Tell overlayfs to look for xattr whiteouts in this directory
+            inode
+                .xattrs
+                .push(XAttr::new(b"trusted.overlay.overlay.opaque", b"x")?);
+            inode.xattrs.push(XAttr::new(b"user.overlay.opaque", b"x")?);
+        }
+        inode.xattrs.sort();
+        inode.content = Content::Directory(entries);
+
+        Ok(())
+    }
+}
+
+fn metacopy_xattr<ObjectID: FsVerityHashValue>(id: &ObjectID) -> Result<XAttr> {
+    // struct ovl_metacopy: version, length, flags, digest algorithm, digest
+    let id = id.as_ref();
+    let mut value = vec![0, 4 + id.len() as u8, 0, ObjectID::ALGORITHM];
+    value.extend_from_slice(id);
+    XAttr::new(b"trusted.overlay.metacopy", &value)
+}
+
+fn redirect_xattr<ObjectID: FsVerityHashValue>(id: &ObjectID) -> Result<XAttr> {
+    let redirect = format!("/{}", id.to_object_pathname());
+    XAttr::new(b"trusted.overlay.redirect", redirect.as_bytes())
+}
+
+/// Moves every xattr which appears on more than one inode into the shared xattr table
+fn share_xattrs(inodes: &mut [Inode]) -> Vec<XAttr> {
+    let mut counts = BTreeMap::<&XAttr, usize>::new();
+    for inode in inodes.iter() {
+        for xattr in &inode.xattrs {
+            *counts.entry(xattr).or_default() += 1;
+        }
+    }
+
+    let shared: Vec<XAttr> = counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(xattr, _)| xattr.clone())
+        .collect();
+
+    for inode in inodes.iter_mut() {
+        inode
+            .xattrs
+            .retain(|xattr| match shared.binary_search(xattr) {
+                Ok(idx) => {
+                    inode.shared.push(idx as u32);
+                    false
+                }
+                Err(..) => true,
+            });
+    }
+
+    shared
+}
+
+/// Where the parts of an inode end up in the image
+#[derive(Debug, Default)]
+struct Placement {
+    compact: bool,
+    /// byte offset of the inode header
+    offset: usize,
+    /// number of full blocks written to the data area
+    n_blocks: usize,
+    /// first block in the data area (if n_blocks > 0)
+    blkaddr: usize,
+    /// number of bytes of data stored inline after the xattrs
+    inline: usize,
+}
+
+struct Writer<'a> {
+    inodes: Vec<Inode<'a>>,
+    shared_xattrs: Vec<XAttr>,
+    placements: Vec<Placement>,
+    build_time: u64,
+}
+
+impl Writer<'_> {
+    fn chunk_bits(size: u64) -> u8 {
+        // One chunk for the entire file, if possible
+        let bits = if size > 1 {
+            (size - 1).ilog2() as u8 + 1
+        } else {
+            0
+        };
+        bits.clamp(BLOCK_BITS, BLOCK_BITS + 31)
+    }
+
+    fn n_chunks(size: u64) -> usize {
+        size.div_ceil(1 << Self::chunk_bits(size)) as usize
+    }
+
+    fn header_size(compact: bool) -> usize {
+        if compact {
+            CompactInodeHeader::SIZE
+        } else {
+            ExtendedInodeHeader::SIZE
+        }
+    }
+
+    /// The data which gets stored in blocks and/or inline: directory blocks, file content, or
+    /// symlink targets.  The nids are only used for the directory entries.
+    fn data(&self, inode: &Inode, nids: &[u64]) -> Vec<u8> {
+        match &inode.content {
+            Content::Directory(entries) => {
+                let mut output = vec![];
+                let blocks = dir_blocks(entries);
+                for (n, block) in blocks.iter().enumerate() {
+                    if n > 0 {
+                        pad_to(&mut output, BLOCK_SIZE);
+                    }
+                    let mut name_offset = block.len() * DirectoryEntryHeader::SIZE;
+                    for entry in block.iter() {
+                        DirectoryEntryHeader {
+                            inode_offset: nids[entry.inode],
+                            name_offset: name_offset as u16,
+                            file_type: entry.file_type,
+                        }
+                        .write(&mut output);
+                        name_offset += entry.name.len();
+                    }
+                    for entry in block.iter() {
+                        output.extend_from_slice(&entry.name);
+                    }
+                }
+                output
+            }
+            Content::Data(data) => data.to_vec(),
+            Content::External(..) | Content::Device(..) | Content::Empty => vec![],
+        }
+    }
+
+    fn compact(&self, inode: &Inode) -> bool {
+        inode.mtime == self.build_time
+            && inode.nlink <= u16::MAX as u32
+            && inode.uid <= u16::MAX as u32
+            && inode.gid <= u16::MAX as u32
+            && inode.size() <= u32::MAX as u64
+    }
+
+    fn place(&mut self) -> usize {
+        let mut offset = SUPERBLOCK_OFFSET + Superblock::SIZE;
+        let zero_nids = vec![0; self.inodes.len()];
+
+        for inode in &self.inodes {
+            let compact = self.compact(inode);
+            let meta_size = Self::header_size(compact) + inode.xattr_size();
+
+            let (mut n_blocks, mut inline) = match inode.content {
+                Content::External(size) => (0, 4 * Self::n_chunks(size)),
+                _ => {
+                    let size = self.data(inode, &zero_nids).len();
+                    (size / BLOCK_SIZE, size % BLOCK_SIZE)
+                }
+            };
+
+            // The inline tail must not cross a block boundary.  Store it in a block of its
+            // own if it's bigger than half a block, or if it can't share a block with the inode.
+            let chunked = matches!(inode.content, Content::External(..));
+            if !chunked
+                && inline > 0
+                && (inline > BLOCK_SIZE / 2 || meta_size + inline > BLOCK_SIZE)
+            {
+                n_blocks += 1;
+                inline = 0;
+            }
+
+            offset = round_up(offset, INODE_SLOT_SIZE);
+            if !chunked && inline > 0 {
+                let tail_start = offset + meta_size;
+                if tail_start / BLOCK_SIZE != (tail_start + inline - 1) / BLOCK_SIZE {
+                    offset = round_up(offset, BLOCK_SIZE);
+                }
+            }
+
+            self.placements.push(Placement {
+                compact,
+                offset,
+                n_blocks,
+                blkaddr: 0,
+                inline,
+            });
+            offset += meta_size + inline;
+        }
+
+        offset
+    }
+
+    fn write_inode(&self, output: &mut Vec<u8>, idx: usize, nids: &[u64], shared_start: usize) {
+        let inode = &self.inodes[idx];
+        let placement = &self.placements[idx];
+
+        let (datalayout, u) = match inode.content {
+            Content::External(size) => (
+                INODE_DATALAYOUT_CHUNK_BASED,
+                (Self::chunk_bits(size) - BLOCK_BITS) as u32,
+            ),
+            Content::Device(rdev) => (INODE_DATALAYOUT_FLAT_PLAIN, rdev),
+            _ if placement.inline > 0 => (INODE_DATALAYOUT_FLAT_INLINE, placement.blkaddr as u32),
+            _ => (INODE_DATALAYOUT_FLAT_PLAIN, placement.blkaddr as u32),
+        };
+
+        let xattr_icount = match inode.xattr_size() {
+            0 => 0,
+            n => (1 + (n - InodeXAttrHeader::SIZE) / 4) as u16,
+        };
+
+        if placement.compact {
+            CompactInodeHeader {
+                format: inode_format(INODE_LAYOUT_COMPACT, datalayout),
+                xattr_icount,
+                mode: inode.mode as u16,
+                nlink: inode.nlink as u16,
+                size: inode.size() as u32,
+                u,
+                ino: idx as u32,
+                uid: inode.uid as u16,
+                gid: inode.gid as u16,
+            }
+            .write(output);
+        } else {
+            ExtendedInodeHeader {
+                format: inode_format(INODE_LAYOUT_EXTENDED, datalayout),
+                xattr_icount,
+                mode: inode.mode as u16,
+                size: inode.size(),
+                u,
+                ino: idx as u32,
+                uid: inode.uid,
+                gid: inode.gid,
+                mtime: inode.mtime,
+                mtime_nsec: 0,
+                nlink: inode.nlink,
+            }
+            .write(output);
+        }
+
+        if xattr_icount > 0 {
+            let shared = inode
+                .shared
+                .iter()
+                .map(|idx| &self.shared_xattrs[*idx as usize]);
+            let present = inode
+                .xattrs
+                .iter()
+                .chain(shared)
+                .fold(0, |bits, xattr| bits | xattr.filter_bit());
+            InodeXAttrHeader {
+                name_filter: !present,
+                shared_count: inode.shared.len() as u8,
+            }
+            .write(output);
+            for idx in &inode.shared {
+                output.extend_from_slice(&self.shared_xattr_id(*idx, shared_start).to_le_bytes());
+            }
+            for xattr in &inode.xattrs {
+                xattr.write(output);
+            }
+        }
+
+        match inode.content {
+            Content::External(size) => {
+                for _ in 0..Self::n_chunks(size) {
+                    output.extend_from_slice(&NULL_ADDR.to_le_bytes());
+                }
+            }
+            _ if placement.inline > 0 => {
+                let data = self.data(inode, nids);
+                output.extend_from_slice(&data[placement.n_blocks * BLOCK_SIZE..]);
+            }
+            _ => {}
+        }
+    }
+
+    fn shared_xattr_id(&self, idx: u32, shared_start: usize) -> u32 {
+        let base = shared_start / BLOCK_SIZE * BLOCK_SIZE;
+        let offset: usize = self.shared_xattrs[..idx as usize]
+            .iter()
+            .map(XAttr::size)
+            .sum();
+        ((shared_start - base + offset) / 4) as u32
+    }
+
+    fn write(mut self) -> Vec<u8> {
+        // Compact inodes take their mtime from the superblock, so use the most common case
+        self.build_time = self.inodes.iter().map(|i| i.mtime).min().unwrap_or(0);
+
+        let inodes_end = self.place();
+        let nids: Vec<u64> = self
+            .placements
+            .iter()
+            .map(|p| (p.offset / INODE_SLOT_SIZE) as u64)
+            .collect();
+
+        // Shared xattrs go right after the inodes, then the data blocks
+        let shared_start = round_up(inodes_end, 4);
+        let shared_size: usize = self.shared_xattrs.iter().map(XAttr::size).sum();
+        let mut blkaddr = round_up(shared_start + shared_size, BLOCK_SIZE) / BLOCK_SIZE;
+        for placement in self.placements.iter_mut() {
+            if placement.n_blocks > 0 {
+                placement.blkaddr = blkaddr;
+                blkaddr += placement.n_blocks;
+            }
+        }
+
+        let has_acl = self.inodes.iter().any(|inode| {
+            inode.xattrs.iter().any(XAttr::is_acl)
+                || inode
+                    .shared
+                    .iter()
+                    .any(|idx| self.shared_xattrs[*idx as usize].is_acl())
+        });
+
+        let mut output = Vec::with_capacity(blkaddr * BLOCK_SIZE);
+
+        ComposefsHeader {
+            magic: COMPOSEFS_MAGIC,
+            version: COMPOSEFS_VERSION,
+            flags: if has_acl { COMPOSEFS_FLAGS_HAS_ACL } else { 0 },
+            composefs_version: COMPOSEFS_FORMAT_VERSION,
+        }
+        .write(&mut output);
+        output.resize(SUPERBLOCK_OFFSET, 0);
+
+        Superblock {
+            magic: EROFS_MAGIC,
+            feature_compat: FEATURE_COMPAT_MTIME | FEATURE_COMPAT_XATTR_FILTER,
+            blkszbits: BLOCK_BITS,
+            root_nid: nids[0] as u16,
+            inos: self.inodes.len() as u64,
+            build_time: self.build_time,
+            blocks: blkaddr as u32,
+            xattr_blkaddr: (shared_start / BLOCK_SIZE) as u32,
+            feature_incompat: FEATURE_INCOMPAT_CHUNKED_FILE,
+            ..Default::default()
+        }
+        .write(&mut output);
+
+        for idx in 0..self.inodes.len() {
+            output.resize(self.placements[idx].offset, 0);
+            self.write_inode(&mut output, idx, &nids, shared_start);
+        }
+
+        output.resize(shared_start, 0);
+        for xattr in &self.shared_xattrs {
+            xattr.write(&mut output);
+        }
+
+        for (inode, placement) in self.inodes.iter().zip(&self.placements) {
+            if placement.n_blocks > 0 {
+                pad_to(&mut output, BLOCK_SIZE);
+                assert_eq!(output.len(), placement.blkaddr * BLOCK_SIZE);
+                let data = self.data(inode, &nids);
+                let end = data.len().min(placement.n_blocks * BLOCK_SIZE);
+                output.extend_from_slice(&data[..end]);
+            }
+        }
+        pad_to(&mut output, BLOCK_SIZE);
+
+        output
+    }
+}
+
+/// Creates a composefs erofs image from the given filesystem
+pub fn mkfs_erofs<ObjectID: FsVerityHashValue>(fs: &FileSystem<ObjectID>) -> Result<Vec<u8>> {
+    let mut collector = InodeCollector {
+        inodes: vec![],
+        first_links: HashMap::new(),
+        hardlinks: HashMap::new(),
+        queue: VecDeque::new(),
+        unresolved: vec![],
+    };
+    collector.collect(&fs.root)?;
+
+    let mut inodes = collector.inodes;
+    let shared_xattrs = share_xattrs(&mut inodes);
+
+    // The inline xattrs of an inode are counted (in 4-byte units) by a u16
+    for inode in &inodes {
+        ensure!(
+            inode.xattr_size() <= InodeXAttrHeader::SIZE + 4 * (u16::MAX as usize - 1),
+            "Inode has too many extended attributes ({} bytes)",
+            inode.xattr_size()
+        );
+    }
+
+    Ok(Writer {
+        inodes,
+        shared_xattrs,
+        placements: vec![],
+        build_time: 0,
+    }
+    .write())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        ffi::{OsStr, OsString},
+        io::{Read, Seek, Write},
+        path::Path,
+        process::{Command, Stdio},
+    };
+
+    use anyhow::{Context, Result};
+
+    use super::*;
+    use crate::{
+        dumpfile::write_dumpfile,
+        dumpfile_parse::{dump, DumpConfig, Entry, Item},
+        fsverity::Sha256HashValue,
+    };
+
+    fn stat(mode: u32, mtime: i64, xattrs: &[(&str, &[u8])]) -> Stat {
+        Stat {
+            st_mode: mode,
+            st_uid: 0,
+            st_gid: 0,
+            st_mtim_sec: mtime,
+            xattrs: RefCell::new(
+                xattrs
+                    .iter()
+                    .map(|(k, v)| (Box::from(OsStr::new(k)), Box::from(*v)))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn leaf(mode: u32, mtime: i64, content: LeafContent<Sha256HashValue>) -> Leaf<Sha256HashValue> {
+        Leaf {
+            stat: stat(mode, mtime, &[]),
+            content,
+        }
+    }
+
+    fn test_filesystem() -> Result<FileSystem<Sha256HashValue>> {
+        let mut fs = FileSystem::new();
+        fs.root.stat = stat(0o555, 1633950376, &[("trusted.foo1", b"bar-1")]);
+
+        fs.mkdir(Path::new("/dir"), stat(0o755, 1633950376, &[]))?;
+        fs.mkdir(Path::new("/dir/sub"), stat(0o700, 1700000000, &[]))?;
+        fs.insert(
+            Path::new("/blockdev"),
+            leaf(0o777, 1633950376, LeafContent::BlockDevice(107690)),
+        )?;
+        fs.insert(
+            Path::new("/chardev"),
+            leaf(0o777, 1633950376, LeafContent::CharacterDevice(10053)),
+        )?;
+        fs.insert(
+            Path::new("/escaped-xattr"),
+            Leaf {
+                stat: stat(
+                    0o777,
+                    1633950376,
+                    &[
+                        ("trusted.overlay.redirect", b"/foo"),
+                        ("user.overlay.redirect", b"/foo"),
+                    ],
+                ),
+                content: LeafContent::InlineFile(vec![]),
+            },
+        )?;
+        fs.insert(
+            Path::new("/external"),
+            leaf(0o644, 1633950376, LeafContent::ExternalFile([0x5a; 32], 42)),
+        )?;
+        fs.insert(
+            Path::new("/dir/large"),
+            leaf(
+                0o644,
+                1633950376,
+                LeafContent::ExternalFile([0xa5; 32], 1 << 30),
+            ),
+        )?;
+        fs.insert(
+            Path::new("/fifo"),
+            leaf(0o777, 1633950376, LeafContent::Fifo),
+        )?;
+        for size in [15, 4095, 4096, 4097, 5000] {
+            fs.insert(
+                Path::new(&format!("/inline-{size}")),
+                Leaf {
+                    stat: stat(0o644, 1633950376, &[("user.foo", b"bar-2")]),
+                    content: LeafContent::InlineFile(vec![b'x'; size]),
+                },
+            )?;
+        }
+        fs.hardlink(Path::new("/dir/hardlink"), OsStr::new("/inline-15"))?;
+        fs.insert(
+            Path::new("/dir/symlink"),
+            leaf(
+                0o777,
+                1000,
+                LeafContent::Symlink(OsString::from("/etc/passwd")),
+            ),
+        )?;
+        fs.insert(
+            Path::new("/dir/whiteout"),
+            leaf(0o644, 1633950376, LeafContent::CharacterDevice(0)),
+        )?;
+        for i in 0..1000 {
+            fs.insert(
+                Path::new(&format!("/dir/sub/a-rather-long-file-name-{i}")),
+                leaf(0o644, 1700000000, LeafContent::InlineFile(vec![])),
+            )?;
+        }
+        Ok(fs)
+    }
+
+    fn mkcomposefs(fs: &FileSystem<Sha256HashValue>) -> Result<Vec<u8>> {
+        let mut mkcomposefs = Command::new("mkcomposefs")
+            .args(["--from-file", "-", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = mkcomposefs.stdin.take().unwrap();
+        write_dumpfile(&mut stdin, fs)?;
+        drop(stdin);
+
+        let mut image = vec![];
+        mkcomposefs.stdout.take().unwrap().read_to_end(&mut image)?;
+        assert!(mkcomposefs.wait()?.success());
+        Ok(image)
+    }
+
+    fn normalize(mut entry: Entry) -> String {
+        // The size of a directory isn't meaningful
+        if let Item::Directory { ref mut size, .. } = entry.item {
+            *size = 0;
+        }
+        // The image stores the inline xattrs before the shared ones
+        entry.xattrs.sort_by(|a, b| a.key.cmp(&b.key));
+        format!("{entry}")
+    }
+
+    #[test]
+    #[ignore = "needs mkcomposefs"]
+    fn test_identical_to_mkcomposefs() -> Result<()> {
+        let fs = test_filesystem()?;
+        assert!(mkfs_erofs(&fs)? == mkcomposefs(&fs)?);
+        Ok(())
+    }
+
+    /// Dumpfiles in src/tests/assets, each with the image that `mkcomposefs --from-file` makes
+    /// from it checked in next to it (`special.dump` and `special.cfs`).  tree.dump is the dumpfile
+    /// of test_filesystem().
+    const GOLDEN_IMAGES: &[&str] = &["empty", "special", "tree"];
+
+    #[test]
+    fn test_golden_images() -> Result<()> {
+        let assets = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tests/assets");
+        for name in GOLDEN_IMAGES {
+            let dumpfile = std::fs::read(assets.join(format!("{name}.dump")))?;
+            let expected =
+                std::fs::read(assets.join(format!("{name}.cfs"))).with_context(|| {
+                    format!(
+                        "No image from mkcomposefs for {name}.dump: create it with \
+                     `mkcomposefs --from-file {name}.dump {name}.cfs`"
+                    )
+                })?;
+            let fs = FileSystem::<Sha256HashValue>::from_dumpfile(&dumpfile[..])?;
+            assert!(
+                mkfs_erofs(&fs)? == expected,
+                "The image made from {name}.dump differs from {name}.cfs"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dumpfile_roundtrip() -> Result<()> {
+        let fs = test_filesystem()?;
+
+        let mut expected = vec![];
+        write_dumpfile(&mut expected, &fs)?;
+        let expected: Vec<String> = std::str::from_utf8(&expected)?
+            .lines()
+            .map(|line| Ok(normalize(Entry::parse(line)?)))
+            .collect::<Result<_>>()?;
+
+        let mut image = tempfile::tempfile()?;
+        image.write_all(&mkfs_erofs(&fs)?)?;
+        image.rewind()?;
+        let mut actual = vec![];
+        dump(image, DumpConfig::default(), |entry| {
+            actual.push(normalize(entry));
+            Ok(())
+        })?;
+
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout() -> Result<()> {
+        let image = mkfs_erofs(&test_filesystem()?)?;
+        assert_eq!(image.len() % BLOCK_SIZE, 0);
+        assert_eq!(image[0..4], COMPOSEFS_MAGIC.to_le_bytes());
+        assert_eq!(
+            image[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + 4],
+            EROFS_MAGIC.to_le_bytes()
+        );
+        // The root directory is the first inode, right after the superblock
+        let root_nid = u16::from_le_bytes(image[1038..1040].try_into()?);
+        assert_eq!(
+            root_nid as usize,
+            (SUPERBLOCK_OFFSET + Superblock::SIZE) / INODE_SLOT_SIZE
+        );
+        // ...and the superblock block count covers the entire image
+        let blocks = u32::from_le_bytes(image[1060..1064].try_into()?);
+        assert_eq!(blocks as usize * BLOCK_SIZE, image.len());
+        Ok(())
+    }
+    #[test]
+    fn test_inode_order() -> Result<()> {
+        // Breadth-first, like mkcomposefs: a queue of directories, each adding its entries in
+        // name order (files with several links are checked below)
+        let image = crate::erofs::reader::Image::new(mkfs_erofs(&test_filesystem()?)?)?;
+        let mut order = vec![image.root_nid()];
+        let mut queue = VecDeque::from([image.root_nid()]);
+        while let Some(nid) = queue.pop_front() {
+            for entry in image.inode(nid)?.entries()? {
+                if entry.file_type == FILE_TYPE_DIR {
+                    queue.push_back(entry.nid);
+                } else if image.inode(entry.nid)?.nlink > 1 {
+                    continue;
+                }
+                order.push(entry.nid);
+            }
+        }
+        assert!(order.is_sorted());
+
+        // ...except that a file with several links is numbered at its first link in the dumpfile,
+        // even if another one is reached earlier: /inline-15 is linked from /dir/hardlink
+        let root = image.inode(image.root_nid())?.entries()?;
+        let find = |entries: &[crate::erofs::reader::DirEntry], name: &[u8]| {
+            entries.iter().find(|e| e.name == name).unwrap().nid
+        };
+        let dir = image.inode(find(&root, b"dir"))?.entries()?;
+        let inline = find(&root, b"inline-15");
+        assert_eq!(inline, find(&dir, b"hardlink"));
+        assert!(root.iter().all(|entry| entry.nid <= inline));
+        assert!(dir
+            .iter()
+            .filter(|e| e.name < &b"hardlink"[..])
+            .all(|e| e.nid < inline));
+        Ok(())
+    }
+
+    #[test]
+    fn test_xattr_too_long() -> Result<()> {
+        let fs = test_filesystem()?;
+        let name = format!("user.{}", "x".repeat(256));
+        fs.root
+            .stat
+            .xattrs
+            .borrow_mut()
+            .insert(Box::from(OsStr::new(&name)), Box::from(&b""[..]));
+        assert!(mkfs_erofs(&fs).is_err());
+
+        // A 255-byte name suffix is fine, but a 64k value isn't
+        let fs = test_filesystem()?;
+        let name = format!("user.{}", "x".repeat(255));
+        fs.root
+            .stat
+            .xattrs
+            .borrow_mut()
+            .insert(Box::from(OsStr::new(&name)), Box::from(&b"x"[..]));
+        assert!(mkfs_erofs(&fs).is_ok());
+        fs.root
+            .stat
+            .xattrs
+            .borrow_mut()
+            .insert(Box::from(OsStr::new(&name)), vec![0; 65536].into());
+        assert!(mkfs_erofs(&fs).is_err());
+        Ok(())
+    }
+}
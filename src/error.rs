@@ -0,0 +1,322 @@
+//! The error type returned by the library.
+//!
+//! Errors that callers are likely to want to handle specially (missing objects, fs-verity
+//! mismatches, a busy repository and so on) get their own variants, carrying the digest or path
+//! involved.  Everything else is [`Error::Other`], which holds a (human-readable) chain of
+//! contexts.  Use [`Error::raw_os_error`] to find out if an error was caused by a failed system
+//! call.
+
+use std::{fmt, io, path::PathBuf, process::ExitStatus};
+
+use rustix::io::Errno;
+
+/// The typed variants have a `context` field, holding the contexts that were added to the error
+/// before it was converted from an [`anyhow::Error`], outermost first.  It's usually empty.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A failed system call or other I/O error
+    Io(io::Error),
+    /// The object with the given digest (in hex) isn't in the repository
+    ObjectMissing {
+        digest: String,
+        context: Vec<String>,
+    },
+    /// An image, stream or ref isn't in the repository.  The path is relative to the repository.
+    NotFound { path: String, context: Vec<String> },
+    /// The measured fs-verity digest of a file isn't the expected one.  The path is relative to
+    /// the repository (or absolute, for objects in an alternate), and the digests are in hex.
+    VerityMismatch {
+        path: String,
+        expected: String,
+        measured: String,
+        context: Vec<String>,
+    },
+    /// Some content doesn't have the SHA-256 digest it's supposed to have.  The digests are in
+    /// hex.
+    ContentMismatch {
+        expected: String,
+        actual: String,
+        context: Vec<String>,
+    },
+    /// A blob of a container image (a manifest, config or layer) doesn't match the digest in its
+    /// descriptor.  The digests are in `algorithm:hex` form.
+    DescriptorMismatch {
+        media_type: String,
+        expected: String,
+        actual: String,
+        context: Vec<String>,
+    },
+    /// The repository lock couldn't be taken without waiting longer than allowed by
+    /// [`crate::repository::LockWait`]
+    RepositoryBusy { path: PathBuf, context: Vec<String> },
+    /// The repository uses a format (version, hash algorithm, ...) that we don't support
+    UnsupportedRepository {
+        path: PathBuf,
+        reason: String,
+        context: Vec<String>,
+    },
+    /// A compare-and-swap ref update failed because the ref doesn't point at the expected digest
+    RefConflict {
+        name: String,
+        expected: String,
+        current: String,
+        context: Vec<String>,
+    },
+    /// An external command failed
+    CommandFailed {
+        command: String,
+        status: ExitStatus,
+        context: Vec<String>,
+    },
+    /// Any other error
+    Other(anyhow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// If the content of the repository doesn't match its digests, ie: it's corrupt, or it has
+    /// been tampered with
+    pub fn is_corruption(&self) -> bool {
+        matches!(
+            self,
+            Error::VerityMismatch { .. }
+                | Error::ContentMismatch { .. }
+                | Error::DescriptorMismatch { .. }
+        )
+    }
+
+    /// The errno of the failed system call that caused this error, if that's what it was
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::Io(err) => err.raw_os_error(),
+            Error::Other(err) => err.chain().find_map(|cause| {
+                if let Some(err) = cause.downcast_ref::<io::Error>() {
+                    err.raw_os_error()
+                } else if let Some(err) = cause.downcast_ref::<Error>() {
+                    err.raw_os_error()
+                } else {
+                    cause
+                        .downcast_ref::<Errno>()
+                        .map(|errno| errno.raw_os_error())
+                }
+            }),
+            _ => None,
+        }
+    }
+
+    /// The contexts added to a typed error, outermost first
+    pub fn context(&self) -> &[String] {
+        match self {
+            Error::ObjectMissing { context, .. }
+            | Error::NotFound { context, .. }
+            | Error::VerityMismatch { context, .. }
+            | Error::ContentMismatch { context, .. }
+            | Error::DescriptorMismatch { context, .. }
+            | Error::RepositoryBusy { context, .. }
+            | Error::UnsupportedRepository { context, .. }
+            | Error::RefConflict { context, .. }
+            | Error::CommandFailed { context, .. } => context,
+            Error::Io(..) | Error::Other(..) => &[],
+        }
+    }
+
+    fn context_mut(&mut self) -> Option<&mut Vec<String>> {
+        match self {
+            Error::ObjectMissing { context, .. }
+            | Error::NotFound { context, .. }
+            | Error::VerityMismatch { context, .. }
+            | Error::ContentMismatch { context, .. }
+            | Error::DescriptorMismatch { context, .. }
+            | Error::RepositoryBusy { context, .. }
+            | Error::UnsupportedRepository { context, .. }
+            | Error::RefConflict { context, .. }
+            | Error::CommandFailed { context, .. } => Some(context),
+            Error::Io(..) | Error::Other(..) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for context in self.context() {
+            write!(f, "{context}: ")?;
+        }
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::ObjectMissing { digest, .. } => write!(f, "Object {digest} is missing"),
+            Error::NotFound { path, .. } => write!(f, "Cannot find {path}"),
+            Error::VerityMismatch {
+                path,
+                expected,
+                measured,
+                ..
+            } => write!(
+                f,
+                "fs-verity digest of {path} is {measured} instead of the expected {expected}"
+            ),
+            Error::ContentMismatch {
+                expected, actual, ..
+            } => write!(
+                f,
+                "Content has SHA-256 digest {actual} instead of the expected {expected}"
+            ),
+            Error::DescriptorMismatch {
+                media_type,
+                expected,
+                actual,
+                ..
+            } => write!(f, "{media_type} {expected} has digest {actual}"),
+            Error::RepositoryBusy { path, .. } => {
+                write!(f, "Composefs repository {path:?} is busy")
+            }
+            Error::UnsupportedRepository { path, reason, .. } => {
+                write!(f, "Cannot open composefs repository {path:?}: {reason}")
+            }
+            Error::RefConflict {
+                name,
+                expected,
+                current,
+                ..
+            } => write!(
+                f,
+                "Ref {name:?} points at {current} instead of the expected {expected}"
+            ),
+            Error::CommandFailed {
+                command, status, ..
+            } => write!(f, "{command} failed ({status})"),
+            // Like anyhow: the causes are left to source(), unless asked for with {:#}
+            Error::Other(err) if f.alternate() => write!(f, "{err:#}"),
+            Error::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => err.source(),
+            Error::Other(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<Errno> for Error {
+    fn from(errno: Errno) -> Self {
+        Error::Io(errno.into())
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        // Typed errors stay typed, even if some context was added on the way, which is kept in
+        // their `context`.  An I/O error with context stays an anyhow error, which has room for
+        // it (and raw_os_error() still finds it).
+        let contexts = Vec::from_iter(
+            err.chain()
+                .take_while(|cause| cause.downcast_ref::<Error>().is_none())
+                .map(ToString::to_string),
+        );
+        match err.downcast_ref::<Error>() {
+            None | Some(Error::Other(..)) => Error::Other(err),
+            Some(Error::Io(..)) if !contexts.is_empty() => Error::Other(err),
+            Some(..) => {
+                let mut typed: Error = err.downcast().unwrap();
+                if let Some(context) = typed.context_mut() {
+                    context.splice(0..0, contexts);
+                }
+                typed
+            }
+        }
+    }
+}
+
+/// Errors from other libraries (and the less interesting ones from std) are [`Error::Other`]
+macro_rules! other_from {
+    ($($type:ty),* $(,)?) => {
+        $(
+            impl From<$type> for Error {
+                fn from(err: $type) -> Self {
+                    Error::Other(err.into())
+                }
+            }
+        )*
+    };
+}
+
+other_from!(
+    std::ffi::FromBytesWithNulError,
+    std::ffi::NulError,
+    std::fmt::Error,
+    std::num::ParseIntError,
+    std::num::TryFromIntError,
+    std::path::StripPrefixError,
+    std::str::ParseBoolError,
+    std::str::Utf8Error,
+    containers_image_proxy::Error,
+    hex::FromHexError,
+    oci_spec::OciSpecError,
+    regex_automata::hybrid::BuildError,
+);
+
+/// Like [`anyhow::bail!`], but for functions returning either kind of `Result`
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(anyhow::anyhow!($($arg)*).into())
+    };
+}
+
+/// Like [`anyhow::ensure!`], but for functions returning either kind of `Result`
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)*) => {
+        if !$cond {
+            $crate::error::bail!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {bail, ensure};
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let missing = || -> Result<()> {
+            Err(Error::ObjectMissing {
+                digest: "1234".into(),
+                context: vec![],
+            })
+        };
+        let err = Error::from(
+            missing()
+                .context("Cannot open image")
+                .context("Cannot mount")
+                .unwrap_err(),
+        );
+        assert!(matches!(err, Error::ObjectMissing { .. }));
+        assert_eq!(err.context(), ["Cannot mount", "Cannot open image"]);
+        assert_eq!(
+            err.to_string(),
+            "Cannot mount: Cannot open image: Object 1234 is missing"
+        );
+
+        let io = || -> Result<()> { Err(Errno::NOENT.into()) };
+        let err = Error::from(io().context("Cannot open image").unwrap_err());
+        assert_eq!(err.raw_os_error(), Some(Errno::NOENT.raw_os_error()));
+        assert_eq!(err.to_string(), "Cannot open image");
+        assert!(format!("{err:#}").starts_with("Cannot open image: "));
+        assert!(std::error::Error::source(&err).is_some());
+    }
+}
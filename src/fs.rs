@@ -3,13 +3,14 @@ use std::{
     collections::{BTreeMap, HashMap},
     ffi::OsString,
     ffi::{CStr, OsStr},
+    fs::File,
+    io::BufReader,
     mem::MaybeUninit,
     os::unix::ffi::{OsStrExt, OsStringExt},
     path::Path,
     rc::Rc,
 };
 
-use anyhow::{bail, ensure, Result};
 use rustix::{
     fd::{AsFd, OwnedFd},
     fs::{
@@ -20,11 +21,12 @@ use rustix::{
 };
 
 use crate::{
-    fsverity::{digest::FsVerityHasher, Sha256HashValue},
+    error::{bail, ensure, Result},
+    fsverity::{digest::FsVerityHasher, FsVerityHashValue},
     image::{DirEnt, Directory, FileSystem, Inode, Leaf, LeafContent, Stat},
     repository::Repository,
     selabel::selabel,
-    util::proc_self_fd,
+    util::{parallel_map, proc_self_fd},
     INLINE_CONTENT_MAX,
 };
 
@@ -62,11 +64,11 @@ fn set_file_contents(dirfd: &OwnedFd, name: &OsStr, stat: &Stat, data: &[u8]) ->
     Ok(())
 }
 
-fn write_directory(
-    dir: &Directory,
+fn write_directory<ObjectID: FsVerityHashValue>(
+    dir: &Directory<ObjectID>,
     dirfd: &OwnedFd,
     name: &OsStr,
-    repo: &Repository,
+    repo: &Repository<ObjectID>,
 ) -> Result<()> {
     match mkdirat(dirfd, name, dir.stat.st_mode.into()) {
         Ok(()) | Err(Errno::EXIST) => {}
@@ -77,7 +79,12 @@ fn write_directory(
     write_directory_contents(dir, &fd, repo)
 }
 
-fn write_leaf(leaf: &Leaf, dirfd: &OwnedFd, name: &OsStr, repo: &Repository) -> Result<()> {
+fn write_leaf<ObjectID: FsVerityHashValue>(
+    leaf: &Leaf<ObjectID>,
+    dirfd: &OwnedFd,
+    name: &OsStr,
+    repo: &Repository<ObjectID>,
+) -> Result<()> {
     let mode = leaf.stat.st_mode.into();
 
     match leaf.content {
@@ -101,7 +108,11 @@ fn write_leaf(leaf: &Leaf, dirfd: &OwnedFd, name: &OsStr, repo: &Repository) ->
     Ok(())
 }
 
-fn write_directory_contents(dir: &Directory, fd: &OwnedFd, repo: &Repository) -> Result<()> {
+fn write_directory_contents<ObjectID: FsVerityHashValue>(
+    dir: &Directory<ObjectID>,
+    fd: &OwnedFd,
+    repo: &Repository<ObjectID>,
+) -> Result<()> {
     for DirEnt { name, inode } in &dir.entries {
         match inode {
             Inode::Directory(ref dir) => write_directory(dir, fd, name, repo),
@@ -113,19 +124,32 @@ fn write_directory_contents(dir: &Directory, fd: &OwnedFd, repo: &Repository) ->
 }
 
 // NB: hardlinks not supported
-pub fn write_to_path(repo: &Repository, dir: &Directory, output_dir: &Path) -> Result<()> {
+pub fn write_to_path<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    dir: &Directory<ObjectID>,
+    output_dir: &Path,
+) -> Result<()> {
     let fd = openat(CWD, output_dir, OFlags::PATH | OFlags::DIRECTORY, 0.into())?;
     write_directory_contents(dir, &fd, repo)
 }
 
-pub struct FilesystemReader<'repo> {
+/// A large regular file which is waiting to be stored in the repository, along with the other
+/// ones in its directory
+struct PendingFile {
+    name: OsString,
+    fd: OwnedFd,
+    buf: rustix::fs::Stat,
+    stat: Stat,
+}
+
+pub struct FilesystemReader<'repo, ObjectID: FsVerityHashValue> {
     st_dev: u64,
-    repo: Option<&'repo Repository>,
-    inodes: HashMap<u64, Rc<Leaf>>,
+    repo: Option<&'repo Repository<ObjectID>>,
+    inodes: HashMap<u64, Rc<Leaf<ObjectID>>>,
     root_mtime: i64,
 }
 
-impl<'repo> FilesystemReader<'repo> {
+impl<ObjectID: FsVerityHashValue> FilesystemReader<'_, ObjectID> {
     fn read_xattrs(&mut self, fd: &OwnedFd) -> Result<BTreeMap<Box<OsStr>, Box<[u8]>>> {
         // flistxattr() and fgetxattr() don't with with O_PATH fds, so go via /proc/self/fd. Note:
         // we want the symlink-following version of this call, which produces the correct behaviour
@@ -193,23 +217,23 @@ impl<'repo> FilesystemReader<'repo> {
         ))
     }
 
-    fn read_leaf_content(&mut self, fd: OwnedFd, buf: rustix::fs::Stat) -> Result<LeafContent> {
+    fn read_leaf_content(
+        &mut self,
+        fd: OwnedFd,
+        buf: rustix::fs::Stat,
+    ) -> Result<LeafContent<ObjectID>> {
         let content = match FileType::from_raw_mode(buf.st_mode) {
             FileType::Directory | FileType::Unknown => unreachable!(),
+            FileType::RegularFile if buf.st_size > INLINE_CONTENT_MAX as i64 => {
+                // Stream the content: the file might be too large to hold in memory.  With a
+                // repository, read_directory() stores these instead.
+                let id = FsVerityHasher::hash_reader(&mut File::from(fd))?;
+                LeafContent::ExternalFile(id, buf.st_size as u64)
+            }
             FileType::RegularFile => {
                 let mut buffer = vec![MaybeUninit::uninit(); buf.st_size as usize];
                 let (data, _) = read_uninit(fd, &mut buffer)?;
-
-                if buf.st_size > INLINE_CONTENT_MAX as i64 {
-                    let id = if let Some(repo) = self.repo {
-                        repo.ensure_object(data)?
-                    } else {
-                        FsVerityHasher::hash(data)
-                    };
-                    LeafContent::ExternalFile(id, buf.st_size as u64)
-                } else {
-                    LeafContent::InlineFile(Vec::from(data))
-                }
+                LeafContent::InlineFile(Vec::from(data))
             }
             FileType::Symlink => {
                 let target = readlinkat(fd, "", [])?;
@@ -223,7 +247,29 @@ impl<'repo> FilesystemReader<'repo> {
         Ok(content)
     }
 
-    fn read_leaf(&mut self, dirfd: &OwnedFd, name: &OsStr, ifmt: FileType) -> Result<Rc<Leaf>> {
+    fn add_leaf(
+        &mut self,
+        buf: rustix::fs::Stat,
+        stat: Stat,
+        content: LeafContent<ObjectID>,
+    ) -> Rc<Leaf<ObjectID>> {
+        let leaf = Rc::new(Leaf { stat, content });
+        if buf.st_nlink > 1 {
+            self.inodes.insert(buf.st_ino, Rc::clone(&leaf));
+        }
+        leaf
+    }
+
+    /// Reads the leaf called `name` in `dirfd`.  Large regular files aren't stored in the
+    /// repository right away: they're added to `pending` instead, so that they can be stored in
+    /// parallel.
+    fn read_leaf(
+        &mut self,
+        dirfd: &OwnedFd,
+        name: &OsStr,
+        ifmt: FileType,
+        pending: &mut Vec<PendingFile>,
+    ) -> Result<Option<Rc<Leaf<ObjectID>>>> {
         let oflags = match ifmt {
             FileType::RegularFile => OFlags::RDONLY,
             _ => OFlags::PATH,
@@ -239,18 +285,29 @@ impl<'repo> FilesystemReader<'repo> {
         let (buf, stat) = self.stat(&fd, ifmt)?;
 
         if let Some(leafref) = self.inodes.get(&buf.st_ino) {
-            Ok(Rc::clone(leafref))
+            Ok(Some(Rc::clone(leafref)))
+        } else if self.repo.is_some()
+            && FileType::from_raw_mode(buf.st_mode) == FileType::RegularFile
+            && buf.st_size > INLINE_CONTENT_MAX as i64
+        {
+            pending.push(PendingFile {
+                name: name.to_os_string(),
+                fd,
+                buf,
+                stat,
+            });
+            Ok(None)
         } else {
             let content = self.read_leaf_content(fd, buf)?;
-            let leaf = Rc::new(Leaf { stat, content });
-            if buf.st_nlink > 1 {
-                self.inodes.insert(buf.st_ino, Rc::clone(&leaf));
-            }
-            Ok(leaf)
+            Ok(Some(self.add_leaf(buf, stat, content)))
         }
     }
 
-    pub fn read_directory(&mut self, dirfd: impl AsFd, name: &OsStr) -> Result<Directory> {
+    pub fn read_directory(
+        &mut self,
+        dirfd: impl AsFd,
+        name: &OsStr,
+    ) -> Result<Directory<ObjectID>> {
         let fd = openat(
             dirfd,
             name,
@@ -264,6 +321,7 @@ impl<'repo> FilesystemReader<'repo> {
             entries: vec![],
         };
 
+        let mut pending = vec![];
         for item in Dir::read_from(&fd)? {
             let entry = item?;
             let name = OsStr::from_bytes(entry.file_name().to_bytes());
@@ -272,25 +330,41 @@ impl<'repo> FilesystemReader<'repo> {
                 continue;
             }
 
-            let inode = self.read_inode(&fd, name, entry.file_type())?;
-            directory.insert(name, inode);
+            if entry.file_type() == FileType::Directory {
+                let subdir = self.read_directory(&fd, name)?;
+                directory.insert(name, Inode::Directory(Box::new(subdir)));
+            } else if let Some(leaf) = self.read_leaf(&fd, name, entry.file_type(), &mut pending)? {
+                directory.insert(name, Inode::Leaf(leaf));
+            }
         }
 
-        Ok(directory)
-    }
-
-    fn read_inode(&mut self, dirfd: &OwnedFd, name: &OsStr, ifmt: FileType) -> Result<Inode> {
-        if ifmt == FileType::Directory {
-            Ok(Inode::Directory(Box::new(
-                self.read_directory(dirfd, name)?,
-            )))
-        } else {
-            Ok(Inode::Leaf(self.read_leaf(dirfd, name, ifmt)?))
+        // The large files of the directory are stored as a batch, in parallel, like
+        // Repository::ensure_objects_unsynced() does for data in memory.  They're synced at the
+        // end, in read_from_path().
+        if let Some(repo) = self.repo {
+            let fds = Vec::from_iter(pending.iter().map(|file| file.fd.as_fd()));
+            let ids = parallel_map(&fds, |fd| repo.ensure_object_from_fd_unsynced(fd))?;
+            for (file, id) in pending.into_iter().zip(ids) {
+                let leaf = match self.inodes.get(&file.buf.st_ino) {
+                    // a hardlink to another file of the batch
+                    Some(leaf) => Rc::clone(leaf),
+                    None => {
+                        let content = LeafContent::ExternalFile(id, file.buf.st_size as u64);
+                        self.add_leaf(file.buf, file.stat, content)
+                    }
+                };
+                directory.insert(&file.name, Inode::Leaf(leaf));
+            }
         }
+
+        Ok(directory)
     }
 }
 
-pub fn read_from_path(path: &Path, repo: Option<&Repository>) -> Result<FileSystem> {
+pub fn read_from_path<ObjectID: FsVerityHashValue>(
+    path: &Path,
+    repo: Option<&Repository<ObjectID>>,
+) -> Result<FileSystem<ObjectID>> {
     let mut reader = FilesystemReader {
         repo,
         inodes: HashMap::new(),
@@ -304,14 +378,17 @@ pub fn read_from_path(path: &Path, repo: Option<&Repository>) -> Result<FileSyst
 
     // We can only relabel if we have the repo because we need to read the config and policy files
     if let Some(repo) = repo {
+        repo.sync()?;
         selabel(&mut fs, repo)?;
     }
 
     Ok(fs)
 }
 
-pub fn create_image(path: &Path, repo: Option<&Repository>) -> Result<Sha256HashValue> {
-    let fs = read_from_path(path, repo)?;
+fn write_image<ObjectID: FsVerityHashValue>(
+    fs: FileSystem<ObjectID>,
+    repo: Option<&Repository<ObjectID>>,
+) -> Result<ObjectID> {
     let image = super::image::mkcomposefs(fs)?;
     if let Some(repo) = repo {
         Ok(repo.write_image(None, &image)?)
@@ -320,7 +397,26 @@ pub fn create_image(path: &Path, repo: Option<&Repository>) -> Result<Sha256Hash
     }
 }
 
-pub fn create_dumpfile(path: &Path) -> Result<()> {
-    let fs = read_from_path(path, None)?;
+pub fn create_image<ObjectID: FsVerityHashValue>(
+    path: &Path,
+    repo: Option<&Repository<ObjectID>>,
+) -> Result<ObjectID> {
+    let fs = read_from_path(path, repo)?;
+    write_image(fs, repo)
+}
+
+pub fn create_image_from_dumpfile<ObjectID: FsVerityHashValue>(
+    path: &Path,
+    repo: Option<&Repository<ObjectID>>,
+) -> Result<ObjectID> {
+    let mut fs = FileSystem::from_dumpfile(BufReader::new(File::open(path)?))?;
+    if let Some(repo) = repo {
+        selabel(&mut fs, repo)?;
+    }
+    write_image(fs, repo)
+}
+
+pub fn create_dumpfile<ObjectID: FsVerityHashValue>(path: &Path) -> Result<()> {
+    let fs = read_from_path::<ObjectID>(path, None)?;
     super::dumpfile::write_dumpfile(&mut std::io::stdout(), &fs)
 }
@@ -1,49 +1,75 @@
-use std::cmp::min;
+use std::{cmp::min, io::Read};
 
-use sha2::{Digest, Sha256};
+use sha2::{digest::core_api::BlockSizeUser, Digest};
 
-use super::Sha256HashValue;
+use super::{FsVerityHashValue, FsVerityParams};
 
-// TODO: support Sha512
+/// Enough zeros to pad the largest supported block
+static ZEROS: [u8; 65536] = [0; 65536];
 
-struct FsVerityLayer {
-    context: Sha256,
+struct FsVerityLayer<H: FsVerityHashValue> {
+    context: H::Digest,
+    /// The salt, padded to a multiple of the block size of the hash function
+    salt: Vec<u8>,
+    block_size: usize,
     remaining: usize,
 }
 
-impl FsVerityLayer {
-    fn new() -> FsVerityLayer {
+impl<H: FsVerityHashValue> FsVerityLayer<H> {
+    fn new(salt: &[u8], block_size: usize) -> Self {
+        let mut context = H::Digest::new();
+        Digest::update(&mut context, salt);
         FsVerityLayer {
-            context: Sha256::new(),
-            remaining: 4096,
+            context,
+            salt: salt.to_vec(),
+            block_size,
+            remaining: block_size,
         }
     }
 
     fn add_data(&mut self, data: &[u8]) {
-        self.context.update(data);
+        Digest::update(&mut self.context, data);
         self.remaining -= data.len();
     }
 
-    fn complete(&mut self) -> Sha256HashValue {
-        self.context.update(&[0u8; 4096][..self.remaining]);
-        self.remaining = 4096;
-        self.context.finalize_reset().into()
+    fn complete(&mut self) -> H {
+        Digest::update(&mut self.context, &ZEROS[..self.remaining]);
+        self.remaining = self.block_size;
+        let value = H::from_digest(&Digest::finalize_reset(&mut self.context));
+        Digest::update(&mut self.context, &self.salt);
+        value
     }
 }
 
-pub struct FsVerityHasher {
-    layers: Vec<FsVerityLayer>,
-    value: Option<Sha256HashValue>,
+/// Computes fs-verity digests in userspace.  The hash algorithm is chosen by the type of the
+/// digest, `H`, and the block size and salt by the [`FsVerityParams`].
+pub struct FsVerityHasher<H: FsVerityHashValue> {
+    params: FsVerityParams,
+    /// The salt, padded to a multiple of the block size of the hash function (or empty)
+    padded_salt: Vec<u8>,
+    layers: Vec<FsVerityLayer<H>>,
+    value: Option<H>,
     n_bytes: u64,
 }
 
-impl FsVerityHasher {
-    pub fn hash(buffer: &[u8]) -> Sha256HashValue {
-        let mut hasher = FsVerityHasher::new();
+impl<H: FsVerityHashValue> Default for FsVerityHasher<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: FsVerityHashValue> FsVerityHasher<H> {
+    pub fn hash(buffer: &[u8]) -> H {
+        Self::hash_with_params(buffer, &FsVerityParams::default())
+    }
+
+    pub fn hash_with_params(buffer: &[u8], params: &FsVerityParams) -> H {
+        let mut hasher = FsVerityHasher::with_params(params);
+        let block_size = params.block_size();
 
         let mut start = 0;
         while start < buffer.len() {
-            let end = min(start + 4096, buffer.len());
+            let end = min(start + block_size, buffer.len());
             hasher.add_data(&buffer[start..end]);
             start = end;
         }
@@ -51,33 +77,85 @@ impl FsVerityHasher {
         hasher.digest()
     }
 
-    pub fn new() -> FsVerityHasher {
+    /// Hashes everything read from `reader` without holding it all in memory
+    pub fn hash_reader(reader: &mut impl Read) -> std::io::Result<H> {
+        Self::hash_reader_with_params(reader, &FsVerityParams::default())
+    }
+
+    pub fn hash_reader_with_params(
+        reader: &mut impl Read,
+        params: &FsVerityParams,
+    ) -> std::io::Result<H> {
+        let mut hasher = FsVerityHasher::with_params(params);
+        let mut block = vec![0u8; params.block_size()];
+
+        loop {
+            let mut filled = 0;
+            while filled < block.len() {
+                match reader.read(&mut block[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if filled > 0 {
+                hasher.add_data(&block[..filled]);
+            }
+            if filled < block.len() {
+                return Ok(hasher.digest());
+            }
+        }
+    }
+
+    pub fn new() -> Self {
+        Self::with_params(&FsVerityParams::default())
+    }
+
+    pub fn with_params(params: &FsVerityParams) -> Self {
+        // fs-verity prepends the salt to each block that it hashes, padded out to the block size
+        // of the hash function (so that the padded salt can be precomputed)
+        let mut padded_salt = params.salt().to_vec();
+        if !padded_salt.is_empty() {
+            let hash_block_size = <H::Digest as BlockSizeUser>::block_size();
+            padded_salt.resize(padded_salt.len().next_multiple_of(hash_block_size), 0);
+        }
+
         FsVerityHasher {
+            params: params.clone(),
+            padded_salt,
             layers: vec![],
             value: None,
             n_bytes: 0,
         }
     }
 
+    /// The block size: data must be added one complete block at a time (except for the last one)
+    pub fn block_size(&self) -> usize {
+        self.params.block_size()
+    }
+
+    fn new_layer(&self) -> FsVerityLayer<H> {
+        FsVerityLayer::new(&self.padded_salt, self.params.block_size())
+    }
+
     pub fn add_data(&mut self, data: &[u8]) {
         if let Some(value) = self.value {
             // We had a complete value, but now we're adding new data.
             // This means that we need to add a new hash layer...
-            let mut new_layer = FsVerityLayer::new();
-            new_layer.add_data(&value);
+            let mut new_layer = self.new_layer();
+            new_layer.add_data(value.as_ref());
             self.layers.push(new_layer);
             self.value = None;
         }
 
         // Get the value of this block
-        let mut context = FsVerityLayer::new();
+        let mut context = self.new_layer();
         context.add_data(data);
         let mut value = context.complete();
         self.n_bytes += data.len() as u64;
 
         for layer in self.layers.iter_mut() {
             // We have a layer we need to hash this value into
-            layer.add_data(&value);
+            layer.add_data(value.as_ref());
             if layer.remaining != 0 {
                 return;
             }
@@ -89,22 +167,23 @@ impl FsVerityHasher {
         self.value = Some(value);
     }
 
-    pub fn root_hash(&mut self) -> Sha256HashValue {
+    pub fn root_hash(&mut self) -> H {
         if let Some(value) = self.value {
             value
         } else {
-            let mut value = [0u8; 32];
+            let mut value = H::EMPTY;
+            let block_size = self.params.block_size();
 
             for layer in self.layers.iter_mut() {
                 // We have a layer we need to hash this value into
-                if value != [0u8; 32] {
-                    layer.add_data(&value);
+                if value != H::EMPTY {
+                    layer.add_data(value.as_ref());
                 }
-                if layer.remaining != 4096 {
+                if layer.remaining != block_size {
                     // ...but now this layer itself is complete, so get the value of *it*.
                     value = layer.complete();
                 } else {
-                    value = [0u8; 32];
+                    value = H::EMPTY;
                 }
             }
 
@@ -114,37 +193,24 @@ impl FsVerityHasher {
         }
     }
 
-    pub fn digest(&mut self) -> Sha256HashValue {
-        /*
-        let descriptor = FsVerityDescriptor {
-            version: 1,
-            hash_algorithm: 1,
-            log_blocksize: 12,
-            salt_size: 0,
-            reserved_0x04: 0,
-            data_size: self.n_bytes,
-            root_hash: (self.root_hash(), [0; 32]),
-            salt: [0; 32],
-            reserved: [0; 144],
-        };
+    pub fn digest(&mut self) -> H {
+        let root_hash = self.root_hash();
+        let salt = self.params.salt();
 
-        let mut context = Sha256::new();
-        context.update(descriptor);
-        return context.finalize().into();
-        */
-
-        let mut context = Sha256::new();
-        context.update(1u8.to_le_bytes()); /* version */
-        context.update(1u8.to_le_bytes()); /* hash_algorithm */
-        context.update(12u8.to_le_bytes()); /* log_blocksize */
-        context.update(0u8.to_le_bytes()); /* salt_size */
-        context.update([0; 4]); /* reserved */
-        context.update(self.n_bytes.to_le_bytes());
-        context.update(self.root_hash());
-        context.update([0; 32]);
-        context.update([0; 32]); /* salt */
-        context.update([0; 144]); /* reserved */
-        context.finalize().into()
+        // struct fsverity_descriptor, which is hashed without the salt
+        let mut context = H::Digest::new();
+        Digest::update(&mut context, 1u8.to_le_bytes()); /* version */
+        Digest::update(&mut context, H::ALGORITHM.to_le_bytes()); /* hash_algorithm */
+        Digest::update(&mut context, self.params.log_blocksize().to_le_bytes()); /* log_blocksize */
+        Digest::update(&mut context, (salt.len() as u8).to_le_bytes()); /* salt_size */
+        Digest::update(&mut context, [0; 4]); /* reserved */
+        Digest::update(&mut context, self.n_bytes.to_le_bytes());
+        Digest::update(&mut context, root_hash); /* root_hash, padded to 64 bytes */
+        Digest::update(&mut context, &[0; 64][root_hash.as_ref().len()..]);
+        Digest::update(&mut context, salt); /* salt, padded to 32 bytes */
+        Digest::update(&mut context, &[0; 32][salt.len()..]);
+        Digest::update(&mut context, [0; 144]); /* reserved */
+        H::from_digest(&context.finalize())
     }
 }
 
@@ -153,10 +219,11 @@ mod tests {
     use anyhow::Result;
 
     use super::*;
+    use crate::fsverity::{FsVerityParams, Sha256HashValue, Sha512HashValue};
 
     #[test]
     fn test_digest() -> Result<()> {
-        let digest = FsVerityHasher::hash(b"hello world");
+        let digest: Sha256HashValue = FsVerityHasher::hash(b"hello world");
         assert_eq!(
             digest,
             [
@@ -166,4 +233,105 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_digest_sha512() -> Result<()> {
+        // fsverity digest --hash-alg=sha512
+        let digest: Sha512HashValue = FsVerityHasher::hash(b"hello world");
+        assert_eq!(
+            hex::encode(digest),
+            "18430270729d162d4e469daca123ae61893db4b0583d8f7081e3bf4f92b88ba514e7982f10733fb6aa895195c5ae8fd2eb2c47a8be05513ce5a0c51a6f570409"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_params() -> Result<()> {
+        // fsverity digest --block-size=... [--salt=...]
+        let data: Vec<u8> = (0..100000).map(|i| i as u8).collect();
+        let hash256 = |block_size, salt: &[u8]| -> Result<String> {
+            let params = FsVerityParams::new(block_size, salt)?;
+            let digest: Sha256HashValue = FsVerityHasher::hash_with_params(&data, &params);
+            Ok(hex::encode(digest))
+        };
+        let hash512 = |block_size, salt: &[u8]| -> Result<String> {
+            let params = FsVerityParams::new(block_size, salt)?;
+            let digest: Sha512HashValue = FsVerityHasher::hash_with_params(&data, &params);
+            Ok(hex::encode(digest))
+        };
+
+        assert_eq!(
+            hash256(1024, b"")?,
+            "6adf5be56f5e5bd50014dfb7ed318032966cfada29f8e457314b725ba3b8dbf8"
+        );
+        assert_eq!(
+            hash256(4096, b"")?,
+            "c84bc7bd0ce821ca20eba76abf6a86f33146f60ade6567749101f54cf280fa0d"
+        );
+        assert_eq!(
+            hash256(16384, b"")?,
+            "75ebaccf81567907964cfeb1f87fbb81c2158a045029a787e2632eb7a9a5cda0"
+        );
+        assert_eq!(
+            hash256(65536, b"")?,
+            "f98318300be1a5b2456557d96b75a281a911c1b980bc853558b36d669c448d89"
+        );
+        assert_eq!(
+            hash512(1024, b"")?,
+            "ec3a5558672e88bad4346df71421868daeeeac4427dbc2c8c1a5fe267142a96f\
+             624aee8fa15611059489134521ba1cb0544f86187581c623a8e1fd8afc9cd640"
+        );
+        assert_eq!(
+            hash512(65536, b"")?,
+            "902b0b1efa07236f8085dadcb9031c66d4be89b84e873adcfa3377c679fceba7\
+             0dcd800f5cc0eed663290d6aba60089142a55935a6d7c939128c55ab33b4efea"
+        );
+        assert_eq!(
+            hash256(4096, b"composefs")?,
+            "651b37e08dc7449a1d6b5cedc75ea4bc465f11d21f1c49c79c9f3b20ab86980f"
+        );
+        let salt: Vec<u8> = (0..32).collect();
+        assert_eq!(
+            hash512(1024, &salt)?,
+            "bfb9c95d27355ad87d2ea2f6abb23a573a7c8b597b81b164e07a276890462887\
+             2a2bd7888e01e287dacd7432a73f21c65109b740bccebf8097fbd45b56475b64"
+        );
+
+        // the salt is recorded in the descriptor even if there's no data
+        let params = FsVerityParams::new(4096, b"salt")?;
+        let digest: Sha256HashValue = FsVerityHasher::hash_with_params(b"", &params);
+        assert_eq!(
+            hex::encode(digest),
+            "4c6bcdafe644dc5ea2924e07eacdada63195a2d45702128ade89cb722e4eac50"
+        );
+
+        assert!(FsVerityParams::new(512, b"").is_err());
+        assert!(FsVerityParams::new(3000, b"").is_err());
+        assert!(FsVerityParams::new(4096, &[0; 33]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_reader() -> Result<()> {
+        for size in [0, 1, 4095, 4096, 4097, 3 * 4096, 1 << 20] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            assert_eq!(
+                FsVerityHasher::<Sha256HashValue>::hash_reader(&mut &data[..])?,
+                FsVerityHasher::<Sha256HashValue>::hash(&data)
+            );
+            assert_eq!(
+                FsVerityHasher::<Sha512HashValue>::hash_reader(&mut &data[..])?,
+                FsVerityHasher::<Sha512HashValue>::hash(&data)
+            );
+            let params = FsVerityParams::new(1024, b"salt")?;
+            assert_eq!(
+                FsVerityHasher::<Sha256HashValue>::hash_reader_with_params(
+                    &mut &data[..],
+                    &params
+                )?,
+                FsVerityHasher::<Sha256HashValue>::hash_with_params(&data, &params)
+            );
+        }
+        Ok(())
+    }
 }
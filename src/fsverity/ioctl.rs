@@ -1,9 +1,10 @@
 use std::os::fd::AsFd;
 
-use anyhow::Result;
 use rustix::ioctl;
 
-use super::FsVerityHashValue;
+use super::{FsVerityHashValue, FsVerityParams};
+
+use crate::error::Result;
 
 // See /usr/include/linux/fsverity.h
 #[repr(C)]
@@ -22,19 +23,29 @@ pub struct FsVerityEnableArg {
 // #define FS_IOC_ENABLE_VERITY    _IOW('f', 133, struct fsverity_enable_arg)
 type FsIocEnableVerity = ioctl::WriteOpcode<b'f', 133, FsVerityEnableArg>;
 
-pub fn fs_ioc_enable_verity<F: AsFd, H: FsVerityHashValue>(fd: F) -> Result<()> {
+/// Enables fs-verity on the file, with the hash algorithm of `H` and the given block size and salt.
+/// The file must not be open for writing.  If a builtin signature is given (see
+/// [`super::signature`]), the kernel checks it against the `.fs-verity` keyring.
+pub fn fs_ioc_enable_verity<F: AsFd, H: FsVerityHashValue>(
+    fd: F,
+    params: &FsVerityParams,
+    signature: Option<&[u8]>,
+) -> Result<()> {
+    let salt = params.salt();
+    let signature = signature.unwrap_or_default();
+    // SAFETY: the salt and signature outlive the ioctl() call
     unsafe {
         ioctl::ioctl(
             fd,
             ioctl::Setter::<FsIocEnableVerity, FsVerityEnableArg>::new(FsVerityEnableArg {
                 version: 1,
                 hash_algorithm: H::ALGORITHM as u32,
-                block_size: 4096,
-                salt_size: 0,
-                salt_ptr: 0,
-                sig_size: 0,
+                block_size: params.block_size() as u32,
+                salt_size: salt.len() as u32,
+                salt_ptr: salt.as_ptr() as u64,
+                sig_size: signature.len() as u32,
                 __reserved1: 0,
-                sig_ptr: 0,
+                sig_ptr: signature.as_ptr() as u64,
                 __reserved2: [0; 11],
             }),
         )?;
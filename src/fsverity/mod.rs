@@ -1,21 +1,141 @@
 pub mod digest;
 pub mod ioctl;
+pub mod signature;
 
-pub trait FsVerityHashValue {
+use std::{fmt, hash::Hash};
+
+use anyhow::Context;
+use sha2::{
+    digest::{core_api::BlockSizeUser, FixedOutputReset},
+    Digest, Sha256, Sha512,
+};
+
+use crate::error::{ensure, Result};
+
+/// A fs-verity digest.  Objects in a repository are named by one of these, and the type of the
+/// digest determines the hash algorithm used by fs-verity.
+pub trait FsVerityHashValue:
+    Copy + Eq + Ord + Hash + fmt::Debug + AsRef<[u8]> + AsMut<[u8]> + Send + Sync + 'static
+{
+    /// The hash function used for the Merkle tree and for the final digest
+    type Digest: Digest + FixedOutputReset + BlockSizeUser;
+    /// The algorithm number used by the kernel (FS_VERITY_HASH_ALG_*)
     const ALGORITHM: u8;
     const EMPTY: Self;
+    /// The name of the algorithm, as used in repository metadata
+    const ID: &'static str;
+
+    /// Creates a value from the output of the hash function
+    fn from_digest(digest: &[u8]) -> Self {
+        let mut value = Self::EMPTY;
+        value.as_mut().copy_from_slice(digest);
+        value
+    }
+
+    /// Parses a hex string of exactly the right length
+    fn from_hex(hex: impl AsRef<[u8]>) -> Result<Self> {
+        let mut value = Self::EMPTY;
+        hex::decode_to_slice(hex.as_ref(), value.as_mut()).with_context(|| {
+            format!(
+                "Invalid {} digest {:?}",
+                Self::ID,
+                String::from_utf8_lossy(hex.as_ref())
+            )
+        })?;
+        Ok(value)
+    }
+
+    /// Parses the path of an object relative to the objects directory, like `ab/cdef...`
+    fn from_object_pathname(pathname: impl AsRef<[u8]>) -> Result<Self> {
+        let pathname = pathname.as_ref();
+        ensure!(
+            pathname.len() == 2 * std::mem::size_of::<Self>() + 1 && pathname[2] == b'/',
+            "Invalid object pathname {:?}",
+            String::from_utf8_lossy(pathname)
+        );
+        let mut value = Self::EMPTY;
+        hex::decode_to_slice(&pathname[..2], &mut value.as_mut()[..1])?;
+        hex::decode_to_slice(&pathname[3..], &mut value.as_mut()[1..])?;
+        Ok(value)
+    }
+
+    /// The path of the object relative to the objects directory, like `ab/cdef...`
+    fn to_object_pathname(&self) -> String {
+        let bytes = self.as_ref();
+        format!("{:02x}/{}", bytes[0], hex::encode(&bytes[1..]))
+    }
 }
 
 pub type Sha256HashValue = [u8; 32];
 
 impl FsVerityHashValue for Sha256HashValue {
+    type Digest = Sha256;
     const ALGORITHM: u8 = 1;
     const EMPTY: Self = [0; 32];
+    const ID: &'static str = "sha256";
 }
 
 pub type Sha512HashValue = [u8; 64];
 
 impl FsVerityHashValue for Sha512HashValue {
+    type Digest = Sha512;
     const ALGORITHM: u8 = 2;
     const EMPTY: Self = [0; 64];
+    const ID: &'static str = "sha512";
+}
+
+/// The parameters (other than the hash algorithm) which affect the fs-verity digest of a file: the
+/// size of the Merkle tree blocks and the salt.  The defaults are 4096 byte blocks and no salt,
+/// which is what `fsverity enable` uses unless told otherwise.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FsVerityParams {
+    log_blocksize: u8,
+    salt: Vec<u8>,
+}
+
+impl Default for FsVerityParams {
+    fn default() -> Self {
+        FsVerityParams {
+            log_blocksize: 12,
+            salt: vec![],
+        }
+    }
+}
+
+impl FsVerityParams {
+    /// The largest salt supported by fs-verity
+    pub const MAX_SALT_SIZE: usize = 32;
+
+    /// Creates a set of parameters.  The block size must be a power of two between 1024 and
+    /// 65536, and the salt can be at most 32 bytes long.  Note that the kernel only supports block
+    /// sizes up to the page size (and, for some filesystems, the filesystem block size).
+    pub fn new(block_size: usize, salt: &[u8]) -> Result<Self> {
+        ensure!(
+            block_size.is_power_of_two() && (1024..=65536).contains(&block_size),
+            "Invalid fs-verity block size {block_size}"
+        );
+        ensure!(
+            salt.len() <= Self::MAX_SALT_SIZE,
+            "fs-verity salt is too long ({} bytes, the maximum is {})",
+            salt.len(),
+            Self::MAX_SALT_SIZE
+        );
+        Ok(FsVerityParams {
+            log_blocksize: block_size.trailing_zeros() as u8,
+            salt: salt.to_vec(),
+        })
+    }
+
+    /// The size of the Merkle tree blocks, which is also the granularity of the verification
+    pub fn block_size(&self) -> usize {
+        1 << self.log_blocksize
+    }
+
+    pub fn log_blocksize(&self) -> u8 {
+        self.log_blocksize
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
 }
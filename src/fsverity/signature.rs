@@ -0,0 +1,36 @@
+//! fs-verity builtin signatures.
+//!
+//! With builtin signatures, fs-verity is enabled on a file along with a PKCS#7 signature of its
+//! digest.  The kernel checks the signature against the certificates in the `.fs-verity` keyring
+//! and, if `fs.verity.require_signatures` is set, refuses to open files without a valid one.
+//!
+//! The signature is a detached signature with no signed attributes and no certificates over the
+//! "formatted digest" of the file, which is what [`formatted_digest`] returns.  Making one is left
+//! to the caller (`cfsctl sign-image` uses the `openssl` command-line tool, the same way that
+//! `fsverity sign` does).
+
+use super::FsVerityHashValue;
+
+/// Returns the message which is signed for a file with the given fs-verity digest: `struct
+/// fsverity_formatted_digest` from the kernel.
+pub fn formatted_digest<H: FsVerityHashValue>(digest: &H) -> Vec<u8> {
+    let digest = digest.as_ref();
+    let mut message = b"FSVerity".to_vec();
+    message.extend_from_slice(&u16::from(H::ALGORITHM).to_le_bytes());
+    message.extend_from_slice(&(digest.len() as u16).to_le_bytes());
+    message.extend_from_slice(digest);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formatted_digest() {
+        let message = formatted_digest(&[0xaa; 32]);
+        assert_eq!(message[..12], *b"FSVerity\x01\x00\x20\x00");
+        assert_eq!(message[12..], [0xaa; 32]);
+        assert_eq!(formatted_digest(&[0xbb; 64])[8..12], [2, 0, 64, 0]);
+    }
+}
@@ -3,15 +3,21 @@ use std::{
     cmp::{Ord, Ordering},
     collections::BTreeMap,
     ffi::{OsStr, OsString},
-    io::Read,
+    io::{BufRead, Read},
     path::Path,
     process::{Command, Stdio},
     rc::Rc,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::Context;
+use rustix::fs::FileType;
 
-use crate::{dumpfile::write_dumpfile, fsverity::Sha256HashValue};
+use crate::{
+    dumpfile::write_dumpfile,
+    dumpfile_parse::{Entry, Item},
+    error::{bail, Error, Result},
+    fsverity::FsVerityHashValue,
+};
 
 #[derive(Debug)]
 pub struct Stat {
@@ -23,9 +29,9 @@ pub struct Stat {
 }
 
 #[derive(Debug)]
-pub enum LeafContent {
+pub enum LeafContent<ObjectID: FsVerityHashValue> {
     InlineFile(Vec<u8>),
-    ExternalFile(Sha256HashValue, u64),
+    ExternalFile(ObjectID, u64),
     BlockDevice(u64),
     CharacterDevice(u64),
     Fifo,
@@ -34,31 +40,31 @@ pub enum LeafContent {
 }
 
 #[derive(Debug)]
-pub struct Leaf {
+pub struct Leaf<ObjectID: FsVerityHashValue> {
     pub stat: Stat,
-    pub content: LeafContent,
+    pub content: LeafContent<ObjectID>,
 }
 
 #[derive(Debug)]
-pub struct Directory {
+pub struct Directory<ObjectID: FsVerityHashValue> {
     pub stat: Stat,
-    pub entries: Vec<DirEnt>,
+    pub entries: Vec<DirEnt<ObjectID>>,
 }
 
 #[derive(Debug)]
-pub enum Inode {
-    Directory(Box<Directory>),
-    Leaf(Rc<Leaf>),
+pub enum Inode<ObjectID: FsVerityHashValue> {
+    Directory(Box<Directory<ObjectID>>),
+    Leaf(Rc<Leaf<ObjectID>>),
 }
 
 #[derive(Debug)]
-pub struct DirEnt {
+pub struct DirEnt<ObjectID: FsVerityHashValue> {
     pub name: OsString,
-    pub inode: Inode,
+    pub inode: Inode<ObjectID>,
 }
 
-impl Directory {
-    pub fn find_entry(&self, name: &OsStr) -> Result<usize, usize> {
+impl<ObjectID: FsVerityHashValue> Directory<ObjectID> {
+    pub fn find_entry(&self, name: &OsStr) -> std::result::Result<usize, usize> {
         // OCI layer tarballs are typically sorted, with the entries for a particular directory
         // written out immediately after that directory was created.  That means that it's very
         // likely that the thing we're looking for is either the last entry or the insertion point
@@ -75,7 +81,7 @@ impl Directory {
         }
     }
 
-    pub fn recurse(&mut self, name: impl AsRef<OsStr>) -> Result<&mut Directory> {
+    pub fn recurse(&mut self, name: impl AsRef<OsStr>) -> Result<&mut Directory<ObjectID>> {
         match self.find_entry(name.as_ref()) {
             Ok(idx) => match &mut self.entries[idx].inode {
                 Inode::Directory(ref mut subdir) => Ok(subdir),
@@ -114,7 +120,7 @@ impl Directory {
         }
     }
 
-    pub fn insert(&mut self, name: &OsStr, inode: Inode) {
+    pub fn insert(&mut self, name: &OsStr, inode: Inode<ObjectID>) {
         match self.find_entry(name) {
             Ok(idx) => {
                 // found existing item
@@ -133,7 +139,7 @@ impl Directory {
         }
     }
 
-    pub fn get_for_link(&self, name: &OsStr) -> Result<Rc<Leaf>> {
+    pub fn get_for_link(&self, name: &OsStr) -> Result<Rc<Leaf<ObjectID>>> {
         match self.find_entry(name) {
             Ok(idx) => match self.entries[idx].inode {
                 Inode::Leaf(ref leaf) => Ok(Rc::clone(leaf)),
@@ -171,17 +177,17 @@ impl Directory {
     }
 }
 
-pub struct FileSystem {
-    pub root: Directory,
+pub struct FileSystem<ObjectID: FsVerityHashValue> {
+    pub root: Directory<ObjectID>,
 }
 
-impl Default for FileSystem {
+impl<ObjectID: FsVerityHashValue> Default for FileSystem<ObjectID> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl FileSystem {
+impl<ObjectID: FsVerityHashValue> FileSystem<ObjectID> {
     pub fn new() -> Self {
         FileSystem {
             root: Directory {
@@ -197,7 +203,7 @@ impl FileSystem {
         }
     }
 
-    fn get_parent_dir<'a>(&'a mut self, name: &Path) -> Result<&'a mut Directory> {
+    fn get_parent_dir<'a>(&'a mut self, name: &Path) -> Result<&'a mut Directory<ObjectID>> {
         let mut dir = &mut self.root;
 
         if let Some(parent) = name.parent() {
@@ -223,7 +229,7 @@ impl FileSystem {
         Ok(())
     }
 
-    pub fn insert_rc(&mut self, name: &Path, leaf: Rc<Leaf>) -> Result<()> {
+    pub fn insert_rc(&mut self, name: &Path, leaf: Rc<Leaf<ObjectID>>) -> Result<()> {
         if let Some(filename) = name.file_name() {
             let dir = self.get_parent_dir(name)?;
             dir.insert(filename, Inode::Leaf(leaf));
@@ -233,11 +239,11 @@ impl FileSystem {
         }
     }
 
-    pub fn insert(&mut self, name: &Path, leaf: Leaf) -> Result<()> {
+    pub fn insert(&mut self, name: &Path, leaf: Leaf<ObjectID>) -> Result<()> {
         self.insert_rc(name, Rc::new(leaf))
     }
 
-    fn get_for_link(&mut self, name: &Path) -> Result<Rc<Leaf>> {
+    fn get_for_link(&mut self, name: &Path) -> Result<Rc<Leaf<ObjectID>>> {
         if let Some(filename) = name.file_name() {
             let dir = self.get_parent_dir(name)?;
             dir.get_for_link(filename)
@@ -261,6 +267,73 @@ impl FileSystem {
         }
     }
 
+    /// Builds a filesystem from the lines of a composefs dumpfile.  Hardlink entries must refer to
+    /// a file which appears earlier in the dumpfile.
+    pub fn from_dumpfile(reader: impl BufRead) -> Result<Self> {
+        let mut fs = FileSystem::new();
+
+        for (lineno, line) in reader.lines().enumerate() {
+            let line = line?;
+            fs.add_dumpfile_entry(Entry::parse(&line)?)
+                .with_context(|| format!("Processing dumpfile line {}", lineno + 1))?;
+        }
+
+        fs.done();
+        Ok(fs)
+    }
+
+    fn add_dumpfile_entry(&mut self, entry: Entry) -> Result<()> {
+        let stat = Stat {
+            st_mode: entry.mode & 0o7777,
+            st_uid: entry.uid,
+            st_gid: entry.gid,
+            st_mtim_sec: entry.mtime.sec as i64,
+            xattrs: RefCell::new(
+                entry
+                    .xattrs
+                    .into_iter()
+                    .map(|xattr| (Box::from(&*xattr.key), Box::from(&*xattr.value)))
+                    .collect(),
+            ),
+        };
+
+        let content = match entry.item {
+            Item::Directory { .. } if entry.path.as_os_str() == "/" => {
+                self.root.stat = stat;
+                return Ok(());
+            }
+            Item::Directory { .. } => return self.mkdir(&entry.path, stat),
+            Item::Hardlink { target } => {
+                return self.hardlink(&entry.path, target.as_os_str());
+            }
+            Item::RegularInline { content, .. } => LeafContent::InlineFile(content.into_owned()),
+            Item::Regular {
+                size,
+                path,
+                fsverity_digest,
+                ..
+            } => {
+                // The digest is optional: the backing path in the repository is named after it
+                let id = match fsverity_digest {
+                    Some(digest) => ObjectID::from_hex(digest)?,
+                    None => ObjectID::from_hex(path.to_string_lossy().replace('/', ""))
+                        .with_context(|| format!("Unsupported backing path {path:?}"))?,
+                };
+                LeafContent::ExternalFile(id, size)
+            }
+            Item::Device { rdev, .. } => match FileType::from_raw_mode(entry.mode) {
+                FileType::BlockDevice => LeafContent::BlockDevice(rdev),
+                _ => LeafContent::CharacterDevice(rdev),
+            },
+            Item::Symlink { target, .. } => {
+                LeafContent::Symlink(target.into_owned().into_os_string())
+            }
+            Item::Fifo { .. } => LeafContent::Fifo,
+        };
+
+        self.insert(&entry.path, Leaf { stat, content })
+    }
+
     pub fn done(&mut self) {
         // We need to look at the root entry and deal with the "assign later" fields
         let stat = &mut self.root.stat;
@@ -281,12 +354,19 @@ impl FileSystem {
     }
 }
 
-pub fn mkcomposefs(filesystem: FileSystem) -> Result<Vec<u8>> {
+/// Creates the composefs image of `filesystem` with `mkcomposefs`.
+///
+/// Image digests are compared across machines, so this keeps using `mkcomposefs` until the output
+/// of [`crate::erofs::writer::mkfs_erofs`] is known to be identical to it.
+pub fn mkcomposefs<ObjectID: FsVerityHashValue>(
+    filesystem: FileSystem<ObjectID>,
+) -> Result<Vec<u8>> {
     let mut mkcomposefs = Command::new("mkcomposefs")
         .args(["--from-file", "-", "-"])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn()?;
+        .spawn()
+        .context("Unable to run mkcomposefs")?;
 
     let mut stdin = mkcomposefs.stdin.take().unwrap();
     write_dumpfile(&mut stdin, &filesystem)?;
@@ -297,9 +377,80 @@ pub fn mkcomposefs(filesystem: FileSystem) -> Result<Vec<u8>> {
     stdout.read_to_end(&mut image)?;
     drop(stdout);
 
-    if !mkcomposefs.wait()?.success() {
-        bail!("mkcomposefs failed");
-    };
+    let status = mkcomposefs.wait()?;
+    if !status.success() {
+        return Err(Error::CommandFailed {
+            command: "mkcomposefs".to_string(),
+            status,
+            context: vec![],
+        });
+    }
 
     Ok(image)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dumpfile::write_dumpfile, fsverity::Sha256HashValue};
+
+    fn entries(dumpfile: &str) -> Result<Vec<Entry<'_>>> {
+        dumpfile
+            .lines()
+            .map(|line| {
+                let mut entry = Entry::parse(line)?;
+                // The size of a directory isn't meaningful
+                if let Item::Directory { ref mut size, .. } = entry.item {
+                    *size = 0;
+                }
+                entry.xattrs.sort_by(|a, b| a.key.cmp(&b.key));
+                Ok(entry)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_from_dumpfile_roundtrip() -> Result<()> {
+        const SPECIAL_DUMP: &str = include_str!("tests/assets/special.dump");
+
+        let fs = FileSystem::<Sha256HashValue>::from_dumpfile(SPECIAL_DUMP.as_bytes())?;
+        let mut output = vec![];
+        write_dumpfile(&mut output, &fs)?;
+
+        assert_eq!(
+            entries(SPECIAL_DUMP)?,
+            entries(std::str::from_utf8(&output)?)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_dumpfile_hardlinks() -> Result<()> {
+        const DUMP: &str = "/ 0 40755 3 0 0 0 0.0 - - -\n\
+/a 0 40755 2 0 0 0 0.0 - - -\n\
+/a/file 5 100644 2 0 0 0 0.0 - hello -\n\
+/link 0 @120000 - - - - 0.0 /a/file - -\n";
+
+        let fs = FileSystem::<Sha256HashValue>::from_dumpfile(DUMP.as_bytes())?;
+        let Inode::Directory(ref a) = fs.root.entries[0].inode else {
+            panic!("/a is not a directory");
+        };
+        let (Inode::Leaf(file), Inode::Leaf(link)) =
+            (&a.entries[0].inode, &fs.root.entries[1].inode)
+        else {
+            panic!("expected two leaves");
+        };
+        assert!(Rc::ptr_eq(file, link));
+
+        let mut output = vec![];
+        write_dumpfile(&mut output, &fs)?;
+        assert_eq!(std::str::from_utf8(&output)?, DUMP);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_dumpfile_missing_hardlink_target() {
+        let dump = "/ 4096 40755 2 0 0 0 0.0 - - -\n/link 0 @120000 - - - - 0.0 /missing - -\n";
+        assert!(FileSystem::<Sha256HashValue>::from_dumpfile(dump.as_bytes()).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 pub mod dumpfile;
 pub mod dumpfile_parse;
+pub mod erofs;
+pub mod error;
 pub mod fs;
 pub mod fsverity;
 pub mod image;
@@ -10,6 +12,8 @@ pub mod selabel;
 pub mod splitstream;
 pub mod util;
 
+pub use error::{Error, Result};
+
 /// All files that contain 64 or fewer bytes (size <= INLINE_CONTENT_MAX) should be stored inline
 /// in the erofs image (and also in splitstreams).  All files with 65 or more bytes (size > MAX)
 /// should be written to the object storage and referred to from the image (and splitstreams).
@@ -4,7 +4,6 @@ use std::{
     path::Path,
 };
 
-use anyhow::Result;
 use rustix::{
     fs::{major, minor, stat, symlink, CWD},
     io::Errno,
@@ -14,7 +13,7 @@ use rustix::{
     },
 };
 
-use crate::fsverity;
+use crate::{error::Result, fsverity};
 
 struct FsHandle {
     pub fd: OwnedFd,
@@ -29,7 +28,7 @@ impl FsHandle {
 }
 
 impl AsFd for FsHandle {
-    fn as_fd(&self) -> BorrowedFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
         self.fd.as_fd()
     }
 }
@@ -76,7 +75,37 @@ fn proc_self_fd<A: AsFd>(fd: A) -> String {
     format!("/proc/self/fd/{}", fd.as_fd().as_raw_fd())
 }
 
-pub fn composefs_fsmount(image: impl AsFd, basedir: &Path) -> Result<OwnedFd> {
+/// How overlayfs should check the fs-verity digests of the files in the object store against the
+/// digests recorded in the image (the overlayfs `verity=` option)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerityMode {
+    /// Every file must have a recorded digest, and it must match (the default)
+    Require,
+    /// Recorded digests are checked against the files (which must have fs-verity enabled)
+    On,
+    /// Nothing is checked.  This is for repositories on filesystems without fs-verity support.
+    Off,
+}
+
+impl VerityMode {
+    /// The value of the overlayfs `verity=` option
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VerityMode::Require => "require",
+            VerityMode::On => "on",
+            VerityMode::Off => "off",
+        }
+    }
+}
+
+/// Creates (but doesn't attach) a composefs mount of `image`.  The objects referred to by the
+/// image are looked up in each of `basedirs` in turn.  It's up to the caller to warn the user if
+/// `verity` is anything other than [`VerityMode::Require`].
+pub fn composefs_fsmount(
+    image: impl AsFd,
+    basedirs: &[impl AsRef<Path>],
+    verity: VerityMode,
+) -> Result<OwnedFd> {
     let erofs = FsHandle::open("erofs")?;
     fsconfig_set_string(erofs.as_fd(), "source", proc_self_fd(&image))?;
     fsconfig_create(erofs.as_fd())?;
@@ -84,12 +113,18 @@ pub fn composefs_fsmount(image: impl AsFd, basedir: &Path) -> Result<OwnedFd> {
     let overlayfs = FsHandle::open("overlay")?;
     fsconfig_set_string(overlayfs.as_fd(), "metacopy", "on")?;
     fsconfig_set_string(overlayfs.as_fd(), "redirect_dir", "on")?;
-    fsconfig_set_string(overlayfs.as_fd(), "verity", "require")?;
+    fsconfig_set_string(overlayfs.as_fd(), "verity", verity.as_str())?;
+    if verity != VerityMode::Require {
+        // make it obvious in /proc/self/mountinfo that the contents aren't verified
+        fsconfig_set_string(overlayfs.as_fd(), "source", "composefs-unverified")?;
+    }
 
     // unfortunately we can't do this via the fd: we need a tmpdir mountpoint
     let tmp = TmpMount::mount(erofs.as_fd())?; // NB: must live until the "create" operation
     fsconfig_set_string(overlayfs.as_fd(), "lowerdir+", tmp.dir.path())?;
-    fsconfig_set_string(overlayfs.as_fd(), "datadir+", basedir)?;
+    for basedir in basedirs {
+        fsconfig_set_string(overlayfs.as_fd(), "datadir+", basedir.as_ref())?;
+    }
     fsconfig_create(overlayfs.as_fd())?;
 
     Ok(fsmount(
@@ -99,8 +134,13 @@ pub fn composefs_fsmount(image: impl AsFd, basedir: &Path) -> Result<OwnedFd> {
     )?)
 }
 
-pub fn mount_fd<F: AsFd>(image: F, basedir: &Path, mountpoint: &str) -> Result<()> {
-    let mnt = composefs_fsmount(image, basedir)?;
+pub fn mount_fd<F: AsFd>(
+    image: F,
+    basedirs: &[impl AsRef<Path>],
+    mountpoint: &str,
+    verity: VerityMode,
+) -> Result<()> {
+    let mnt = composefs_fsmount(image, basedirs, verity)?;
 
     move_mount(
         mnt.as_fd(),
@@ -113,7 +153,12 @@ pub fn mount_fd<F: AsFd>(image: F, basedir: &Path, mountpoint: &str) -> Result<(
     Ok(())
 }
 
-pub fn pivot_sysroot(image: impl AsFd, basedir: &Path, sysroot: &Path) -> Result<()> {
+pub fn pivot_sysroot(
+    image: impl AsFd,
+    basedirs: &[impl AsRef<Path>],
+    sysroot: &Path,
+    verity: VerityMode,
+) -> Result<()> {
     // https://github.com/systemd/systemd/issues/35017
     let rootdev = stat("/dev/gpt-auto-root")?;
     let target = format!(
@@ -123,7 +168,7 @@ pub fn pivot_sysroot(image: impl AsFd, basedir: &Path, sysroot: &Path) -> Result
     );
     symlink(target, "/run/systemd/volatile-root")?;
 
-    let mnt = composefs_fsmount(image, basedir)?;
+    let mnt = composefs_fsmount(image, basedirs, verity)?;
 
     // try to move /sysroot to /sysroot/sysroot if it exists
     let prev = open_tree(CWD, sysroot, OpenTreeFlags::OPEN_TREE_CLONE)?;
@@ -192,13 +237,21 @@ impl<'a> MountOptions<'a> {
         let image = std::fs::File::open(self.image)?;
 
         if let Some(expected) = self.digest {
-            let measured: fsverity::Sha256HashValue =
-                fsverity::ioctl::fs_ioc_measure_verity(&image)?;
-            if expected != hex::encode(measured) {
+            // The length of the digest tells us which algorithm it's for
+            let measured = if expected.len() == 128 {
+                let digest: fsverity::Sha512HashValue =
+                    fsverity::ioctl::fs_ioc_measure_verity(&image)?;
+                hex::encode(digest)
+            } else {
+                let digest: fsverity::Sha256HashValue =
+                    fsverity::ioctl::fs_ioc_measure_verity(&image)?;
+                hex::encode(digest)
+            };
+            if expected != measured {
                 panic!("expected {:?} measured {:?}", expected, measured);
             }
         }
 
-        mount_fd(image, self.basedir, mountpoint)
+        mount_fd(image, &[self.basedir], mountpoint, VerityMode::Require)
     }
 }
@@ -1,18 +1,21 @@
 use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Component, rc::Rc};
 
-use anyhow::{bail, Result};
-use oci_spec::image::ImageConfiguration;
+use anyhow::Context;
 
 use crate::{
     dumpfile::write_dumpfile,
-    fsverity::Sha256HashValue,
+    error::{bail, Result},
+    fsverity::FsVerityHashValue,
     image::{mkcomposefs, FileSystem, Inode, Leaf},
     oci,
     repository::Repository,
     selabel::selabel,
 };
 
-pub fn process_entry(filesystem: &mut FileSystem, entry: oci::tar::TarEntry) -> Result<()> {
+pub fn process_entry<ObjectID: FsVerityHashValue>(
+    filesystem: &mut FileSystem<ObjectID>,
+    entry: oci::tar::TarEntry<ObjectID>,
+) -> Result<()> {
     let mut components = entry.path.components();
 
     let Some(Component::Normal(filename)) = components.next_back() else {
@@ -54,7 +57,10 @@ pub fn process_entry(filesystem: &mut FileSystem, entry: oci::tar::TarEntry) ->
     Ok(())
 }
 
-pub fn compose_filesystem(repo: &Repository, layers: &[String]) -> Result<FileSystem> {
+pub fn compose_filesystem<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    layers: &[String],
+) -> Result<FileSystem<ObjectID>> {
     let mut filesystem = FileSystem::new();
 
     for layer in layers {
@@ -70,27 +76,31 @@ pub fn compose_filesystem(repo: &Repository, layers: &[String]) -> Result<FileSy
     Ok(filesystem)
 }
 
-pub fn create_dumpfile(repo: &Repository, layers: &[String]) -> Result<()> {
+pub fn create_dumpfile<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    layers: &[String],
+) -> Result<()> {
     let filesystem = compose_filesystem(repo, layers)?;
     let mut stdout = std::io::stdout();
     write_dumpfile(&mut stdout, &filesystem)?;
     Ok(())
 }
 
-pub fn create_image(
-    repo: &Repository,
+pub fn create_image<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
     config: &str,
     name: Option<&str>,
-    verity: Option<&Sha256HashValue>,
-) -> Result<Sha256HashValue> {
+    verity: Option<&ObjectID>,
+) -> Result<ObjectID> {
     let mut filesystem = FileSystem::new();
 
-    let mut config_stream = repo.open_stream(config, verity)?;
-    let config = ImageConfiguration::from_reader(&mut config_stream)?;
+    let (config, refs) = super::read_config(repo, config, verity)?;
 
     for diff_id in config.rootfs().diff_ids() {
         let layer_sha256 = super::sha256_from_digest(diff_id)?;
-        let layer_verity = config_stream.lookup(&layer_sha256)?;
+        let layer_verity = refs
+            .lookup(&layer_sha256)
+            .with_context(|| format!("Layer {diff_id} is not connected to the config"))?;
 
         let mut layer_stream = repo.open_stream(&hex::encode(layer_sha256), Some(layer_verity))?;
         while let Some(entry) = oci::tar::get_entry(&mut layer_stream)? {
@@ -106,12 +116,15 @@ pub fn create_image(
 }
 
 #[cfg(test)]
-use crate::image::{LeafContent, Stat};
+use crate::{
+    fsverity::Sha256HashValue,
+    image::{LeafContent, Stat},
+};
 #[cfg(test)]
 use std::{cell::RefCell, collections::BTreeMap, io::BufRead, path::PathBuf};
 
 #[cfg(test)]
-fn file_entry(path: &str) -> oci::tar::TarEntry {
+fn file_entry(path: &str) -> oci::tar::TarEntry<Sha256HashValue> {
     oci::tar::TarEntry {
         path: PathBuf::from(path),
         stat: Stat {
@@ -126,7 +139,7 @@ fn file_entry(path: &str) -> oci::tar::TarEntry {
 }
 
 #[cfg(test)]
-fn dir_entry(path: &str) -> oci::tar::TarEntry {
+fn dir_entry(path: &str) -> oci::tar::TarEntry<Sha256HashValue> {
     oci::tar::TarEntry {
         path: PathBuf::from(path),
         stat: Stat {
@@ -141,7 +154,7 @@ fn dir_entry(path: &str) -> oci::tar::TarEntry {
 }
 
 #[cfg(test)]
-fn assert_files(fs: &FileSystem, expected: &[&str]) -> Result<()> {
+fn assert_files(fs: &FileSystem<Sha256HashValue>, expected: &[&str]) -> Result<()> {
     let mut out = vec![];
     write_dumpfile(&mut out, fs)?;
     let actual: Vec<String> = out
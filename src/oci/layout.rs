@@ -0,0 +1,180 @@
+//! Reading images from OCI image layouts and oci-archives without going through skopeo.
+//!
+//! An [OCI image layout] is a directory with an `oci-layout` marker file, an `index.json` listing
+//! the images in it and a `blobs/` directory with everything else, named by digest.  An
+//! oci-archive (as written by `podman save --format oci-archive`) is the same thing in a tarball,
+//! which is read in place: blobs are read straight out of the tarball at their offsets.
+//!
+//! These are referred to like skopeo does: `oci:PATH[:REFERENCE]` and
+//! `oci-archive:PATH[:REFERENCE]`, where the reference is matched against the
+//! `org.opencontainers.image.ref.name` annotation of the images in `index.json`.  Without one,
+//! the layout must contain exactly one image.
+//!
+//! [OCI image layout]: https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use oci_spec::image::{Descriptor, ImageIndex, OciLayout};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{check_descriptor, hash, sha256_from_descriptor};
+use crate::error::{bail, ensure, Result};
+
+/// The annotation that names the images in `index.json`
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+enum Files {
+    Directory(PathBuf),
+    /// The offset and size of each regular file in the tarball, by path
+    Archive {
+        path: PathBuf,
+        files: HashMap<String, (u64, u64)>,
+    },
+}
+
+/// An image in an OCI image layout directory or oci-archive
+pub struct ImageLayout {
+    files: Files,
+    /// The descriptor of the image (from `index.json`), which might be for an index
+    descriptor: Descriptor,
+}
+
+impl ImageLayout {
+    /// Opens the image referred to by `imgref`, if it's an `oci:` or `oci-archive:` reference.
+    /// Returns `None` for other transports.
+    pub fn open(imgref: &str) -> Result<Option<Self>> {
+        let (files, reference) = if let Some(rest) = imgref.strip_prefix("oci:") {
+            let (path, reference) = split_reference(rest);
+            (Files::Directory(path.into()), reference)
+        } else if let Some(rest) = imgref.strip_prefix("oci-archive:") {
+            let (path, reference) = split_reference(rest);
+            (Files::open_archive(Path::new(path))?, reference)
+        } else {
+            return Ok(None);
+        };
+
+        let oci_layout = OciLayout::from_reader(files.read("oci-layout")?.as_slice())?;
+        ensure!(
+            oci_layout.image_layout_version() == "1.0.0",
+            "Unsupported OCI image layout version {}",
+            oci_layout.image_layout_version()
+        );
+        let index = ImageIndex::from_reader(files.read("index.json")?.as_slice())?;
+        let descriptor = find_image(&index, reference)?;
+        Ok(Some(ImageLayout { files, descriptor }))
+    }
+
+    /// The descriptor of the image, which might be an image manifest or an image index
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// Reads a blob (like a manifest or a config) and checks it against its descriptor
+    pub fn read_blob(&self, descriptor: &Descriptor) -> Result<Vec<u8>> {
+        let data = self.files.read(&blob_path(descriptor)?)?;
+        check_descriptor(descriptor, &hash(&data))?;
+        Ok(data)
+    }
+
+    /// Opens a blob (like a layer) for reading.  Unlike [`Self::read_blob`], it's up to the caller
+    /// to check the content against the descriptor.
+    pub async fn open_blob(&self, descriptor: &Descriptor) -> Result<impl AsyncRead + Unpin> {
+        let (file, size) = self.files.open(&blob_path(descriptor)?)?;
+        Ok(tokio::fs::File::from_std(file).take(size))
+    }
+}
+
+/// Splits `PATH[:REFERENCE]`
+fn split_reference(rest: &str) -> (&str, Option<&str>) {
+    match rest.split_once(':') {
+        Some((path, reference)) if !reference.is_empty() => (path, Some(reference)),
+        Some((path, _)) => (path, None),
+        None => (rest, None),
+    }
+}
+
+/// Finds the image in `index.json` with the given reference, or the only one
+fn find_image(index: &ImageIndex, reference: Option<&str>) -> Result<Descriptor> {
+    let manifests = index.manifests();
+    let found = match reference {
+        Some(reference) => manifests.iter().find(|descriptor| {
+            descriptor
+                .annotations()
+                .as_ref()
+                .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+                .is_some_and(|name| name == reference)
+        }),
+        None if manifests.len() == 1 => manifests.first(),
+        None => bail!(
+            "The OCI layout contains {} images: a reference is needed to pick one",
+            manifests.len()
+        ),
+    };
+    match found {
+        Some(descriptor) => Ok(descriptor.clone()),
+        None => bail!(
+            "Cannot find image {:?} in the OCI layout",
+            reference.unwrap_or_default()
+        ),
+    }
+}
+
+/// The path of a blob in the layout.  Only sha256 digests are supported.
+fn blob_path(descriptor: &Descriptor) -> Result<String> {
+    let sha256 = sha256_from_descriptor(descriptor)?;
+    Ok(format!("blobs/sha256/{}", hex::encode(sha256)))
+}
+
+impl Files {
+    fn open_archive(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Cannot open {path:?}"))?;
+        let mut archive = tar::Archive::new(file);
+        let mut files = HashMap::new();
+        for entry in archive.entries_with_seek()? {
+            let entry = entry?;
+            if entry.header().entry_type().is_file() {
+                let name = entry.path()?.to_string_lossy().into_owned();
+                let name = name.trim_start_matches("./").to_string();
+                files.insert(name, (entry.raw_file_position(), entry.size()));
+            }
+        }
+        Ok(Files::Archive {
+            path: path.to_path_buf(),
+            files,
+        })
+    }
+
+    /// Opens a file in the layout, returning it (positioned at the start of the content) and its
+    /// size
+    fn open(&self, name: &str) -> Result<(File, u64)> {
+        match self {
+            Files::Directory(dir) => {
+                let path = dir.join(name);
+                let file = File::open(&path).with_context(|| format!("Cannot open {path:?}"))?;
+                let size = file.metadata()?.len();
+                Ok((file, size))
+            }
+            Files::Archive { path, files } => {
+                let Some(&(offset, size)) = files.get(name) else {
+                    bail!("Cannot find {name} in {path:?}");
+                };
+                let mut file = File::open(path).with_context(|| format!("Cannot open {path:?}"))?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok((file, size))
+            }
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let (file, size) = self.open(name)?;
+        let mut data = vec![];
+        file.take(size).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
@@ -1,34 +1,57 @@
 pub mod image;
+pub mod layout;
 pub mod tar;
 
-use std::{collections::HashMap, io::Read, iter::zip, path::Path};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::Read,
+    iter::zip,
+    path::Path,
+    pin::Pin,
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
 
-use anyhow::{bail, ensure, Context, Result};
-use async_compression::tokio::bufread::GzipDecoder;
+use anyhow::Context;
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
 use containers_image_proxy::{ImageProxy, ImageProxyConfig, OpenedImage};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest};
+use oci_spec::image::{
+    Arch, Descriptor, ImageConfiguration, ImageIndex, ImageManifest, MediaType, Os,
+};
 use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, BufReader, ReadBuf};
 
 use crate::{
+    error::{bail, ensure, Error, Result},
     fs::write_to_path,
-    fsverity::Sha256HashValue,
-    oci::tar::{get_entry, split_async},
+    fsverity::{FsVerityHashValue, Sha256HashValue},
+    mount::VerityMode,
+    oci::{
+        layout::ImageLayout,
+        tar::{get_entry, split_async},
+    },
     repository::Repository,
     splitstream::DigestMap,
     util::parse_sha256,
 };
 
-pub fn import_layer(
-    repo: &Repository,
+pub fn import_layer<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
     sha256: &Sha256HashValue,
     name: Option<&str>,
     tar_stream: &mut impl Read,
-) -> Result<Sha256HashValue> {
+) -> Result<ObjectID> {
     repo.ensure_stream(sha256, |writer| tar::split(tar_stream, writer), name)
 }
 
-pub fn ls_layer(repo: &Repository, name: &str) -> Result<()> {
+pub fn ls_layer<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    name: &str,
+) -> Result<()> {
     let mut split_stream = repo.open_stream(name, None)?;
 
     while let Some(entry) = get_entry(&mut split_stream)? {
@@ -38,13 +61,61 @@ pub fn ls_layer(repo: &Repository, name: &str) -> Result<()> {
     Ok(())
 }
 
-struct ImageOp<'repo> {
-    repo: &'repo Repository,
-    proxy: ImageProxy,
-    img: OpenedImage,
+/// Where an image is pulled from
+enum ImageSource {
+    /// Anything that skopeo can read, via its image proxy
+    Proxy { proxy: ImageProxy, img: OpenedImage },
+    /// An OCI image layout or oci-archive, read directly
+    Layout { layout: Arc<ImageLayout> },
+}
+
+struct ImageOp<'repo, ObjectID: FsVerityHashValue> {
+    repo: &'repo Repository<ObjectID>,
+    source: ImageSource,
     progress: MultiProgress,
 }
 
+/// The media types of gzipped layers in Docker images, which don't have OCI equivalents
+const DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+const DOCKER_FOREIGN_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+
+/// How a layer blob is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl LayerCompression {
+    /// Determines the compression of a layer from the media type in its descriptor
+    pub fn from_media_type(media_type: &MediaType) -> Result<Self> {
+        Ok(match media_type {
+            MediaType::ImageLayer | MediaType::ImageLayerNonDistributable => Self::None,
+            MediaType::ImageLayerGzip | MediaType::ImageLayerNonDistributableGzip => Self::Gzip,
+            MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => Self::Zstd,
+            MediaType::Other(other)
+                if other == DOCKER_LAYER_GZIP || other == DOCKER_FOREIGN_LAYER_GZIP =>
+            {
+                Self::Gzip
+            }
+            other => bail!("Unsupported layer media type {other}"),
+        })
+    }
+
+    /// Wraps a layer blob in the matching decompressor, giving the tar stream
+    pub fn decoder<'a>(
+        self,
+        blob: impl AsyncBufRead + Unpin + 'a,
+    ) -> Box<dyn AsyncRead + Unpin + 'a> {
+        match self {
+            Self::None => Box::new(blob),
+            Self::Gzip => Box::new(GzipDecoder::new(blob)),
+            Self::Zstd => Box::new(ZstdDecoder::new(blob)),
+        }
+    }
+}
+
 fn sha256_from_descriptor(descriptor: &Descriptor) -> Result<Sha256HashValue> {
     let Some(digest) = descriptor.as_digest_sha256() else {
         bail!("Descriptor in oci config is not sha256");
@@ -59,30 +130,280 @@ fn sha256_from_digest(digest: &str) -> Result<Sha256HashValue> {
     }
 }
 
-type ContentAndVerity = (Sha256HashValue, Sha256HashValue);
+/// Checks that a blob with the given sha256 is the one described by `descriptor`
+fn check_descriptor(descriptor: &Descriptor, sha256: &Sha256HashValue) -> Result<()> {
+    check_digest(
+        descriptor.media_type(),
+        descriptor.digest().as_ref(),
+        sha256,
+    )
+}
+
+/// Checks that a blob with the given sha256 has the `expected` digest (like `sha256:...`)
+fn check_digest(media_type: &MediaType, expected: &str, sha256: &Sha256HashValue) -> Result<()> {
+    let actual = format!("sha256:{}", hex::encode(sha256));
+    if actual != expected {
+        return Err(Error::DescriptorMismatch {
+            media_type: media_type.to_string(),
+            expected: expected.to_string(),
+            actual,
+            context: vec![],
+        });
+    }
+    Ok(())
+}
+
+/// Computes the sha256 of everything read through it
+struct DigestReader<R> {
+    inner: R,
+    context: Sha256,
+}
+
+impl<R: AsyncRead + Unpin> DigestReader<R> {
+    fn new(inner: R) -> Self {
+        DigestReader {
+            inner,
+            context: Sha256::new(),
+        }
+    }
+
+    /// Reads (and hashes) whatever is left, and returns the digest of everything
+    async fn finish(mut self) -> Result<Sha256HashValue> {
+        tokio::io::copy(&mut self, &mut tokio::io::sink()).await?;
+        Ok(self.context.finalize().into())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DigestReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let start = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.context.update(&buf.filled()[start..]);
+        }
+        result
+    }
+}
+
+type ContentAndVerity<ObjectID> = (Sha256HashValue, ObjectID);
+
+/// The platform that an image is for: an operating system and a CPU architecture (as named by
+/// Go, like `linux` and `arm64`) and optionally a variant of the architecture (like `v8`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// The platform we're running on (with no particular variant)
+    pub fn host() -> Self {
+        Platform {
+            os: Os::default().to_string(),
+            architecture: Arch::default().to_string(),
+            variant: None,
+        }
+    }
+
+    /// If an image for the given platform is one for this platform.  The variant is only compared
+    /// if this platform has one.
+    fn matches(&self, os: &str, architecture: &str, variant: Option<&str>) -> bool {
+        self.os == os
+            && self.architecture == architecture
+            && (self.variant.is_none() || self.variant.as_deref() == variant)
+    }
+
+    fn matches_config(&self, config: &ImageConfiguration) -> bool {
+        self.matches(
+            &config.os().to_string(),
+            &config.architecture().to_string(),
+            config.variant().as_deref(),
+        )
+    }
+
+    /// The platform of an entry in an image index, unless it doesn't say or it isn't an image
+    /// (like the attestation manifests which have `unknown/unknown` as their platform)
+    fn from_descriptor(descriptor: &Descriptor) -> Option<Self> {
+        let platform = descriptor.platform().as_ref()?;
+        let os = platform.os().to_string();
+        let architecture = platform.architecture().to_string();
+        if os == "unknown" || architecture == "unknown" {
+            return None;
+        }
+        Some(Platform {
+            os,
+            architecture,
+            variant: platform.variant().clone(),
+        })
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Error;
+
+    /// Parses `os/architecture` or `os/architecture/variant`
+    fn from_str(platform: &str) -> Result<Self> {
+        let parts: Vec<&str> = platform.split('/').collect();
+        let [os, architecture, variant @ ..] = parts.as_slice() else {
+            bail!("Invalid platform {platform:?}: expected os/architecture[/variant]");
+        };
+        ensure!(
+            variant.len() <= 1 && parts.iter().all(|part| !part.is_empty()),
+            "Invalid platform {platform:?}: expected os/architecture[/variant]"
+        );
+        Ok(Platform {
+            os: os.to_string(),
+            architecture: architecture.to_string(),
+            variant: variant.first().map(|v| v.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{variant}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Options used when pulling an image with [`pull_with_options`].  These only make a difference
+/// for multi-platform images (image indexes and Docker manifest lists).
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct PullOptions {
+    /// The platform to pull the image for, instead of the host platform
+    pub platform: Option<Platform>,
+    /// Pull the image for every platform in the index
+    pub all_platforms: bool,
+}
+
+/// The digest in an image reference like `docker://example.com/image@sha256:...`, if there is one
+fn pinned_digest(imgref: &str) -> Option<&str> {
+    imgref
+        .rsplit_once('@')
+        .map(|(_, digest)| digest)
+        .filter(|digest| digest.starts_with("sha256:"))
+}
+
+/// `imgref` pinned to the manifest with the given digest (like `sha256:...`), for registry
+/// references like `docker://example.com/image:tag`.  Other transports can't refer to a
+/// manifest by digest.
+fn pin_digest(imgref: &str, digest: &str) -> Option<String> {
+    let name = imgref.strip_prefix("docker://")?;
+    let name = name.split_once('@').map_or(name, |(name, _)| name);
+    // the tag comes after the last /, which might be followed by a registry port
+    let name = match name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => name,
+    };
+    Some(format!("docker://{name}@{digest}"))
+}
+
+/// Fetches the manifest (or index) of an image as it is.  This is the only place that the
+/// manifest is fetched: the image proxy would convert Docker manifests to OCI ones, and it only
+/// gives us the manifest of one platform of a multi-platform image, so we ask skopeo directly.
+fn fetch_raw_manifest(imgref: &str) -> Result<Vec<u8>> {
+    let output = Command::new("skopeo")
+        .args(["inspect", "--raw"])
+        .arg(imgref)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Unable to run skopeo")?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed {
+            command: "skopeo inspect --raw".to_string(),
+            status: output.status,
+            context: vec![],
+        });
+    }
+    Ok(output.stdout)
+}
 
-impl<'repo> ImageOp<'repo> {
-    async fn new(repo: &'repo Repository, imgref: &str) -> Result<Self> {
-        let config = ImageProxyConfig {
+impl<'repo, ObjectID: FsVerityHashValue> ImageOp<'repo, ObjectID> {
+    /// Opens the image.  If it's a multi-platform image then the one for `platform` (or, by
+    /// default, the host platform) is chosen.
+    async fn new(
+        repo: &'repo Repository<ObjectID>,
+        imgref: &str,
+        platform: Option<&Platform>,
+    ) -> Result<Self> {
+        let mut config = ImageProxyConfig {
             // auth_anonymous: true, debug: true, insecure_skip_tls_verification: Some(true),
             ..ImageProxyConfig::default()
         };
+        if let Some(platform) = platform {
+            let mut skopeo = Command::new("skopeo");
+            skopeo
+                .arg("--override-os")
+                .arg(&platform.os)
+                .arg("--override-arch")
+                .arg(&platform.architecture);
+            if let Some(variant) = &platform.variant {
+                skopeo.arg("--override-variant").arg(variant);
+            }
+            config.skopeo_cmd = Some(skopeo);
+        }
         let proxy = containers_image_proxy::ImageProxy::new_with_config(config).await?;
         let img = proxy.open_image(imgref).await.context("Opening image")?;
         let progress = MultiProgress::new();
         Ok(ImageOp {
             repo,
-            proxy,
-            img,
+            source: ImageSource::Proxy { proxy, img },
             progress,
         })
     }
 
+    /// Opens the images in an OCI image layout
+    fn from_layout(repo: &'repo Repository<ObjectID>, layout: &Arc<ImageLayout>) -> Self {
+        ImageOp {
+            repo,
+            source: ImageSource::Layout {
+                layout: Arc::clone(layout),
+            },
+            progress: MultiProgress::new(),
+        }
+    }
+
+    /// Splits a layer blob into a stream, checking it against its descriptor on the way
+    async fn store_layer(
+        &self,
+        layer_sha256: &Sha256HashValue,
+        descriptor: &Descriptor,
+        compression: LayerCompression,
+        blob_reader: impl AsyncRead + Unpin,
+    ) -> Result<ObjectID> {
+        let bar = self.progress.add(ProgressBar::new(descriptor.size()));
+        bar.set_style(
+            ProgressStyle::with_template(
+                "[eta {eta}] {bar:40.cyan/blue} {decimal_bytes:>7}/{decimal_total_bytes:7} {msg}",
+            )
+            .unwrap()
+            .progress_chars("##-"),
+        );
+        let mut blob = DigestReader::new(bar.wrap_async_read(blob_reader));
+        self.progress
+            .println(format!("Fetching layer {}", hex::encode(layer_sha256)))?;
+        let decoder = compression.decoder(BufReader::new(&mut blob));
+        let mut splitstream = self.repo.create_stream(Some(*layer_sha256), None);
+        split_async(decoder, &mut splitstream).await?;
+        // The decompressor doesn't necessarily read all the way to the end of the blob
+        check_descriptor(descriptor, &blob.finish().await?)?;
+        self.repo.write_stream(splitstream, None)
+    }
+
     pub async fn ensure_layer(
         &self,
         layer_sha256: &Sha256HashValue,
         descriptor: &Descriptor,
-    ) -> Result<Sha256HashValue> {
+    ) -> Result<ObjectID> {
         // We need to use the per_manifest descriptor to download the compressed layer but it gets
         // stored in the repository via the per_config descriptor.  Our return value is the
         // fsverity digest for the corresponding splitstream.
@@ -93,20 +414,22 @@ impl<'repo> ImageOp<'repo> {
             Ok(layer_id)
         } else {
             // Otherwise, we need to fetch it...
-            let (blob_reader, driver) = self.proxy.get_descriptor(&self.img, descriptor).await?;
-            let bar = self.progress.add(ProgressBar::new(descriptor.size()));
-            bar.set_style(ProgressStyle::with_template("[eta {eta}] {bar:40.cyan/blue} {decimal_bytes:>7}/{decimal_total_bytes:7} {msg}")
-                .unwrap()
-                .progress_chars("##-"));
-            let progress = bar.wrap_async_read(blob_reader);
-            self.progress
-                .println(format!("Fetching layer {}", hex::encode(layer_sha256)))?;
-            let decoder = GzipDecoder::new(progress);
-            let mut splitstream = self.repo.create_stream(Some(*layer_sha256), None);
-            split_async(decoder, &mut splitstream).await?;
-            let layer_id = self.repo.write_stream(splitstream, None)?;
-            driver.await?;
-            Ok(layer_id)
+            let compression = LayerCompression::from_media_type(descriptor.media_type())?;
+            match &self.source {
+                ImageSource::Proxy { proxy, img } => {
+                    let (blob_reader, driver) = proxy.get_descriptor(img, descriptor).await?;
+                    let layer_id = self
+                        .store_layer(layer_sha256, descriptor, compression, blob_reader)
+                        .await?;
+                    driver.await?;
+                    Ok(layer_id)
+                }
+                ImageSource::Layout { layout, .. } => {
+                    let blob_reader = layout.open_blob(descriptor).await?;
+                    self.store_layer(layer_sha256, descriptor, compression, blob_reader)
+                        .await
+                }
+            }
         }
     }
 
@@ -114,7 +437,7 @@ impl<'repo> ImageOp<'repo> {
         &self,
         manifest_layers: &[Descriptor],
         descriptor: &Descriptor,
-    ) -> Result<ContentAndVerity> {
+    ) -> Result<ContentAndVerity<ObjectID>> {
         let config_sha256 = sha256_from_descriptor(descriptor)?;
         if let Some(config_id) = self.repo.check_stream(&config_sha256)? {
             // We already got this config?  Nice.
@@ -129,7 +452,19 @@ impl<'repo> ImageOp<'repo> {
             //
             self.progress
                 .println(format!("Fetching config {}", hex::encode(config_sha256)))?;
-            let raw_config = self.proxy.fetch_config_raw(&self.img).await?;
+            // by its descriptor: fetching it "as the config" would make the proxy fetch the
+            // manifest again
+            let raw_config = match &self.source {
+                ImageSource::Proxy { proxy, img } => {
+                    let (mut blob_reader, driver) = proxy.get_descriptor(img, descriptor).await?;
+                    let mut raw_config = vec![];
+                    blob_reader.read_to_end(&mut raw_config).await?;
+                    driver.await?;
+                    raw_config
+                }
+                ImageSource::Layout { layout, .. } => layout.read_blob(descriptor)?,
+            };
+            check_descriptor(descriptor, &hash(&raw_config))?;
             let config = ImageConfiguration::from_reader(raw_config.as_slice())?;
 
             let mut config_maps = DigestMap::new();
@@ -152,60 +487,395 @@ impl<'repo> ImageOp<'repo> {
         }
     }
 
-    pub async fn pull(&self) -> Result<(Sha256HashValue, Sha256HashValue)> {
-        let (_manifest_digest, raw_manifest) = self
-            .proxy
-            .fetch_manifest_raw_oci(&self.img)
+    /// Fetches the manifest of the image from the image proxy, for the (rare) multi-platform
+    /// images which can't be pinned by digest with [`pin_digest`].  `descriptor` is the entry of
+    /// the image in the index.  The proxy converts Docker manifests to OCI ones, in which case
+    /// the manifest can't be checked against the digest (the config and the layers still are),
+    /// and it's stored in its converted form.
+    async fn fetch_manifest(&self, descriptor: &Descriptor) -> Result<Vec<u8>> {
+        let (proxy, img) = match &self.source {
+            ImageSource::Proxy { proxy, img } => (proxy, img),
+            ImageSource::Layout { layout, .. } => return layout.read_blob(descriptor),
+        };
+        let (digest, raw_manifest) = proxy
+            .fetch_manifest_raw_oci(img)
             .await
             .context("Fetching manifest")?;
+        // this is the image that the index refers to...
+        check_descriptor(descriptor, &sha256_from_digest(&digest)?)?;
+        // ... and that's what we got, unless it was converted
+        if *descriptor.media_type() == MediaType::ImageManifest {
+            check_descriptor(descriptor, &hash(&raw_manifest))?;
+        } else {
+            self.progress.println(format!(
+                "Manifest {digest} was converted to OCI format: not checking its digest"
+            ))?;
+        }
+        Ok(raw_manifest)
+    }
+
+    /// Pulls the image with the given manifest, returning the digests of its manifest and config
+    /// streams.  `raw_manifest` is stored as it is, so if it's the manifest as the registry has
+    /// it then the image is found by its registry digest.  If `expected_digest` is given (like
+    /// `sha256:...`) then the manifest must have that digest.
+    pub async fn pull(
+        &self,
+        raw_manifest: &[u8],
+        expected_digest: Option<&str>,
+    ) -> Result<(ContentAndVerity<ObjectID>, ContentAndVerity<ObjectID>)> {
+        if let Some(expected) = expected_digest {
+            check_digest(&MediaType::ImageManifest, expected, &hash(raw_manifest))?;
+        }
 
         // We need to add the manifest to the repo.  We need to parse the manifest and make
         // sure we have the config first (which will also pull in the layers).
-        let manifest = ImageManifest::from_reader(raw_manifest.as_slice())?;
+        let manifest = ImageManifest::from_reader(raw_manifest)?;
         let config_descriptor = manifest.config();
         let layers = manifest.layers();
-        self.ensure_config(layers, config_descriptor)
+        let config = self
+            .ensure_config(layers, config_descriptor)
             .await
-            .with_context(|| format!("Failed to pull config {config_descriptor:?}"))
+            .with_context(|| format!("Failed to pull config {config_descriptor:?}"))?;
+        let manifest = write_manifest(self.repo, raw_manifest, &config, None)?;
+        Ok((manifest, config))
     }
 }
 
 /// Pull the target image, and add the provided tag. If this is a mountable
 /// image (i.e. not an artifact), it is *not* unpacked by default.
-pub async fn pull(repo: &Repository, imgref: &str, reference: Option<&str>) -> Result<()> {
-    let op = ImageOp::new(repo, imgref).await?;
-    let (sha256, id) = op
-        .pull()
-        .await
-        .with_context(|| format!("Unable to pull container image {imgref}"))?;
+///
+/// The tag names the manifest stream, which refers to the config stream.
+pub async fn pull<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    imgref: &str,
+    reference: Option<&str>,
+) -> Result<()> {
+    pull_with_options(repo, imgref, reference, &PullOptions::default()).await
+}
 
+/// Like [`pull`], but for multi-platform images, `options` says which platforms to pull.  The
+/// index of a multi-platform image is stored too, referring to the manifests of the platforms
+/// that were pulled, and it's what the tag names.
+pub async fn pull_with_options<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    imgref: &str,
+    reference: Option<&str>,
+    options: &PullOptions,
+) -> Result<()> {
+    // OCI layouts and oci-archives are read directly, everything else goes via skopeo.  The
+    // manifest (or index) is only fetched once, as it is.
+    let layout = ImageLayout::open(imgref)
+        .with_context(|| format!("Unable to open {imgref}"))?
+        .map(Arc::new);
+    let raw_manifest = match &layout {
+        Some(layout) => layout.read_blob(layout.descriptor())?,
+        None => fetch_raw_manifest(imgref)
+            .with_context(|| format!("Unable to fetch manifest of {imgref}"))?,
+    };
+    // If the image was pulled by digest then it must be the one we got
+    let pinned = pinned_digest(imgref);
+
+    let Some(index) = parse_index(&raw_manifest) else {
+        let op = match &layout {
+            Some(layout) => ImageOp::from_layout(repo, layout),
+            // the proxy only fetches blobs by digest, but it might as well be the same image
+            None => {
+                let digest = format!("sha256:{}", hex::encode(hash(&raw_manifest)));
+                let pinned_ref = pin_digest(imgref, &digest);
+                ImageOp::new(repo, pinned_ref.as_deref().unwrap_or(imgref), None).await?
+            }
+        };
+        let ((manifest_sha256, _), (sha256, id)) = op
+            .pull(&raw_manifest, pinned)
+            .await
+            .with_context(|| format!("Unable to pull container image {imgref}"))?;
+
+        if let Some(name) = reference {
+            repo.name_stream(manifest_sha256, name)?;
+        }
+        println!("sha256 {}", hex::encode(sha256));
+        println!("verity {}", hex::encode(id));
+        println!("manifest {}", hex::encode(manifest_sha256));
+        return Ok(());
+    };
+
+    if let Some(pinned) = pinned {
+        check_digest(&MediaType::ImageIndex, pinned, &hash(&raw_manifest))?;
+    }
+    let mut manifests = vec![];
+    for (descriptor, platform) in select_platforms(&index, options)? {
+        let (manifest, (sha256, id)) =
+            pull_platform(repo, imgref, layout.as_ref(), descriptor, &platform)
+                .await
+                .with_context(|| format!("Unable to pull {platform} image of {imgref}"))?;
+        println!("platform {platform}");
+        println!("sha256 {}", hex::encode(sha256));
+        println!("verity {}", hex::encode(id));
+        println!("manifest {}", hex::encode(manifest.0));
+        manifests.push(manifest);
+    }
+    let (index_sha256, _) = write_index(repo, &raw_manifest, &manifests, reference)?;
+    println!("index {}", hex::encode(index_sha256));
+    Ok(())
+}
+
+/// Pulls the image for one platform of a multi-platform image, given its entry in the index
+async fn pull_platform<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    imgref: &str,
+    layout: Option<&Arc<ImageLayout>>,
+    descriptor: &Descriptor,
+    platform: &Platform,
+) -> Result<(ContentAndVerity<ObjectID>, ContentAndVerity<ObjectID>)> {
+    let digest = descriptor.digest().as_ref();
+    if let Some(layout) = layout {
+        let raw_manifest = layout.read_blob(descriptor)?;
+        let op = ImageOp::from_layout(repo, layout);
+        return op.pull(&raw_manifest, Some(digest)).await;
+    }
+
+    match pin_digest(imgref, digest) {
+        // Fetched by digest, so it's the manifest in the index, even if the tag moved since
+        Some(pinned_ref) => {
+            let raw_manifest = fetch_raw_manifest(&pinned_ref)?;
+            let op = ImageOp::new(repo, &pinned_ref, None).await?;
+            op.pull(&raw_manifest, Some(digest)).await
+        }
+        None => {
+            let op = ImageOp::new(repo, imgref, Some(platform)).await?;
+            let raw_manifest = op.fetch_manifest(descriptor).await?;
+            op.pull(&raw_manifest, None).await
+        }
+    }
+}
+
+/// Picks the entries of a multi-platform image to pull, along with their platforms
+fn select_platforms<'a>(
+    index: &'a ImageIndex,
+    options: &PullOptions,
+) -> Result<Vec<(&'a Descriptor, Platform)>> {
+    let available = index
+        .manifests()
+        .iter()
+        .filter_map(|descriptor| Some((descriptor, Platform::from_descriptor(descriptor)?)));
+    if options.all_platforms {
+        return Ok(available.collect());
+    }
+
+    let wanted = options.platform.clone().unwrap_or_else(Platform::host);
+    let found = available.clone().find(|(_, platform)| {
+        wanted.matches(
+            &platform.os,
+            &platform.architecture,
+            platform.variant.as_deref(),
+        )
+    });
+    match found {
+        Some(entry) => Ok(vec![entry]),
+        None => {
+            let available: Vec<String> = available.map(|(_, p)| p.to_string()).collect();
+            bail!(
+                "There's no image for {wanted} (only for {})",
+                available.join(", ")
+            );
+        }
+    }
+}
+
+/// Stores the manifest of an image as a splitstream which refers to its config stream (which must
+/// already be in the repository), and optionally names it.  The manifest is stored as-is, so it's
+/// found under the sha256 of `raw_manifest`.
+pub fn write_manifest<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    raw_manifest: &[u8],
+    config: &ContentAndVerity<ObjectID>,
+    reference: Option<&str>,
+) -> Result<ContentAndVerity<ObjectID>> {
+    let manifest = ImageManifest::from_reader(raw_manifest)?;
+    let (config_sha256, config_id) = config;
+    check_descriptor(manifest.config(), config_sha256)?;
+
+    let sha256 = hash(raw_manifest);
+    let mut refs = DigestMap::new();
+    refs.insert(config_sha256, config_id);
+    let mut stream = repo.create_stream(Some(sha256), Some(refs));
+    stream.write_inline(raw_manifest);
+    let id = repo.write_stream(stream, reference)?;
+    Ok((sha256, id))
+}
+
+/// Stores the index of a multi-platform image (or a Docker manifest list) as a splitstream which
+/// refers to the manifest streams of the platforms that were pulled (which must already be in the
+/// repository), and optionally names it.  Like manifests, the index is stored as-is.  If the index
+/// was stored before then the platforms that were pulled then are kept.
+pub fn write_index<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    raw_index: &[u8],
+    manifests: &[ContentAndVerity<ObjectID>],
+    reference: Option<&str>,
+) -> Result<ContentAndVerity<ObjectID>> {
+    ensure!(
+        parse_index(raw_index).is_some(),
+        "Not an image index or manifest list"
+    );
+
+    let sha256 = hash(raw_index);
+    let id = {
+        // Another pull of the same index could be adding its platforms at the same time: hold
+        // the lock from reading the old stream until it's replaced, so that neither is lost
+        let _lock = repo.lock_refs("streams")?;
+        let mut refs = DigestMap::new();
+        if let Some(id) = repo.has_stream(&sha256)? {
+            for entry in repo.open_stream(&hex::encode(sha256), Some(&id))?.refs.map {
+                refs.insert(&entry.body, &entry.verity);
+            }
+        }
+        for (manifest_sha256, manifest_id) in manifests {
+            refs.insert(manifest_sha256, manifest_id);
+        }
+        let mut stream = repo.create_stream(Some(sha256), Some(refs));
+        stream.write_inline(raw_index);
+        repo.replace_stream(stream, None)?
+    };
+    // naming the stream takes the lock itself
     if let Some(name) = reference {
         repo.name_stream(sha256, name)?;
     }
-    println!("sha256 {}", hex::encode(sha256));
-    println!("verity {}", hex::encode(id));
-    Ok(())
+    Ok((sha256, id))
+}
+
+/// Finds the sha256 of an image stream from its name: a ref (like `refs/some/tag`) or a digest
+/// (with or without the `sha256:` prefix)
+fn stream_sha256<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    name: &str,
+) -> Result<Sha256HashValue> {
+    if name.starts_with("refs/") {
+        repo.show_ref("streams", name)
+    } else {
+        parse_sha256(name.strip_prefix("sha256:").unwrap_or(name))
+            .context("Containers must be referred to by sha256 or ref if verity is missing")
+            .map_err(Error::from)
+    }
+}
+
+/// If `raw` is an image manifest, returns it along with the sha256 of its config
+fn parse_manifest(raw: &[u8]) -> Option<(ImageManifest, Sha256HashValue)> {
+    let manifest = ImageManifest::from_reader(raw).ok()?;
+    let config_sha256 = sha256_from_descriptor(manifest.config()).ok()?;
+    Some((manifest, config_sha256))
+}
+
+/// If `raw` is an image index or a Docker manifest list, returns it
+fn parse_index(raw: &[u8]) -> Option<ImageIndex> {
+    if parse_manifest(raw).is_some() {
+        return None;
+    }
+    ImageIndex::from_reader(raw).ok()
+}
+
+/// Reads the config of an image, given the name of its config stream, a manifest stream which
+/// refers to it, or an index stream which refers to that (in which case the image for the host
+/// platform is picked).  Streams referred to by others are opened with the verity recorded in the
+/// referring stream.
+fn read_config<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    name: &str,
+    verity: Option<&ObjectID>,
+) -> Result<(ImageConfiguration, DigestMap<ObjectID>)> {
+    let mut stream = repo.open_stream(name.strip_prefix("sha256:").unwrap_or(name), verity)?;
+    let mut raw = vec![];
+    stream.read_to_end(&mut raw)?;
+    if parse_index(&raw).is_some() {
+        let host = Platform::host();
+        for entry in &stream.refs.map {
+            let (config, refs) = read_config(repo, &hex::encode(entry.body), Some(&entry.verity))?;
+            if host.matches_config(&config) {
+                return Ok((config, refs));
+            }
+        }
+        bail!("Index {name} has no image for {host}");
+    }
+    if let Some((_, config_sha256)) = parse_manifest(&raw) {
+        let config_id = stream.refs.lookup(&config_sha256).with_context(|| {
+            format!(
+                "Config {} is not connected to manifest {name}",
+                hex::encode(config_sha256)
+            )
+        })?;
+        stream = repo.open_stream(&hex::encode(config_sha256), Some(config_id))?;
+        raw.clear();
+        stream.read_to_end(&mut raw)?;
+    }
+    let config = ImageConfiguration::from_reader(raw.as_slice())?;
+    Ok((config, stream.refs))
 }
 
-pub fn open_config(
-    repo: &Repository,
+/// Opens the config of an image, given the name of its config stream or of a manifest stream
+/// which refers to it: a digest or a ref.  Without `verity`, the stream (and everything it refers
+/// to) is checked against its digest first.
+pub fn open_config<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
     name: &str,
-    verity: Option<&Sha256HashValue>,
-) -> Result<(ImageConfiguration, DigestMap)> {
-    let id = match verity {
-        Some(id) => id,
+    verity: Option<&ObjectID>,
+) -> Result<(ImageConfiguration, DigestMap<ObjectID>)> {
+    match verity {
+        Some(id) => read_config(repo, name, Some(id)),
         None => {
             // take the expensive route
-            let sha256 = parse_sha256(name)
-                .context("Containers must be referred to by sha256 if verity is missing")?;
-            &repo
+            let sha256 = stream_sha256(repo, name)?;
+            let id = repo
                 .check_stream(&sha256)?
-                .with_context(|| format!("Object {name} is unknown to us"))?
+                .with_context(|| format!("Object {name} is unknown to us"))?;
+            read_config(repo, &hex::encode(sha256), Some(&id))
         }
+    }
+}
+
+/// Reads a stream given its digest or a ref.  Without `verity`, the content is checked against
+/// its digest.
+fn read_stream<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    name: &str,
+    verity: Option<&ObjectID>,
+) -> Result<(Vec<u8>, DigestMap<ObjectID>)> {
+    let sha256 = stream_sha256(repo, name)?;
+    let mut stream = repo.open_stream(&hex::encode(sha256), verity)?;
+    let mut raw = vec![];
+    stream.read_to_end(&mut raw)?;
+    if verity.is_none() {
+        ensure!(hash(&raw) == sha256, "Data integrity issue");
+    }
+    Ok((raw, stream.refs))
+}
+
+/// Opens the manifest of an image, given its digest or a ref.  Without `verity`, the manifest is
+/// checked against its digest.
+pub fn open_manifest<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    name: &str,
+    verity: Option<&ObjectID>,
+) -> Result<(ImageManifest, DigestMap<ObjectID>)> {
+    let (raw_manifest, refs) = read_stream(repo, name, verity)?;
+    let Some((manifest, _)) = parse_manifest(&raw_manifest) else {
+        bail!("{name} is not an image manifest");
     };
-    let mut stream = repo.open_stream(name, Some(id))?;
-    let config = ImageConfiguration::from_reader(&mut stream)?;
-    Ok((config, stream.refs))
+    Ok((manifest, refs))
+}
+
+/// Opens the index of a multi-platform image, given its digest or a ref.  Its refs are the
+/// manifests of the platforms that were pulled.  Without `verity`, the index is checked against
+/// its digest.
+pub fn open_index<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    name: &str,
+    verity: Option<&ObjectID>,
+) -> Result<(ImageIndex, DigestMap<ObjectID>)> {
+    let (raw_index, refs) = read_stream(repo, name, verity)?;
+    let Some(index) = parse_index(&raw_index) else {
+        bail!("{name} is not an image index");
+    };
+    Ok((index, refs))
 }
 
 fn hash(bytes: &[u8]) -> Sha256HashValue {
@@ -214,32 +884,44 @@ fn hash(bytes: &[u8]) -> Sha256HashValue {
     context.finalize().into()
 }
 
-pub fn open_config_shallow(
-    repo: &Repository,
+pub fn open_config_shallow<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
     name: &str,
-    verity: Option<&Sha256HashValue>,
+    verity: Option<&ObjectID>,
 ) -> Result<ImageConfiguration> {
     match verity {
         // with verity deep opens are just as fast as shallow ones
         Some(id) => Ok(open_config(repo, name, Some(id))?.0),
         None => {
             // we need to manually check the content digest
-            let expected_hash = parse_sha256(name)
-                .context("Containers must be referred to by sha256 if verity is missing")?;
-            let mut stream = repo.open_stream(name, None)?;
+            let expected_hash = stream_sha256(repo, name)?;
+            let mut stream = repo.open_stream(&hex::encode(expected_hash), None)?;
             let mut raw_config = vec![];
             stream.read_to_end(&mut raw_config)?;
             ensure!(hash(&raw_config) == expected_hash, "Data integrity issue");
+            if let Some((_, config_sha256)) = parse_manifest(&raw_config) {
+                return open_config_shallow(repo, &hex::encode(config_sha256), None);
+            }
+            if parse_index(&raw_config).is_some() {
+                let host = Platform::host();
+                for entry in &stream.refs.map {
+                    let config = open_config_shallow(repo, &hex::encode(entry.body), None)?;
+                    if host.matches_config(&config) {
+                        return Ok(config);
+                    }
+                }
+                bail!("Index {name} has no image for {host}");
+            }
             Ok(ImageConfiguration::from_reader(&mut raw_config.as_slice())?)
         }
     }
 }
 
-pub fn write_config(
-    repo: &Repository,
+pub fn write_config<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
     config: &ImageConfiguration,
-    refs: DigestMap,
-) -> Result<(Sha256HashValue, Sha256HashValue)> {
+    refs: DigestMap<ObjectID>,
+) -> Result<ContentAndVerity<ObjectID>> {
     let json = config.to_string()?;
     let json_bytes = json.as_bytes();
     let sha256 = hash(json_bytes);
@@ -249,11 +931,11 @@ pub fn write_config(
     Ok((sha256, id))
 }
 
-pub fn seal(
-    repo: &Repository,
+pub fn seal<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
     name: &str,
-    verity: Option<&Sha256HashValue>,
-) -> Result<(Sha256HashValue, Sha256HashValue)> {
+    verity: Option<&ObjectID>,
+) -> Result<ContentAndVerity<ObjectID>> {
     let (mut config, refs) = open_config(repo, name, verity)?;
     let mut myconfig = config.config().clone().context("no config!")?;
     let labels = myconfig.labels_mut().get_or_insert_with(HashMap::new);
@@ -263,12 +945,12 @@ pub fn seal(
     write_config(repo, &config, refs)
 }
 
-pub fn mount(
-    repo: &Repository,
+pub fn mount<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
     name: &str,
     mountpoint: &str,
-    verity: Option<&Sha256HashValue>,
-) -> Result<()> {
+    verity: Option<&ObjectID>,
+) -> Result<VerityMode> {
     let config = open_config_shallow(repo, name, verity)?;
     let Some(id) = config.get_config_annotation("containers.composefs.fsverity") else {
         bail!("Can only mount sealed containers");
@@ -276,7 +958,11 @@ pub fn mount(
     repo.mount(id, mountpoint)
 }
 
-pub fn meta_layer(repo: &Repository, name: &str, verity: Option<&Sha256HashValue>) -> Result<()> {
+pub fn meta_layer<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    name: &str,
+    verity: Option<&ObjectID>,
+) -> Result<()> {
     let (config, refs) = open_config(repo, name, verity)?;
 
     let ids = config.rootfs().diff_ids();
@@ -293,10 +979,10 @@ pub fn meta_layer(repo: &Repository, name: &str, verity: Option<&Sha256HashValue
     }
 }
 
-pub fn prepare_boot(
-    repo: &Repository,
+pub fn prepare_boot<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
     name: &str,
-    verity: Option<&Sha256HashValue>,
+    verity: Option<&ObjectID>,
     output_dir: &Path,
 ) -> Result<()> {
     let (config, refs) = open_config(repo, name, verity)?;
@@ -330,3 +1016,79 @@ pub fn prepare_boot(
 
     write_to_path(repo, boot, output_dir)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_compression::tokio::bufread::GzipEncoder;
+    use oci_spec::image::DescriptorBuilder;
+
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn descriptor(data: &[u8]) -> Descriptor {
+        DescriptorBuilder::default()
+            .media_type(MediaType::ImageLayerGzip)
+            .digest(
+                format!("sha256:{}", hex::encode(hash(data)))
+                    .parse::<oci_spec::image::Digest>()
+                    .unwrap(),
+            )
+            .size(data.len() as u64)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_digest_reader() -> Result<()> {
+        let mut blob = vec![];
+        block_on(GzipEncoder::new(&b"hello world"[..]).read_to_end(&mut blob))?;
+        // gzip doesn't care about trailing garbage, but the digest does
+        blob.extend_from_slice(b"trailing");
+
+        let mut reader = DigestReader::new(blob.as_slice());
+        let mut content = vec![];
+        block_on(
+            LayerCompression::Gzip
+                .decoder(BufReader::new(&mut reader))
+                .read_to_end(&mut content),
+        )?;
+        assert_eq!(content, b"hello world");
+        let sha256 = block_on(reader.finish())?;
+        assert_eq!(sha256, hash(&blob));
+
+        check_descriptor(&descriptor(&blob), &sha256)?;
+        let err = check_descriptor(&descriptor(b"something else"), &sha256).unwrap_err();
+        assert!(err.is_corruption());
+        assert!(matches!(
+            err,
+            Error::DescriptorMismatch { media_type, .. } if media_type == MediaType::ImageLayerGzip.to_string()
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_digest() {
+        let digest = "sha256:0123";
+        let pinned = |imgref| pin_digest(imgref, digest);
+        assert_eq!(
+            pinned("docker://quay.io/fedora/fedora:latest").as_deref(),
+            Some("docker://quay.io/fedora/fedora@sha256:0123")
+        );
+        assert_eq!(
+            pinned("docker://localhost:5000/image").as_deref(),
+            Some("docker://localhost:5000/image@sha256:0123")
+        );
+        assert_eq!(
+            pinned("docker://localhost:5000/image:tag@sha256:4567").as_deref(),
+            Some("docker://localhost:5000/image@sha256:0123")
+        );
+        assert_eq!(pinned("containers-storage:image:tag"), None);
+    }
+}
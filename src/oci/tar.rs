@@ -8,19 +8,24 @@ use std::{
     path::PathBuf,
 };
 
-use anyhow::{bail, ensure, Result};
 use rustix::fs::makedev;
 use tar::{EntryType, Header, PaxExtensions};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::{
     dumpfile,
+    error::{bail, ensure, Result},
+    fsverity::FsVerityHashValue,
     image::{LeafContent, Stat},
     splitstream::{SplitStreamData, SplitStreamReader, SplitStreamWriter},
     util::{read_exactish, read_exactish_async},
     INLINE_CONTENT_MAX,
 };
 
+/// Larger external files are copied to the repository in chunks of this size, without holding
+/// them in memory
+const CHUNK_SIZE: usize = 1 << 20;
+
 fn read_header<R: Read>(reader: &mut R) -> Result<Option<Header>> {
     let mut header = Header::new_gnu();
     if read_exactish(reader, header.as_mut_bytes())? {
@@ -42,7 +47,10 @@ async fn read_header_async(reader: &mut (impl AsyncRead + Unpin)) -> Result<Opti
 /// Splits the tar file from tar_stream into a Split Stream.  The store_data function is
 /// responsible for ensuring that "external data" is in the composefs repository and returns the
 /// fsverity hash value of that data.
-pub fn split<R: Read>(tar_stream: &mut R, writer: &mut SplitStreamWriter) -> Result<()> {
+pub fn split<R: Read, ObjectID: FsVerityHashValue>(
+    tar_stream: &mut R,
+    writer: &mut SplitStreamWriter<ObjectID>,
+) -> Result<()> {
     while let Some(header) = read_header(tar_stream)? {
         // the header always gets stored as inline data
         writer.write_inline(header.as_bytes());
@@ -54,24 +62,39 @@ pub fn split<R: Read>(tar_stream: &mut R, writer: &mut SplitStreamWriter) -> Res
         // read the corresponding data, if there is any
         let actual_size = header.entry_size()? as usize;
         let storage_size = (actual_size + 511) & !511;
-        let mut buffer = vec![0u8; storage_size];
-        tar_stream.read_exact(&mut buffer)?;
 
-        if header.entry_type() == EntryType::Regular && actual_size > INLINE_CONTENT_MAX {
-            // non-empty regular file: store the data in the object store
+        if header.entry_type() == EntryType::Regular && actual_size > CHUNK_SIZE {
+            // large regular file: stream the data into the object store, a chunk at a time
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            let mut remaining = actual_size;
+            while remaining > 0 {
+                let chunk = &mut buffer[..remaining.min(CHUNK_SIZE)];
+                tar_stream.read_exact(chunk)?;
+                writer.write_external_chunk(chunk)?;
+                remaining -= chunk.len();
+            }
+            let mut padding = vec![0u8; storage_size - actual_size];
+            tar_stream.read_exact(&mut padding)?;
+            writer.finish_external(padding)?;
+        } else if header.entry_type() == EntryType::Regular && actual_size > INLINE_CONTENT_MAX {
+            // other non-empty regular file: the writer stores these in parallel batches
+            let mut buffer = vec![0u8; storage_size];
+            tar_stream.read_exact(&mut buffer)?;
             let padding = buffer.split_off(actual_size);
             writer.write_external(&buffer, padding)?;
         } else {
             // else: store the data inline in the split stream
+            let mut buffer = vec![0u8; storage_size];
+            tar_stream.read_exact(&mut buffer)?;
             writer.write_inline(&buffer);
         }
     }
     Ok(())
 }
 
-pub async fn split_async(
+pub async fn split_async<ObjectID: FsVerityHashValue>(
     mut tar_stream: impl AsyncRead + Unpin,
-    writer: &mut SplitStreamWriter<'_>,
+    writer: &mut SplitStreamWriter<'_, ObjectID>,
 ) -> Result<()> {
     while let Some(header) = read_header_async(&mut tar_stream).await? {
         // the header always gets stored as inline data
@@ -84,15 +107,30 @@ pub async fn split_async(
         // read the corresponding data, if there is any
         let actual_size = header.entry_size()? as usize;
         let storage_size = (actual_size + 511) & !511;
-        let mut buffer = vec![0u8; storage_size];
-        tar_stream.read_exact(&mut buffer).await?;
 
-        if header.entry_type() == EntryType::Regular && actual_size > INLINE_CONTENT_MAX {
-            // non-empty regular file: store the data in the object store
+        if header.entry_type() == EntryType::Regular && actual_size > CHUNK_SIZE {
+            // large regular file: stream the data into the object store, a chunk at a time
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            let mut remaining = actual_size;
+            while remaining > 0 {
+                let chunk = &mut buffer[..remaining.min(CHUNK_SIZE)];
+                tar_stream.read_exact(chunk).await?;
+                writer.write_external_chunk(chunk)?;
+                remaining -= chunk.len();
+            }
+            let mut padding = vec![0u8; storage_size - actual_size];
+            tar_stream.read_exact(&mut padding).await?;
+            writer.finish_external(padding)?;
+        } else if header.entry_type() == EntryType::Regular && actual_size > INLINE_CONTENT_MAX {
+            // other non-empty regular file: the writer stores these in parallel batches
+            let mut buffer = vec![0u8; storage_size];
+            tar_stream.read_exact(&mut buffer).await?;
             let padding = buffer.split_off(actual_size);
             writer.write_external(&buffer, padding)?;
         } else {
             // else: store the data inline in the split stream
+            let mut buffer = vec![0u8; storage_size];
+            tar_stream.read_exact(&mut buffer).await?;
             writer.write_inline(&buffer);
         }
     }
@@ -100,20 +138,20 @@ pub async fn split_async(
 }
 
 #[derive(Debug)]
-pub enum TarItem {
+pub enum TarItem<ObjectID: FsVerityHashValue> {
     Directory,
-    Leaf(LeafContent),
+    Leaf(LeafContent<ObjectID>),
     Hardlink(OsString),
 }
 
 #[derive(Debug)]
-pub struct TarEntry {
+pub struct TarEntry<ObjectID: FsVerityHashValue> {
     pub path: PathBuf,
     pub stat: Stat,
-    pub item: TarItem,
+    pub item: TarItem<ObjectID>,
 }
 
-impl fmt::Display for TarEntry {
+impl<ObjectID: FsVerityHashValue> fmt::Display for TarEntry<ObjectID> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self.item {
             TarItem::Hardlink(ref target) => dumpfile::write_hardlink(fmt, &self.path, target),
@@ -156,7 +194,9 @@ fn symlink_target_from_tar(pax: Option<Box<[u8]>>, gnu: Vec<u8>, short: &[u8]) -
     }
 }
 
-pub fn get_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<TarEntry>> {
+pub fn get_entry<R: Read, ObjectID: FsVerityHashValue>(
+    reader: &mut SplitStreamReader<R, ObjectID>,
+) -> Result<Option<TarEntry<ObjectID>>> {
     let mut gnu_longlink: Vec<u8> = vec![];
     let mut gnu_longname: Vec<u8> = vec![];
     let mut pax_longlink: Option<Box<[u8]>> = None;
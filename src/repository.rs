@@ -1,65 +1,1157 @@
 use std::{
     collections::HashSet,
-    ffi::CStr,
+    ffi::{CStr, CString},
+    fmt,
     fs::File,
-    io::{ErrorKind, Read, Write},
-    os::fd::OwnedFd,
+    io::{BufRead, BufReader, ErrorKind, Read, Seek, Write},
+    marker::PhantomData,
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
     path::{Path, PathBuf},
-    process::Command,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, ensure, Context, Result};
+use anyhow::Context;
 use rustix::{
     fs::{
-        accessat, fdatasync, flock, linkat, mkdirat, open, openat, readlinkat, symlinkat, Access,
-        AtFlags, Dir, FileType, FlockOperation, Mode, OFlags, CWD,
+        accessat, copy_file_range, fchmod, fchown, fdatasync, flock, fsetxattr, ioctl_ficlone,
+        linkat, mkdirat, open, openat, readlinkat, renameat, statat, symlinkat, syncfs, unlinkat,
+        Access, AtFlags, Dir, FileType, FlockOperation, Mode, OFlags, XattrFlags, CWD,
     },
-    io::{Errno, Result as ErrnoResult},
+    io::{pread, Errno, Result as ErrnoResult},
+    process::{geteuid, Gid, Uid},
 };
 use sha2::{Digest, Sha256};
 
 use crate::{
+    erofs::reader::{collect_objects, Image},
+    error::{bail, ensure, Error, Result},
     fsverity::{
         digest::FsVerityHasher,
         ioctl::{fs_ioc_enable_verity, fs_ioc_measure_verity},
-        FsVerityHashValue, Sha256HashValue,
+        FsVerityHashValue, FsVerityParams, Sha256HashValue,
     },
-    mount::{mount_fd, pivot_sysroot},
+    mount::{mount_fd, pivot_sysroot, VerityMode},
     splitstream::{DigestMap, SplitStreamReader, SplitStreamWriter},
-    util::{parse_sha256, proc_self_fd},
+    util::{parallel_map, proc_self_fd},
+    INLINE_CONTENT_MAX,
 };
 
-pub struct Repository {
+/// The outcome of a garbage collection run
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// Paths (relative to the repository) that were removed, or would have been in dry-run mode
+    pub removed: Vec<String>,
+    /// The number of unreferenced objects
+    pub objects_removed: u64,
+    /// The total size of everything removed from the object directories
+    pub bytes_freed: u64,
+    /// Images and streams which are referred to (by a ref or by another stream) but missing, as
+    /// paths relative to the repository like `streams/{sha256}`.  These don't stop garbage
+    /// collection: `fsck` can find out more.
+    pub dangling: Vec<String>,
+    /// Entries under `images/refs/` or `streams/refs/` which aren't symlinks to an image or
+    /// stream, as paths relative to the repository.  They're skipped (and left in place) rather
+    /// than stopping garbage collection.
+    pub invalid: Vec<String>,
+}
+
+/// The kind of problem found by [`Repository::fsck`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckCategory {
+    /// A file in `objects/` is malformed or doesn't match its fs-verity digest
+    Object,
+    /// An entry in `images/` or `streams/` is malformed or dangling
+    Symlink,
+    /// An image is inconsistent
+    Image,
+    /// A stream is corrupt or its content doesn't match its sha256
+    Stream,
+    /// An image or stream refers to an object which doesn't exist
+    MissingObject,
+    /// A ref is malformed or dangling
+    Ref,
+}
+
+impl fmt::Display for FsckCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FsckCategory::Object => "object",
+            FsckCategory::Symlink => "symlink",
+            FsckCategory::Image => "image",
+            FsckCategory::Stream => "stream",
+            FsckCategory::MissingObject => "missing-object",
+            FsckCategory::Ref => "ref",
+        })
+    }
+}
+
+/// A single problem found by [`Repository::fsck`]
+#[derive(Debug)]
+pub struct FsckProblem {
+    pub category: FsckCategory,
+    /// The path (relative to the repository) where the problem was found
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.category, self.path, self.message)
+    }
+}
+
+/// The outcome of [`Repository::fsck`]
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub problems: Vec<FsckProblem>,
+}
+
+impl FsckReport {
+    fn add(&mut self, category: FsckCategory, path: impl Into<String>, message: impl ToString) {
+        self.problems.push(FsckProblem {
+            category,
+            path: path.into(),
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Copies the entire content of `src` to `dst` (which must be empty), using the fastest method
+/// that works: FICLONE, then copy_file_range(), then plain reads and writes.
+fn copy_file_contents(src: impl AsFd, dst: impl AsFd) -> Result<()> {
+    if ioctl_ficlone(&dst, &src).is_ok() {
+        return Ok(());
+    }
+
+    let mut offset = 0;
+    loop {
+        match copy_file_range(&src, Some(&mut offset), &dst, None, 1 << 30) {
+            Ok(0) => return Ok(()),
+            Ok(..) => {}
+            // Not supported between these files: fall back, unless we already copied something
+            Err(Errno::XDEV | Errno::INVAL | Errno::OPNOTSUPP | Errno::NOSYS) if offset == 0 => {
+                break;
+            }
+            Err(err) => Err(err)?,
+        }
+    }
+
+    let mut src = PreadReader::new(src.as_fd());
+    std::io::copy(&mut src, &mut File::from(dst.as_fd().try_clone_to_owned()?))?;
+    Ok(())
+}
+
+/// Reads a file from the start with pread(), leaving the file offset of the fd alone
+struct PreadReader<'a> {
+    fd: BorrowedFd<'a>,
+    offset: u64,
+}
+
+impl<'a> PreadReader<'a> {
+    fn new(fd: BorrowedFd<'a>) -> Self {
+        PreadReader { fd, offset: 0 }
+    }
+}
+
+impl Read for PreadReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = pread(self.fd, buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writes a single object to the repository incrementally.  Write the content using the
+/// [`Write`] implementation and then call [`ObjectWriter::finish()`] to store it.  Dropping the
+/// writer without finishing it discards the content.
+pub struct ObjectWriter<'a, ObjectID: FsVerityHashValue> {
+    repo: &'a Repository<ObjectID>,
+    file: File,
+    hasher: FsVerityHasher<ObjectID>,
+    /// The fs-verity hasher works on complete blocks: this holds the incomplete last block
+    block: Vec<u8>,
+}
+
+impl<ObjectID: FsVerityHashValue> Write for ObjectWriter<'_, ObjectID> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write_all(buf)?;
+
+        let mut data = buf;
+        while !data.is_empty() {
+            let block_size = self.hasher.block_size();
+            let n = data.len().min(block_size - self.block.len());
+            self.block.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.block.len() == block_size {
+                self.hasher.add_data(&self.block);
+                self.block.clear();
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<ObjectID: FsVerityHashValue> ObjectWriter<'_, ObjectID> {
+    /// Stores the object (if it doesn't exist already) and returns its ID
+    pub fn finish(self) -> Result<ObjectID> {
+        self.finish_with_sync(true)
+    }
+
+    /// Like [`Self::finish`], but the object isn't necessarily on disk until the next
+    /// [`Repository::sync`].
+    pub(crate) fn finish_unsynced(self) -> Result<ObjectID> {
+        self.finish_with_sync(false)
+    }
+
+    fn finish_with_sync(mut self, sync: bool) -> Result<ObjectID> {
+        if !self.block.is_empty() {
+            self.hasher.add_data(&self.block);
+        }
+        let digest = self.hasher.digest();
+        self.repo
+            .store_object(OwnedFd::from(self.file), &digest, sync)?;
+        Ok(digest)
+    }
+}
+
+/// A ref update which is applied when its [`Transaction`] is committed
+struct StagedRef {
+    category: String,
+    name: String,
+    /// The digest of the image or stream, in hex
+    target: String,
+    expected: Option<String>,
+}
+
+/// Something written to the repository during a transaction, as recorded in its journal
+enum JournalEntry<ObjectID: FsVerityHashValue> {
+    Object(ObjectID),
+    Stream(Sha256HashValue),
+    Image(ObjectID),
+}
+
+/// A transaction on a repository, created by [`Repository::begin_transaction`].  While it's in
+/// progress, garbage collection keeps everything written to the repository, whether or not it's
+/// referenced yet.  Ref updates can be staged with [`Transaction::update_ref`] and are applied
+/// together by [`Transaction::commit`].  Dropping the transaction without committing it discards
+/// the staged ref updates, and anything that was written becomes eligible for garbage collection
+/// if nothing refers to it.
+pub struct Transaction<'a, ObjectID: FsVerityHashValue> {
+    repo: &'a Repository<ObjectID>,
+    /// The path of the journal, relative to the repository
+    journal: String,
+    refs: Vec<StagedRef>,
+}
+
+impl<ObjectID: FsVerityHashValue> Transaction<'_, ObjectID> {
+    /// Stages an update of the ref `name` in `category` to point at `target`, as for
+    /// [`Repository::update_ref`].  If `expected` is given then the commit fails unless the ref
+    /// points at it when the transaction is committed.
+    pub fn update_ref<D: FsVerityHashValue>(
+        &mut self,
+        category: &str,
+        name: &str,
+        target: &D,
+        expected: Option<&D>,
+    ) -> Result<()> {
+        let path = Repository::<ObjectID>::ref_path(category, name)?;
+        self.repo.check_ref_access(&path)?;
+        self.refs.push(StagedRef {
+            category: category.to_string(),
+            name: name.to_string(),
+            target: hex::encode(target),
+            expected: expected.map(hex::encode),
+        });
+        Ok(())
+    }
+
+    /// Applies the staged ref updates and ends the transaction.  The refs of the categories
+    /// involved are locked while the updates are checked and applied.  If any of the targets are
+    /// missing or any of the expected values don't match, none of the updates are made.  If
+    /// applying one of the updates fails, the ones that were already applied are rolled back
+    /// before the error is returned.  A crash in the middle of the commit can still leave some of
+    /// the updates applied.
+    pub fn commit(mut self) -> Result<()> {
+        let refs = std::mem::take(&mut self.refs);
+        let mut categories = Vec::from_iter(refs.iter().map(|r| r.category.as_str()));
+        categories.sort();
+        categories.dedup();
+        let _locks = categories
+            .iter()
+            .map(|category| self.repo.lock_refs(category))
+            .collect::<Result<Vec<_>>>()?;
+
+        // The current target of each ref (if it exists), for rolling back
+        let mut previous = vec![];
+        for r in &refs {
+            let target_path = format!("{}/{}", r.category, r.target);
+            statat(
+                &self.repo.repository,
+                &target_path,
+                AtFlags::SYMLINK_NOFOLLOW,
+            )
+            .with_context(|| format!("Cannot find {target_path}"))?;
+            let current = match self.repo.ref_target(&r.category, &r.name) {
+                Ok(current) => Some(current),
+                Err(err) if err.raw_os_error() == Some(Errno::NOENT.raw_os_error()) => None,
+                Err(err) => return Err(err),
+            };
+            if let Some(expected) = &r.expected {
+                if current.as_ref() != Some(expected) {
+                    return Err(Error::RefConflict {
+                        name: r.name.clone(),
+                        expected: expected.clone(),
+                        current: current.unwrap_or_default(),
+                        context: vec![],
+                    });
+                }
+            }
+            previous.push(current);
+        }
+
+        let mut applied = vec![];
+        for (r, previous) in refs.iter().zip(previous) {
+            let result = Repository::<ObjectID>::ref_path(&r.category, &r.name)
+                .and_then(|path| self.repo.open_ref_dir(&r.category, &path, true))
+                .and_then(|dir| {
+                    dir.replace(&r.target)?;
+                    Ok(dir)
+                })
+                .with_context(|| format!("Cannot update ref {:?}", r.name));
+            match result {
+                Ok(dir) => applied.push((dir, previous)),
+                Err(err) => {
+                    // Best effort: we're already failing
+                    for (dir, previous) in applied.into_iter().rev() {
+                        let _ = match previous {
+                            Some(target) => dir.replace(&target),
+                            None => dir.remove().map_err(Error::from),
+                        };
+                    }
+                    return Err(err.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<ObjectID: FsVerityHashValue> Drop for Transaction<'_, ObjectID> {
+    fn drop(&mut self) {
+        // Remove the journal before closing it (which releases the lock), so that GC never sees
+        // it unlocked and mistakes it for an abandoned one
+        let _ = unlinkat(&self.repo.repository, &self.journal, AtFlags::empty());
+        *self.repo.journal.lock().unwrap() = None;
+    }
+}
+
+/// The newest version of the on-disk repository format that we understand.  Version 2 added
+/// non-default fs-verity parameters: repositories using the default ones are still created as
+/// version 1, so that older versions of composefs can open them.
+pub const REPOSITORY_VERSION: u32 = 2;
+
+/// The name of the file (in the top-level directory of the repository) which holds its metadata
+const METADATA_FILE: &str = "meta";
+
+/// The name of the file (in the top-level directory of the repository) which lists the paths of
+/// its alternate object stores, one per line
+const ALTERNATES_FILE: &str = "alternates";
+
+/// Options used when creating a new repository with [`Repository::init`]
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct RepositoryOptions {
+    /// Create an insecure repository, for filesystems without fs-verity support.  Objects are
+    /// named by digests computed in userspace, fs-verity isn't enabled on them, and images are
+    /// mounted without verifying their contents.
+    pub insecure: bool,
+    /// The fs-verity block size and salt for the objects in the repository.  The block size can't
+    /// be larger than the page size (or, on some filesystems, the filesystem block size).
+    pub fsverity: FsVerityParams,
+    /// Other repositories whose objects are used (read-only) instead of storing copies of them
+    /// in this one.  They must use the same hash algorithm and fs-verity parameters.  Relative
+    /// paths are relative to the new repository.
+    pub alternates: Vec<PathBuf>,
+}
+
+/// How long to wait for the lock on a repository when it's in use by somebody else.  Opening a
+/// repository takes a shared lock, which only has to wait for garbage collection, and garbage
+/// collection takes an exclusive lock, which has to wait for everybody else.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockWait {
+    /// Wait for as long as it takes
+    #[default]
+    Block,
+    /// Fail with [`Error::RepositoryBusy`] right away
+    NonBlocking,
+    /// Fail with [`Error::RepositoryBusy`] if the lock can't be taken within the given time
+    Timeout(Duration),
+}
+
+/// Options used when opening a repository with [`Repository::open_path_with_options`]
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct OpenOptions {
+    /// How to wait for the repository lock, both when opening the repository and when running
+    /// garbage collection, and for the locks on refs and transaction journals
+    pub lock_wait: LockWait,
+}
+
+/// Takes a shared or exclusive lock on `fd` (the repository, or something in it), waiting as
+/// allowed by `wait`.  There's no flock() with a timeout, so that's done by polling.
+fn lock_repository(fd: &OwnedFd, exclusive: bool, wait: LockWait, path: &Path) -> Result<()> {
+    let (blocking, non_blocking) = if exclusive {
+        (
+            FlockOperation::LockExclusive,
+            FlockOperation::NonBlockingLockExclusive,
+        )
+    } else {
+        (
+            FlockOperation::LockShared,
+            FlockOperation::NonBlockingLockShared,
+        )
+    };
+
+    let Some(deadline) = lock_deadline(wait) else {
+        return Ok(flock(fd, blocking).with_context(|| format!("Cannot lock {path:?}"))?);
+    };
+
+    poll_lock(deadline, path, || match flock(fd, non_blocking) {
+        Ok(()) => Ok(true),
+        Err(Errno::WOULDBLOCK) => Ok(false),
+        Err(err) => Err(err).with_context(|| format!("Cannot lock {path:?}"))?,
+    })
+}
+
+/// When to give up waiting for a lock, or None to wait forever
+fn lock_deadline(wait: LockWait) -> Option<Instant> {
+    match wait {
+        LockWait::Block => None,
+        LockWait::NonBlocking => Some(Instant::now()),
+        LockWait::Timeout(timeout) => Some(Instant::now() + timeout),
+    }
+}
+
+/// Calls `attempt` until it manages to take a lock (returning true) or `deadline` passes, in which
+/// case the lock on `path` is reported as busy
+fn poll_lock(
+    deadline: Instant,
+    path: &Path,
+    mut attempt: impl FnMut() -> Result<bool>,
+) -> Result<()> {
+    let mut interval = Duration::from_millis(1);
+    loop {
+        if attempt()? {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::RepositoryBusy {
+                path: path.to_path_buf(),
+                context: vec![],
+            });
+        }
+        std::thread::sleep(interval.min(deadline - now));
+        interval = (interval * 2).min(Duration::from_millis(100));
+    }
+}
+
+/// Holds the exclusive lock on a repository (see [`Repository::lock_exclusive`]) until it's
+/// downgraded back to the shared lock
+struct ExclusiveLock<'a> {
+    fd: &'a OwnedFd,
+}
+
+impl ExclusiveLock<'_> {
+    /// Goes back to the shared lock
+    fn downgrade(self) -> Result<()> {
+        let result = flock(self.fd, FlockOperation::LockShared);
+        std::mem::forget(self);
+        Ok(result.context("Cannot downgrade repository lock")?)
+    }
+}
+
+impl Drop for ExclusiveLock<'_> {
+    fn drop(&mut self) {
+        // Only reached on error paths (see downgrade()).  If this fails too then we keep the
+        // exclusive lock until the repository is closed, which is safe, if unfriendly.
+        let _ = flock(self.fd, FlockOperation::LockShared);
+    }
+}
+
+/// Writes `data` to `filename` in `dirfd` atomically, via a temporary file that's renamed over it
+/// once synced.  The temporary file is removed again on failure.
+fn write_file_atomic(dirfd: &OwnedFd, filename: &str, data: &[u8]) -> Result<()> {
+    let tmp = format!(".{filename}.tmp-{}", std::process::id());
+    let fd = openat(
+        dirfd,
+        &tmp,
+        OFlags::WRONLY | OFlags::CREATE | OFlags::TRUNC | OFlags::CLOEXEC,
+        0o644.into(),
+    )?;
+    let result = (|| -> Result<()> {
+        let mut file = File::from(fd);
+        file.write_all(data)?;
+        file.sync_all()?;
+        renameat(dirfd, &tmp, dirfd, filename)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = unlinkat(dirfd, &tmp, AtFlags::empty());
+    }
+    result
+}
+
+/// Creates a symlink called `filename` in `dirfd` pointing to `target`, atomically replacing
+/// anything that was there before.  The new symlink is created under a temporary name (a hidden
+/// file in the same directory) and then renamed into place.
+fn replace_symlink_at(dirfd: &OwnedFd, filename: &str, target: &Path) -> Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    loop {
+        let tmp = format!(
+            ".{filename}.tmp-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        match symlinkat(target, dirfd, &tmp) {
+            Ok(()) => {}
+            Err(Errno::EXIST) => continue, // debris from an earlier process with our pid
+            Err(err) => Err(err)?,
+        }
+
+        if let Err(err) = renameat(dirfd, &tmp, dirfd, filename) {
+            let _ = unlinkat(dirfd, &tmp, AtFlags::empty());
+            Err(err)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Returns the uid if `name` is the name of a per-user namespace directory.
+fn parse_namespace(name: &str) -> Option<u32> {
+    if name.bytes().all(|c| c.is_ascii_digit()) {
+        name.parse().ok().filter(|uid| *uid != u32::MAX)
+    } else {
+        None
+    }
+}
+
+/// Opens the subdirectory `name` of `dirfd`, failing if it's a symlink
+fn open_dir_nofollow(dirfd: &OwnedFd, name: &str) -> ErrnoResult<OwnedFd> {
+    openat(
+        dirfd,
+        name,
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+}
+
+/// The directory containing a ref, opened by [`Repository::open_ref_dir`].  Users can modify
+/// their own namespace directories however they like, so every directory on the way to the ref
+/// is opened without following symlinks, and the ref itself is only ever accessed by its name
+/// relative to the directory containing it.
+struct RefDir {
+    /// `{category}/refs`, followed by each directory below it down to the one containing the ref
+    dirs: Vec<OwnedFd>,
+    /// The names of the directories below `{category}/refs`
+    names: Vec<String>,
+    /// The name of the ref itself
+    filename: String,
+}
+
+impl RefDir {
+    fn dirfd(&self) -> &OwnedFd {
+        self.dirs.last().unwrap()
+    }
+
+    /// The relative symlink target for pointing the ref at `{category}/{target}`
+    fn symlink_target(&self, target: &str) -> PathBuf {
+        let mut relative = PathBuf::new();
+        for _ in &self.dirs {
+            relative.push("..");
+        }
+        relative.push(target);
+        relative
+    }
+
+    /// The digest (in hex) of the image or stream that the ref points to, whatever kind of
+    /// digest it is
+    fn target(&self) -> ErrnoResult<String> {
+        let link = readlinkat(self.dirfd(), &self.filename, [])?;
+        let link = Path::new(std::ffi::OsStr::from_bytes(link.as_bytes()));
+        // a ref with a bogus target is still a ref, it just doesn't resolve
+        Ok(link
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned())
+    }
+
+    /// Points the ref at `{category}/{target}`, replacing it if it already exists
+    fn replace(&self, target: &str) -> Result<()> {
+        replace_symlink_at(self.dirfd(), &self.filename, &self.symlink_target(target))
+    }
+
+    /// Creates the ref, pointing at `{category}/{target}`.  Fails if it already exists.
+    fn create(&self, target: &str) -> ErrnoResult<()> {
+        symlinkat(self.symlink_target(target), self.dirfd(), &self.filename)
+    }
+
+    /// Removes the ref, along with the directories containing it, as long as they're empty.
+    /// Per-user namespace directories are kept, since they can only be created by root.
+    fn remove(&self) -> ErrnoResult<()> {
+        unlinkat(self.dirfd(), &self.filename, AtFlags::empty())?;
+        for (idx, name) in self.names.iter().enumerate().rev() {
+            if idx == 0 && parse_namespace(name).is_some() {
+                break;
+            }
+            match unlinkat(&self.dirs[idx], name, AtFlags::REMOVEDIR) {
+                Ok(()) => {}
+                Err(Errno::NOTEMPTY | Errno::EXIST) => break,
+                Err(err) => Err(err)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The metadata recorded in a repository when it's created.  It's stored in the `meta` file at
+/// the top of the repository as `key = value` lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepositoryMetadata {
+    /// The version of the repository format
+    pub version: u32,
+    /// The hash algorithm used for fs-verity digests (ie: object names): "sha256" or "sha512"
+    pub algorithm: String,
+    /// Files of this size or smaller are stored inline instead of as separate objects
+    pub inline_threshold: usize,
+    /// When the repository was created, in seconds since the epoch
+    pub created: u64,
+    /// If fs-verity is disabled for this repository (see [`RepositoryOptions::insecure`])
+    pub insecure: bool,
+    /// The fs-verity block size and salt (see [`RepositoryOptions::fsverity`])
+    pub fsverity: FsVerityParams,
+}
+
+impl RepositoryMetadata {
+    /// Reads the metadata of the repository at `path` without opening the repository.  Returns
+    /// None if the repository was created without metadata.  This can be used to find out which
+    /// hash algorithm to open a repository with.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let dirfd = open(path, OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty())
+            .with_context(|| format!("Cannot open composefs repository {path:?}"))?;
+        Ok(RepositoryMetadata::read_at(&dirfd)
+            .with_context(|| format!("Cannot parse metadata of repository {path:?}"))?)
+    }
+
+    fn read_at(dirfd: impl AsFd) -> Result<Option<Self>> {
+        match openat(
+            dirfd,
+            METADATA_FILE,
+            OFlags::RDONLY | OFlags::CLOEXEC,
+            Mode::empty(),
+        ) {
+            Ok(fd) => Ok(Some(RepositoryMetadata::parse(&std::io::read_to_string(
+                File::from(fd),
+            )?)?)),
+            Err(Errno::NOENT) => Ok(None),
+            Err(err) => Err(err)?,
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut version = None;
+        let mut algorithm = None;
+        let mut inline_threshold = None;
+        let mut created = None;
+        let mut insecure = false;
+        let mut block_size = 4096;
+        let mut salt = vec![];
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                bail!("Invalid line {line:?} in repository metadata");
+            };
+            let value = value.trim();
+            match key.trim() {
+                "version" => version = Some(value.parse()?),
+                "algorithm" => algorithm = Some(value.to_string()),
+                "inline-threshold" => inline_threshold = Some(value.parse()?),
+                "created" => created = Some(value.parse()?),
+                "insecure" => insecure = value.parse()?,
+                "block-size" => block_size = value.parse()?,
+                "salt" => salt = hex::decode(value).context("Invalid salt")?,
+                _ => {} // might be added by a future minor revision; ignore
+            }
+        }
+
+        Ok(RepositoryMetadata {
+            version: version.context("Repository metadata has no version")?,
+            algorithm: algorithm.context("Repository metadata has no algorithm")?,
+            inline_threshold: inline_threshold
+                .context("Repository metadata has no inline-threshold")?,
+            created: created.unwrap_or(0),
+            insecure,
+            fsverity: FsVerityParams::new(block_size, &salt)?,
+        })
+    }
+
+    /// Checks that this is a repository that we know how to work with, returning the reason if
+    /// it isn't
+    fn check_supported(&self) -> std::result::Result<(), String> {
+        if !(1..=REPOSITORY_VERSION).contains(&self.version) {
+            return Err(format!(
+                "Unsupported repository format version {} (this version of composefs supports up to {})",
+                self.version, REPOSITORY_VERSION
+            ));
+        }
+        if !matches!(self.algorithm.as_str(), "sha256" | "sha512") {
+            return Err(format!(
+                "Unsupported repository hash algorithm {:?}",
+                self.algorithm
+            ));
+        }
+        // The threshold affects how files get split between splitstreams and objects, so we can't
+        // work on a repository created with a different one
+        if self.inline_threshold != INLINE_CONTENT_MAX {
+            return Err(format!(
+                "Unsupported repository inline threshold {} (expected {INLINE_CONTENT_MAX})",
+                self.inline_threshold
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RepositoryMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "version = {}", self.version)?;
+        writeln!(f, "algorithm = {}", self.algorithm)?;
+        writeln!(f, "inline-threshold = {}", self.inline_threshold)?;
+        writeln!(f, "created = {}", self.created)?;
+        if self.insecure {
+            writeln!(f, "insecure = true")?;
+        }
+        writeln!(f, "block-size = {}", self.fsverity.block_size())?;
+        if !self.fsverity.salt().is_empty() {
+            writeln!(f, "salt = {}", hex::encode(self.fsverity.salt()))?;
+        }
+        Ok(())
+    }
+}
+
+/// A composefs repository.  Objects are named by their fs-verity digest, computed with the hash
+/// algorithm determined by `ObjectID`: [`Sha256HashValue`] or [`crate::fsverity::Sha512HashValue`].
+pub struct Repository<ObjectID: FsVerityHashValue> {
     repository: OwnedFd,
     path: PathBuf,
+    metadata: Option<RepositoryMetadata>,
+    fsverity: FsVerityParams,
+    lock_wait: LockWait,
+    /// The journal of the transaction in progress, if any (see [`Repository::begin_transaction`])
+    journal: Mutex<Option<File>>,
+    alternates: Vec<Alternate>,
+    _data: PhantomData<ObjectID>,
 }
 
-impl Drop for Repository {
+/// Another repository whose objects are used by this one (see [`RepositoryOptions::alternates`]).
+/// It's only ever read from, and isn't locked.
+struct Alternate {
+    path: PathBuf,
+    objects: OwnedFd,
+}
+
+impl<ObjectID: FsVerityHashValue> Drop for Repository<ObjectID> {
     fn drop(&mut self) {
-        flock(&self.repository, FlockOperation::Unlock).expect("repository unlock failed");
+        // Closing the fd unlocks the repository anyway
+        let _ = flock(&self.repository, FlockOperation::Unlock);
+    }
+}
+
+/// Encodes a POSIX access ACL (in the format of the `system.posix_acl_access` xattr) which grants
+/// rwx to the owner and to `uid`, and nothing to anybody else.
+fn posix_acl_for_user(uid: u32) -> Vec<u8> {
+    const ACL_VERSION: u32 = 2;
+    const ACL_UNDEFINED_ID: u32 = u32::MAX;
+    const RWX: u16 = 7;
+    // (tag, permissions, id), sorted by tag as the kernel requires
+    let entries = [
+        (0x01, RWX, ACL_UNDEFINED_ID), // ACL_USER_OBJ
+        (0x02, RWX, uid),              // ACL_USER
+        (0x04, 0, ACL_UNDEFINED_ID),   // ACL_GROUP_OBJ
+        (0x10, RWX, ACL_UNDEFINED_ID), // ACL_MASK
+        (0x20, 0, ACL_UNDEFINED_ID),   // ACL_OTHER
+    ];
+
+    let mut acl = ACL_VERSION.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries {
+        acl.extend_from_slice(&u16::to_le_bytes(tag));
+        acl.extend_from_slice(&u16::to_le_bytes(perm));
+        acl.extend_from_slice(&u32::to_le_bytes(id));
     }
+    acl
 }
 
-impl Repository {
-    pub fn open_path(path: PathBuf) -> Result<Repository> {
+impl<ObjectID: FsVerityHashValue> Repository<ObjectID> {
+    /// Opens an existing repository.  Repositories created by [`Repository::init`] have their
+    /// format version checked and are refused if they're newer than we understand, or if they use
+    /// a different hash algorithm than `ObjectID`.  Repositories without metadata (created before
+    /// it was introduced) are opened as-is, but only with SHA-256.
+    pub fn open_path(path: PathBuf) -> Result<Self> {
+        Self::open_path_with_options(path, &OpenOptions::default())
+    }
+
+    /// Opens the repository at `path`, like [`Self::open_path`], with the given options.  If the
+    /// repository can't be locked in time (see [`OpenOptions::lock_wait`]) then the error is
+    /// [`Error::RepositoryBusy`].  Repositories in a format that we don't support give
+    /// [`Error::UnsupportedRepository`].
+    pub fn open_path_with_options(path: PathBuf, options: &OpenOptions) -> Result<Self> {
         // O_PATH isn't enough because flock()
         let repository = open(&path, OFlags::RDONLY, Mode::empty())
             .with_context(|| format!("Cannot open composefs repository {path:?}"))?;
 
-        flock(&repository, FlockOperation::LockShared)
-            .with_context(|| format!("Cannot lock repository {path:?}"))?;
+        lock_repository(&repository, false, options.lock_wait, &path)?;
+
+        let metadata = RepositoryMetadata::read_at(&repository)
+            .with_context(|| format!("Cannot parse metadata of repository {path:?}"))?;
+        let algorithm = match &metadata {
+            Some(metadata) => {
+                metadata
+                    .check_supported()
+                    .map_err(|reason| Error::UnsupportedRepository {
+                        path: path.clone(),
+                        reason,
+                        context: vec![],
+                    })?;
+                metadata.algorithm.as_str()
+            }
+            None => "sha256",
+        };
+        if algorithm != ObjectID::ID {
+            return Err(Error::UnsupportedRepository {
+                path,
+                reason: format!("uses {algorithm} digests, not {}", ObjectID::ID),
+                context: vec![],
+            });
+        }
+
+        let fsverity = match &metadata {
+            Some(metadata) => metadata.fsverity.clone(),
+            None => FsVerityParams::default(),
+        };
+
+        let insecure = metadata.as_ref().is_some_and(|metadata| metadata.insecure);
+        let alternates = match openat(
+            &repository,
+            ALTERNATES_FILE,
+            OFlags::RDONLY | OFlags::CLOEXEC,
+            Mode::empty(),
+        ) {
+            Ok(fd) => {
+                let text = std::io::read_to_string(File::from(fd))?;
+                Self::open_alternates(&text, &path, &fsverity, insecure)?
+            }
+            Err(Errno::NOENT) => vec![],
+            Err(err) => Err(err)?,
+        };
+
+        Ok(Repository {
+            repository,
+            path,
+            metadata,
+            fsverity,
+            lock_wait: options.lock_wait,
+            journal: Mutex::new(None),
+            alternates,
+            _data: PhantomData,
+        })
+    }
 
-        Ok(Repository { repository, path })
+    /// Opens the objects directories of the alternates listed in `text` (the content of the
+    /// alternates file of the repository at `path`), checking that their objects are named the
+    /// same way as ours
+    fn open_alternates(
+        text: &str,
+        path: &Path,
+        fsverity: &FsVerityParams,
+        insecure: bool,
+    ) -> Result<Vec<Alternate>> {
+        let mut alternates = vec![];
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let alternate = path.join(line);
+            let metadata = RepositoryMetadata::read(&alternate)?;
+            let (algorithm, alt_fsverity, alt_insecure) = match &metadata {
+                Some(metadata) => (
+                    metadata.algorithm.as_str(),
+                    metadata.fsverity.clone(),
+                    metadata.insecure,
+                ),
+                None => ("sha256", FsVerityParams::default(), false),
+            };
+            ensure!(
+                algorithm == ObjectID::ID && alt_fsverity == *fsverity,
+                "Alternate {alternate:?} of repository {path:?} uses different fs-verity digests"
+            );
+            // fs-verity isn't enabled on the objects of insecure repositories
+            ensure!(
+                insecure || !alt_insecure,
+                "Alternate {alternate:?} of repository {path:?} is insecure"
+            );
+            let objects = open(
+                alternate.join("objects"),
+                OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+                Mode::empty(),
+            )
+            .with_context(|| format!("Cannot open alternate {alternate:?} of {path:?}"))?;
+            alternates.push(Alternate {
+                path: alternate,
+                objects,
+            });
+        }
+        Ok(alternates)
     }
 
-    pub fn open_user() -> Result<Repository> {
+    /// Creates a new repository at `path` (which may already exist, but must not already contain
+    /// a repository) and opens it.  This creates the directory layout up front and records the
+    /// format version and `options` in the repository metadata.
+    /// The hash algorithm of the repository is determined by `ObjectID`.
+    pub fn init(path: PathBuf, options: &RepositoryOptions) -> Result<Self> {
+        let existed = path.exists();
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Cannot create repository directory {path:?}"))?;
+        let dirfd = open(&path, OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty())?;
+        if statat(&dirfd, METADATA_FILE, AtFlags::empty()).is_ok() {
+            bail!("{path:?} already contains a composefs repository");
+        }
+
+        // Check the alternates before writing anything into the repository, so a bad one doesn't
+        // leave a half-created repository behind.  Relative paths are relative to the repository
+        // directory, so that needs to exist first.
+        let mut alternates = String::new();
+        let checked = options.alternates.iter().try_for_each(|alternate| {
+            let Some(alternate) = alternate.to_str().filter(|s| !s.contains('\n')) else {
+                bail!("Invalid alternate path {alternate:?}");
+            };
+            alternates.push_str(alternate);
+            alternates.push('\n');
+            Ok(())
+        });
+        if let Err(err) = checked.and_then(|()| {
+            Self::open_alternates(&alternates, &path, &options.fsverity, options.insecure)
+        }) {
+            if !existed {
+                let _ = std::fs::remove_dir(&path);
+            }
+            return Err(err);
+        }
+
+        let mkdir = |name: &str| match mkdirat(&dirfd, name, 0o755.into()) {
+            Ok(()) | Err(Errno::EXIST) => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("Cannot create {name} in {path:?}")),
+        };
+        mkdir("objects")?;
+        for first_byte in 0x0..=0xff {
+            mkdir(&format!("objects/{first_byte:02x}"))?;
+        }
+        for category in ["images", "streams"] {
+            mkdir(category)?;
+            mkdir(&format!("{category}/refs"))?;
+        }
+
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let version = if options.fsverity == FsVerityParams::default() {
+            1
+        } else {
+            2
+        };
+        let metadata = RepositoryMetadata {
+            version,
+            algorithm: ObjectID::ID.to_string(),
+            inline_threshold: INLINE_CONTENT_MAX,
+            created,
+            insecure: options.insecure,
+            fsverity: options.fsverity.clone(),
+        };
+
+        if !options.alternates.is_empty() {
+            write_file_atomic(&dirfd, ALTERNATES_FILE, alternates.as_bytes())?;
+        }
+        // Write the metadata last: it's what marks the repository as complete
+        write_file_atomic(&dirfd, METADATA_FILE, metadata.to_string().as_bytes())?;
+
+        Self::open_path(path)
+    }
+
+    /// The metadata of the repository, or None for repositories created without it
+    pub fn metadata(&self) -> Option<&RepositoryMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// The fs-verity block size and salt used for the objects in the repository
+    pub fn fsverity_params(&self) -> &FsVerityParams {
+        &self.fsverity
+    }
+
+    /// The paths of the repositories whose objects are shared with this one (see
+    /// [`RepositoryOptions::alternates`])
+    pub fn alternates(&self) -> impl Iterator<Item = &Path> {
+        self.alternates
+            .iter()
+            .map(|alternate| alternate.path.as_path())
+    }
+
+    /// If this is an insecure repository, without fs-verity (see [`RepositoryOptions::insecure`])
+    pub fn is_insecure(&self) -> bool {
+        self.metadata
+            .as_ref()
+            .is_some_and(|metadata| metadata.insecure)
+    }
+
+    /// Measures the fs-verity digest of the file `fd`.  In insecure repositories, fs-verity isn't
+    /// enabled on the files, so the digest is computed in userspace instead (which means reading
+    /// the entire file).  Either way, the file offset is left at the start of the file.
+    fn measure_verity(&self, fd: impl AsFd) -> Result<ObjectID> {
+        if self.is_insecure() {
+            let mut file = File::from(fd.as_fd().try_clone_to_owned()?);
+            file.rewind()?;
+            let digest =
+                FsVerityHasher::<ObjectID>::hash_reader_with_params(&mut file, &self.fsverity)?;
+            file.rewind()?;
+            Ok(digest)
+        } else {
+            Ok(fs_ioc_measure_verity::<_, ObjectID>(fd)?)
+        }
+    }
+
+    /// The default location of the repository of the current user: `~/.var/lib/composefs`
+    pub fn user_path() -> Result<PathBuf> {
         let home = std::env::var("HOME").with_context(|| "$HOME must be set when in user mode")?;
+        Ok(PathBuf::from(home).join(".var/lib/composefs"))
+    }
 
-        Repository::open_path(PathBuf::from(home).join(".var/lib/composefs"))
+    /// The location of the system repository: `/sysroot/composefs`
+    pub fn system_path() -> PathBuf {
+        PathBuf::from("/sysroot/composefs")
     }
 
-    pub fn open_system() -> Result<Repository> {
-        Repository::open_path(PathBuf::from("/sysroot/composefs".to_string()))
+    pub fn open_user() -> Result<Self> {
+        Self::open_path(Self::user_path()?)
+    }
+
+    pub fn open_system() -> Result<Self> {
+        Self::open_path(Self::system_path())
+    }
+
+    /// Begins a [`Transaction`], which keeps garbage collection from removing anything written
+    /// to the repository (through this `Repository`) until it's committed or dropped.  Only one
+    /// transaction can be in progress on a `Repository` at a time.
+    ///
+    /// Everything written during the transaction is recorded in a journal in `transactions/`,
+    /// which is locked for as long as the transaction is in progress.  If the process dies in the
+    /// middle of a transaction, the next garbage collection removes the journal along with
+    /// whatever was written during the transaction and isn't referenced.
+    pub fn begin_transaction(&self) -> Result<Transaction<'_, ObjectID>> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let mut journal = self.journal.lock().unwrap();
+        ensure!(journal.is_none(), "A transaction is already in progress");
+
+        // Lock the journal before it's linked into place, so that GC never sees it unlocked
+        self.ensure_dir("transactions")?;
+        let fd = openat(
+            &self.repository,
+            "transactions",
+            OFlags::WRONLY | OFlags::CLOEXEC | OFlags::TMPFILE,
+            0o644.into(),
+        )?;
+        lock_repository(&fd, true, self.lock_wait, &self.path.join("transactions"))?;
+        let path = loop {
+            let path = format!(
+                "transactions/{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+            match linkat(
+                CWD,
+                proc_self_fd(&fd),
+                &self.repository,
+                &path,
+                AtFlags::SYMLINK_FOLLOW,
+            ) {
+                Ok(()) => break path,
+                Err(Errno::EXIST) => continue, // debris from an earlier process with our pid
+                Err(err) => Err(err)?,
+            }
+        };
+
+        *journal = Some(File::from(fd));
+        Ok(Transaction {
+            repo: self,
+            journal: path,
+            refs: vec![],
+        })
+    }
+
+    /// Upgrades our shared lock on the repository to an exclusive one, until the returned guard
+    /// is downgraded (or dropped).
+    ///
+    /// Converting a flock() isn't atomic: the shared lock is dropped before the exclusive one is
+    /// taken, and isn't given back if that fails.  When polling, the shared lock is taken again
+    /// after every failed attempt, so that the repository isn't left unlocked in between, and that
+    /// too is given up on once the wait is over, leaving the repository unlocked and returning
+    /// [`Error::RepositoryBusy`].
+    fn lock_exclusive(&self) -> Result<ExclusiveLock<'_>> {
+        let fd = &self.repository;
+        match lock_deadline(self.lock_wait) {
+            None => flock(fd, FlockOperation::LockExclusive)
+                .with_context(|| format!("Cannot lock repository {:?}", self.path))?,
+            Some(deadline) => poll_lock(deadline, &self.path, || {
+                match flock(fd, FlockOperation::NonBlockingLockExclusive) {
+                    Ok(()) => return Ok(true),
+                    Err(Errno::WOULDBLOCK) => {}
+                    Err(err) => Err(err)
+                        .with_context(|| format!("Cannot lock repository {:?}", self.path))?,
+                }
+                let wait = LockWait::Timeout(deadline.saturating_duration_since(Instant::now()));
+                lock_repository(fd, false, wait, &self.path)?;
+                Ok(false)
+            })?,
+        }
+        Ok(ExclusiveLock { fd })
+    }
+
+    /// Records an object, stream or image in the journal of the transaction in progress, if
+    /// there is one.  This needs to happen before it's linked into the repository.
+    fn record(&self, kind: &str, digest: impl AsRef<[u8]>) -> Result<()> {
+        if let Some(journal) = self.journal.lock().unwrap().as_mut() {
+            writeln!(journal, "{kind} {}", hex::encode(digest))?;
+        }
+        Ok(())
     }
 
     fn ensure_dir(&self, dir: impl AsRef<Path>) -> ErrnoResult<()> {
@@ -69,13 +1161,66 @@ impl Repository {
         })
     }
 
-    pub fn ensure_object(&self, data: &[u8]) -> Result<Sha256HashValue> {
-        let digest = FsVerityHasher::hash(data);
-        let dir = PathBuf::from(format!("objects/{:02x}", digest[0]));
-        let file = dir.join(hex::encode(&digest[1..]));
+    /// Stores `data` as an object, unless it already exists in the repository or one of its
+    /// alternates, and returns its ID
+    pub fn ensure_object(&self, data: &[u8]) -> Result<ObjectID> {
+        self.write_object(data, None, true, true)
+    }
+
+    /// Like [`Self::ensure_object`], but the object is always stored in this repository, even if
+    /// an alternate has it.  Images and streams need this, since their symlinks point at it.
+    pub(crate) fn ensure_local_object(&self, data: &[u8]) -> Result<ObjectID> {
+        self.write_object(data, None, true, false)
+    }
+
+    /// Stores a batch of objects, hashing and writing them in parallel, and returns their IDs in
+    /// the same order.  Rather than syncing each object individually, the filesystem is synced
+    /// once when they've all been written.
+    pub fn ensure_objects<T: AsRef<[u8]> + Sync>(&self, objects: &[T]) -> Result<Vec<ObjectID>> {
+        let ids = self.ensure_objects_unsynced(objects)?;
+        self.sync()?;
+        Ok(ids)
+    }
+
+    /// Like [`Self::ensure_objects`], but the objects aren't necessarily on disk until the next
+    /// [`Self::sync`].
+    pub(crate) fn ensure_objects_unsynced<T: AsRef<[u8]> + Sync>(
+        &self,
+        objects: &[T],
+    ) -> Result<Vec<ObjectID>> {
+        parallel_map(objects, |data| {
+            self.write_object(data.as_ref(), None, false, true)
+        })
+    }
+
+    /// Flushes everything written to the repository's filesystem to disk
+    pub(crate) fn sync(&self) -> Result<()> {
+        Ok(syncfs(&self.repository)?)
+    }
+
+    /// Stores `data` as an object, if it doesn't already exist (in this repository or, if
+    /// `alternates` is set, in one of its alternates).  If a builtin signature is given then
+    /// fs-verity is enabled with it (an object which already exists is left as it is).  Unless
+    /// `sync` is set, the object isn't necessarily on disk until the next [`Self::sync`].
+    fn write_object(
+        &self,
+        data: &[u8],
+        signature: Option<&[u8]>,
+        sync: bool,
+        alternates: bool,
+    ) -> Result<ObjectID> {
+        let digest = FsVerityHasher::<ObjectID>::hash_with_params(data, &self.fsverity);
+        let dir = PathBuf::from(format!("objects/{:02x}", digest.as_ref()[0]));
+        let file = PathBuf::from(format!("objects/{}", digest.to_object_pathname()));
+        self.record("object", digest)?;
 
         // fairly common...
-        if accessat(&self.repository, &file, Access::READ_OK, AtFlags::empty()) == Ok(()) {
+        if self.has_object(&digest, alternates) {
+            // fs-verity can only be enabled once, so the signature can't be added after the fact
+            ensure!(
+                signature.is_none() || self.is_insecure(),
+                "{file:?} is already in the repository, and can't be given a signature"
+            );
             return Ok(digest);
         }
 
@@ -88,18 +1233,141 @@ impl Repository {
             OFlags::RDWR | OFlags::CLOEXEC | OFlags::TMPFILE,
             0o666.into(),
         )?;
-        rustix::io::write(&fd, data)?; // TODO: no write_all() here...
-        fdatasync(&fd)?;
+        File::from(fd.try_clone()?).write_all(data)?;
+        self.link_object(fd, &digest, &file, signature, sync)?;
+        Ok(digest)
+    }
+
+    /// Creates an [`ObjectWriter`] for storing an object whose content isn't available all at
+    /// once.  The content is streamed to a temporary file and its fs-verity digest is computed
+    /// as it's written, so it never needs to be held in memory.
+    pub fn create_object_writer(&self) -> Result<ObjectWriter<'_, ObjectID>> {
+        Ok(ObjectWriter {
+            repo: self,
+            file: File::from(self.object_tmpfile()?),
+            hasher: FsVerityHasher::with_params(&self.fsverity),
+            block: Vec::with_capacity(self.fsverity.block_size()),
+        })
+    }
+
+    /// Stores everything read from `reader` as an object, without buffering it in memory.
+    pub fn ensure_object_from_reader(&self, reader: &mut impl Read) -> Result<ObjectID> {
+        let mut writer = self.create_object_writer()?;
+        std::io::copy(reader, &mut writer)?;
+        writer.finish()
+    }
+
+    /// Stores the entire content of the regular file `fd` as an object.  If `fd` is on the same
+    /// filesystem as the repository then the data is shared with a reflink if possible, or
+    /// copied in the kernel with copy_file_range() otherwise.  It's only read and written in
+    /// userspace as a last resort.  Nothing is copied if the object already exists.  The file is
+    /// read with pread(), so its file offset isn't changed.
+    pub fn ensure_object_from_fd(&self, fd: impl AsFd) -> Result<ObjectID> {
+        self.store_object_from_fd(fd, true)
+    }
+
+    /// Like [`Self::ensure_object_from_fd`], but the object isn't necessarily on disk until the
+    /// next [`Self::sync`].
+    pub(crate) fn ensure_object_from_fd_unsynced(&self, fd: impl AsFd) -> Result<ObjectID> {
+        self.store_object_from_fd(fd, false)
+    }
+
+    fn store_object_from_fd(&self, fd: impl AsFd, sync: bool) -> Result<ObjectID> {
+        // Hash the source first, so objects we already have aren't copied at all
+        let digest = FsVerityHasher::<ObjectID>::hash_reader_with_params(
+            &mut PreadReader::new(fd.as_fd()),
+            &self.fsverity,
+        )?;
+        if self.has_object(&digest, true) {
+            self.record("object", digest)?;
+            return Ok(digest);
+        }
+
+        let tmp = self.object_tmpfile()?;
+        copy_file_contents(&fd, &tmp)?;
+        self.store_object(tmp, &digest, sync)?;
+        Ok(digest)
+    }
+
+    /// Opens an unnamed temporary file in the objects directory
+    fn object_tmpfile(&self) -> Result<OwnedFd> {
+        self.ensure_dir("objects")?;
+        Ok(openat(
+            &self.repository,
+            "objects",
+            OFlags::RDWR | OFlags::CLOEXEC | OFlags::TMPFILE,
+            0o666.into(),
+        )?)
+    }
+
+    /// Links the temporary file `fd` holding the object with the given digest into the
+    /// repository, unless the object already exists.
+    fn store_object(&self, fd: OwnedFd, digest: &ObjectID, sync: bool) -> Result<()> {
+        let dir = PathBuf::from(format!("objects/{:02x}", digest.as_ref()[0]));
+        let file = PathBuf::from(format!("objects/{}", digest.to_object_pathname()));
+        self.record("object", digest)?;
+
+        if !self.has_object(digest, true) {
+            self.ensure_dir(&dir)?;
+            self.link_object(fd, digest, &file, None, sync)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks if the object exists in this repository or, if `alternates` is set, in one of its
+    /// alternates
+    fn has_object(&self, digest: &ObjectID, alternates: bool) -> bool {
+        let pathname = digest.to_object_pathname();
+        let exists = |dirfd: &OwnedFd, path: &str| {
+            accessat(dirfd, path, Access::READ_OK, AtFlags::empty()).is_ok()
+        };
+        exists(&self.repository, &format!("objects/{pathname}"))
+            || (alternates
+                && self
+                    .alternates
+                    .iter()
+                    .any(|alternate| exists(&alternate.objects, &pathname)))
+    }
+
+    /// Enables fs-verity on the (unlinked) temporary file `fd` holding the object with the given
+    /// digest and links it into the repository as `file`.  If a builtin signature is given then
+    /// the kernel checks it when enabling fs-verity.
+    fn link_object(
+        &self,
+        fd: OwnedFd,
+        digest: &ObjectID,
+        file: &Path,
+        signature: Option<&[u8]>,
+        sync: bool,
+    ) -> Result<()> {
+        // Enabling fs-verity writes back the data before building the Merkle tree, so the object
+        // can't be linked with content that isn't on disk, and the caller can sync a whole batch
+        // of objects at once instead.  In insecure mode there's nothing else forcing the data out
+        // before the link, so we always sync.
+        if sync || self.is_insecure() {
+            fdatasync(&fd)?;
+        }
 
         // We can't enable verity with an open writable fd, so re-open and close the old one.
         let ro_fd = open(proc_self_fd(&fd), OFlags::RDONLY, Mode::empty())?;
         drop(fd);
 
-        fs_ioc_enable_verity::<&OwnedFd, Sha256HashValue>(&ro_fd)?;
-
-        // double-check
-        let measured_digest: Sha256HashValue = fs_ioc_measure_verity(&ro_fd)?;
-        assert!(measured_digest == digest);
+        // In insecure mode the digest was computed in userspace and that's all we've got
+        if !self.is_insecure() {
+            fs_ioc_enable_verity::<&OwnedFd, ObjectID>(&ro_fd, &self.fsverity, signature)?;
+
+            // double-check
+            let measured_digest: ObjectID = fs_ioc_measure_verity(&ro_fd)?;
+            if measured_digest != *digest {
+                return Err(Error::VerityMismatch {
+                    path: file.display().to_string(),
+                    expected: hex::encode(digest),
+                    measured: hex::encode(measured_digest),
+                    context: vec![],
+                });
+            }
+        }
 
         if let Err(err) = linkat(
             CWD,
@@ -113,19 +1381,41 @@ impl Repository {
             }
         }
 
-        drop(ro_fd);
-        Ok(digest)
+        Ok(())
+    }
+
+    /// Opens `filename` for reading, failing with [`Error::NotFound`] if it doesn't exist
+    fn open_file(&self, filename: &str) -> Result<OwnedFd> {
+        match self.openat(filename, OFlags::RDONLY) {
+            Ok(fd) => Ok(fd),
+            Err(Errno::NOENT) => Err(Error::NotFound {
+                path: filename.to_string(),
+                context: vec![],
+            }),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn open_with_verity(&self, filename: &str, expected_verity: &ObjectID) -> Result<OwnedFd> {
+        let fd = self.open_file(filename)?;
+        self.check_verity(fd, filename, expected_verity)
     }
 
-    fn open_with_verity(
+    /// Checks that the file `fd` (called `filename` in errors) has the expected fs-verity digest
+    fn check_verity(
         &self,
+        fd: OwnedFd,
         filename: &str,
-        expected_verity: &Sha256HashValue,
+        expected_verity: &ObjectID,
     ) -> Result<OwnedFd> {
-        let fd = self.openat(filename, OFlags::RDONLY)?;
-        let measured_verity: Sha256HashValue = fs_ioc_measure_verity(&fd)?;
+        let measured_verity = self.measure_verity(&fd)?;
         if measured_verity != *expected_verity {
-            bail!("bad verity!")
+            Err(Error::VerityMismatch {
+                path: filename.to_string(),
+                expected: hex::encode(expected_verity),
+                measured: hex::encode(measured_verity),
+                context: vec![],
+            })
         } else {
             Ok(fd)
         }
@@ -137,36 +1427,25 @@ impl Repository {
     pub fn create_stream(
         &self,
         sha256: Option<Sha256HashValue>,
-        maps: Option<DigestMap>,
-    ) -> SplitStreamWriter {
+        maps: Option<DigestMap<ObjectID>>,
+    ) -> SplitStreamWriter<'_, ObjectID> {
         SplitStreamWriter::new(self, maps, sha256)
     }
 
-    fn parse_object_path(path: impl AsRef<[u8]>) -> Result<Sha256HashValue> {
+    fn parse_object_path(path: impl AsRef<[u8]>) -> Result<ObjectID> {
         // "objects/0c/9513d99b120ee9a709c4d6554d938f6b2b7e213cf5b26f2e255c0b77e40379"
-        let bytes = path.as_ref();
-        ensure!(bytes.len() == 73, "stream symlink has incorrect length");
-        ensure!(
-            bytes.starts_with(b"objects/"),
-            "stream symlink has incorrect prefix"
-        );
-        ensure!(
-            bytes[10] == b'/',
-            "stream symlink has incorrect path separator"
-        );
-        let mut result = Sha256HashValue::EMPTY;
-        hex::decode_to_slice(&bytes[8..10], &mut result[..1])
-            .context("stream symlink has incorrect format")?;
-        hex::decode_to_slice(&bytes[11..], &mut result[1..])
-            .context("stream symlink has incorrect format")?;
-        Ok(result)
+        let Some(pathname) = path.as_ref().strip_prefix(b"objects/") else {
+            bail!("stream symlink has incorrect prefix");
+        };
+        Ok(ObjectID::from_object_pathname(pathname)
+            .context("stream symlink has incorrect format")?)
     }
 
-    fn format_object_path(id: &Sha256HashValue) -> String {
-        format!("objects/{:02x}/{}", id[0], hex::encode(&id[1..]))
+    fn format_object_path(id: &ObjectID) -> String {
+        format!("objects/{}", id.to_object_pathname())
     }
 
-    pub fn has_stream(&self, sha256: &Sha256HashValue) -> Result<Option<Sha256HashValue>> {
+    pub fn has_stream(&self, sha256: &Sha256HashValue) -> Result<Option<ObjectID>> {
         let stream_path = format!("streams/{}", hex::encode(sha256));
 
         match readlinkat(&self.repository, &stream_path, []) {
@@ -183,7 +1462,7 @@ impl Repository {
                     bytes.starts_with(b"../"),
                     "stream symlink has incorrect prefix"
                 );
-                Ok(Some(Repository::parse_object_path(&bytes[3..])?))
+                Ok(Some(Self::parse_object_path(&bytes[3..])?))
             }
             Err(Errno::NOENT) => Ok(None),
             Err(err) => Err(err)?,
@@ -191,10 +1470,10 @@ impl Repository {
     }
 
     /// Basically the same as has_stream() except that it performs expensive verification
-    pub fn check_stream(&self, sha256: &Sha256HashValue) -> Result<Option<Sha256HashValue>> {
+    pub fn check_stream(&self, sha256: &Sha256HashValue) -> Result<Option<ObjectID>> {
         match self.openat(&format!("streams/{}", hex::encode(sha256)), OFlags::RDONLY) {
             Ok(stream) => {
-                let measured_verity: Sha256HashValue = fs_ioc_measure_verity(&stream)?;
+                let measured_verity = self.measure_verity(&stream)?;
                 let mut context = Sha256::new();
                 let mut split_stream = SplitStreamReader::new(File::from(stream))?;
 
@@ -211,8 +1490,13 @@ impl Repository {
                     File::from(self.open_object(id)?).read_to_end(&mut data)?;
                     Ok(data)
                 })?;
-                if *sha256 != Into::<[u8; 32]>::into(context.finalize()) {
-                    bail!("Content didn't match!");
+                let actual: Sha256HashValue = context.finalize().into();
+                if *sha256 != actual {
+                    return Err(Error::ContentMismatch {
+                        expected: hex::encode(sha256),
+                        actual: hex::encode(actual),
+                        context: vec![],
+                    });
                 }
 
                 Ok(Some(measured_verity))
@@ -224,32 +1508,55 @@ impl Repository {
 
     pub fn write_stream(
         &self,
-        writer: SplitStreamWriter,
+        writer: SplitStreamWriter<ObjectID>,
+        reference: Option<&str>,
+    ) -> Result<ObjectID> {
+        self.store_stream(writer, reference, false)
+    }
+
+    /// Like [`Self::write_stream`], but if there's already a stream with the same content then it's
+    /// replaced by this one.  This is for streams whose references to other streams can change
+    /// while their content stays the same.
+    pub fn replace_stream(
+        &self,
+        writer: SplitStreamWriter<ObjectID>,
         reference: Option<&str>,
-    ) -> Result<Sha256HashValue> {
-        let Some((.., ref sha256)) = writer.sha256 else {
+    ) -> Result<ObjectID> {
+        self.store_stream(writer, reference, true)
+    }
+
+    fn store_stream(
+        &self,
+        writer: SplitStreamWriter<ObjectID>,
+        reference: Option<&str>,
+        replace: bool,
+    ) -> Result<ObjectID> {
+        let Some((.., sha256)) = writer.sha256 else {
             bail!("Writer doesn't have sha256 enabled");
         };
         let stream_path = format!("streams/{}", hex::encode(sha256));
         let object_id = writer.done()?;
-        let object_path = Repository::format_object_path(&object_id);
-        self.ensure_symlink(&stream_path, &object_path)?;
+        let object_path = Self::format_object_path(&object_id);
+        self.record("stream", sha256)?;
+        if replace {
+            self.replace_symlink(Path::new(&stream_path), Path::new(&object_path))?;
+        } else {
+            self.ensure_symlink(&stream_path, &object_path)?;
+        }
 
         if let Some(name) = reference {
-            let reference_path = format!("streams/refs/{name}");
-            self.symlink(&reference_path, &stream_path)?;
+            self.update_ref("streams", &Self::default_ref_name(name), &sha256, None)?;
         }
 
         Ok(object_id)
     }
 
     /// Assign the given name to a stream.  The stream must already exist.  After this operation it
-    /// will be possible to refer to the stream by its new name 'refs/{name}'.
+    /// will be possible to refer to the stream by its new name 'refs/{name}'.  If the name was
+    /// already in use, it's atomically replaced.  Users other than root get the ref in their own
+    /// namespace (see [`Self::default_ref_name`]).
     pub fn name_stream(&self, sha256: Sha256HashValue, name: &str) -> Result<()> {
-        let stream_path = format!("streams/{}", hex::encode(sha256));
-        let reference_path = format!("streams/refs/{name}");
-        self.symlink(&reference_path, &stream_path)?;
-        Ok(())
+        self.update_ref("streams", &Self::default_ref_name(name), &sha256, None)
     }
 
     /// Ensures that the stream with a given SHA256 digest exists in the repository.
@@ -262,17 +1569,18 @@ impl Repository {
     ///
     /// In both cases, if `reference` is provided, it is used to provide a fixed name for the
     /// object.  Any object that doesn't have a fixed reference to it is subject to garbage
-    /// collection.  It is an error if this reference already exists.
+    /// collection.  If this reference already exists, it's atomically replaced.
     ///
     /// On success, the object ID of the new object is returned.  It is expected that this object
     /// ID will be used when referring to the stream from other linked streams.
     pub fn ensure_stream(
         &self,
         sha256: &Sha256HashValue,
-        callback: impl FnOnce(&mut SplitStreamWriter) -> Result<()>,
+        callback: impl FnOnce(&mut SplitStreamWriter<ObjectID>) -> Result<()>,
         reference: Option<&str>,
-    ) -> Result<Sha256HashValue> {
+    ) -> Result<ObjectID> {
         let stream_path = format!("streams/{}", hex::encode(sha256));
+        self.record("stream", sha256)?;
 
         let object_id = match self.has_stream(sha256)? {
             Some(id) => id,
@@ -281,180 +1589,711 @@ impl Repository {
                 callback(&mut writer)?;
                 let object_id = writer.done()?;
 
-                let object_path = Repository::format_object_path(&object_id);
-                self.ensure_symlink(&stream_path, &object_path)?;
-                object_id
+                let object_path = Self::format_object_path(&object_id);
+                self.ensure_symlink(&stream_path, &object_path)?;
+                object_id
+            }
+        };
+
+        if let Some(name) = reference {
+            self.update_ref("streams", &Self::default_ref_name(name), sha256, None)?;
+        }
+
+        Ok(object_id)
+    }
+
+    pub fn open_stream(
+        &self,
+        name: &str,
+        verity: Option<&ObjectID>,
+    ) -> Result<SplitStreamReader<File, ObjectID>> {
+        let filename = format!("streams/{}", self.resolve_name("streams", name)?);
+
+        let file = File::from(if let Some(verity_hash) = verity {
+            self.open_with_verity(&filename, verity_hash)?
+        } else {
+            self.open_file(&filename)?
+        });
+
+        SplitStreamReader::new(file)
+    }
+
+    /// Opens the object with the given digest, from this repository or one of its alternates,
+    /// and checks its fs-verity digest.  Fails with [`Error::ObjectMissing`] if there's no such
+    /// object.
+    pub fn open_object(&self, id: &ObjectID) -> Result<OwnedFd> {
+        match self.open_with_verity(&Self::format_object_path(id), id) {
+            Err(Error::NotFound { .. }) => {}
+            result => return result,
+        }
+
+        let pathname = id.to_object_pathname();
+        for alternate in &self.alternates {
+            match openat(
+                &alternate.objects,
+                &pathname,
+                OFlags::RDONLY | OFlags::CLOEXEC,
+                Mode::empty(),
+            ) {
+                Ok(fd) => {
+                    let path = alternate.path.join("objects").join(&pathname);
+                    return self.check_verity(fd, &path.to_string_lossy(), id);
+                }
+                Err(Errno::NOENT) => continue,
+                Err(err) => Err(err)?,
+            }
+        }
+
+        Err(Error::ObjectMissing {
+            digest: hex::encode(id),
+            context: vec![],
+        })
+    }
+
+    pub fn merge_splitstream(
+        &self,
+        name: &str,
+        verity: Option<&ObjectID>,
+        stream: &mut impl Write,
+    ) -> Result<()> {
+        let mut split_stream = self.open_stream(name, verity)?;
+        split_stream.cat(stream, |id| -> Result<Vec<u8>> {
+            let mut data = vec![];
+            File::from(self.open_object(id)?).read_to_end(&mut data)?;
+            Ok(data)
+        })?;
+
+        Ok(())
+    }
+
+    /// Stores an image, naming it `name` if given.  Users other than root get the ref in their own
+    /// namespace (see [`Self::default_ref_name`]).
+    ///
+    /// this function is not safe for untrusted users
+    pub fn write_image(&self, name: Option<&str>, data: &[u8]) -> Result<ObjectID> {
+        self.write_image_with_signature(name, data, None)
+    }
+
+    /// Like [`Self::write_image`], but the image is stored with an fs-verity builtin signature
+    /// (see [`crate::fsverity::signature`]), which the kernel verifies against its `.fs-verity`
+    /// keyring.  The signature is ignored in insecure repositories.  Otherwise, it's an error if
+    /// the image is already in the repository, since fs-verity can't be enabled on it again.
+    ///
+    /// this function is not safe for untrusted users
+    pub fn write_image_signed(
+        &self,
+        name: Option<&str>,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<ObjectID> {
+        self.write_image_with_signature(name, data, Some(signature))
+    }
+
+    fn write_image_with_signature(
+        &self,
+        name: Option<&str>,
+        data: &[u8],
+        signature: Option<&[u8]>,
+    ) -> Result<ObjectID> {
+        let object_id = self.write_object(data, signature, true, false)?;
+
+        let object_path = Self::format_object_path(&object_id);
+        let image_path = format!("images/{}", hex::encode(object_id));
+
+        self.record("image", object_id)?;
+        self.ensure_symlink(&image_path, &object_path)?;
+
+        if let Some(reference) = name {
+            self.update_ref(
+                "images",
+                &Self::default_ref_name(reference),
+                &object_id,
+                None,
+            )?;
+        }
+
+        Ok(object_id)
+    }
+
+    /// this function is not safe for untrusted users
+    pub fn import_image<R: Read>(&self, name: &str, image: &mut R) -> Result<ObjectID> {
+        let mut data = vec![];
+        image.read_to_end(&mut data)?;
+        self.write_image(Some(name), &data)
+    }
+
+    /// Like [`Self::import_image`], with an fs-verity builtin signature for the image (see
+    /// [`Self::write_image_signed`]).
+    ///
+    /// this function is not safe for untrusted users
+    pub fn import_image_signed<R: Read>(
+        &self,
+        name: &str,
+        image: &mut R,
+        signature: &[u8],
+    ) -> Result<ObjectID> {
+        let mut data = vec![];
+        image.read_to_end(&mut data)?;
+        self.write_image_signed(Some(name), &data, signature)
+    }
+
+    /// Mounts the image `name` at `mountpoint`.  Returns the verity mode it was mounted with:
+    /// anything other than [`VerityMode::Require`] means that file contents aren't verified.
+    pub fn mount(&self, name: &str, mountpoint: &str) -> Result<VerityMode> {
+        let id = self.resolve_name("images", name)?;
+        let image = self.open_with_verity(&format!("images/{id}"), &ObjectID::from_hex(&id)?)?;
+
+        let verity = self.verity_mode();
+        mount_fd(image, &self.object_dirs(), mountpoint, verity)?;
+        Ok(verity)
+    }
+
+    /// Like [`Self::mount`], but pivots the image into place at `mountpoint` (see
+    /// [`crate::mount::pivot_sysroot`]).
+    pub fn pivot_sysroot(&self, name: &str, mountpoint: &Path) -> Result<VerityMode> {
+        let filename = format!("images/{}", name);
+        let image = self.open_with_verity(&filename, &ObjectID::from_hex(name)?)?;
+        let verity = self.verity_mode();
+        pivot_sysroot(image, &self.object_dirs(), mountpoint, verity)?;
+        Ok(verity)
+    }
+
+    /// The directories that overlayfs looks for objects in when mounting an image: ours, then
+    /// those of the alternates
+    fn object_dirs(&self) -> Vec<PathBuf> {
+        std::iter::once(&self.path)
+            .chain(self.alternates.iter().map(|alternate| &alternate.path))
+            .map(|path| path.join("objects"))
+            .collect()
+    }
+
+    /// The overlayfs verity mode for mounting images from this repository: `require`, except for
+    /// insecure repositories, where the objects don't have fs-verity enabled.
+    pub fn verity_mode(&self) -> VerityMode {
+        if self.is_insecure() {
+            VerityMode::Off
+        } else {
+            VerityMode::Require
+        }
+    }
+
+    /// Computes the relative path for a symlink at `name` pointing to `target` (both relative to
+    /// the repository), creating the symlink's parent directories as needed.
+    fn symlink_target(&self, name: &Path, target: &Path) -> ErrnoResult<PathBuf> {
+        let mut symlink_components = name.parent().unwrap().components().peekable();
+        let mut target_components = target.components().peekable();
+
+        let mut symlink_ancestor = PathBuf::new();
+
+        // remove common leading components
+        while symlink_components.peek() == target_components.peek() {
+            symlink_ancestor.push(symlink_components.next().unwrap());
+            target_components.next().unwrap();
+        }
+
+        let mut relative = PathBuf::new();
+        // prepend a "../" for each ancestor of the symlink
+        // and create those ancestors as we do so
+        for symlink_component in symlink_components {
+            symlink_ancestor.push(symlink_component);
+            self.ensure_dir(&symlink_ancestor)?;
+            relative.push("..");
+        }
+
+        // now build the relative path from the remaining components of the target
+        for target_component in target_components {
+            relative.push(target_component);
+        }
+
+        Ok(relative)
+    }
+
+    pub fn symlink(&self, name: impl AsRef<Path>, target: impl AsRef<Path>) -> ErrnoResult<()> {
+        let name = name.as_ref();
+        let relative = self.symlink_target(name, target.as_ref())?;
+        symlinkat(relative, &self.repository, name)
+    }
+
+    /// Creates a symlink at `name` pointing to `target`, atomically replacing anything that was
+    /// there before (see [`replace_symlink_at`]).
+    fn replace_symlink(&self, name: &Path, target: &Path) -> Result<()> {
+        let relative = self.symlink_target(name, target)?;
+        let (Some(parent), Some(filename)) = (name.parent(), name.file_name()) else {
+            bail!("Invalid symlink name {name:?}");
+        };
+        let dirfd = self.openat(
+            &parent.to_string_lossy(),
+            OFlags::RDONLY | OFlags::DIRECTORY,
+        )?;
+        replace_symlink_at(&dirfd, &filename.to_string_lossy(), &relative)
+    }
+
+    pub fn ensure_symlink<P: AsRef<Path>>(&self, name: P, target: &str) -> ErrnoResult<()> {
+        self.symlink(name, target).or_else(|e| match e {
+            Errno::EXIST => Ok(()),
+            _ => Err(e),
+        })
+    }
+
+    fn check_ref_category(category: &str) -> Result<()> {
+        ensure!(
+            matches!(category, "images" | "streams"),
+            "Invalid ref category {category:?}"
+        );
+        Ok(())
+    }
+
+    /// Returns the path (relative to the repository) of the ref `name` in `category`, which is
+    /// either "images" or "streams".  The name may optionally start with "refs/".  Components
+    /// starting with '.' are reserved for the temporary symlinks used while updating refs.
+    fn ref_path(category: &str, name: &str) -> Result<String> {
+        Self::check_ref_category(category)?;
+        let name = name.strip_prefix("refs/").unwrap_or(name);
+        ensure!(
+            name.split('/')
+                .all(|part| !part.is_empty() && !part.starts_with('.')),
+            "Invalid ref name {name:?}"
+        );
+        Ok(format!("{category}/refs/{name}"))
+    }
+
+    fn walk_refs<D: FsVerityHashValue>(
+        fd: OwnedFd,
+        prefix: &str,
+        refs: &mut Vec<(String, D)>,
+    ) -> Result<()> {
+        for item in Dir::read_from(&fd)? {
+            let entry = item?;
+            let filename = entry.file_name();
+            if filename.to_bytes().starts_with(b".") {
+                continue; // ., .. and temporary symlinks from in-progress updates
+            }
+            let Ok(name) = filename.to_str() else {
+                bail!("Invalid ref name {filename:?}");
+            };
+            match entry.file_type() {
+                FileType::Directory => {
+                    let dirfd = open_dir_nofollow(&fd, name)?;
+                    Self::walk_refs(dirfd, &format!("{prefix}{name}/"), refs)?;
+                }
+                FileType::Symlink => {
+                    let value = Self::read_symlink_hashvalue(&fd, filename)?;
+                    refs.push((format!("{prefix}{name}"), value));
+                }
+                _ => {
+                    bail!("Unexpected file type encountered");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists the refs in `category` ("images" or "streams") which are below the directory
+    /// `prefix` (or all of them, if the prefix is empty), sorted by name.  Each ref is returned
+    /// with the digest of the image or stream that it points to: `D` is `ObjectID` for images and
+    /// [`Sha256HashValue`] for streams.
+    pub fn list_refs<D: FsVerityHashValue>(
+        &self,
+        category: &str,
+        prefix: &str,
+    ) -> Result<Vec<(String, D)>> {
+        let prefix = match prefix.trim_matches('/') {
+            "refs" => "",
+            prefix => prefix.strip_prefix("refs/").unwrap_or(prefix),
+        };
+        let (fd, prefix) = match prefix {
+            "" => {
+                Self::check_ref_category(category)?;
+                let flags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW;
+                (
+                    self.openat(&format!("{category}/refs"), flags)
+                        .map_err(Error::from),
+                    String::new(),
+                )
+            }
+            prefix => {
+                let path = Self::ref_path(category, prefix)?;
+                let fd = self
+                    .open_ref_dir(category, &path, false)
+                    .and_then(|dir| Ok(open_dir_nofollow(dir.dirfd(), &dir.filename)?));
+                (fd, format!("{prefix}/"))
             }
         };
 
-        if let Some(name) = reference {
-            let reference_path = format!("streams/refs/{name}");
-            self.symlink(&reference_path, &stream_path)?;
+        let mut refs = vec![];
+        match fd {
+            Ok(fd) => Self::walk_refs(fd, &prefix, &mut refs)?,
+            Err(err) if err.raw_os_error() == Some(Errno::NOENT.raw_os_error()) => {}
+            Err(err) => return Err(err),
         }
-
-        Ok(object_id)
+        refs.sort();
+        Ok(refs)
     }
 
-    pub fn open_stream(
-        &self,
-        name: &str,
-        verity: Option<&Sha256HashValue>,
-    ) -> Result<SplitStreamReader<File>> {
-        let filename = format!("streams/{}", name);
+    /// Returns the digest of the image or stream that the given ref points to.  As with
+    /// [`Repository::list_refs`], `D` depends on the category.
+    ///
+    /// A name which isn't in a namespace is looked for in the caller's own namespace first (see
+    /// [`Repository::default_ref_name`]).
+    pub fn show_ref<D: FsVerityHashValue>(&self, category: &str, name: &str) -> Result<D> {
+        let dir = self.lookup_ref(category, name)?;
+        let filename = CString::new(dir.filename.as_str())?;
+        Ok(Self::read_symlink_hashvalue(dir.dirfd(), &filename)
+            .with_context(|| format!("Cannot read ref {name:?}"))?)
+    }
 
-        let file = File::from(if let Some(verity_hash) = verity {
-            self.open_with_verity(&filename, verity_hash)?
-        } else {
-            self.openat(&filename, OFlags::RDONLY)?
-        });
+    /// Finds the ref `name` in `category` for reading.  A name which isn't in a namespace is
+    /// looked for in the caller's own namespace first, since that's where
+    /// [`Repository::default_ref_name`] puts it, and then as it is.
+    fn lookup_ref(&self, category: &str, name: &str) -> Result<RefDir> {
+        let name = name.strip_prefix("refs/").unwrap_or(name);
+        let default = Self::default_ref_name(name);
+        if default != name {
+            let path = Self::ref_path(category, &default)?;
+            if let Ok(dir) = self.open_ref_dir(category, &path, false) {
+                if statat(dir.dirfd(), &dir.filename, AtFlags::SYMLINK_NOFOLLOW).is_ok() {
+                    return Ok(dir);
+                }
+            }
+        }
+        let path = Self::ref_path(category, name)?;
+        Ok(self
+            .open_ref_dir(category, &path, false)
+            .with_context(|| format!("Cannot find ref {name:?}"))?)
+    }
 
-        SplitStreamReader::new(file)
+    /// Resolves `name`, which is either the digest of an image or stream in `category` or a ref
+    /// (starting with `refs/`), to the digest.  Refs are resolved here, rather than by following
+    /// the symlinks, so that nothing outside of the repository can be opened via a symlink planted
+    /// in a namespace directory.
+    fn resolve_name(&self, category: &str, name: &str) -> Result<String> {
+        if !name.starts_with("refs/") {
+            return Ok(name.to_string());
+        }
+        let target = self
+            .lookup_ref(category, name)?
+            .target()
+            .with_context(|| format!("Cannot read ref {name:?}"))?;
+        ensure!(!target.is_empty(), "Ref {name:?} has an invalid target");
+        Ok(target)
     }
 
-    pub fn open_object(&self, id: &Sha256HashValue) -> Result<OwnedFd> {
-        self.open_with_verity(
-            &format!("objects/{:02x}/{}", id[0], hex::encode(&id[1..])),
-            id,
-        )
+    /// Returns the namespace that refs created by the current user belong in: `system` for root,
+    /// and the (effective) uid for everybody else.
+    pub fn ref_namespace() -> String {
+        let uid = geteuid();
+        if uid.is_root() {
+            "system".to_string()
+        } else {
+            uid.as_raw().to_string()
+        }
     }
 
-    pub fn merge_splitstream(
-        &self,
-        name: &str,
-        verity: Option<&Sha256HashValue>,
-        stream: &mut impl Write,
-    ) -> Result<()> {
-        let mut split_stream = self.open_stream(name, verity)?;
-        split_stream.cat(stream, |id| -> Result<Vec<u8>> {
-            let mut data = vec![];
-            File::from(self.open_object(id)?).read_to_end(&mut data)?;
-            Ok(data)
-        })?;
+    /// Returns `name` placed in the namespace of the current user (see
+    /// [`Repository::ref_namespace`]), like `1000/flatpak/some_id` or `system/rootfs/some_id`.
+    pub fn namespaced_ref_name(name: &str) -> String {
+        let name = name.strip_prefix("refs/").unwrap_or(name);
+        format!("{}/{name}", Self::ref_namespace())
+    }
 
-        Ok(())
+    /// Returns the name that a ref called `name` is created with by [`Repository::write_image`],
+    /// [`Repository::name_stream`] and the other functions which name what they store.  A name
+    /// which isn't already in a namespace (`system` or a uid) is placed in the caller's own (see
+    /// [`Repository::namespaced_ref_name`]), which is `system` for root.
+    /// [`Repository::update_ref`] and friends always use the name as it is.
+    pub fn default_ref_name(name: &str) -> String {
+        let name = name.strip_prefix("refs/").unwrap_or(name);
+        let namespace = name.split('/').next().unwrap_or_default();
+        if namespace == "system" || parse_namespace(namespace).is_some() {
+            name.to_string()
+        } else {
+            Self::namespaced_ref_name(name)
+        }
     }
 
-    /// this function is not safe for untrusted users
-    pub fn write_image(&self, name: Option<&str>, data: &[u8]) -> Result<Sha256HashValue> {
-        let object_id = self.ensure_object(data)?;
+    /// If `path` (a ref path like `images/refs/1000/...`) is in a per-user namespace, returns the
+    /// uid of that namespace.
+    fn namespace_uid(path: &str) -> Option<u32> {
+        let mut components = path.splitn(4, '/').skip(2);
+        match (components.next(), components.next()) {
+            (Some(namespace), Some(_)) => parse_namespace(namespace),
+            _ => None,
+        }
+    }
 
-        let object_path = format!(
-            "objects/{:02x}/{}",
-            object_id[0],
-            hex::encode(&object_id[1..])
+    /// Checks that the current user may modify the ref at `path`.  Root can modify any ref, and
+    /// everybody else is limited to the refs in their own namespace.
+    fn check_ref_access(&self, path: &str) -> Result<()> {
+        let uid = geteuid();
+        if uid.is_root() {
+            return Ok(());
+        }
+        ensure!(
+            Self::namespace_uid(path) == Some(uid.as_raw()),
+            "Refusing to modify {path}: users can only modify refs under refs/{}/",
+            uid.as_raw()
         );
-        let image_path = format!("images/{}", hex::encode(object_id));
-
-        self.ensure_symlink(&image_path, &object_path)?;
+        Ok(())
+    }
 
-        if let Some(reference) = name {
-            let ref_path = format!("images/refs/{}", reference);
-            self.symlink(&ref_path, &image_path)?;
+    /// Creates the directory for the refs of `uid` in `refs` (the `{category}/refs` directory):
+    /// owned by root, mode 0700, with a POSIX ACL which gives that user full access.  This keeps
+    /// the user from corrupting the layout of the repository while still letting them manage refs
+    /// within their own namespace.
+    fn create_namespace_dir(refs: &OwnedFd, uid: u32) -> Result<()> {
+        let name = uid.to_string();
+        match mkdirat(refs, &name, 0o700.into()) {
+            Ok(()) => {}
+            Err(Errno::EXIST) => return Ok(()),
+            Err(err) => Err(err)?,
         }
 
-        Ok(object_id)
+        let fd = open_dir_nofollow(refs, &name)?;
+        fchown(&fd, Some(Uid::ROOT), Some(Gid::ROOT))?;
+        fchmod(&fd, 0o700.into())?;
+        fsetxattr(
+            &fd,
+            "system.posix_acl_access",
+            &posix_acl_for_user(uid),
+            XattrFlags::empty(),
+        )
+        .with_context(|| format!("Cannot set ACL on namespace directory {uid}"))?;
+        Ok(())
     }
 
-    /// this function is not safe for untrusted users
-    pub fn import_image<R: Read>(&self, name: &str, image: &mut R) -> Result<Sha256HashValue> {
-        let mut data = vec![];
-        image.read_to_end(&mut data)?;
-        self.write_image(Some(name), &data)
+    /// Creates the per-user namespace directories for `uid` in both `images/refs/` and
+    /// `streams/refs/`.  This needs to be done by root before the user can create refs in a shared
+    /// repository.
+    pub fn create_ref_namespace(&self, uid: u32) -> Result<()> {
+        ensure!(geteuid().is_root(), "Only root can create ref namespaces");
+        for category in ["images", "streams"] {
+            self.ensure_dir(category)?;
+            self.ensure_dir(format!("{category}/refs"))?;
+            let refs = self.openat(
+                &format!("{category}/refs"),
+                OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW,
+            )?;
+            Self::create_namespace_dir(&refs, uid)?;
+        }
+        Ok(())
     }
 
-    pub fn mount(&self, name: &str, mountpoint: &str) -> Result<()> {
-        let filename = format!("images/{}", name);
+    /// Opens the directory containing the ref at `path` (as returned by [`Self::ref_path`]),
+    /// without following symlinks.  With `create`, missing directories are created: if root is
+    /// creating a ref in a per-user namespace, the namespace directory is set up with the right
+    /// ownership and ACL, and any directories created below it are given to the user so that they
+    /// can manage the ref later on.
+    fn open_ref_dir(&self, category: &str, path: &str, create: bool) -> Result<RefDir> {
+        let refs = format!("{category}/refs");
+        let Some(mut names) = path
+            .strip_prefix(&refs)
+            .and_then(|name| name.strip_prefix('/'))
+            .map(|name| Vec::from_iter(name.split('/').map(String::from)))
+        else {
+            bail!("Invalid ref path {path:?}");
+        };
+        let filename = names.pop().unwrap();
 
-        let image = if name.contains("/") {
-            // no fsverity checking on this path
-            Ok(self.openat(&filename, OFlags::RDONLY)?)
-        } else {
-            self.open_with_verity(&filename, &parse_sha256(name)?)
-        }?;
+        if create {
+            self.ensure_dir(category)?;
+            self.ensure_dir(&refs)?;
+        }
+        let flags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW;
+        let mut dirs = vec![self.openat(&refs, flags)?];
+
+        // SAFETY: namespace_uid() never returns u32::MAX (ie: -1)
+        let owner = Self::namespace_uid(path)
+            .filter(|_| create && geteuid().is_root())
+            .map(|uid| unsafe { Uid::from_raw(uid) });
+        if let Some(owner) = owner {
+            Self::create_namespace_dir(&dirs[0], owner.as_raw())?;
+        }
 
-        let object_path = self.path.join("objects");
-        mount_fd(image, &object_path, mountpoint)
-    }
+        for name in &names {
+            let parent = dirs.last().unwrap();
+            let fd = match open_dir_nofollow(parent, name) {
+                Err(Errno::NOENT) if create => {
+                    let created = match mkdirat(parent, name, 0o755.into()) {
+                        Ok(()) => true,
+                        Err(Errno::EXIST) => false,
+                        Err(err) => Err(err)?,
+                    };
+                    let fd = open_dir_nofollow(parent, name)?;
+                    // the namespace directory itself was set up above
+                    if let Some(owner) = owner.filter(|_| created && dirs.len() > 1) {
+                        fchown(&fd, Some(owner), None)?;
+                    }
+                    fd
+                }
+                result => result.with_context(|| format!("Cannot open ref directory {name:?}"))?,
+            };
+            dirs.push(fd);
+        }
 
-    pub fn pivot_sysroot(&self, name: &str, mountpoint: &Path) -> Result<()> {
-        let filename = format!("images/{}", name);
-        let object_path = self.path.join("objects");
-        let image = self.open_with_verity(&filename, &parse_sha256(name)?)?;
-        pivot_sysroot(image, &object_path, mountpoint)
+        Ok(RefDir {
+            dirs,
+            names,
+            filename,
+        })
     }
 
-    pub fn symlink(&self, name: impl AsRef<Path>, target: impl AsRef<Path>) -> ErrnoResult<()> {
-        let name = name.as_ref();
-
-        let mut symlink_components = name.parent().unwrap().components().peekable();
-        let mut target_components = target.as_ref().components().peekable();
-
-        let mut symlink_ancestor = PathBuf::new();
+    /// Takes an exclusive lock on the refs of `category`, which is held until the returned fd is
+    /// dropped.  This serializes ref modifications so that compare-and-swap updates are reliable.
+    /// Don't modify refs while holding it: that takes the lock again, and waits forever (or
+    /// fails with [`Error::RepositoryBusy`], depending on [`OpenOptions::lock_wait`]).
+    pub(crate) fn lock_refs(&self, category: &str) -> Result<OwnedFd> {
+        self.ensure_dir(category)?;
+        self.ensure_dir(format!("{category}/refs"))?;
+        let fd = self.openat(
+            &format!("{category}/refs"),
+            OFlags::RDONLY | OFlags::DIRECTORY,
+        )?;
+        let path = self.path.join(category).join("refs");
+        lock_repository(&fd, true, self.lock_wait, &path)?;
+        Ok(fd)
+    }
 
-        // remove common leading components
-        while symlink_components.peek() == target_components.peek() {
-            symlink_ancestor.push(symlink_components.next().unwrap());
-            target_components.next().unwrap();
+    /// Points the ref `name` in `category` ("images" or "streams") at the image or stream with
+    /// the given digest, atomically replacing the ref if it already exists.  Images are named by
+    /// their fs-verity digest and streams by the SHA-256 of their content, as with
+    /// [`Repository::show_ref`].
+    ///
+    /// If `expected` is given then the update is a compare-and-swap: it only happens if the ref
+    /// currently exists and points at `expected`.  Otherwise, an error is returned and the ref is
+    /// left alone.
+    pub fn update_ref<D: FsVerityHashValue>(
+        &self,
+        category: &str,
+        name: &str,
+        target: &D,
+        expected: Option<&D>,
+    ) -> Result<()> {
+        let path = Self::ref_path(category, name)?;
+        let target = hex::encode(target);
+        let target_path = format!("{category}/{target}");
+        statat(&self.repository, &target_path, AtFlags::SYMLINK_NOFOLLOW)
+            .with_context(|| format!("Cannot find {target_path}"))?;
+
+        self.check_ref_access(&path)?;
+        let _lock = self.lock_refs(category)?;
+        let dir = self.open_ref_dir(category, &path, true)?;
+        if let Some(expected) = expected {
+            let current = dir
+                .target()
+                .with_context(|| format!("Cannot read ref {name:?}"))?;
+            let expected = hex::encode(expected);
+            if current != expected {
+                return Err(Error::RefConflict {
+                    name: name.to_string(),
+                    expected,
+                    current,
+                    context: vec![],
+                });
+            }
         }
 
-        let mut relative = PathBuf::new();
-        // prepend a "../" for each ancestor of the symlink
-        // and create those ancestors as we do so
-        for symlink_component in symlink_components {
-            symlink_ancestor.push(symlink_component);
-            self.ensure_dir(&symlink_ancestor)?;
-            relative.push("..");
-        }
+        Ok(dir
+            .replace(&target)
+            .with_context(|| format!("Cannot update ref {name:?}"))?)
+    }
 
-        // now build the relative path from the remaining components of the target
-        for target_component in target_components {
-            relative.push(target_component);
-        }
+    /// Removes a ref.  The image or stream that it pointed to becomes eligible for garbage
+    /// collection if nothing else refers to it.  Any directories that contained only the ref are
+    /// removed too, except for per-user namespace directories.
+    pub fn remove_ref(&self, category: &str, name: &str) -> Result<()> {
+        let path = Self::ref_path(category, name)?;
+        self.check_ref_access(&path)?;
+        let _lock = self.lock_refs(category)?;
+        self.open_ref_dir(category, &path, false)?
+            .remove()
+            .with_context(|| format!("Cannot remove ref {name:?}"))?;
+        Ok(())
+    }
 
-        symlinkat(relative, &self.repository, name)
+    /// Gives a ref a new name.  Fails if a ref with the new name already exists.
+    pub fn rename_ref(&self, category: &str, old: &str, new: &str) -> Result<()> {
+        let old_path = Self::ref_path(category, old)?;
+        let new_path = Self::ref_path(category, new)?;
+        self.check_ref_access(&old_path)?;
+        self.check_ref_access(&new_path)?;
+        let _lock = self.lock_refs(category)?;
+        let old_dir = self.open_ref_dir(category, &old_path, false)?;
+        let target = old_dir
+            .target()
+            .with_context(|| format!("Cannot read ref {old:?}"))?;
+        ensure!(!target.is_empty(), "Ref {old:?} has an invalid target");
+
+        // The symlinks are relative, so the link needs to be recreated if the depth changes
+        self.open_ref_dir(category, &new_path, true)?
+            .create(&target)
+            .with_context(|| format!("Cannot create ref {new:?}"))?;
+        Ok(old_dir.remove()?)
     }
 
-    pub fn ensure_symlink<P: AsRef<Path>>(&self, name: P, target: &str) -> ErrnoResult<()> {
-        self.symlink(name, target).or_else(|e| match e {
-            Errno::EXIST => Ok(()),
-            _ => Err(e),
-        })
+    /// The digest (in hex) of the image or stream that a ref points to, whatever kind of digest
+    /// it is
+    fn ref_target(&self, category: &str, name: &str) -> Result<String> {
+        let path = Self::ref_path(category, name)?;
+        let target = self
+            .open_ref_dir(category, &path, false)
+            .and_then(|dir| Ok(dir.target()?))
+            .with_context(|| format!("Cannot read ref {name:?}"))?;
+        ensure!(!target.is_empty(), "Ref {name:?} has an invalid target");
+        Ok(target)
     }
 
-    fn read_symlink_hashvalue(dirfd: &OwnedFd, name: &CStr) -> Result<Sha256HashValue> {
+    fn read_symlink_hashvalue<D: FsVerityHashValue>(dirfd: &OwnedFd, name: &CStr) -> Result<D> {
         let link_content = readlinkat(dirfd, name, [])?;
         let link_bytes = link_content.to_bytes();
         let link_size = link_bytes.len();
+        let hex_size = 2 * std::mem::size_of::<D>();
         // XXX: check correctness of leading ../?
         // XXX: or is that something for fsck?
-        if link_size > 64 {
-            let mut value = Sha256HashValue::EMPTY;
-            hex::decode_to_slice(&link_bytes[link_size - 64..link_size], &mut value)?;
-            Ok(value)
+        if link_size > hex_size {
+            D::from_hex(&link_bytes[link_size - hex_size..link_size])
         } else {
             bail!("symlink has wrong format")
         }
     }
 
-    fn walk_symlinkdir(fd: OwnedFd, objects: &mut HashSet<Sha256HashValue>) -> Result<()> {
+    /// Collects the targets of the symlinks under `fd` (which is `path` in the repository).
+    /// Anything that isn't a symlink to an image or stream is added to `invalid` and otherwise
+    /// left alone: ref namespaces are writable by their users, so they mustn't be able to make
+    /// garbage collection fail.
+    fn walk_symlinkdir<D: FsVerityHashValue>(
+        fd: OwnedFd,
+        path: &str,
+        objects: &mut HashSet<D>,
+        invalid: &mut Vec<String>,
+    ) -> Result<()> {
         for item in Dir::read_from(&fd)? {
             let entry = item?;
+            let filename = entry.file_name();
+            if filename == c"." || filename == c".." {
+                continue;
+            }
+            let entry_path = format!("{path}/{}", filename.to_string_lossy());
+
             // NB: the underlying filesystem must support returning filetype via direntry
             // that's a reasonable assumption, since it must also support fsverity...
             match entry.file_type() {
                 FileType::Directory => {
-                    let filename = entry.file_name();
-                    if filename != c"." && filename != c".." {
-                        let dirfd = openat(&fd, filename, OFlags::RDONLY, Mode::empty())?;
-                        Repository::walk_symlinkdir(dirfd, objects)?;
-                    }
-                }
-                FileType::Symlink => {
-                    objects.insert(Repository::read_symlink_hashvalue(&fd, entry.file_name())?);
-                }
-                _ => {
-                    bail!("Unexpected file type encountered");
+                    let dirfd = openat(
+                        &fd,
+                        filename,
+                        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW,
+                        Mode::empty(),
+                    )?;
+                    Self::walk_symlinkdir(dirfd, &entry_path, objects, invalid)?;
                 }
+                FileType::Symlink => match Self::read_symlink_hashvalue(&fd, filename) {
+                    Ok(value) => {
+                        objects.insert(value);
+                    }
+                    Err(_) => invalid.push(entry_path),
+                },
+                _ => invalid.push(entry_path),
             }
         }
 
@@ -471,18 +2310,42 @@ impl Repository {
         )
     }
 
-    fn gc_category(&self, category: &str) -> Result<HashSet<Sha256HashValue>> {
-        let mut objects = HashSet::<Sha256HashValue>::new();
+    /// Returns the list of objects referred to by the composefs image with the given object ID.
+    fn image_objects(&self, id: &ObjectID) -> Result<Vec<ObjectID>> {
+        let image = Image::read(File::from(self.open_object(id)?))?;
+        Ok(collect_objects(&image)?.into_iter().collect())
+    }
+
+    fn gc_category<D: FsVerityHashValue>(
+        &self,
+        category: &str,
+        report: &mut GcReport,
+    ) -> Result<HashSet<D>> {
+        let mut objects = HashSet::new();
+
+        let path = format!("{category}/refs");
+        let refs = match self.openat(&path, OFlags::RDONLY | OFlags::DIRECTORY) {
+            Ok(fd) => fd,
+            Err(Errno::NOENT) => return Ok(objects),
+            Err(err) => Err(err)?,
+        };
+        Self::walk_symlinkdir(refs, &path, &mut objects, &mut report.invalid)?;
 
-        let category_fd = self.openat(category, OFlags::RDONLY | OFlags::DIRECTORY)?;
+        Ok(objects)
+    }
 
-        let refs = openat(
-            &category_fd,
-            "refs",
-            OFlags::RDONLY | OFlags::DIRECTORY,
-            Mode::empty(),
-        )?;
-        Repository::walk_symlinkdir(refs, &mut objects)?;
+    fn gc_prune_category<D: FsVerityHashValue>(
+        &self,
+        category: &str,
+        live: &HashSet<D>,
+        dry_run: bool,
+        report: &mut GcReport,
+    ) -> Result<()> {
+        let category_fd = match self.openat(category, OFlags::RDONLY | OFlags::DIRECTORY) {
+            Ok(fd) => fd,
+            Err(Errno::NOENT) => return Ok(()),
+            Err(err) => Err(err)?,
+        };
 
         for item in Dir::read_from(&category_fd)? {
             let entry = item?;
@@ -491,87 +2354,460 @@ impl Repository {
                 if entry.file_type() != FileType::Symlink {
                     bail!("category directory contains non-symlink");
                 }
-                let mut value = Sha256HashValue::EMPTY;
-                hex::decode_to_slice(filename.to_bytes(), &mut value)?;
+                let value = D::from_hex(filename.to_bytes())?;
+
+                if !live.contains(&value) {
+                    report
+                        .removed
+                        .push(format!("{category}/{}", filename.to_string_lossy()));
+                    if !dry_run {
+                        unlinkat(&category_fd, filename, AtFlags::empty())?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the journals of the transactions which are in progress and returns everything
+    /// recorded in them.  The journals of abandoned transactions (whose process died without
+    /// ending them) are removed.
+    fn gc_transactions(
+        &self,
+        dry_run: bool,
+        report: &mut GcReport,
+    ) -> Result<Vec<JournalEntry<ObjectID>>> {
+        let dirfd = match self.openat("transactions", OFlags::RDONLY | OFlags::DIRECTORY) {
+            Ok(fd) => fd,
+            Err(Errno::NOENT) => return Ok(vec![]),
+            Err(err) => Err(err)?,
+        };
+
+        let mut entries = vec![];
+        for item in Dir::read_from(&dirfd)? {
+            let entry = item?;
+            let filename = entry.file_name();
+            if filename == c"." || filename == c".." {
+                continue;
+            }
+
+            let fd = openat(
+                &dirfd,
+                filename,
+                OFlags::RDONLY | OFlags::CLOEXEC,
+                Mode::empty(),
+            )?;
+            match flock(&fd, FlockOperation::NonBlockingLockExclusive) {
+                Err(Errno::WOULDBLOCK) => {
+                    // In progress.  The last line might be incomplete: skip anything we can't parse.
+                    for line in BufReader::new(File::from(fd)).lines() {
+                        let line = line?;
+                        let entry = match line.split_once(' ') {
+                            Some(("object", hex)) => {
+                                ObjectID::from_hex(hex).map(JournalEntry::Object)
+                            }
+                            Some(("stream", hex)) => {
+                                Sha256HashValue::from_hex(hex).map(JournalEntry::Stream)
+                            }
+                            Some(("image", hex)) => {
+                                ObjectID::from_hex(hex).map(JournalEntry::Image)
+                            }
+                            _ => continue,
+                        };
+                        entries.extend(entry.ok());
+                    }
+                }
+                Ok(()) => {
+                    report
+                        .removed
+                        .push(format!("transactions/{}", filename.to_string_lossy()));
+                    if !dry_run {
+                        unlinkat(&dirfd, filename, AtFlags::empty())?;
+                    }
+                }
+                Err(err) => Err(err)?,
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn gc_objects(
+        &self,
+        objects: &HashSet<ObjectID>,
+        dry_run: bool,
+        report: &mut GcReport,
+    ) -> Result<()> {
+        for first_byte in 0x0..=0xff {
+            let dirname = format!("objects/{first_byte:02x}");
+            let dirfd = match self.openat(&dirname, OFlags::RDONLY | OFlags::DIRECTORY) {
+                Ok(fd) => fd,
+                Err(Errno::NOENT) => continue,
+                Err(err) => Err(err)?,
+            };
+
+            for item in Dir::read_from(&dirfd)? {
+                let entry = item?;
+                let filename = entry.file_name();
+                if filename == c"." || filename == c".." || entry.file_type() == FileType::Directory
+                {
+                    continue;
+                }
+
+                // Anything that isn't a regular file with a well-formed name is debris (from an
+                // interrupted write, for example) and gets removed along with unreferenced objects.
+                let mut value = ObjectID::EMPTY;
+                value.as_mut()[0] = first_byte;
+                let is_object = entry.file_type() == FileType::RegularFile
+                    && hex::decode_to_slice(filename.to_bytes(), &mut value.as_mut()[1..]).is_ok();
+                if is_object && objects.contains(&value) {
+                    continue;
+                }
 
-                if !objects.contains(&value) {
-                    println!("rm {}/{:?}", category, filename);
+                let size = statat(&dirfd, filename, AtFlags::SYMLINK_NOFOLLOW)?.st_size as u64;
+                if is_object {
+                    report.objects_removed += 1;
+                }
+                report.bytes_freed += size;
+                report
+                    .removed
+                    .push(format!("{dirname}/{}", filename.to_string_lossy()));
+                if !dry_run {
+                    unlinkat(&dirfd, filename, AtFlags::empty())?;
                 }
             }
         }
 
-        Ok(objects)
+        Ok(())
     }
 
-    pub fn gc(&self) -> Result<()> {
-        flock(&self.repository, FlockOperation::LockExclusive)?;
+    /// Deletes everything in the repository which isn't reachable from a ref.
+    ///
+    /// Images named in `images/refs/` and streams named in `streams/refs/` are live, as are any
+    /// streams referred to from the digest map of a live stream.  Every object used by a live
+    /// image or stream is kept.  Everything else is removed: unreferenced objects, the symlinks
+    /// in `images/` and `streams/` for unreferenced images and streams, and any debris in the
+    /// object directories.
+    ///
+    /// Everything written during a transaction which is still in progress (see
+    /// [`Repository::begin_transaction`]) is live as well.
+    ///
+    /// If `dry_run` is set then nothing is deleted, but the report describes what would have been.
+    ///
+    /// The exclusive lock needed for garbage collection is waited for as specified by
+    /// [`OpenOptions::lock_wait`] when the repository was opened.
+    pub fn gc(&self, dry_run: bool) -> Result<GcReport> {
+        let lock = self.lock_exclusive()?;
 
         let mut objects = HashSet::new();
+        let mut report = GcReport::default();
 
-        for ref object in self.gc_category("images")? {
-            println!("{} lives as an image", hex::encode(object));
-            objects.insert(*object);
-
-            // composefs-info mmaps the file, so pipes aren't normally OK but we pass the
-            // underlying file directly, which works.
-            let output = Command::new("composefs-info")
-                .stdin(File::from(self.open_object(object)?))
-                .args(["objects", "/proc/self/fd/0"])
-                .output()?
-                .stdout;
+        let mut images = self.gc_category::<ObjectID>("images", &mut report)?;
+        let mut todo = Vec::from_iter(self.gc_category::<Sha256HashValue>("streams", &mut report)?);
 
-            if output.len() % 66 != 0 {
-                bail!("composefs-info gave invalid output (wrong size)");
+        for entry in self.gc_transactions(dry_run, &mut report)? {
+            match entry {
+                JournalEntry::Object(id) => {
+                    objects.insert(id);
+                }
+                JournalEntry::Image(id) => {
+                    images.insert(id);
+                }
+                // The journal entry is written before the stream is linked into place
+                JournalEntry::Stream(sha256) => {
+                    if self.has_stream(&sha256)?.is_some() {
+                        todo.push(sha256);
+                    }
+                }
             }
+        }
+
+        for object in &images {
+            objects.insert(*object);
 
-            for line in output.chunks_exact(66) {
-                if line[2] != b'/' || line[65] != b'\n' {
-                    bail!("composefs-info gave invalid output");
+            match self.image_objects(object) {
+                Ok(ids) => objects.extend(ids),
+                Err(Error::ObjectMissing { .. }) => {
+                    report
+                        .dangling
+                        .push(format!("images/{}", hex::encode(object)));
                 }
-                let mut value = Sha256HashValue::EMPTY;
-                hex::decode_to_slice(&line[0..2], &mut value[0..1])?;
-                hex::decode_to_slice(&line[3..65], &mut value[1..32])?;
-                println!("    with {}", hex::encode(value));
-                objects.insert(value);
+                Err(err) => return Err(err),
             }
         }
 
-        for object in self.gc_category("streams")? {
-            println!("{} lives as a stream", hex::encode(object));
-            objects.insert(object);
+        // Streams are named by their content hash and can refer to other streams, so we need to
+        // walk the graph to find all of the live ones.
+        let mut streams = HashSet::new();
+        while let Some(sha256) = todo.pop() {
+            if !streams.insert(sha256) {
+                continue;
+            }
+
+            let name = hex::encode(sha256);
+            let Some(object_id) = self.has_stream(&sha256)? else {
+                report.dangling.push(format!("streams/{name}"));
+                continue;
+            };
+            objects.insert(object_id);
 
-            let mut split_stream = self.open_stream(&hex::encode(object), None)?;
+            let mut split_stream = self.open_stream(&name, None)?;
+            split_stream.get_stream_refs(|body| todo.push(*body));
             split_stream.get_object_refs(|id| {
-                println!("   with {}", hex::encode(*id));
                 objects.insert(*id);
             })?;
         }
 
+        self.gc_prune_category("images", &images, dry_run, &mut report)?;
+        self.gc_prune_category("streams", &streams, dry_run, &mut report)?;
+        self.gc_objects(&objects, dry_run, &mut report)?;
+
+        lock.downgrade()?;
+        Ok(report)
+    }
+
+    fn fsck_objects(&self, report: &mut FsckReport) -> Result<HashSet<ObjectID>> {
+        let mut objects = HashSet::new();
+
         for first_byte in 0x0..=0xff {
-            let dirfd = self.openat(
-                &format!("objects/{first_byte:02x}"),
-                OFlags::RDONLY | OFlags::DIRECTORY,
-            )?;
-            for item in Dir::new(dirfd)? {
+            let dirname = format!("objects/{first_byte:02x}");
+            let dirfd = match self.openat(&dirname, OFlags::RDONLY | OFlags::DIRECTORY) {
+                Ok(fd) => fd,
+                Err(Errno::NOENT) => continue,
+                Err(err) => Err(err)?,
+            };
+
+            for item in Dir::read_from(&dirfd)? {
                 let entry = item?;
                 let filename = entry.file_name();
-                if filename != c"." && filename != c".." {
-                    let mut value = Sha256HashValue::EMPTY;
-                    value[0] = first_byte;
-                    hex::decode_to_slice(filename.to_bytes(), &mut value[1..])?;
-                    if !objects.contains(&value) {
-                        println!("rm objects/{first_byte:02x}/{filename:?}");
-                    } else {
-                        println!("# objects/{first_byte:02x}/{filename:?} lives");
+                if filename == c"." || filename == c".." {
+                    continue;
+                }
+                let path = format!("{dirname}/{}", filename.to_string_lossy());
+
+                let mut value = ObjectID::EMPTY;
+                value.as_mut()[0] = first_byte;
+                if entry.file_type() != FileType::RegularFile
+                    || hex::decode_to_slice(filename.to_bytes(), &mut value.as_mut()[1..]).is_err()
+                {
+                    report.add(FsckCategory::Object, path, "not an object");
+                    continue;
+                }
+
+                let measured = openat(
+                    &dirfd,
+                    filename,
+                    OFlags::RDONLY | OFlags::CLOEXEC,
+                    Mode::empty(),
+                )
+                .map_err(Error::from)
+                .and_then(|fd| self.measure_verity(fd));
+                match measured {
+                    Ok(measured) if measured == value => {
+                        objects.insert(value);
+                    }
+                    Ok(measured) => report.add(
+                        FsckCategory::Object,
+                        path,
+                        format!("measured fs-verity digest is {}", hex::encode(measured)),
+                    ),
+                    Err(err) => report.add(
+                        FsckCategory::Object,
+                        path,
+                        format!("unable to measure fs-verity digest: {err:#}"),
+                    ),
+                }
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// Checks the images or streams of the repository.  `objects` holds the valid objects found
+    /// so far: objects from alternates are added as they're checked.
+    fn fsck_category(
+        &self,
+        category: &str,
+        objects: &mut HashSet<ObjectID>,
+        report: &mut FsckReport,
+    ) -> Result<()> {
+        let category_fd = match self.openat(category, OFlags::RDONLY | OFlags::DIRECTORY) {
+            Ok(fd) => fd,
+            Err(Errno::NOENT) => return Ok(()),
+            Err(err) => Err(err)?,
+        };
+
+        for item in Dir::read_from(&category_fd)? {
+            let entry = item?;
+            let filename = entry.file_name();
+            if filename == c"refs" || filename == c"." || filename == c".." {
+                continue;
+            }
+            let name = filename.to_string_lossy();
+            let path = format!("{category}/{name}");
+
+            // images are named by their fs-verity digest and streams by their sha256
+            let digest_size = if category == "images" {
+                std::mem::size_of::<ObjectID>()
+            } else {
+                std::mem::size_of::<Sha256HashValue>()
+            };
+            let value = match hex::decode(filename.to_bytes()) {
+                Ok(value)
+                    if entry.file_type() == FileType::Symlink && value.len() == digest_size =>
+                {
+                    value
+                }
+                _ => {
+                    report.add(FsckCategory::Symlink, path, "not a digest symlink");
+                    continue;
+                }
+            };
+
+            let target = readlinkat(&category_fd, filename, [])?;
+            let parsed = match target.as_bytes().strip_prefix(b"../") {
+                Some(object_path) => Self::parse_object_path(object_path),
+                None => Err(anyhow::anyhow!("symlink has incorrect prefix").into()),
+            };
+            let id = match parsed {
+                Ok(id) => id,
+                Err(err) => {
+                    report.add(FsckCategory::Symlink, path, err);
+                    continue;
+                }
+            };
+            if !objects.contains(&id) {
+                report.add(
+                    FsckCategory::Symlink,
+                    path,
+                    format!("target object {} is missing or corrupt", hex::encode(id)),
+                );
+                continue;
+            }
+
+            let referenced = if category == "images" {
+                if id.as_ref() != value {
+                    report.add(
+                        FsckCategory::Image,
+                        &path,
+                        "symlink points at the wrong object",
+                    );
+                }
+                self.image_objects(&id)
+            } else {
+                // This is the expensive check: it reassembles the stream (and all of the streams
+                // it refers to) and verifies the sha256 of the content.
+                match self.check_stream(&Sha256HashValue::from_digest(&value)) {
+                    Ok(Some(measured)) if measured == id => {}
+                    Ok(_) => report.add(FsckCategory::Stream, &path, "stream changed during check"),
+                    Err(err) => report.add(FsckCategory::Stream, &path, format!("{err:#}")),
+                }
+                self.open_stream(&name, Some(&id)).and_then(|mut stream| {
+                    let mut referenced = vec![];
+                    stream.get_object_refs(|id| referenced.push(*id))?;
+                    Ok(referenced)
+                })
+            };
+
+            match referenced {
+                Ok(referenced) => {
+                    for object in referenced {
+                        if objects.contains(&object) {
+                            continue;
+                        }
+                        // not here, so it should be in one of the alternates
+                        match self.open_object(&object) {
+                            Ok(_) => {
+                                objects.insert(object);
+                            }
+                            Err(Error::ObjectMissing { .. }) => report.add(
+                                FsckCategory::MissingObject,
+                                &path,
+                                format!("refers to missing object {}", hex::encode(object)),
+                            ),
+                            Err(err) => report.add(
+                                FsckCategory::MissingObject,
+                                &path,
+                                format!("refers to object {}: {err:#}", hex::encode(object)),
+                            ),
+                        }
                     }
                 }
+                Err(err) => report.add(
+                    if category == "images" {
+                        FsckCategory::Image
+                    } else {
+                        FsckCategory::Stream
+                    },
+                    &path,
+                    format!("unable to list referenced objects: {err:#}"),
+                ),
             }
         }
 
-        Ok(flock(&self.repository, FlockOperation::LockShared)?) // XXX: finally { } ?
+        Ok(())
     }
 
-    pub fn fsck(&self) -> Result<()> {
+    fn fsck_refs(&self, dirfd: &OwnedFd, path: &str, report: &mut FsckReport) -> Result<()> {
+        for item in Dir::read_from(dirfd)? {
+            let entry = item?;
+            let filename = entry.file_name();
+            if filename == c"." || filename == c".." {
+                continue;
+            }
+            let path = format!("{path}/{}", filename.to_string_lossy());
+
+            match entry.file_type() {
+                FileType::Directory => {
+                    let fd = openat(
+                        dirfd,
+                        filename,
+                        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW,
+                        Mode::empty(),
+                    )?;
+                    self.fsck_refs(&fd, &path, report)?;
+                }
+                FileType::Symlink => {
+                    // follows the entire chain of symlinks down to the object
+                    if let Err(err) = statat(dirfd, filename, AtFlags::empty()) {
+                        report.add(FsckCategory::Ref, path, format!("does not resolve: {err}"));
+                    }
+                }
+                _ => report.add(FsckCategory::Ref, path, "not a symlink"),
+            }
+        }
+
         Ok(())
     }
+
+    /// Checks the integrity of the entire repository.
+    ///
+    /// This measures the fs-verity digest of every object, checks that every symlink in
+    /// `images/` and `streams/` (and every ref) resolves, verifies the content hash of every
+    /// stream, and makes sure that every object referred to by an image or stream exists.  Objects
+    /// which are only in an alternate are checked too, but the rest of the alternate isn't.
+    ///
+    /// Problems with the repository content are collected in the returned report.  An `Err` is
+    /// only returned if the check itself couldn't be performed.
+    pub fn fsck(&self) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+
+        let mut objects = self.fsck_objects(&mut report)?;
+
+        for category in ["images", "streams"] {
+            self.fsck_category(category, &mut objects, &mut report)?;
+
+            match self.openat(
+                &format!("{category}/refs"),
+                OFlags::RDONLY | OFlags::DIRECTORY,
+            ) {
+                Ok(fd) => self.fsck_refs(&fd, &format!("{category}/refs"), &mut report)?,
+                Err(Errno::NOENT) => {}
+                Err(err) => Err(err)?,
+            }
+        }
+
+        Ok(report)
+    }
 }
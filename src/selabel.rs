@@ -7,10 +7,12 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{bail, ensure, Context, Result};
+use anyhow::Context;
 use regex_automata::{hybrid::dfa, util::syntax, Anchored, Input};
 
 use crate::{
+    error::{bail, ensure, Result},
+    fsverity::FsVerityHashValue,
     image::{DirEnt, Directory, FileSystem, Inode, Leaf, LeafContent, Stat},
     repository::Repository,
 };
@@ -105,10 +107,10 @@ struct Policy {
     contexts: Vec<String>,
 }
 
-pub fn openat<'a>(
-    dir: &'a Directory,
+pub fn openat<'a, ObjectID: FsVerityHashValue>(
+    dir: &'a Directory<ObjectID>,
     filename: impl AsRef<OsStr>,
-    repo: &Repository,
+    repo: &Repository<ObjectID>,
 ) -> Result<Option<Box<dyn Read + 'a>>> {
     let Ok(idx) = dir.find_entry(filename.as_ref()) else {
         return Ok(None);
@@ -127,7 +129,10 @@ pub fn openat<'a>(
 }
 
 impl Policy {
-    pub fn build(dir: &Directory, repo: &Repository) -> Result<Self> {
+    pub fn build<ObjectID: FsVerityHashValue>(
+        dir: &Directory<ObjectID>,
+        repo: &Repository<ObjectID>,
+    ) -> Result<Self> {
         let mut aliases = HashMap::new();
         let mut regexps = vec![];
         let mut contexts = vec![];
@@ -209,7 +214,11 @@ fn relabel(stat: &Stat, path: &Path, ifmt: u8, policy: &mut Policy) {
     }
 }
 
-fn relabel_leaf(leaf: &Leaf, path: &Path, policy: &mut Policy) {
+fn relabel_leaf<ObjectID: FsVerityHashValue>(
+    leaf: &Leaf<ObjectID>,
+    path: &Path,
+    policy: &mut Policy,
+) {
     let ifmt = match leaf.content {
         LeafContent::InlineFile(..) | LeafContent::ExternalFile(..) => b'-',
         LeafContent::Fifo => b'p', // NB: 'pipe', not 'fifo'
@@ -221,14 +230,22 @@ fn relabel_leaf(leaf: &Leaf, path: &Path, policy: &mut Policy) {
     relabel(&leaf.stat, path, ifmt, policy);
 }
 
-fn relabel_inode(inode: &Inode, path: &mut PathBuf, policy: &mut Policy) {
+fn relabel_inode<ObjectID: FsVerityHashValue>(
+    inode: &Inode<ObjectID>,
+    path: &mut PathBuf,
+    policy: &mut Policy,
+) {
     match inode {
         Inode::Directory(ref dir) => relabel_dir(dir, path, policy),
         Inode::Leaf(ref leaf) => relabel_leaf(leaf, path, policy),
     }
 }
 
-fn relabel_dir(dir: &Directory, path: &mut PathBuf, policy: &mut Policy) {
+fn relabel_dir<ObjectID: FsVerityHashValue>(
+    dir: &Directory<ObjectID>,
+    path: &mut PathBuf,
+    policy: &mut Policy,
+) {
     relabel(&dir.stat, path, b'd', policy);
 
     for DirEnt { name, inode } in dir.entries.iter() {
@@ -245,7 +262,7 @@ fn parse_config(file: impl Read) -> Result<Option<String>> {
     for line in BufReader::new(file).lines() {
         if let Some((key, value)) = line?.split_once('=') {
             // this might be a comment, but then key will start with '#'
-            if key.trim().to_ascii_uppercase() == "SELINUXTYPE" {
+            if key.trim().eq_ignore_ascii_case("SELINUXTYPE") {
                 return Ok(Some(value.trim().to_string()));
             }
         }
@@ -253,7 +270,10 @@ fn parse_config(file: impl Read) -> Result<Option<String>> {
     Ok(None)
 }
 
-pub fn selabel(fs: &mut FileSystem, repo: &Repository) -> Result<()> {
+pub fn selabel<ObjectID: FsVerityHashValue>(
+    fs: &mut FileSystem<ObjectID>,
+    repo: &Repository<ObjectID>,
+) -> Result<()> {
     // if /etc/selinux/config doesn't exist then it's not an error
     let Ok(etc) = fs.root.recurse("etc") else {
         return Ok(());
@@ -5,44 +5,44 @@
 
 use std::io::{BufReader, Read, Write};
 
-use anyhow::{bail, Result};
 use sha2::{Digest, Sha256};
 use zstd::stream::{read::Decoder, write::Encoder};
 
 use crate::{
+    error::{bail, Error, Result},
     fsverity::{FsVerityHashValue, Sha256HashValue},
-    repository::Repository,
+    repository::{ObjectWriter, Repository},
     util::read_exactish,
 };
 
-pub struct DigestMapEntry {
+pub struct DigestMapEntry<ObjectID: FsVerityHashValue> {
     pub body: Sha256HashValue,
-    pub verity: Sha256HashValue,
+    pub verity: ObjectID,
 }
 
-pub struct DigestMap {
-    pub map: Vec<DigestMapEntry>,
+pub struct DigestMap<ObjectID: FsVerityHashValue> {
+    pub map: Vec<DigestMapEntry<ObjectID>>,
 }
 
-impl Default for DigestMap {
+impl<ObjectID: FsVerityHashValue> Default for DigestMap<ObjectID> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl DigestMap {
+impl<ObjectID: FsVerityHashValue> DigestMap<ObjectID> {
     pub fn new() -> Self {
         DigestMap { map: vec![] }
     }
 
-    pub fn lookup(&self, body: &Sha256HashValue) -> Option<&Sha256HashValue> {
+    pub fn lookup(&self, body: &Sha256HashValue) -> Option<&ObjectID> {
         match self.map.binary_search_by_key(body, |e| e.body) {
             Ok(idx) => Some(&self.map[idx].verity),
             Err(..) => None,
         }
     }
 
-    pub fn insert(&mut self, body: &Sha256HashValue, verity: &Sha256HashValue) {
+    pub fn insert(&mut self, body: &Sha256HashValue, verity: &ObjectID) {
         match self.map.binary_search_by_key(body, |e| e.body) {
             Ok(idx) => assert_eq!(self.map[idx].verity, *verity), // or else, bad things...
             Err(idx) => self.map.insert(
@@ -56,19 +56,44 @@ impl DigestMap {
     }
 }
 
-pub struct SplitStreamWriter<'a> {
-    repo: &'a Repository,
+/// External objects passed to write_external() are queued up and stored in parallel once there
+/// are this many of them...
+const BATCH_OBJECTS: usize = 1024;
+/// ...or once they add up to this many bytes.
+const BATCH_SIZE: usize = 64 << 20;
+
+/// A fragment of the stream which hasn't been encoded yet because it comes after (or is) an
+/// external object which hasn't been stored yet
+enum QueuedFragment<ObjectID: FsVerityHashValue> {
+    Inline(Vec<u8>),
+    Reference(ObjectID),
+    /// An index into the pending objects
+    Pending(usize),
+}
+
+pub struct SplitStreamWriter<'a, ObjectID: FsVerityHashValue> {
+    repo: &'a Repository<ObjectID>,
     inline_content: Vec<u8>,
     writer: Encoder<'a, Vec<u8>>,
+    /// The external object currently being written with write_external_chunk()
+    external: Option<ObjectWriter<'a, ObjectID>>,
+    /// The content of external objects which are waiting to be stored
+    pending: Vec<Vec<u8>>,
+    pending_size: usize,
+    /// The fragments of the stream which are waiting for the pending objects to be stored, in
+    /// order
+    queue: Vec<QueuedFragment<ObjectID>>,
+    /// Set when objects have been stored without syncing them
+    unsynced: bool,
     pub sha256: Option<(Sha256, Sha256HashValue)>,
 }
 
-impl SplitStreamWriter<'_> {
+impl<'a, ObjectID: FsVerityHashValue> SplitStreamWriter<'a, ObjectID> {
     pub fn new(
-        repo: &Repository,
-        refs: Option<DigestMap>,
+        repo: &'a Repository<ObjectID>,
+        refs: Option<DigestMap<ObjectID>>,
         sha256: Option<Sha256HashValue>,
-    ) -> SplitStreamWriter {
+    ) -> Self {
         // SAFETY: we surely can't get an error writing the header to a Vec<u8>
         let mut writer = Encoder::new(vec![], 0).unwrap();
 
@@ -77,7 +102,7 @@ impl SplitStreamWriter<'_> {
                 writer.write_all(&(map.len() as u64).to_le_bytes()).unwrap();
                 for ref entry in map {
                     writer.write_all(&entry.body).unwrap();
-                    writer.write_all(&entry.verity).unwrap();
+                    writer.write_all(entry.verity.as_ref()).unwrap();
                 }
             }
             None => {
@@ -85,10 +110,15 @@ impl SplitStreamWriter<'_> {
             }
         }
 
-        SplitStreamWriter {
+        Self {
             repo,
             inline_content: vec![],
             writer,
+            external: None,
+            pending: vec![],
+            pending_size: 0,
+            queue: vec![],
+            unsynced: false,
             sha256: sha256.map(|x| (Sha256::new(), x)),
         }
     }
@@ -101,12 +131,40 @@ impl SplitStreamWriter<'_> {
     /// flush any buffered inline data, taking new_value as the new value of the buffer
     fn flush_inline(&mut self, new_value: Vec<u8>) -> Result<()> {
         if !self.inline_content.is_empty() {
-            SplitStreamWriter::write_fragment(
-                &mut self.writer,
-                self.inline_content.len(),
-                &self.inline_content,
-            )?;
-            self.inline_content = new_value;
+            let content = std::mem::replace(&mut self.inline_content, new_value);
+            if self.queue.is_empty() {
+                Self::write_fragment(&mut self.writer, content.len(), &content)?;
+            } else {
+                self.queue.push(QueuedFragment::Inline(content));
+            }
+        }
+        Ok(())
+    }
+
+    /// store all of the pending external objects (in parallel) and write out the queued
+    /// fragments, now that we know the IDs of the objects
+    fn flush_queue(&mut self) -> Result<()> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+
+        let ids = self.repo.ensure_objects_unsynced(&self.pending)?;
+        self.unsynced = true;
+        self.pending.clear();
+        self.pending_size = 0;
+
+        for fragment in std::mem::take(&mut self.queue) {
+            match fragment {
+                QueuedFragment::Inline(content) => {
+                    Self::write_fragment(&mut self.writer, content.len(), &content)?
+                }
+                QueuedFragment::Reference(id) => {
+                    Self::write_fragment(&mut self.writer, 0, id.as_ref())?
+                }
+                QueuedFragment::Pending(idx) => {
+                    Self::write_fragment(&mut self.writer, 0, ids[idx].as_ref())?
+                }
+            }
         }
         Ok(())
     }
@@ -123,45 +181,103 @@ impl SplitStreamWriter<'_> {
     /// write a reference to external data to the stream.  If the external data had padding in the
     /// stream which is not stored in the object then pass it here as well and it will be stored
     /// inline after the reference.
-    fn write_reference(&mut self, reference: Sha256HashValue, padding: Vec<u8>) -> Result<()> {
+    fn write_reference(&mut self, reference: ObjectID, padding: Vec<u8>) -> Result<()> {
         // Flush the inline data before we store the external reference.  Any padding from the
         // external data becomes the start of a new inline block.
         self.flush_inline(padding)?;
 
-        SplitStreamWriter::write_fragment(&mut self.writer, 0, &reference)
+        if self.queue.is_empty() {
+            Self::write_fragment(&mut self.writer, 0, reference.as_ref())
+        } else {
+            self.queue.push(QueuedFragment::Reference(reference));
+            Ok(())
+        }
     }
 
+    /// write external data to the stream.  The object isn't necessarily stored right away:
+    /// objects are collected into batches which are hashed and written in parallel.  The
+    /// resulting stream is the same either way.
     pub fn write_external(&mut self, data: &[u8], padding: Vec<u8>) -> Result<()> {
         if let Some((ref mut sha256, ..)) = self.sha256 {
             sha256.update(data);
             sha256.update(&padding);
         }
-        let id = self.repo.ensure_object(data)?;
+        self.flush_inline(padding)?;
+
+        self.queue.push(QueuedFragment::Pending(self.pending.len()));
+        self.pending.push(data.to_vec());
+        self.pending_size += data.len();
+
+        if self.pending.len() >= BATCH_OBJECTS || self.pending_size >= BATCH_SIZE {
+            self.flush_queue()?;
+        }
+        Ok(())
+    }
+
+    /// write part of the content of an external object to the stream, for content which is too
+    /// large to hold in memory at once.  Call this as many times as needed and then call
+    /// .finish_external() to store the object and write the reference.
+    pub fn write_external_chunk(&mut self, data: &[u8]) -> Result<()> {
+        if let Some((ref mut sha256, ..)) = self.sha256 {
+            sha256.update(data);
+        }
+        let external = match self.external {
+            Some(ref mut external) => external,
+            None => self.external.insert(self.repo.create_object_writer()?),
+        };
+        Ok(external.write_all(data)?)
+    }
+
+    /// finish the external object started with .write_external_chunk().  The padding is handled
+    /// as for .write_external().
+    pub fn finish_external(&mut self, padding: Vec<u8>) -> Result<()> {
+        let Some(external) = self.external.take() else {
+            bail!("No external object is being written");
+        };
+        if let Some((ref mut sha256, ..)) = self.sha256 {
+            sha256.update(&padding);
+        }
+        let id = external.finish_unsynced()?;
+        self.unsynced = true;
         self.write_reference(id, padding)
     }
 
-    pub fn done(mut self) -> Result<Sha256HashValue> {
+    pub fn done(mut self) -> Result<ObjectID> {
+        if self.external.is_some() {
+            bail!("Unfinished external object in split stream");
+        }
         self.flush_inline(vec![])?;
+        self.flush_queue()?;
 
         if let Some((context, expected)) = self.sha256 {
-            if Into::<Sha256HashValue>::into(context.finalize()) != expected {
-                bail!("Content doesn't have expected SHA256 hash value!");
+            let actual: Sha256HashValue = context.finalize().into();
+            if actual != expected {
+                return Err(Error::ContentMismatch {
+                    expected: hex::encode(expected),
+                    actual: hex::encode(actual),
+                    context: vec![],
+                });
             }
         }
 
-        self.repo.ensure_object(&self.writer.finish()?)
+        // The objects referred to by the stream must be on disk before the stream is written
+        if self.unsynced {
+            self.repo.sync()?;
+        }
+
+        self.repo.ensure_local_object(&self.writer.finish()?)
     }
 }
 
-pub enum SplitStreamData {
+pub enum SplitStreamData<ObjectID: FsVerityHashValue> {
     Inline(Vec<u8>),
-    External(Sha256HashValue),
+    External(ObjectID),
 }
 
 // utility class to help read splitstreams
-pub struct SplitStreamReader<R: Read> {
+pub struct SplitStreamReader<R: Read, ObjectID: FsVerityHashValue> {
     decoder: Decoder<'static, BufReader<R>>,
-    pub refs: DigestMap,
+    pub refs: DigestMap<ObjectID>,
     inline_bytes: usize,
 }
 
@@ -184,14 +300,14 @@ fn read_into_vec(reader: &mut impl Read, vec: &mut Vec<u8>, size: usize) -> Resu
     Ok(())
 }
 
-enum ChunkType {
+enum ChunkType<ObjectID: FsVerityHashValue> {
     Eof,
     Inline,
-    External(Sha256HashValue),
+    External(ObjectID),
 }
 
-impl<R: Read> SplitStreamReader<R> {
-    pub fn new(reader: R) -> Result<SplitStreamReader<R>> {
+impl<R: Read, ObjectID: FsVerityHashValue> SplitStreamReader<R, ObjectID> {
+    pub fn new(reader: R) -> Result<Self> {
         let mut decoder = Decoder::new(reader)?;
 
         let n_map_entries = {
@@ -204,15 +320,15 @@ impl<R: Read> SplitStreamReader<R> {
             map: Vec::with_capacity(n_map_entries),
         };
         for _ in 0..n_map_entries {
-            let mut body = [0u8; 32];
-            let mut verity = [0u8; 32];
+            let mut body = Sha256HashValue::EMPTY;
+            let mut verity = ObjectID::EMPTY;
 
             decoder.read_exact(&mut body)?;
-            decoder.read_exact(&mut verity)?;
+            decoder.read_exact(verity.as_mut())?;
             refs.map.push(DigestMapEntry { body, verity });
         }
 
-        Ok(SplitStreamReader {
+        Ok(Self {
             decoder,
             refs,
             inline_bytes: 0,
@@ -224,7 +340,7 @@ impl<R: Read> SplitStreamReader<R> {
         eof_ok: bool,
         ext_ok: bool,
         expected_bytes: usize,
-    ) -> Result<ChunkType> {
+    ) -> Result<ChunkType<ObjectID>> {
         if self.inline_bytes == 0 {
             match read_u64_le(&mut self.decoder)? {
                 None => {
@@ -237,8 +353,8 @@ impl<R: Read> SplitStreamReader<R> {
                     if !ext_ok {
                         bail!("Unexpected external reference when parsing splitstream");
                     }
-                    let mut id = Sha256HashValue::EMPTY;
-                    self.decoder.read_exact(&mut id)?;
+                    let mut id = ObjectID::EMPTY;
+                    self.decoder.read_exact(id.as_mut())?;
                     return Ok(ChunkType::External(id));
                 }
                 Some(size) => {
@@ -279,7 +395,7 @@ impl<R: Read> SplitStreamReader<R> {
         &mut self,
         actual_size: usize,
         stored_size: usize,
-    ) -> Result<SplitStreamData> {
+    ) -> Result<SplitStreamData<ObjectID>> {
         if let ChunkType::External(id) = self.ensure_chunk(false, true, stored_size)? {
             // ...and the padding
             if actual_size < stored_size {
@@ -298,7 +414,7 @@ impl<R: Read> SplitStreamReader<R> {
     pub fn cat(
         &mut self,
         output: &mut impl Write,
-        mut load_data: impl FnMut(&Sha256HashValue) -> Result<Vec<u8>>,
+        mut load_data: impl FnMut(&ObjectID) -> Result<Vec<u8>>,
     ) -> Result<()> {
         let mut buffer = vec![];
 
@@ -317,7 +433,7 @@ impl<R: Read> SplitStreamReader<R> {
         }
     }
 
-    pub fn get_object_refs(&mut self, mut callback: impl FnMut(&Sha256HashValue)) -> Result<()> {
+    pub fn get_object_refs(&mut self, mut callback: impl FnMut(&ObjectID)) -> Result<()> {
         let mut buffer = vec![];
 
         for entry in &self.refs.map {
@@ -344,7 +460,7 @@ impl<R: Read> SplitStreamReader<R> {
         }
     }
 
-    pub fn lookup(&self, body: &Sha256HashValue) -> Result<&Sha256HashValue> {
+    pub fn lookup(&self, body: &Sha256HashValue) -> Result<&ObjectID> {
         match self.refs.lookup(body) {
             Some(id) => Ok(id),
             None => bail!("Reference is not found in splitstream"),
@@ -352,7 +468,7 @@ impl<R: Read> SplitStreamReader<R> {
     }
 }
 
-impl<F: Read> Read for SplitStreamReader<F> {
+impl<F: Read, ObjectID: FsVerityHashValue> Read for SplitStreamReader<F, ObjectID> {
     fn read(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
         match self.ensure_chunk(true, false, 1) {
             Ok(ChunkType::Eof) => Ok(0),
@@ -363,7 +479,7 @@ impl<F: Read> Read for SplitStreamReader<F> {
                 Ok(n_bytes)
             }
             Ok(ChunkType::External(..)) => unreachable!(),
-            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+            Err(e) => Err(std::io::Error::other(e)),
         }
     }
 }
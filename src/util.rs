@@ -1,13 +1,20 @@
 use std::{
     io::Read,
     os::fd::{AsFd, AsRawFd},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
 };
 
-use anyhow::{Context, Result};
+use anyhow::Context;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::fsverity::{FsVerityHashValue, Sha256HashValue};
 
+use crate::error::Result;
+
 pub fn proc_self_fd<A: AsFd>(fd: &A) -> String {
     format!("/proc/self/fd/{}", fd.as_fd().as_raw_fd())
 }
@@ -71,3 +78,50 @@ pub fn parse_sha256(string: impl AsRef<str>) -> Result<Sha256HashValue> {
     hex::decode_to_slice(string.as_ref(), &mut value).context("Invalid SHA256 hash value")?;
     Ok(value)
 }
+
+/// Applies `f` to each of `items` using a pool of worker threads (one per CPU, at most) and
+/// returns the results in the same order as the items.  If any call fails, one of the errors is
+/// returned, and the workers stop picking up new items.
+pub fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    f: impl Fn(&T) -> Result<R> + Sync,
+) -> Result<Vec<R>> {
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(items.len());
+    if workers <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    let failed = Mutex::new(None);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                if idx >= items.len() || failed.lock().unwrap().is_some() {
+                    break;
+                }
+                match f(&items[idx]) {
+                    Ok(result) => results.lock().unwrap()[idx] = Some(result),
+                    Err(err) => {
+                        failed.lock().unwrap().get_or_insert(err);
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    if let Some(err) = failed.into_inner().unwrap() {
+        return Err(err);
+    }
+    Ok(results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect())
+}
@@ -0,0 +1,602 @@
+use std::{io::Read, path::Path};
+
+use anyhow::Result;
+use oci_spec::image::{ImageIndex, MediaType};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use composefs::{
+    fsverity::Sha256HashValue,
+    oci::{self, LayerCompression, Platform, PullOptions},
+    repository::{Repository, RepositoryOptions},
+    splitstream::DigestMap,
+};
+
+fn sha256(data: &[u8]) -> Sha256HashValue {
+    Sha256::digest(data).into()
+}
+
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?)
+}
+
+fn example_layer() -> Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (name, size) in [("small", 10), ("large", 100000)] {
+        let mut header = tar::Header::new_ustar();
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(size);
+        builder.append_data(&mut header, name, std::io::repeat(b'x').take(size))?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// The config of a single-layer image
+fn example_config(layer_sha256: &Sha256HashValue, architecture: &str) -> String {
+    format!(
+        r#"{{"architecture":"{architecture}","os":"linux","config":{{}},"rootfs":{{"type":"layers","diff_ids":["sha256:{}"]}},"history":[]}}"#,
+        hex::encode(layer_sha256)
+    )
+}
+
+/// Stores a single-layer image for `architecture` the way that pulling it would, returning the
+/// digests of the manifest stream and the sha256 of the config
+fn store_image(
+    repo: &Repository<Sha256HashValue>,
+    layer: &[u8],
+    architecture: &str,
+) -> Result<((Sha256HashValue, Sha256HashValue), Sha256HashValue)> {
+    let layer_sha256 = sha256(layer);
+    let layer_id = oci::import_layer(repo, &layer_sha256, None, &mut &layer[..])?;
+    let raw_config = example_config(&layer_sha256, architecture);
+    let config_sha256 = sha256(raw_config.as_bytes());
+    let mut refs = DigestMap::new();
+    refs.insert(&layer_sha256, &layer_id);
+    let mut writer = repo.create_stream(Some(config_sha256), Some(refs));
+    writer.write_inline(raw_config.as_bytes());
+    let config = (config_sha256, repo.write_stream(writer, None)?);
+
+    let raw_manifest = format!(
+        r#"{{"schemaVersion":2,"mediaType":"{}","config":{{"mediaType":"{}","digest":"sha256:{}","size":{}}},"layers":[]}}"#,
+        MediaType::ImageManifest,
+        MediaType::ImageConfig,
+        hex::encode(config_sha256),
+        raw_config.len()
+    );
+    let manifest = oci::write_manifest(repo, raw_manifest.as_bytes(), &config, None)?;
+    Ok((manifest, config_sha256))
+}
+
+/// Compresses `layer` as indicated by `media_type`
+fn compress(layer: &[u8], media_type: &MediaType) -> Result<Vec<u8>> {
+    let mut blob = vec![];
+    match LayerCompression::from_media_type(media_type)? {
+        LayerCompression::None => blob.extend_from_slice(layer),
+        LayerCompression::Gzip => {
+            runtime()?.block_on(
+                async_compression::tokio::bufread::GzipEncoder::new(layer).read_to_end(&mut blob),
+            )?;
+        }
+        LayerCompression::Zstd => blob = zstd::encode_all(layer, 0)?,
+    }
+    Ok(blob)
+}
+
+/// Adds a file to an archive being built
+fn append_file(builder: &mut tar::Builder<Vec<u8>>, name: &str, data: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_ustar();
+    header.set_mode(0o644);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(data.len() as u64);
+    Ok(builder.append_data(&mut header, name, data)?)
+}
+
+/// Adds a blob to an archive being built, returning its descriptor (as JSON).  If `tamper` is set
+/// then the blob doesn't match the descriptor.
+fn append_blob(
+    builder: &mut tar::Builder<Vec<u8>>,
+    media_type: &MediaType,
+    data: &[u8],
+    tamper: bool,
+) -> Result<String> {
+    let digest = hex::encode(sha256(data));
+    let mut content = data.to_vec();
+    if tamper {
+        *content.last_mut().unwrap() ^= 1;
+    }
+    append_file(builder, &format!("blobs/sha256/{digest}"), &content)?;
+    Ok(format!(
+        r#"{{"mediaType":"{media_type}","digest":"sha256:{digest}","size":{}}}"#,
+        data.len()
+    ))
+}
+
+/// Adds the blobs of a single-layer image for `architecture` to an archive being built, with the
+/// layer stored with the given media type (and, if `tamper` is set, corrupted).  Returns the
+/// descriptor of the manifest.
+fn append_image(
+    builder: &mut tar::Builder<Vec<u8>>,
+    layer: &[u8],
+    media_type: &MediaType,
+    tamper: bool,
+    architecture: &str,
+) -> Result<String> {
+    let layer_descriptor = append_blob(builder, media_type, &compress(layer, media_type)?, tamper)?;
+    let config = example_config(&sha256(layer), architecture);
+    let config_descriptor =
+        append_blob(builder, &MediaType::ImageConfig, config.as_bytes(), false)?;
+    let manifest = format!(
+        r#"{{"schemaVersion":2,"mediaType":"{}","config":{config_descriptor},"layers":[{layer_descriptor}]}}"#,
+        MediaType::ImageManifest
+    );
+    append_blob(
+        builder,
+        &MediaType::ImageManifest,
+        manifest.as_bytes(),
+        false,
+    )
+}
+
+/// Writes an oci-archive (a tarball of an OCI image layout) to `path`, with `index.json` referring
+/// to the given manifest (or index) as `latest`
+fn write_archive(path: &Path, mut builder: tar::Builder<Vec<u8>>, descriptor: &str) -> Result<()> {
+    append_file(
+        &mut builder,
+        "oci-layout",
+        br#"{"imageLayoutVersion":"1.0.0"}"#,
+    )?;
+    let index = format!(
+        r#"{{"schemaVersion":2,"manifests":[{},"annotations":{{"org.opencontainers.image.ref.name":"latest"}}}}]}}"#,
+        descriptor.strip_suffix('}').unwrap()
+    );
+    append_file(&mut builder, "index.json", index.as_bytes())?;
+    std::fs::write(path, builder.into_inner()?)?;
+    Ok(())
+}
+
+/// Writes an oci-archive of a single-layer amd64 image to `path`, with the layer stored with the
+/// given media type (and, if `tamper` is set, corrupted)
+fn write_oci_archive(
+    path: &Path,
+    layer: &[u8],
+    media_type: &MediaType,
+    tamper: bool,
+) -> Result<()> {
+    let mut builder = tar::Builder::new(vec![]);
+    let manifest_descriptor = append_image(&mut builder, layer, media_type, tamper, "amd64")?;
+    write_archive(path, builder, &manifest_descriptor)
+}
+
+/// Writes an oci-archive of a multi-platform image to `path`, with a single-layer image for each
+/// of the given architectures
+fn write_multi_platform_archive(path: &Path, layer: &[u8], architectures: &[&str]) -> Result<()> {
+    let mut builder = tar::Builder::new(vec![]);
+    let mut manifests = vec![];
+    for architecture in architectures {
+        let descriptor = append_image(
+            &mut builder,
+            layer,
+            &MediaType::ImageLayer,
+            false,
+            architecture,
+        )?;
+        manifests.push(format!(
+            r#"{},"platform":{{"os":"linux","architecture":"{architecture}"}}}}"#,
+            descriptor.strip_suffix('}').unwrap()
+        ));
+    }
+    let index = format!(
+        r#"{{"schemaVersion":2,"mediaType":"{}","manifests":[{}]}}"#,
+        MediaType::ImageIndex,
+        manifests.join(",")
+    );
+    let index_descriptor = append_blob(
+        &mut builder,
+        &MediaType::ImageIndex,
+        index.as_bytes(),
+        false,
+    )?;
+    write_archive(path, builder, &index_descriptor)
+}
+
+const LAYER_MEDIA_TYPES: [MediaType; 3] = [
+    MediaType::ImageLayer,
+    MediaType::ImageLayerGzip,
+    MediaType::ImageLayerZstd,
+];
+
+#[test]
+fn test_layer_compression() -> Result<()> {
+    let layer = example_layer()?;
+    let layer_sha256 = sha256(&layer);
+
+    // insecure, so that anywhere will do
+    let tmpdir = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    let repo = Repository::<Sha256HashValue>::init(tmpdir.path().join("repo"), &options)?;
+
+    let mut ids = vec![];
+    for media_type in LAYER_MEDIA_TYPES {
+        let blob = compress(&layer, &media_type)?;
+        let decoder = LayerCompression::from_media_type(&media_type)?.decoder(blob.as_slice());
+        let mut writer = repo.create_stream(Some(layer_sha256), None);
+        runtime()?.block_on(oci::tar::split_async(decoder, &mut writer))?;
+        ids.push(repo.write_stream(writer, None)?);
+    }
+    // the same tar stream, whatever the compression
+    assert!(ids.iter().all(|id| *id == ids[0]));
+    assert!(repo.check_stream(&layer_sha256)?.is_some());
+
+    // the docker media type is gzip
+    assert_eq!(
+        LayerCompression::from_media_type(&MediaType::Other(
+            "application/vnd.docker.image.rootfs.diff.tar.gzip".into()
+        ))?,
+        LayerCompression::Gzip
+    );
+    let err = LayerCompression::from_media_type(&MediaType::Other(
+        "application/vnd.oci.image.layer.v1.tar+bzip2".into(),
+    ))
+    .unwrap_err();
+    assert!(err.to_string().contains("tar+bzip2"));
+
+    Ok(())
+}
+
+#[test]
+fn test_manifest() -> Result<()> {
+    let layer = example_layer()?;
+    let layer_sha256 = sha256(&layer);
+
+    let tmpdir = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    let repo = Repository::<Sha256HashValue>::init(tmpdir.path().join("repo"), &options)?;
+
+    // what pull stores: the layer, the config and the manifest
+    let layer_id = oci::import_layer(&repo, &layer_sha256, None, &mut layer.as_slice())?;
+    let raw_config = example_config(&layer_sha256, "amd64");
+    let config_sha256 = sha256(raw_config.as_bytes());
+    let mut refs = DigestMap::new();
+    refs.insert(&layer_sha256, &layer_id);
+    let mut writer = repo.create_stream(Some(config_sha256), Some(refs));
+    writer.write_inline(raw_config.as_bytes());
+    let config = (config_sha256, repo.write_stream(writer, None)?);
+
+    let manifest = |config_digest: &str| {
+        format!(
+            r#"{{"schemaVersion":2,"mediaType":"{}","config":{{"mediaType":"{}","digest":"sha256:{config_digest}","size":{}}},"layers":[],"annotations":{{"org.example":"yes"}}}}"#,
+            MediaType::ImageManifest,
+            MediaType::ImageConfig,
+            raw_config.len()
+        )
+    };
+    let raw_manifest = manifest(&hex::encode(config_sha256));
+    let (manifest_sha256, manifest_id) =
+        oci::write_manifest(&repo, raw_manifest.as_bytes(), &config, Some("image"))?;
+    assert_eq!(manifest_sha256, sha256(raw_manifest.as_bytes()));
+
+    // the manifest has to describe the config
+    let err = oci::write_manifest(&repo, manifest(&"00".repeat(32)).as_bytes(), &config, None)
+        .unwrap_err();
+    assert!(err.is_corruption());
+
+    // the config can be found from the manifest, by digest or by tag, or directly
+    let manifest_hex = hex::encode(manifest_sha256);
+    for name in [
+        manifest_hex.clone(),
+        format!("sha256:{manifest_hex}"),
+        "refs/image".to_string(),
+        hex::encode(config_sha256),
+    ] {
+        let (image_config, refs) = oci::open_config(&repo, &name, None)?;
+        assert_eq!(image_config.os().to_string(), "linux");
+        assert_eq!(refs.lookup(&layer_sha256), Some(&layer_id));
+        oci::open_config_shallow(&repo, &name, None)?;
+    }
+    let (_, refs) = oci::open_config(&repo, &manifest_hex, Some(&manifest_id))?;
+    assert_eq!(refs.lookup(&layer_sha256), Some(&layer_id));
+
+    // the manifest itself is stored as-is
+    let (image_manifest, refs) = oci::open_manifest(&repo, "refs/image", None)?;
+    assert_eq!(
+        image_manifest.annotations().as_ref().unwrap()["org.example"],
+        "yes"
+    );
+    assert_eq!(refs.lookup(&config_sha256), Some(&config.1));
+    let mut content = vec![];
+    repo.merge_splitstream("refs/image", None, &mut content)?;
+    assert_eq!(content, raw_manifest.as_bytes());
+    assert!(oci::open_manifest(&repo, &hex::encode(config_sha256), None).is_err());
+
+    // the tag keeps the config and the layer alive
+    repo.gc(false)?;
+    assert!(repo.check_stream(&manifest_sha256)?.is_some());
+    assert!(repo.check_stream(&config_sha256)?.is_some());
+    assert!(repo.check_stream(&layer_sha256)?.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn test_platform() -> Result<()> {
+    let platform: Platform = "linux/arm64/v8".parse()?;
+    assert_eq!(platform.os, "linux");
+    assert_eq!(platform.architecture, "arm64");
+    assert_eq!(platform.variant.as_deref(), Some("v8"));
+    assert_eq!(platform.to_string(), "linux/arm64/v8");
+    assert_eq!("linux/amd64".parse::<Platform>()?.variant, None);
+    for invalid in ["linux", "linux/", "/amd64", "linux/arm/v7/extra", ""] {
+        assert!(invalid.parse::<Platform>().is_err(), "{invalid}");
+    }
+
+    let host = Platform::host();
+    assert_eq!(host.os, std::env::consts::OS);
+    assert_eq!(host.variant, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_index() -> Result<()> {
+    let layer = example_layer()?;
+
+    let tmpdir = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    let repo = Repository::<Sha256HashValue>::init(tmpdir.path().join("repo"), &options)?;
+
+    // an image for the host, and one for something else
+    let host = Platform::host();
+    let other = if host.architecture == "s390x" {
+        "riscv64"
+    } else {
+        "s390x"
+    };
+    let (host_manifest, host_config) = store_image(&repo, &layer, &host.architecture)?;
+    let (other_manifest, other_config) = store_image(&repo, &layer, other)?;
+    let raw_index = format!(
+        r#"{{"schemaVersion":2,"mediaType":"{}","manifests":[]}}"#,
+        MediaType::ImageIndex
+    );
+    oci::write_index(&repo, raw_index.as_bytes(), &[other_manifest], None)?;
+    // pulling another platform adds to the index
+    let (index_sha256, index_id) =
+        oci::write_index(&repo, raw_index.as_bytes(), &[host_manifest], Some("image"))?;
+    // a manifest isn't an index
+    assert!(oci::write_index(&repo, b"{}", &[], None).is_err());
+
+    // opening the index finds the image for the host
+    for name in ["refs/image".to_string(), hex::encode(index_sha256)] {
+        let (config, _) = oci::open_config(&repo, &name, None)?;
+        assert_eq!(config.architecture().to_string(), host.architecture);
+        let config = oci::open_config_shallow(&repo, &name, None)?;
+        assert_eq!(config.architecture().to_string(), host.architecture);
+    }
+    let (config, _) = oci::open_config(&repo, "refs/image", Some(&index_id))?;
+    assert_eq!(config.architecture().to_string(), host.architecture);
+
+    // the other image is there too
+    let (index, refs) = oci::open_index(&repo, "refs/image", None)?;
+    assert!(index.manifests().is_empty());
+    assert_eq!(refs.lookup(&other_manifest.0), Some(&other_manifest.1));
+    let (config, _) = oci::open_config(&repo, &hex::encode(other_manifest.0), None)?;
+    assert_eq!(config.architecture().to_string(), other);
+    assert!(oci::open_index(&repo, &hex::encode(other_manifest.0), None).is_err());
+
+    // the index keeps everything alive
+    repo.gc(false)?;
+    for sha256 in [host_config, other_config, sha256(&layer)] {
+        assert!(repo.check_stream(&sha256)?.is_some());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_pull_oci_archive() -> Result<()> {
+    let layer = example_layer()?;
+    let layer_sha256 = sha256(&layer);
+
+    for media_type in LAYER_MEDIA_TYPES {
+        let tmpdir = tempfile::TempDir::with_prefix("composefs-test-")?;
+        let mut options = RepositoryOptions::default();
+        options.insecure = true;
+        let repo = Repository::<Sha256HashValue>::init(tmpdir.path().join("repo"), &options)?;
+
+        let archive = tmpdir.path().join("image.tar");
+        write_oci_archive(&archive, &layer, &media_type, false)?;
+        let imgref = format!("oci-archive:{}", archive.display());
+        runtime()?.block_on(oci::pull(&repo, &imgref, Some("image")))?;
+        assert!(repo.check_stream(&layer_sha256)?.is_some());
+        oci::open_config(&repo, "refs/image", None)?;
+        assert!(repo.fsck()?.problems.is_empty());
+    }
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "needs skopeo"]
+fn test_pull_via_skopeo() -> Result<()> {
+    let layer = example_layer()?;
+    let layer_sha256 = sha256(&layer);
+
+    for media_type in LAYER_MEDIA_TYPES {
+        let tmpdir = tempfile::TempDir::with_prefix("composefs-test-")?;
+        let mut options = RepositoryOptions::default();
+        options.insecure = true;
+        let repo = Repository::<Sha256HashValue>::init(tmpdir.path().join("repo"), &options)?;
+
+        // the image in skopeo's dir: format, which (unlike OCI layouts) isn't read directly
+        let archive = tmpdir.path().join("image.tar");
+        write_oci_archive(&archive, &layer, &media_type, false)?;
+        let layout = tmpdir.path().join("layout");
+        tar::Archive::new(std::fs::File::open(&archive)?).unpack(&layout)?;
+        let dir = tmpdir.path().join("dir");
+        std::fs::create_dir(&dir)?;
+        for blob in std::fs::read_dir(layout.join("blobs/sha256"))? {
+            let blob = blob?;
+            std::fs::copy(blob.path(), dir.join(blob.file_name()))?;
+        }
+        let index = ImageIndex::from_file(layout.join("index.json"))?;
+        let manifest_digest = index.manifests()[0].digest().to_string();
+        let manifest_hex = manifest_digest.strip_prefix("sha256:").unwrap();
+        std::fs::copy(dir.join(manifest_hex), dir.join("manifest.json"))?;
+        std::fs::write(dir.join("version"), "Directory Transport Version: 1.1\n")?;
+
+        let imgref = format!("dir:{}", dir.display());
+        runtime()?.block_on(oci::pull(&repo, &imgref, Some("image")))?;
+        assert!(repo.check_stream(&layer_sha256)?.is_some());
+        // the manifest was stored as it was fetched
+        assert_eq!(
+            hex::encode(repo.show_ref::<Sha256HashValue>("streams", "image")?),
+            manifest_hex
+        );
+        oci::open_config(&repo, "refs/image", None)?;
+        assert!(repo.fsck()?.problems.is_empty());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_pull_tampered_layer() -> Result<()> {
+    let layer = example_layer()?;
+    let tmpdir = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    let repo = Repository::<Sha256HashValue>::init(tmpdir.path().join("repo"), &options)?;
+
+    // the pull fails before the ref is written
+    let archive = tmpdir.path().join("image.tar");
+    write_oci_archive(&archive, &layer, &MediaType::ImageLayer, true)?;
+    let imgref = format!("oci-archive:{}", archive.display());
+    assert!(runtime()?
+        .block_on(oci::pull(&repo, &imgref, Some("image")))
+        .is_err());
+    assert!(repo
+        .show_ref::<Sha256HashValue>("streams", "image")
+        .is_err());
+
+    // the same goes for the manifest
+    let mut builder = tar::Builder::new(vec![]);
+    let manifest = format!(
+        r#"{{"schemaVersion":2,"mediaType":"{}","config":{{"mediaType":"{}","digest":"sha256:{}","size":0}},"layers":[]}}"#,
+        MediaType::ImageManifest,
+        MediaType::ImageConfig,
+        hex::encode(sha256(b""))
+    );
+    let descriptor = append_blob(
+        &mut builder,
+        &MediaType::ImageManifest,
+        manifest.as_bytes(),
+        true,
+    )?;
+    write_archive(&archive, builder, &descriptor)?;
+    let err = runtime()?
+        .block_on(oci::pull(&repo, &imgref, Some("image")))
+        .unwrap_err();
+    assert!(matches!(err, composefs::Error::DescriptorMismatch { .. }));
+
+    Ok(())
+}
+
+#[test]
+fn test_pull_multi_platform() -> Result<()> {
+    let layer = example_layer()?;
+    let tmpdir = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    let repo = Repository::<Sha256HashValue>::init(tmpdir.path().join("repo"), &options)?;
+
+    let archive = tmpdir.path().join("image.tar");
+    write_multi_platform_archive(&archive, &layer, &["amd64", "arm64"])?;
+    let imgref = format!("oci-archive:{}", archive.display());
+
+    // one platform
+    let mut options = PullOptions::default();
+    options.platform = Some("linux/arm64".parse()?);
+    runtime()?.block_on(oci::pull_with_options(
+        &repo,
+        &imgref,
+        Some("arm64"),
+        &options,
+    ))?;
+    let (_, refs) = oci::open_index(&repo, "refs/arm64", None)?;
+    assert_eq!(refs.map.len(), 1);
+    let (manifest, _) = oci::open_manifest(&repo, &hex::encode(refs.map[0].body), None)?;
+    assert_eq!(manifest.layers().len(), 1);
+
+    // all of them
+    let mut options = PullOptions::default();
+    options.all_platforms = true;
+    runtime()?.block_on(oci::pull_with_options(
+        &repo,
+        &imgref,
+        Some("all"),
+        &options,
+    ))?;
+    let (index, refs) = oci::open_index(&repo, "refs/all", None)?;
+    assert_eq!(index.manifests().len(), 2);
+    assert_eq!(refs.map.len(), 2);
+
+    // a platform that isn't there
+    let mut options = PullOptions::default();
+    options.platform = Some("linux/s390x".parse()?);
+    assert!(runtime()?
+        .block_on(oci::pull_with_options(&repo, &imgref, None, &options))
+        .is_err());
+
+    assert!(repo.fsck()?.problems.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_pull_oci_layout() -> Result<()> {
+    let layer = example_layer()?;
+    let tmpdir = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    let repo = Repository::<Sha256HashValue>::init(tmpdir.path().join("repo"), &options)?;
+
+    // the same image, as an oci-archive and as an OCI layout directory
+    let archive = tmpdir.path().join("image.tar");
+    write_oci_archive(&archive, &layer, &MediaType::ImageLayerGzip, false)?;
+    let layout = tmpdir.path().join("layout");
+    tar::Archive::new(std::fs::File::open(&archive)?).unpack(&layout)?;
+
+    let archive_ref = format!("oci-archive:{}:latest", archive.display());
+    runtime()?.block_on(oci::pull(&repo, &archive_ref, Some("archive")))?;
+    let layout_ref = format!("oci:{}", layout.display());
+    runtime()?.block_on(oci::pull(&repo, &layout_ref, Some("layout")))?;
+    let archive_manifest = repo.show_ref::<Sha256HashValue>("streams", "archive")?;
+    assert_eq!(
+        archive_manifest,
+        repo.show_ref::<Sha256HashValue>("streams", "layout")?
+    );
+    assert!(repo.check_stream(&sha256(&layer))?.is_some());
+
+    // the manifest was stored as-is, so it's the one in the layout
+    let index = ImageIndex::from_file(layout.join("index.json"))?;
+    assert_eq!(
+        index.manifests()[0].digest().to_string(),
+        format!("sha256:{}", hex::encode(archive_manifest))
+    );
+
+    // images are picked by the name in the index
+    let missing = format!("oci:{}:missing", layout.display());
+    let err = runtime()?
+        .block_on(oci::pull(&repo, &missing, None))
+        .unwrap_err();
+    assert!(err.to_string().contains("missing"));
+
+    assert!(repo.fsck()?.problems.is_empty());
+    Ok(())
+}
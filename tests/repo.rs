@@ -1,9 +1,24 @@
-use std::{fmt::Write, fs::create_dir_all, io::Read, path::PathBuf};
+use std::{
+    fmt::Write as _,
+    fs::create_dir_all,
+    io::{Read, Seek, Write},
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
 use sha2::{Digest, Sha256};
 
-use composefs::{oci, repository::Repository};
+use composefs::{
+    erofs::writer::mkfs_erofs,
+    fsverity::{digest::FsVerityHasher, FsVerityParams, Sha256HashValue, Sha512HashValue},
+    image::FileSystem,
+    oci,
+    repository::{
+        FsckCategory, LockWait, OpenOptions, Repository, RepositoryOptions, REPOSITORY_VERSION,
+    },
+};
 
 fn append_data(builder: &mut tar::Builder<Vec<u8>>, name: &str, size: usize) -> Result<()> {
     let mut header = tar::Header::new_ustar();
@@ -24,6 +39,16 @@ fn example_layer() -> Result<Vec<u8>> {
     Ok(builder.into_inner()?)
 }
 
+/// An image of an empty directory with the given mtime
+fn empty_image(mtime: i64) -> Result<Vec<u8>> {
+    let mut fs = FileSystem::<Sha256HashValue>::new();
+    fs.root.stat.st_mode = 0o755;
+    fs.root.stat.st_uid = 0;
+    fs.root.stat.st_gid = 0;
+    fs.root.stat.st_mtim_sec = mtime;
+    Ok(mkfs_erofs(&fs)?)
+}
+
 fn test_global_tmpdir() -> Result<PathBuf> {
     if let Some(path) = std::env::var_os("CFS_TEST_TMPDIR") {
         return Ok(path.into());
@@ -45,7 +70,7 @@ fn test_layer() -> Result<()> {
     let layer_id: [u8; 32] = context.finalize().into();
 
     let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
-    let repo = Repository::open_path(tmpfile.path().to_path_buf())?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
     let id = oci::import_layer(&repo, &layer_id, Some("name"), &mut layer.as_slice())?;
 
     let mut dump = String::new();
@@ -61,3 +86,859 @@ fn test_layer() -> Result<()> {
 ");
     Ok(())
 }
+
+#[test]
+fn test_gc() -> Result<()> {
+    let layer = example_layer()?;
+    let mut context = Sha256::new();
+    context.update(&layer);
+    let layer_id: [u8; 32] = context.finalize().into();
+
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+
+    // an unnamed stream is garbage, along with all of its objects
+    oci::import_layer(&repo, &layer_id, None, &mut layer.as_slice())?;
+
+    let report = repo.gc(true)?;
+    assert_eq!(report.objects_removed, 4); // three files plus the stream itself
+    assert!(report
+        .removed
+        .contains(&format!("streams/{}", hex::encode(layer_id))));
+    assert!(repo.has_stream(&layer_id)?.is_some());
+
+    let report = repo.gc(false)?;
+    assert_eq!(report.objects_removed, 4);
+    assert!(repo.has_stream(&layer_id)?.is_none());
+
+    // a named stream keeps everything alive
+    oci::import_layer(&repo, &layer_id, Some("name"), &mut layer.as_slice())?;
+    let report = repo.gc(false)?;
+    assert_eq!(report.objects_removed, 0);
+    assert!(report.removed.is_empty());
+    assert!(repo.check_stream(&layer_id)?.is_some());
+
+    // a dangling ref is reported, but doesn't stop GC
+    let missing = hex::encode([0xaa; 32]);
+    std::os::unix::fs::symlink(
+        format!("../{missing}"),
+        tmpfile.path().join("streams/refs/dangling"),
+    )?;
+    let report = repo.gc(false)?;
+    assert_eq!(report.dangling, [format!("streams/{missing}")]);
+    assert_eq!(report.objects_removed, 0);
+    assert!(repo.check_stream(&layer_id)?.is_some());
+
+    // ...and so is anything else that shouldn't be in the refs
+    std::os::unix::fs::symlink("../streams/00", tmpfile.path().join("streams/refs/broken"))?;
+    std::fs::create_dir(tmpfile.path().join("streams/refs/dir"))?;
+    std::fs::write(tmpfile.path().join("streams/refs/dir/file"), "")?;
+    let mut report = repo.gc(false)?;
+    report.invalid.sort();
+    assert_eq!(
+        report.invalid,
+        ["streams/refs/broken", "streams/refs/dir/file"]
+    );
+    assert_eq!(report.objects_removed, 0);
+    assert!(repo.check_stream(&layer_id)?.is_some());
+    assert!(tmpfile.path().join("streams/refs/dir/file").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_transaction() -> Result<()> {
+    let layer = example_layer()?;
+    let layer_id: [u8; 32] = Sha256::digest(&layer).into();
+
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+
+    // everything written during the transaction survives GC until it's committed
+    let mut transaction = repo.begin_transaction()?;
+    assert!(repo.begin_transaction().is_err());
+    oci::import_layer(&repo, &layer_id, None, &mut layer.as_slice())?;
+    let image = repo.write_image(None, &empty_image(1)?)?;
+    transaction.update_ref("streams", "layer", &layer_id, None)?;
+    transaction.update_ref("images", "image", &image, None)?;
+    assert!(repo
+        .show_ref::<Sha256HashValue>("streams", "layer")
+        .is_err());
+
+    let report = repo.gc(false)?;
+    assert_eq!(report.objects_removed, 0);
+    assert!(repo.check_stream(&layer_id)?.is_some());
+
+    transaction.commit()?;
+    assert_eq!(
+        repo.show_ref::<Sha256HashValue>("streams", "layer")?,
+        layer_id
+    );
+    assert_eq!(repo.show_ref::<Sha256HashValue>("images", "image")?, image);
+    assert_eq!(repo.gc(false)?.objects_removed, 0);
+
+    // a failed commit doesn't update any of the refs
+    let mut transaction = repo.begin_transaction()?;
+    let other = repo.write_image(None, &empty_image(2)?)?;
+    transaction.update_ref("images", "image", &other, None)?;
+    transaction.update_ref("images", "image2", &other, Some(&image))?;
+    assert!(transaction.commit().is_err());
+    assert_eq!(repo.show_ref::<Sha256HashValue>("images", "image")?, image);
+
+    // ...and if applying an update fails, the ones already applied are rolled back
+    let mut transaction = repo.begin_transaction()?;
+    transaction.update_ref("images", "image", &other, Some(&image))?;
+    transaction.update_ref("images", "new", &other, None)?;
+    transaction.update_ref("images", "new/nested", &other, None)?;
+    assert!(transaction.commit().is_err());
+    assert_eq!(repo.show_ref::<Sha256HashValue>("images", "image")?, image);
+    assert!(repo.show_ref::<Sha256HashValue>("images", "new").is_err());
+
+    // ...and what was written in it is garbage now
+    let report = repo.gc(false)?;
+    assert_eq!(report.objects_removed, 1);
+    assert!(report
+        .removed
+        .contains(&format!("images/{}", hex::encode(other))));
+
+    // the journal of a transaction whose process died is cleaned up
+    std::fs::write(tmpfile.path().join("transactions/1-0"), b"object 00")?;
+    let report = repo.gc(false)?;
+    assert!(report.removed.contains(&"transactions/1-0".to_string()));
+    assert!(!tmpfile.path().join("transactions/1-0").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_lock_wait() -> Result<()> {
+    // no objects are involved, so anywhere will do
+    let tmpfile = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let path = tmpfile.path().to_path_buf();
+    Repository::<Sha256HashValue>::init(path.clone(), &RepositoryOptions::default())?;
+    let is_busy = |err: &composefs::Error| matches!(err, composefs::Error::RepositoryBusy { .. });
+
+    let mut options = OpenOptions::default();
+    options.lock_wait = LockWait::NonBlocking;
+
+    // the repository can't be opened while GC (ie: an exclusive lock) is in progress
+    let fd = rustix::fs::open(&path, rustix::fs::OFlags::RDONLY, rustix::fs::Mode::empty())?;
+    rustix::fs::flock(&fd, rustix::fs::FlockOperation::LockExclusive)?;
+    let result = Repository::<Sha256HashValue>::open_path_with_options(path.clone(), &options);
+    assert!(is_busy(&result.err().unwrap()));
+    drop(fd);
+
+    let repo = Repository::<Sha256HashValue>::open_path_with_options(path.clone(), &options)?;
+    let other = Repository::<Sha256HashValue>::open_path_with_options(path.clone(), &options)?;
+
+    // GC can't run while somebody else has the repository open
+    assert!(is_busy(&repo.gc(false).unwrap_err()));
+    drop(repo);
+    options.lock_wait = LockWait::Timeout(Duration::from_millis(100));
+    let repo = Repository::<Sha256HashValue>::open_path_with_options(path.clone(), &options)?;
+    let start = Instant::now();
+    assert!(is_busy(&repo.gc(false).unwrap_err()));
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    // ...but the failed attempt didn't leave the repository unlocked
+    drop(other);
+    let fd = rustix::fs::open(&path, rustix::fs::OFlags::RDONLY, rustix::fs::Mode::empty())?;
+    assert!(rustix::fs::flock(&fd, rustix::fs::FlockOperation::NonBlockingLockExclusive).is_err());
+    drop(fd);
+
+    // refs can't be changed while somebody else holds the lock on them
+    let fd = rustix::fs::open(
+        path.join("streams/refs"),
+        rustix::fs::OFlags::RDONLY | rustix::fs::OFlags::DIRECTORY,
+        rustix::fs::Mode::empty(),
+    )?;
+    rustix::fs::flock(&fd, rustix::fs::FlockOperation::LockExclusive)?;
+    assert!(is_busy(&repo.remove_ref("streams", "missing").unwrap_err()));
+    drop(fd);
+
+    // nor does a successful one
+    repo.gc(false)?;
+    Repository::<Sha256HashValue>::open_path_with_options(path.clone(), &options)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_fsck() -> Result<()> {
+    let layer = example_layer()?;
+    let mut context = Sha256::new();
+    context.update(&layer);
+    let layer_id: [u8; 32] = context.finalize().into();
+
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+    oci::import_layer(&repo, &layer_id, Some("name"), &mut layer.as_slice())?;
+    assert!(repo.fsck()?.problems.is_empty());
+
+    let fsck_categories = || -> Result<Vec<FsckCategory>> {
+        Ok(repo
+            .fsck()?
+            .problems
+            .iter()
+            .map(|problem| problem.category)
+            .collect())
+    };
+
+    // remove the object for file4096
+    std::fs::remove_file(
+        tmpfile
+            .path()
+            .join("objects/ba/bc284ee4ffe7f449377fbf6692715b43aec7bc39c094a95878904d34bac97e"),
+    )?;
+    assert_eq!(
+        fsck_categories()?,
+        [FsckCategory::Stream, FsckCategory::MissingObject]
+    );
+
+    // point the stream at an object that doesn't exist, breaking the ref as well
+    let stream_path = tmpfile.path().join("streams").join(hex::encode(layer_id));
+    std::fs::remove_file(&stream_path)?;
+    std::os::unix::fs::symlink(
+        "../objects/00/0000000000000000000000000000000000000000000000000000000000000000",
+        &stream_path,
+    )?;
+    assert_eq!(
+        fsck_categories()?,
+        [FsckCategory::Symlink, FsckCategory::Ref]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_ensure_object_from_reader() -> Result<()> {
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+
+    for size in [0, 4095, 4096, 4097, 3 << 20] {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let id = repo.ensure_object_from_reader(&mut data.as_slice())?;
+        assert_eq!(id, FsVerityHasher::<Sha256HashValue>::hash(&data));
+        // storing it again is a no-op
+        assert_eq!(repo.ensure_object(&data)?, id);
+
+        let mut stored = vec![];
+        std::fs::File::from(repo.open_object(&id)?).read_to_end(&mut stored)?;
+        assert_eq!(stored, data);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_ensure_object_from_fd() -> Result<()> {
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+
+    // on the same filesystem as the repository, so this can use a reflink
+    let data: Vec<u8> = (0..(5 << 20)).map(|i| (i % 251) as u8).collect();
+    let mut file = tempfile::tempfile_in(tmpfile.path())?;
+    file.write_all(&data)?;
+
+    let id = repo.ensure_object_from_fd(&file)?;
+    assert_eq!(id, FsVerityHasher::<Sha256HashValue>::hash(&data));
+    // the file offset is left alone
+    assert_eq!(file.stream_position()?, data.len() as u64);
+
+    let mut stored = vec![];
+    std::fs::File::from(repo.open_object(&id)?).read_to_end(&mut stored)?;
+    assert_eq!(stored, data);
+
+    // storing it again is a no-op
+    assert_eq!(repo.ensure_object_from_fd(&file)?, id);
+
+    Ok(())
+}
+
+#[test]
+fn test_ensure_objects() -> Result<()> {
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+
+    // including duplicates, and an object that's already there
+    let objects: Vec<Vec<u8>> = (0..100).map(|i| vec![(i % 50) as u8; 4096]).collect();
+    let existing = repo.ensure_object(&objects[10])?;
+    let ids = repo.ensure_objects(&objects)?;
+    assert_eq!(ids.len(), objects.len());
+    assert_eq!(ids[10], existing);
+    for (data, id) in objects.iter().zip(&ids) {
+        assert_eq!(*id, FsVerityHasher::<Sha256HashValue>::hash(data));
+        let mut stored = vec![];
+        std::fs::File::from(repo.open_object(id)?).read_to_end(&mut stored)?;
+        assert_eq!(stored, *data);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_read_from_path() -> Result<()> {
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    create_dir_all(tmpfile.path().join("repo"))?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().join("repo"))?;
+
+    // large files (some of them hardlinked) in a few directories, and a small one
+    let tree = tmpfile.path().join("tree");
+    let mut contents = vec![];
+    for dir in ["a", "a/b", "c"] {
+        create_dir_all(tree.join(dir))?;
+        for i in 0..10u8 {
+            let data = vec![i ^ dir.len() as u8; 5000 + usize::from(i)];
+            std::fs::write(tree.join(format!("{dir}/{i}")), &data)?;
+            contents.push(data);
+        }
+        std::fs::hard_link(
+            tree.join(format!("{dir}/0")),
+            tree.join(format!("{dir}/link")),
+        )?;
+    }
+    std::fs::write(tree.join("small"), b"small")?;
+
+    let fs = composefs::fs::read_from_path(&tree, Some(&repo))?;
+    for data in &contents {
+        let id = FsVerityHasher::<Sha256HashValue>::hash(data);
+        let mut stored = vec![];
+        std::fs::File::from(repo.open_object(&id)?).read_to_end(&mut stored)?;
+        assert_eq!(stored, *data);
+    }
+
+    // the same as without a repository, hardlinks included
+    let mut dumpfile = vec![];
+    composefs::dumpfile::write_dumpfile(&mut dumpfile, &fs)?;
+    let mut expected = vec![];
+    let fs = composefs::fs::read_from_path::<Sha256HashValue>(&tree, None)?;
+    composefs::dumpfile::write_dumpfile(&mut expected, &fs)?;
+    assert_eq!(String::from_utf8(dumpfile)?, String::from_utf8(expected)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_layer_batches() -> Result<()> {
+    // enough files for several batches of objects
+    let mut builder = tar::Builder::new(vec![]);
+    for i in 0..3000 {
+        append_data(&mut builder, &format!("file{i}"), 65 + i)?;
+    }
+    let layer = builder.into_inner()?;
+    let layer_id: [u8; 32] = Sha256::digest(&layer).into();
+
+    let mut ids = vec![];
+    for _ in 0..2 {
+        let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+        let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+        ids.push(oci::import_layer(
+            &repo,
+            &layer_id,
+            None,
+            &mut layer.as_slice(),
+        )?);
+
+        let mut merged = vec![];
+        repo.merge_splitstream(&hex::encode(layer_id), Some(&ids[0]), &mut merged)?;
+        assert!(merged == layer);
+    }
+    // the stream doesn't depend on the order in which the objects were stored
+    assert_eq!(ids[0], ids[1]);
+
+    Ok(())
+}
+
+#[test]
+fn test_refs() -> Result<()> {
+    let layer = example_layer()?;
+    let mut context = Sha256::new();
+    context.update(&layer);
+    let layer_id: [u8; 32] = context.finalize().into();
+
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+    oci::import_layer(&repo, &layer_id, Some("a/b/c"), &mut layer.as_slice())?;
+    repo.name_stream(layer_id, "d")?;
+
+    let names = |prefix| -> Result<Vec<String>> {
+        Ok(repo
+            .list_refs::<Sha256HashValue>("streams", prefix)?
+            .into_iter()
+            .map(|(name, id)| {
+                assert_eq!(id, layer_id);
+                name
+            })
+            .collect())
+    };
+    assert_eq!(names("")?, ["system/a/b/c", "system/d"]);
+    assert_eq!(names("system/a")?, ["system/a/b/c"]);
+    assert_eq!(names("refs/system/a/b")?, ["system/a/b/c"]);
+    assert!(names("x")?.is_empty());
+    assert_eq!(
+        repo.show_ref::<Sha256HashValue>("streams", "refs/a/b/c")?,
+        layer_id
+    );
+
+    // can't rename on top of an existing ref
+    assert!(repo
+        .rename_ref("streams", "system/d", "system/a/b/c")
+        .is_err());
+
+    repo.rename_ref("streams", "system/a/b/c", "system/e/f")?;
+    assert_eq!(names("")?, ["system/d", "system/e/f"]);
+    assert!(!tmpfile.path().join("streams/refs/system/a").exists());
+    assert!(repo.open_stream("refs/e/f", None).is_ok());
+
+    repo.remove_ref("streams", "system/e/f")?;
+    assert_eq!(names("")?, ["system/d"]);
+    assert!(!tmpfile.path().join("streams/refs/system/e").exists());
+    assert!(tmpfile.path().join("streams/refs").exists());
+    assert!(repo.remove_ref("streams", "system/e/f").is_err());
+    assert!(repo.show_ref::<Sha256HashValue>("streams", "../d").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_update_ref() -> Result<()> {
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+
+    let mut ids = vec![];
+    for layer in [example_layer()?, tar::Builder::new(vec![]).into_inner()?] {
+        let mut context = Sha256::new();
+        context.update(&layer);
+        let layer_id: [u8; 32] = context.finalize().into();
+        oci::import_layer(&repo, &layer_id, Some("latest"), &mut layer.as_slice())?;
+        ids.push(layer_id);
+    }
+    let [first, second] = ids[..] else {
+        unreachable!()
+    };
+
+    // importing the second layer under the same name replaced the ref
+    assert_eq!(
+        repo.show_ref::<Sha256HashValue>("streams", "latest")?,
+        second
+    );
+    repo.name_stream(first, "latest")?;
+    assert_eq!(
+        repo.show_ref::<Sha256HashValue>("streams", "latest")?,
+        first
+    );
+
+    // compare-and-swap only succeeds if the ref points where we expect
+    assert!(repo
+        .update_ref("streams", "system/latest", &second, Some(&second))
+        .is_err());
+    assert_eq!(
+        repo.show_ref::<Sha256HashValue>("streams", "latest")?,
+        first
+    );
+    repo.update_ref("streams", "system/latest", &second, Some(&first))?;
+    assert_eq!(
+        repo.show_ref::<Sha256HashValue>("streams", "latest")?,
+        second
+    );
+    assert!(repo
+        .update_ref("streams", "system/missing", &first, Some(&second))
+        .is_err());
+
+    // the target has to exist
+    assert!(repo
+        .update_ref("streams", "system/latest", &[0; 32], None)
+        .is_err());
+
+    // no temporary symlinks are left behind
+    assert_eq!(
+        std::fs::read_dir(tmpfile.path().join("streams/refs"))?.count(),
+        1
+    );
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "needs root, to chown"]
+fn test_ref_namespaces() -> Result<()> {
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+    let image = repo.write_image(None, b"not really an image")?;
+
+    assert_eq!(
+        Repository::<Sha256HashValue>::namespaced_ref_name("refs/a/b"),
+        "system/a/b"
+    );
+
+    repo.update_ref("images", "1000/app/latest", &image, None)?;
+    assert_eq!(
+        repo.show_ref::<Sha256HashValue>("images", "1000/app/latest")?,
+        image
+    );
+
+    // the namespace belongs to root, and the directories below it to the user
+    let namespace_dir = tmpfile.path().join("images/refs/1000");
+    let namespace = std::fs::metadata(&namespace_dir)?;
+    // with an ACL, the group bits of the mode are the ACL mask
+    assert_eq!((namespace.uid(), namespace.mode() & 0o7707), (0, 0o700));
+    let mut acl = [0u8; 64];
+    let size = rustix::fs::getxattr(&namespace_dir, "system.posix_acl_access", &mut acl)?;
+    assert_eq!(size, 4 + 5 * 8);
+    assert_eq!(acl[12..20], [2, 0, 7, 0, 0xe8, 3, 0, 0]); // user:1000:rwx
+    let app = std::fs::metadata(tmpfile.path().join("images/refs/1000/app"))?;
+    assert_eq!(app.uid(), 1000);
+
+    // removing the last ref keeps the namespace around
+    repo.remove_ref("images", "1000/app/latest")?;
+    assert!(!tmpfile.path().join("images/refs/1000/app").exists());
+    assert!(tmpfile.path().join("images/refs/1000").exists());
+
+    repo.create_ref_namespace(1001)?;
+    assert!(tmpfile.path().join("streams/refs/1001").is_dir());
+
+    // root's refs go in the system namespace, unless they name one already
+    assert_eq!(
+        Repository::<Sha256HashValue>::default_ref_name("refs/app/latest"),
+        "system/app/latest"
+    );
+    assert_eq!(
+        Repository::<Sha256HashValue>::default_ref_name("refs/1000/app/latest"),
+        "1000/app/latest"
+    );
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "needs root, to create a ref namespace"]
+fn test_ref_namespace_symlinks() -> Result<()> {
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+    let image = repo.write_image(None, b"not really an image")?;
+    repo.create_ref_namespace(1000)?;
+
+    // the user plants symlinks to somewhere outside of the repository in their namespace
+    let outside = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    std::os::unix::fs::symlink(outside.path().join("target"), outside.path().join("latest"))?;
+    let namespace = tmpfile.path().join("images/refs/1000");
+    std::os::unix::fs::symlink(outside.path(), namespace.join("app"))?;
+
+    // root doesn't create, replace, rename or remove anything through them
+    assert!(repo
+        .update_ref("images", "1000/app/latest", &image, None)
+        .is_err());
+    assert!(repo
+        .update_ref("images", "1000/app/sub/latest", &image, None)
+        .is_err());
+    assert!(repo.remove_ref("images", "1000/app/latest").is_err());
+    assert!(repo
+        .rename_ref("images", "1000/app/latest", "1000/other")
+        .is_err());
+    assert!(repo
+        .show_ref::<Sha256HashValue>("images", "1000/app/latest")
+        .is_err());
+    assert!(repo
+        .list_refs::<Sha256HashValue>("images", "1000/app")
+        .is_err());
+
+    let names = std::fs::read_dir(outside.path())?
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(names, ["latest"]);
+    assert_eq!(
+        std::fs::read_link(outside.path().join("latest"))?,
+        outside.path().join("target")
+    );
+    assert!(namespace.join("app").is_symlink());
+
+    // and refs elsewhere in the namespace still work
+    repo.update_ref("images", "1000/real/latest", &image, None)?;
+    assert_eq!(
+        repo.show_ref::<Sha256HashValue>("images", "1000/real/latest")?,
+        image
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_init() -> Result<()> {
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let path = tmpfile.path().join("repo");
+
+    let repo = Repository::<Sha256HashValue>::init(path.clone(), &RepositoryOptions::default())?;
+    let metadata = repo.metadata().expect("new repository has metadata");
+    // with the default fs-verity parameters, older versions can open the repository
+    assert_eq!(metadata.version, 1);
+    assert_eq!(metadata.algorithm, "sha256");
+    assert_eq!(metadata.fsverity, FsVerityParams::default());
+    assert!(path.join("objects/00").is_dir());
+    assert!(path.join("objects/ff").is_dir());
+    assert!(path.join("images/refs").is_dir());
+    assert!(path.join("streams/refs").is_dir());
+
+    // gc works on a fresh repository
+    assert_eq!(repo.gc(false)?.objects_removed, 0);
+    drop(repo);
+
+    // can't initialize it twice
+    assert!(
+        Repository::<Sha256HashValue>::init(path.clone(), &RepositoryOptions::default()).is_err()
+    );
+
+    // reopening checks the version
+    assert!(Repository::<Sha256HashValue>::open_path(path.clone())?
+        .metadata()
+        .is_some());
+    let meta = std::fs::read_to_string(path.join("meta"))?;
+    std::fs::write(
+        path.join("meta"),
+        meta.replace(
+            "version = 1",
+            &format!("version = {}", REPOSITORY_VERSION + 1),
+        ),
+    )?;
+    assert!(matches!(
+        Repository::<Sha256HashValue>::open_path(path.clone()),
+        Err(composefs::Error::UnsupportedRepository { .. })
+    ));
+
+    // repositories from before metadata existed can still be opened
+    std::fs::remove_file(path.join("meta"))?;
+    assert!(Repository::<Sha256HashValue>::open_path(path)?
+        .metadata()
+        .is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_insecure() -> Result<()> {
+    let layer = example_layer()?;
+    let mut context = Sha256::new();
+    context.update(&layer);
+    let layer_id: [u8; 32] = context.finalize().into();
+
+    // no fs-verity required, so anywhere will do
+    let tmpfile = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    let repo = Repository::<Sha256HashValue>::init(tmpfile.path().to_path_buf(), &options)?;
+    assert!(repo.is_insecure());
+
+    let id = oci::import_layer(&repo, &layer_id, Some("name"), &mut layer.as_slice())?;
+    assert_eq!(repo.check_stream(&layer_id)?, Some(id));
+    assert!(repo.open_stream("refs/name", Some(&id)).is_ok());
+    assert!(repo.fsck()?.problems.is_empty());
+
+    // there's no fs-verity to check signatures, so they're ignored
+    let image = repo.write_image_signed(Some("image"), b"not really an image", b"bogus")?;
+    assert_eq!(repo.show_ref::<Sha256HashValue>("images", "image")?, image);
+
+    // contents are still checked against their (userspace-computed) digests
+    std::fs::write(
+        tmpfile
+            .path()
+            .join("objects/ba/bc284ee4ffe7f449377fbf6692715b43aec7bc39c094a95878904d34bac97e"),
+        [1u8; 4096],
+    )?;
+    assert!(!repo.fsck()?.problems.is_empty());
+    assert!(repo.check_stream(&layer_id).unwrap_err().is_corruption());
+    assert!(matches!(
+        repo.open_object(&[0xaa; 32]),
+        Err(composefs::Error::ObjectMissing { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_sign_existing_image() -> Result<()> {
+    let tmpfile = tempfile::TempDir::with_prefix_in("composefs-test-", test_global_tmpdir()?)?;
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+    let image = repo.write_image(Some("image"), b"not really an image")?;
+
+    // fs-verity is already enabled on the image, so it can't be given a signature now
+    assert!(repo
+        .write_image_signed(Some("signed"), b"not really an image", b"bogus")
+        .is_err());
+    assert!(repo
+        .show_ref::<Sha256HashValue>("images", "signed")
+        .is_err());
+    assert_eq!(repo.show_ref::<Sha256HashValue>("images", "image")?, image);
+
+    Ok(())
+}
+
+#[test]
+fn test_sha512() -> Result<()> {
+    let layer = example_layer()?;
+    let mut context = Sha256::new();
+    context.update(&layer);
+    let layer_id: [u8; 32] = context.finalize().into();
+
+    // insecure, so that this works without fs-verity support
+    let tmpfile = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    let repo = Repository::<Sha512HashValue>::init(tmpfile.path().to_path_buf(), &options)?;
+    assert_eq!(repo.metadata().unwrap().algorithm, "sha512");
+
+    let id = oci::import_layer(&repo, &layer_id, Some("name"), &mut layer.as_slice())?;
+    assert_eq!(repo.check_stream(&layer_id)?, Some(id));
+    assert_eq!(
+        repo.show_ref::<Sha256HashValue>("streams", "name")?,
+        layer_id
+    );
+    let mut split_stream = repo.open_stream("refs/name", Some(&id))?;
+    let mut entries = 0;
+    while oci::tar::get_entry(&mut split_stream)?.is_some() {
+        entries += 1;
+    }
+    assert_eq!(entries, 4);
+    assert!(repo.fsck()?.problems.is_empty());
+
+    let data = vec![0x5a; 10000];
+    let object = repo.ensure_object(&data)?;
+    assert_eq!(object, FsVerityHasher::<Sha512HashValue>::hash(&data));
+    let pathname = format!("objects/{:02x}/{}", object[0], hex::encode(&object[1..]));
+    assert!(tmpfile.path().join(pathname).is_file());
+
+    let image = repo.write_image(Some("image"), b"not really an image")?;
+    assert_eq!(repo.show_ref::<Sha512HashValue>("images", "image")?, image);
+    repo.remove_ref("images", "system/image")?;
+
+    assert_eq!(repo.gc(false)?.objects_removed, 2); // the object and the image
+    assert!(repo.check_stream(&layer_id)?.is_some());
+    drop(repo);
+
+    // the repository can't be opened with the wrong algorithm
+    assert!(matches!(
+        Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf()),
+        Err(composefs::Error::UnsupportedRepository { .. })
+    ));
+    assert!(Repository::<Sha512HashValue>::open_path(tmpfile.path().to_path_buf()).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_fsverity_params() -> Result<()> {
+    let layer = example_layer()?;
+    let mut context = Sha256::new();
+    context.update(&layer);
+    let layer_id: [u8; 32] = context.finalize().into();
+
+    // insecure, so that this works without fs-verity support for these parameters
+    let tmpfile = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let params = FsVerityParams::new(1024, b"composefs")?;
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    options.fsverity = params.clone();
+    let repo = Repository::<Sha256HashValue>::init(tmpfile.path().to_path_buf(), &options)?;
+    assert_eq!(repo.metadata().unwrap().version, REPOSITORY_VERSION);
+    assert_eq!(repo.fsverity_params(), &params);
+
+    let data = vec![0x5a; 10000];
+    let object = repo.ensure_object(&data)?;
+    assert_eq!(
+        object,
+        FsVerityHasher::<Sha256HashValue>::hash_with_params(&data, &params)
+    );
+    assert_ne!(object, FsVerityHasher::<Sha256HashValue>::hash(&data));
+    let object = repo.ensure_object_from_reader(&mut data.as_slice())?;
+    assert_eq!(
+        object,
+        FsVerityHasher::<Sha256HashValue>::hash_with_params(&data, &params)
+    );
+
+    oci::import_layer(&repo, &layer_id, Some("name"), &mut layer.as_slice())?;
+    assert!(repo.check_stream(&layer_id)?.is_some());
+    assert!(repo.fsck()?.problems.is_empty());
+    drop(repo);
+
+    // the parameters are remembered
+    let repo = Repository::<Sha256HashValue>::open_path(tmpfile.path().to_path_buf())?;
+    assert_eq!(repo.fsverity_params(), &params);
+    assert!(repo.fsck()?.problems.is_empty());
+
+    Ok(())
+}
+
+/// Counts the objects in the repository at `path`
+fn count_objects(path: &std::path::Path) -> Result<usize> {
+    let mut count = 0;
+    for dir in std::fs::read_dir(path.join("objects"))? {
+        count += std::fs::read_dir(dir?.path())?.count();
+    }
+    Ok(count)
+}
+
+#[test]
+fn test_alternates() -> Result<()> {
+    let layer = example_layer()?;
+    let mut context = Sha256::new();
+    context.update(&layer);
+    let layer_id: [u8; 32] = context.finalize().into();
+
+    // insecure, so that anywhere will do
+    let tmpfile = tempfile::TempDir::with_prefix("composefs-test-")?;
+    let base_path = tmpfile.path().join("base");
+    let mut options = RepositoryOptions::default();
+    options.insecure = true;
+    let base = Repository::<Sha256HashValue>::init(base_path.clone(), &options)?;
+    oci::import_layer(&base, &layer_id, Some("name"), &mut layer.as_slice())?;
+    let base_objects = count_objects(&base_path)?;
+
+    let path = tmpfile.path().join("repo");
+    options.alternates = vec![PathBuf::from("../base")];
+    let repo = Repository::<Sha256HashValue>::init(path.clone(), &options)?;
+    assert_eq!(Vec::from_iter(repo.alternates()), [path.join("../base")]);
+
+    // only the stream itself is stored: its objects are already in the alternate
+    let id = oci::import_layer(&repo, &layer_id, Some("name"), &mut layer.as_slice())?;
+    assert_eq!(count_objects(&path)?, 1);
+    assert_eq!(repo.check_stream(&layer_id)?, Some(id));
+    assert!(repo.fsck()?.problems.is_empty());
+    let data = vec![0x5a; 10000];
+    let object = base.ensure_object(&data)?;
+    assert_eq!(repo.ensure_object(&data)?, object);
+    assert!(repo.open_object(&object).is_ok());
+    assert_eq!(count_objects(&path)?, 1);
+
+    // GC never touches the alternate, even when its objects aren't used by this repository
+    repo.remove_ref("streams", "system/name")?;
+    assert_eq!(repo.gc(false)?.objects_removed, 1);
+    assert_eq!(count_objects(&base_path)?, base_objects + 1);
+
+    // objects missing from the alternate are reported
+    oci::import_layer(&repo, &layer_id, Some("name"), &mut layer.as_slice())?;
+    std::fs::remove_file(
+        base_path.join("objects/ba/bc284ee4ffe7f449377fbf6692715b43aec7bc39c094a95878904d34bac97e"),
+    )?;
+    let categories = Vec::from_iter(repo.fsck()?.problems.iter().map(|p| p.category));
+    assert_eq!(
+        categories,
+        [FsckCategory::Stream, FsckCategory::MissingObject]
+    );
+    drop(repo);
+
+    // an alternate with different digests is refused
+    let other_path = tmpfile.path().join("other");
+    options.alternates = vec![];
+    Repository::<Sha512HashValue>::init(other_path.clone(), &options)?;
+    std::fs::write(
+        path.join("alternates"),
+        format!("{}\n", other_path.display()),
+    )?;
+    assert!(Repository::<Sha256HashValue>::open_path(path).is_err());
+
+    // ... and so is creating a repository with it, before anything is written
+    let refused_path = tmpfile.path().join("refused");
+    options.alternates = vec![other_path];
+    assert!(Repository::<Sha256HashValue>::init(refused_path.clone(), &options).is_err());
+    assert!(!refused_path.exists());
+
+    Ok(())
+}